@@ -0,0 +1,226 @@
+//! Benchmarks of the search cache hot path: deriving Redis keys, sizing
+//! values, the memory cache, the L1 cache in front of Redis, and serving a
+//! large search from the cache or caching it fresh from the backend.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ldap3_proto::proto::{
+    LdapDerefAliases, LdapFilter, LdapPartialAttribute, LdapResult, LdapSearchRequest,
+    LdapSearchResultEntry, LdapSearchScope,
+};
+use ldap3_proto::LdapResultCode;
+use ldap_proxy::metrics::Metrics;
+use ldap_proxy::proxy::{cache_get, cache_set, CachedValue, L1Cache, SearchCacheKey};
+use ldap_proxy::CacheBackend;
+use std::hint::black_box;
+use std::time::SystemTime;
+
+const ENTRY_COUNTS: [usize; 3] = [1, 100, 1000];
+
+/// The entries of a search large enough for copying them to dominate.
+const LARGE_RESULT: usize = 10_000;
+
+fn search_key(n: usize) -> SearchCacheKey {
+    SearchCacheKey::new(
+        "cn=reader,dc=example,dc=com".to_string(),
+        LdapSearchRequest {
+            base: "ou=people,dc=example,dc=com".to_string(),
+            scope: LdapSearchScope::Subtree,
+            aliases: LdapDerefAliases::Never,
+            sizelimit: 0,
+            timelimit: 0,
+            typesonly: false,
+            filter: LdapFilter::And(vec![
+                LdapFilter::Equality("objectClass".to_string(), "person".to_string()),
+                LdapFilter::Equality("uid".to_string(), format!("user{}", n)),
+            ]),
+            attrs: vec!["cn".to_string(), "mail".to_string(), "memberOf".to_string()],
+        },
+        Vec::new(),
+    )
+}
+
+fn cached_value(entries: usize) -> CachedValue {
+    CachedValue {
+        cached_at: SystemTime::now(),
+        entries: (0..entries)
+            .map(|i| {
+                let entry = LdapSearchResultEntry {
+                    dn: format!("uid=user{},ou=people,dc=example,dc=com", i),
+                    attributes: vec![
+                        LdapPartialAttribute {
+                            atype: "cn".to_string(),
+                            vals: vec![format!("User {}", i).into_bytes()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "mail".to_string(),
+                            vals: vec![format!("user{}@example.com", i).into_bytes()],
+                        },
+                        LdapPartialAttribute {
+                            atype: "memberOf".to_string(),
+                            vals: (0..5)
+                                .map(|g| format!("cn=group{},ou=groups,dc=example,dc=com", g))
+                                .map(String::into_bytes)
+                                .collect(),
+                        },
+                    ],
+                };
+                (entry, Vec::new())
+            })
+            .collect(),
+        result: LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: String::new(),
+            message: String::new(),
+            referral: Vec::new(),
+        },
+        ctrl: Vec::new(),
+        source_addr: None,
+    }
+}
+
+fn bench_to_redis_key(c: &mut Criterion) {
+    let key = search_key(0);
+    c.bench_function("search_cache_key/to_redis_key", |b| {
+        b.iter(|| black_box(&key).to_redis_key("ldap_proxy:"))
+    });
+}
+
+fn bench_cached_value_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cached_value/size");
+    for entries in ENTRY_COUNTS {
+        let value = cached_value(entries);
+        group.throughput(Throughput::Elements(entries as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(entries), &value, |b, value| {
+            b.iter(|| black_box(value).size())
+        });
+    }
+    group.finish();
+}
+
+fn bench_memory_cache(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Failed to build runtime");
+    let cache = CacheBackend::memory(64 * 1024 * 1024).expect("Failed to build cache");
+    let cache = &cache;
+    let metrics = &Metrics::default();
+
+    let mut group = c.benchmark_group("memory_cache/set_get");
+    for entries in ENTRY_COUNTS {
+        let value = cached_value(entries);
+        group.bench_with_input(BenchmarkId::from_parameter(entries), &value, |b, value| {
+            b.to_async(&rt).iter_batched(
+                || (search_key(entries), value.clone()),
+                |(key, value)| async move {
+                    cache_set(cache, metrics, key.clone(), value, "", None, &None).await;
+                    black_box(cache_get(cache, metrics, &key, "", &None).await)
+                },
+                BatchSize::SmallInput,
+            )
+        });
+    }
+    group.finish();
+}
+
+/// A search served from the cache takes its only copy out of the cache, and
+/// the entries, result and controls are then moved into the response.
+fn bench_fallback_path(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Failed to build runtime");
+    let cache = CacheBackend::memory(1024 * 1024 * 1024).expect("Failed to build cache");
+    let metrics = Metrics::default();
+    let key = search_key(0);
+    rt.block_on(cache_set(&cache, &metrics, key.clone(), cached_value(LARGE_RESULT), "", None, &None));
+
+    let mut group = c.benchmark_group("search_cache/fallback");
+    group.throughput(Throughput::Elements(LARGE_RESULT as u64));
+    group.bench_function(BenchmarkId::from_parameter(LARGE_RESULT), |b| {
+        b.to_async(&rt).iter(|| async {
+            let value = cache_get(&cache, &metrics, &key, "", &None)
+                .await
+                .expect("The search is not cached");
+            black_box((value.entries, value.result, value.ctrl))
+        })
+    });
+    group.finish();
+}
+
+/// A search fresh from the backend is copied once for the cache, while the
+/// client is sent the original.
+fn bench_fresh_path(c: &mut Criterion) {
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .build()
+        .expect("Failed to build runtime");
+    let cache = CacheBackend::memory(1024 * 1024 * 1024).expect("Failed to build cache");
+    let cache = &cache;
+    let metrics = &Metrics::default();
+    let fresh = cached_value(LARGE_RESULT);
+
+    let mut group = c.benchmark_group("search_cache/fresh");
+    group.throughput(Throughput::Elements(LARGE_RESULT as u64));
+    group.bench_function(BenchmarkId::from_parameter(LARGE_RESULT), |b| {
+        b.to_async(&rt).iter_batched(
+            || (fresh.entries.clone(), fresh.result.clone(), fresh.ctrl.clone()),
+            |(entries, result, ctrl)| async move {
+                let value = CachedValue {
+                    cached_at: SystemTime::now(),
+                    entries: entries.clone(),
+                    result: result.clone(),
+                    ctrl: ctrl.clone(),
+                    source_addr: None,
+                };
+                cache_set(cache, metrics, search_key(0), value, "", None, &None).await;
+                black_box((entries, result, ctrl))
+            },
+            BatchSize::LargeInput,
+        )
+    });
+    group.finish();
+}
+
+fn bench_l1_cache(c: &mut Criterion) {
+    let mut group = c.benchmark_group("l1_cache/get_set");
+    for threads in [1, 4, 16] {
+        let cache = L1Cache::new(1000);
+        let value = cached_value(10);
+        for i in 0..1000 {
+            cache.insert(search_key(i), value.clone());
+        }
+
+        group.throughput(Throughput::Elements(threads as u64 * 100));
+        group.bench_with_input(BenchmarkId::from_parameter(threads), &threads, |b, &threads| {
+            b.iter(|| {
+                std::thread::scope(|s| {
+                    for t in 0..threads {
+                        let cache = &cache;
+                        let value = &value;
+                        s.spawn(move || {
+                            for i in 0..100 {
+                                let key = search_key(t * 100 + i);
+                                // Mostly reads, as in steady state.
+                                if i % 10 == 0 {
+                                    cache.insert(key, value.clone());
+                                } else {
+                                    black_box(cache.get(&key));
+                                }
+                            }
+                        });
+                    }
+                })
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(
+    benches,
+    bench_to_redis_key,
+    bench_cached_value_size,
+    bench_memory_cache,
+    bench_fallback_path,
+    bench_fresh_path,
+    bench_l1_cache
+);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+//! Exponential backoff between attempts to reach a backend that keeps
+//! failing, so that an outage is not met with a fixed rate of connects.
+
+use std::time::Duration;
+
+/// Waits that double with each consecutive failure, from `initial` up to
+/// `max`. Each wait is shortened by a random part of up to `jitter` of it,
+/// so that proxies started together do not probe the backend in step.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Backoff {
+    initial: Duration,
+    max: Duration,
+    jitter: f64,
+}
+
+impl Backoff {
+    /// `jitter` is clamped to between 0 and 1, and `max` to at least
+    /// `initial`.
+    pub fn new(initial: Duration, max: Duration, jitter: f64) -> Self {
+        Backoff {
+            initial,
+            max: max.max(initial),
+            jitter: jitter.clamp(0.0, 1.0),
+        }
+    }
+
+    /// The wait after `failures` consecutive failures beyond the first,
+    /// before jitter is applied.
+    pub fn base_delay(&self, failures: u32) -> Duration {
+        self.initial
+            .checked_mul(2u32.saturating_pow(failures))
+            .map_or(self.max, |delay| delay.min(self.max))
+    }
+
+    /// The wait after `failures` consecutive failures beyond the first.
+    pub fn delay(&self, failures: u32) -> Duration {
+        let base = self.base_delay(failures);
+        if self.jitter == 0.0 {
+            return base;
+        }
+        let mut random = [0u8; 4];
+        // Without randomness the wait is merely not jittered.
+        let fraction = match openssl::rand::rand_bytes(&mut random) {
+            Ok(()) => u32::from_le_bytes(random) as f64 / u32::MAX as f64,
+            Err(_) => 0.0,
+        };
+        base.mul_f64(1.0 - self.jitter * fraction)
+    }
+}
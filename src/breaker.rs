@@ -0,0 +1,164 @@
+use crate::backoff::Backoff;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{info, warn};
+
+enum BreakerState {
+    Closed { consecutive_failures: u32 },
+    /// `failed_probes` probes have failed since the breaker opened, and the
+    /// next is let through after `wait`.
+    Open {
+        since: Instant,
+        wait: Duration,
+        failed_probes: u32,
+    },
+    /// The wait has passed and one probe has been let through.
+    HalfOpen {
+        since: Instant,
+        wait: Duration,
+        failed_probes: u32,
+    },
+}
+
+/// Stops connecting to the backend after repeated failures so that clients
+/// are answered from the fallback cache without waiting on connect timeouts.
+/// Once the cooldown has passed a single probe is allowed through, and its
+/// outcome closes or reopens the breaker.
+pub struct CircuitBreaker {
+    /// What the breaker guards, as named in its logs.
+    service: &'static str,
+    threshold: u32,
+    cooldown: Duration,
+    backoff: Option<Backoff>,
+    state: Mutex<BreakerState>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration) -> Self {
+        CircuitBreaker {
+            service: "the backend",
+            threshold,
+            cooldown,
+            backoff: None,
+            state: Mutex::new(BreakerState::Closed {
+                consecutive_failures: 0,
+            }),
+        }
+    }
+
+    /// Wait longer after each failed probe, as `backoff` describes, instead
+    /// of the fixed cooldown.
+    pub fn with_backoff(mut self, backoff: Backoff) -> Self {
+        self.backoff = Some(backoff);
+        self
+    }
+
+    /// Name what the breaker guards `service` in its logs, rather than the
+    /// backend.
+    pub fn for_service(mut self, service: &'static str) -> Self {
+        self.service = service;
+        self
+    }
+
+    /// How long to wait before probing after `failed_probes` failed probes.
+    fn wait(&self, failed_probes: u32) -> Duration {
+        match &self.backoff {
+            Some(backoff) => backoff.delay(failed_probes),
+            None => self.cooldown,
+        }
+    }
+
+    /// Returns true if the service may be contacted. A caller that is allowed
+    /// through must report the outcome with `record_success` or
+    /// `record_failure`.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed { .. } => true,
+            // A probe that never reported back is replaced after another
+            // cooldown.
+            BreakerState::Open {
+                since,
+                wait,
+                failed_probes,
+            }
+            | BreakerState::HalfOpen {
+                since,
+                wait,
+                failed_probes,
+            } if since.elapsed() >= wait => {
+                info!("Circuit breaker cooldown elapsed, probing {}", self.service);
+                *state = BreakerState::HalfOpen {
+                    since: Instant::now(),
+                    wait,
+                    failed_probes,
+                };
+                true
+            }
+            BreakerState::Open { .. } | BreakerState::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, BreakerState::Closed { .. }) {
+            info!("Closing circuit breaker, {} has recovered", self.service);
+        }
+        *state = BreakerState::Closed {
+            consecutive_failures: 0,
+        };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            BreakerState::Closed {
+                consecutive_failures,
+            } => {
+                let consecutive_failures = consecutive_failures + 1;
+                if consecutive_failures >= self.threshold {
+                    let wait = self.wait(0);
+                    warn!(
+                        consecutive_failures,
+                        cooldown = ?wait,
+                        "Opening circuit breaker, {} will not be contacted until the cooldown passes",
+                        self.service
+                    );
+                    *state = BreakerState::Open {
+                        since: Instant::now(),
+                        wait,
+                        failed_probes: 0,
+                    };
+                } else {
+                    *state = BreakerState::Closed {
+                        consecutive_failures,
+                    };
+                }
+            }
+            BreakerState::HalfOpen { failed_probes, .. } => {
+                let failed_probes = failed_probes.saturating_add(1);
+                let wait = self.wait(failed_probes);
+                warn!(
+                    cooldown = ?wait,
+                    "Probe of {} failed, reopening circuit breaker",
+                    self.service
+                );
+                *state = BreakerState::Open {
+                    since: Instant::now(),
+                    wait,
+                    failed_probes,
+                };
+            }
+            BreakerState::Open { .. } => {}
+        }
+    }
+
+    /// Returns true while the service is not being contacted.
+    pub fn is_open(&self) -> bool {
+        !matches!(
+            *self.state.lock().unwrap(),
+            BreakerState::Closed { .. }
+        )
+    }
+}
@@ -0,0 +1,221 @@
+//! Validate a configuration file without starting the proxy.
+
+use crate::backend_tls_connector;
+use ldap_proxy::proxy::BasicLdapClient;
+use ldap_proxy::tls::server_tls_acceptor;
+use ldap_proxy::{
+    resolve, take_invalid_filters, CacheBackend, CacheConfig, Config, PermissiveDefaultAction,
+};
+use std::path::Path;
+use std::process::ExitCode;
+
+async fn check_connectivity(config: &Config, problems: &mut Vec<String>) {
+    if let Some(hostname) = config.backend_tls_name() {
+        let connector = backend_tls_connector(
+            &config.ldap_ca,
+            hostname,
+            config.ldap_tls_insecure_skip_verify,
+            config.ldap_tls_session_resumption,
+        );
+        let addrs = resolve::resolve_backend_addrs(&config.ldap_url, config.address_preference);
+        if let (Ok(connector), Ok(addrs)) = (connector, addrs) {
+            if BasicLdapClient::build(
+                &addrs,
+                &connector,
+                Some(hostname),
+                config.max_proxy_ber_size,
+                &config.connect_timeouts(),
+            )
+            .await
+            .is_err()
+            {
+                problems.push(format!(
+                    "Unable to connect to backend {} ({:?})",
+                    config.ldap_url, addrs
+                ));
+            }
+        }
+    }
+
+    for backend in &config.backends {
+        let Some(hostname) = backend.ldap_url.host_str() else {
+            continue;
+        };
+        let connector = backend_tls_connector(
+            &backend.ldap_ca,
+            hostname,
+            config.ldap_tls_insecure_skip_verify,
+            config.ldap_tls_session_resumption,
+        );
+        let addrs = resolve::resolve_backend_addrs(&backend.ldap_url, config.address_preference);
+        if let (Ok(connector), Ok(addrs)) = (connector, addrs) {
+            if BasicLdapClient::build(
+                &addrs,
+                &connector,
+                Some(hostname),
+                config.max_proxy_ber_size,
+                &config.connect_timeouts(),
+            )
+            .await
+            .is_err()
+            {
+                problems.push(format!(
+                    "Unable to connect to backend {} ({:?})",
+                    backend.ldap_url, addrs
+                ));
+            }
+        }
+    }
+
+    let cache = config.cache_config();
+    if let CacheConfig::Redis { .. } = &cache {
+        if let Err(e) = CacheBackend::from_config(&cache, true).await {
+            problems.push(e);
+        }
+    }
+}
+
+async fn config_problems(contents: &str, connectivity: bool) -> Vec<String> {
+    let mut raw: toml::Table = match toml::from_str(contents) {
+        Ok(raw) => raw,
+        Err(e) => return vec![format!("Invalid toml -> {}", e)],
+    };
+
+    // Every invalid filter is reported, and the rest of the config is still
+    // checked without them.
+    let mut problems: Vec<String> = take_invalid_filters(&mut raw)
+        .iter()
+        .map(ToString::to_string)
+        .collect();
+
+    let mut config: Config = match toml::Value::Table(raw).try_into() {
+        Ok(c) => c,
+        Err(e) => {
+            problems.push(format!("Invalid config -> {}", e));
+            return problems;
+        }
+    };
+    problems.extend(config.resolve_secrets().iter().map(ToString::to_string));
+
+    if config.bind.addrs().is_empty() {
+        problems.push("No bind addresses configured".to_string());
+    }
+
+    for addr in config.tenants.keys() {
+        if !config.bind.addrs().contains(addr) {
+            problems.push(format!("[tenants] {} is not a bind address", addr));
+        }
+    }
+    for addr in config.proxy_protocol.keys() {
+        if !config.bind.addrs().contains(addr) {
+            problems.push(format!("[proxy_protocol] {} is not a bind address", addr));
+        }
+    }
+
+    if let Err(e) = server_tls_acceptor(&config.tls_chain, &config.tls_key) {
+        problems.push(e);
+    }
+
+    if config.ldap_url.scheme() != "ldaps" {
+        problems.push("LDAPS is required in remote ldap_url".to_string());
+    }
+
+    match config.backend_tls_name() {
+        Some(hostname) => {
+            if let Err(e) = backend_tls_connector(
+                &config.ldap_ca,
+                hostname,
+                config.ldap_tls_insecure_skip_verify,
+                config.ldap_tls_session_resumption,
+            ) {
+                problems.push(e);
+            }
+        }
+        None => problems.push("Unable to determine hostname from ldap_url".to_string()),
+    }
+
+    match resolve::resolve_backend_addrs(&config.ldap_url, config.address_preference) {
+        Ok(addrs) if addrs.is_empty() => {
+            problems.push("ldap_url resolved to no addresses".to_string())
+        }
+        Ok(_) => {}
+        Err(e) => problems.push(format!("Unable to resolve ldap_url -> {}", e)),
+    }
+
+    for backend in &config.backends {
+        let url = &backend.ldap_url;
+        if url.scheme() != "ldaps" {
+            problems.push(format!("LDAPS is required in backend ldap_url {}", url));
+        }
+        match url.host_str() {
+            Some(hostname) => {
+                if let Err(e) = backend_tls_connector(
+                    &backend.ldap_ca,
+                    hostname,
+                    config.ldap_tls_insecure_skip_verify,
+                    config.ldap_tls_session_resumption,
+                ) {
+                    problems.push(e);
+                }
+            }
+            None => problems.push(format!("Unable to determine hostname from {}", url)),
+        }
+        match resolve::resolve_backend_addrs(url, config.address_preference) {
+            Ok(addrs) if addrs.is_empty() => {
+                problems.push(format!("{} resolved to no addresses", url))
+            }
+            Ok(_) => {}
+            Err(e) => problems.push(format!("Unable to resolve {} -> {}", url, e)),
+        }
+    }
+
+    if config.annotate_cached_responses && config.cache_age_control_oid.is_none() {
+        problems.push(
+            "cache_age_control_oid must be set when annotate_cached_responses is enabled"
+                .to_string(),
+        );
+    }
+
+    if config.is_default_dn_permissive()
+        && config.permissive_default_action == PermissiveDefaultAction::Error
+    {
+        problems.push(
+            "allow_all_bind_dns requires allowed_queries in default_dn_config".to_string(),
+        );
+    }
+
+    if connectivity && problems.is_empty() {
+        check_connectivity(&config, &mut problems).await;
+    }
+
+    problems
+}
+
+/// Report every problem found in the config at `path`. The listener is never
+/// bound, and the backend is only contacted when `connectivity` is set.
+pub async fn check_config(path: &Path, connectivity: bool) -> ExitCode {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(c) => c,
+        Err(e) => {
+            eprintln!("Unable to read config file '{}' -> {}", path.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let problems = config_problems(&contents, connectivity).await;
+
+    if problems.is_empty() {
+        println!("Config '{}' is valid", path.display());
+        ExitCode::SUCCESS
+    } else {
+        for problem in &problems {
+            eprintln!("error: {}", problem);
+        }
+        eprintln!(
+            "Config '{}' has {} problem(s)",
+            path.display(),
+            problems.len()
+        );
+        ExitCode::FAILURE
+    }
+}
@@ -0,0 +1,841 @@
+use ldap3_proto::control::{LdapControl, ServerSortRequet, ServerSortResult};
+use ldap3_proto::proto::{LdapBindCred, LdapMsg, LdapOp, LdapResult, SaslCredentials};
+use ldap3_proto::{LdapCodec, LdapResultCode, DEFAULT_MAX_BER_SIZE};
+use std::fmt;
+use std::io;
+use tokio_util::bytes::{BufMut, BytesMut};
+use tokio_util::codec::{Decoder, Encoder};
+
+const BER_BOOLEAN: u8 = 0x01;
+const BER_SEQUENCE: u8 = 0x30;
+const BER_INTEGER: u8 = 0x02;
+const BER_OCTET_STRING: u8 = 0x04;
+const BER_ENUMERATED: u8 = 0x0a;
+const BER_BIND_REQUEST: u8 = 0x60;
+const BER_SIMPLE_CREDENTIALS: u8 = 0x80;
+const BER_SASL_CREDENTIALS: u8 = 0xa3;
+const BER_SERVER_SASL_CREDS: u8 = 0x87;
+const BER_CONTROLS: u8 = 0xa0;
+const BER_REFERRAL: u8 = 0xa3;
+const BER_ORDERING_RULE: u8 = 0x80;
+const BER_REVERSE_ORDER: u8 = 0x81;
+const BER_SORT_ATTRIBUTE_TYPE: u8 = 0x80;
+/// The application tags of the responses that hold an LDAPResult.
+const BER_RESULT_RESPONSES: [u8; 8] = [0x61, 0x65, 0x67, 0x69, 0x6b, 0x6d, 0x6f, 0x78];
+const LDAP_VERSION_3: u8 = 3;
+const OID_MANAGE_DSA_IT: &str = "2.16.840.1.113730.3.4.2";
+const OID_SORT_REQUEST: &str = "1.2.840.113556.1.4.473";
+const OID_SORT_RESULT: &str = "1.2.840.113556.1.4.474";
+pub const OID_PASSWORD_POLICY: &str = "1.3.6.1.4.1.42.2.27.8.5.1";
+const BER_PPOLICY_WARNING: u8 = 0xa0;
+const BER_PPOLICY_TIME_BEFORE_EXPIRATION: u8 = 0x80;
+const BER_PPOLICY_GRACE_AUTHNS_REMAINING: u8 = 0x81;
+const BER_PPOLICY_ERROR: u8 = 0x81;
+
+/// Returned by [ClientCodec] when a client sends a bind request for a protocol
+/// version other than LDAPv3. The message has been consumed from the stream.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnsupportedBindVersion {
+    pub msgid: i32,
+    pub version: u8,
+}
+
+impl fmt::Display for UnsupportedBindVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported ldap bind version {}", self.version)
+    }
+}
+
+impl std::error::Error for UnsupportedBindVersion {}
+
+/// Returned by [BackendCodec] when a message to or from the backend is larger
+/// than its max ber size.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MessageTooLarge {
+    pub max_ber_size: usize,
+}
+
+impl fmt::Display for MessageTooLarge {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ldap message exceeds {} bytes", self.max_ber_size)
+    }
+}
+
+impl std::error::Error for MessageTooLarge {}
+
+/// A response with an extra control whose value `LdapControl` has no way to
+/// carry. The control is appended to any controls already on `msg`.
+#[derive(Debug, Clone)]
+pub struct ResponseWithControl {
+    pub msg: LdapMsg,
+    pub oid: String,
+    pub value: Vec<u8>,
+}
+
+/// The error of a password policy response control.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PasswordPolicyError {
+    PasswordExpired,
+    AccountLocked,
+    ChangeAfterReset,
+    PasswordModNotAllowed,
+    MustSupplyOldPassword,
+    InsufficientPasswordQuality,
+    PasswordTooShort,
+    PasswordTooYoung,
+    PasswordInHistory,
+}
+
+/// The value of a password policy response control, as sent by a backend
+/// with the result of a bind.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PasswordPolicyResponse {
+    /// Seconds until the password expires.
+    pub time_before_expiration: Option<u32>,
+    /// Binds left with the expired password.
+    pub grace_authns_remaining: Option<u32>,
+    pub error: Option<PasswordPolicyError>,
+}
+
+/// Codec for client connections. `LdapCodec` rejects non-LDAPv3 binds
+/// without reporting the msgid, so the bind header is inspected here first.
+/// When `require_ldap_v3` is false the version is rewritten to 3 in place so
+/// that the bind can be forwarded to the backend. `LdapCodec` also loses the
+/// criticality of a ManageDsaIT control, so it is read from the frame and
+/// restored. Neither does it decode SASL bind requests or the keys of a
+/// server side sort request, nor encode the serverSaslCreds of bind
+/// responses or a sort result correctly, so these are handled on the frame
+/// here. The criticality of other controls is lost too, so the oids of the
+/// critical controls of a request are kept until taken with
+/// [ClientCodec::take_critical_controls].
+pub struct ClientCodec {
+    inner: LdapCodec,
+    require_ldap_v3: bool,
+    critical_controls: Option<(i32, Vec<String>)>,
+}
+
+impl ClientCodec {
+    pub fn new(max_ber_size: Option<usize>, require_ldap_v3: bool) -> Self {
+        ClientCodec {
+            inner: LdapCodec::new(max_ber_size),
+            require_ldap_v3,
+            critical_controls: None,
+        }
+    }
+
+    /// The oids of the controls marked critical on the request `msgid`, if
+    /// that is the last message decoded.
+    pub fn take_critical_controls(&mut self, msgid: i32) -> Vec<String> {
+        match self.critical_controls.take() {
+            Some((id, oids)) if id == msgid => oids,
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Read a BER length at `pos`, returning the length and the position of the
+/// first content byte.
+fn ber_length(buf: &[u8], pos: usize) -> Option<(usize, usize)> {
+    let first = *buf.get(pos)?;
+    if first & 0x80 == 0 {
+        return Some((first as usize, pos + 1));
+    }
+    let octets = (first & 0x7f) as usize;
+    if octets == 0 || octets > 4 {
+        return None;
+    }
+    let bytes = buf.get(pos + 1..pos + 1 + octets)?;
+    let len = bytes.iter().fold(0usize, |acc, b| (acc << 8) | *b as usize);
+    Some((len, pos + 1 + octets))
+}
+
+fn put_ber_length(buf: &mut BytesMut, len: usize) {
+    if len < 0x80 {
+        buf.put_u8(len as u8);
+        return;
+    }
+    let bytes = len.to_be_bytes();
+    let skip = bytes.iter().take_while(|b| **b == 0).count();
+    buf.put_u8(0x80 | (bytes.len() - skip) as u8);
+    buf.put_slice(&bytes[skip..]);
+}
+
+fn put_ber_element(buf: &mut BytesMut, tag: u8, content: &[u8]) {
+    buf.put_u8(tag);
+    put_ber_length(buf, content.len());
+    buf.put_slice(content);
+}
+
+/// The position just past the element starting at `pos`.
+fn ber_element_end(buf: &[u8], pos: usize) -> Option<usize> {
+    let (len, content) = ber_length(buf, pos + 1)?;
+    let end = content + len;
+    (end <= buf.len()).then_some(end)
+}
+
+struct BindHeader {
+    frame_len: usize,
+    msgid: i32,
+    version: u8,
+    version_pos: usize,
+}
+
+/// Locate the version of a bind request, if `buf` starts with one.
+fn parse_bind_header(buf: &[u8]) -> Option<BindHeader> {
+    if *buf.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let (seq_len, pos) = ber_length(buf, 1)?;
+    let frame_len = pos + seq_len;
+
+    if *buf.get(pos)? != BER_INTEGER {
+        return None;
+    }
+    let (id_len, pos) = ber_length(buf, pos + 1)?;
+    if id_len == 0 || id_len > 4 {
+        return None;
+    }
+    let id_bytes = buf.get(pos..pos + id_len)?;
+    let msgid = id_bytes
+        .iter()
+        .fold(0i32, |acc, b| (acc << 8) | *b as i32);
+    let pos = pos + id_len;
+
+    if *buf.get(pos)? != BER_BIND_REQUEST {
+        return None;
+    }
+    let (_, pos) = ber_length(buf, pos + 1)?;
+
+    if *buf.get(pos)? != BER_INTEGER || *buf.get(pos + 1)? != 1 {
+        return None;
+    }
+    let version_pos = pos + 2;
+    let version = *buf.get(version_pos)?;
+
+    Some(BindHeader {
+        frame_len,
+        msgid,
+        version,
+        version_pos,
+    })
+}
+
+/// A control found in the frame of a message.
+struct RawControl<'a> {
+    oid: &'a [u8],
+    /// The position of the control within the frame.
+    start: usize,
+    end: usize,
+    critical: bool,
+    value: Option<&'a [u8]>,
+}
+
+/// Find the control with `oid` in `buf`, if `buf` starts with a complete
+/// message that has one.
+fn find_control<'a>(buf: &'a [u8], oid: &str) -> Option<RawControl<'a>> {
+    raw_controls(buf)?
+        .into_iter()
+        .find(|control| control.oid == oid.as_bytes())
+}
+
+/// The controls of the message at the start of `buf`, if it is complete.
+fn raw_controls(buf: &[u8]) -> Option<Vec<RawControl<'_>>> {
+    if *buf.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let (seq_len, body_start) = ber_length(buf, 1)?;
+    let body_end = body_start + seq_len;
+    if body_end > buf.len() {
+        return None;
+    }
+    let msgid_end = ber_element_end(buf, body_start)?;
+    let op_end = ber_element_end(buf, msgid_end)?;
+    if op_end >= body_end || buf.get(op_end) != Some(&BER_CONTROLS) {
+        return Some(Vec::new());
+    }
+
+    let mut pos = ber_length(buf, op_end + 1)?.1;
+    let end = ber_element_end(buf, op_end)?.min(body_end);
+    let mut controls = Vec::new();
+    while pos < end {
+        let control_end = ber_element_end(buf, pos)?;
+        let (oid, mut next) = ber_octet_string(buf, ber_length(buf, pos + 1)?.1)?;
+        let mut critical = false;
+        if next < control_end && buf[next] == BER_BOOLEAN {
+            critical = *buf.get(next + 2)? != 0;
+            next = ber_element_end(buf, next)?;
+        }
+        let value = if next < control_end {
+            Some(ber_octet_string(buf, next)?.0)
+        } else {
+            None
+        };
+        controls.push(RawControl {
+            oid,
+            start: pos,
+            end: control_end,
+            critical,
+            value,
+        });
+        pos = control_end;
+    }
+    Some(controls)
+}
+
+/// Rebuild the message at the start of `buf` without `control`, returning
+/// the length of the original frame and the rebuilt one.
+fn without_control(buf: &[u8], control: &RawControl) -> Option<(usize, BytesMut)> {
+    let (seq_len, body_start) = ber_length(buf, 1)?;
+    let body_end = body_start + seq_len;
+    let msgid_end = ber_element_end(buf, body_start)?;
+    let op_end = ber_element_end(buf, msgid_end)?;
+    let controls_start = ber_length(buf, op_end + 1)?.1;
+    let controls_end = ber_element_end(buf, op_end)?;
+
+    let mut controls = BytesMut::from(buf.get(controls_start..control.start)?);
+    controls.extend_from_slice(buf.get(control.end..controls_end)?);
+    let mut body = BytesMut::from(&buf[body_start..op_end]);
+    if !controls.is_empty() {
+        put_ber_element(&mut body, BER_CONTROLS, &controls);
+    }
+    body.extend_from_slice(buf.get(controls_end..body_end)?);
+
+    let mut frame = BytesMut::new();
+    put_ber_element(&mut frame, BER_SEQUENCE, &body);
+    Some((body_end, frame))
+}
+
+/// Decode the SortKeyList of a server side sort request control.
+fn parse_sort_keys(value: &[u8]) -> Option<Vec<ServerSortRequet>> {
+    if *value.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let mut pos = ber_length(value, 1)?.1;
+    let end = ber_element_end(value, 0)?;
+    let mut keys = Vec::new();
+    while pos < end {
+        if value[pos] != BER_SEQUENCE {
+            return None;
+        }
+        let key_end = ber_element_end(value, pos)?;
+        let (attribute_name, mut next) = ber_octet_string(value, ber_length(value, pos + 1)?.1)?;
+        let mut key = ServerSortRequet {
+            attribute_name: String::from_utf8(attribute_name.to_vec()).ok()?,
+            ordering_rule: None,
+            reverse_order: false,
+        };
+        while next < key_end {
+            let (len, start) = ber_length(value, next + 1)?;
+            let content = value.get(start..start + len)?;
+            match value[next] {
+                BER_ORDERING_RULE => {
+                    key.ordering_rule = Some(String::from_utf8(content.to_vec()).ok()?)
+                }
+                BER_REVERSE_ORDER => key.reverse_order = content.first().is_some_and(|b| *b != 0),
+                _ => return None,
+            }
+            next = start + len;
+        }
+        keys.push(key);
+        pos = key_end;
+    }
+    Some(keys)
+}
+
+/// Read a small non-negative INTEGER or ENUMERATED content.
+fn ber_uint(content: &[u8]) -> Option<u32> {
+    if content.is_empty() || content.len() > 4 || content[0] & 0x80 != 0 {
+        return None;
+    }
+    Some(content.iter().fold(0u32, |acc, b| (acc << 8) | *b as u32))
+}
+
+/// Decode the value of a password policy response control.
+pub fn parse_password_policy(value: &[u8]) -> Option<PasswordPolicyResponse> {
+    if *value.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let mut pos = ber_length(value, 1)?.1;
+    let end = ber_element_end(value, 0)?;
+    let mut policy = PasswordPolicyResponse::default();
+
+    if pos < end && value[pos] == BER_PPOLICY_WARNING {
+        let (_, start) = ber_length(value, pos + 1)?;
+        let (len, content) = ber_length(value, start + 1)?;
+        let warning = ber_uint(value.get(content..content + len)?)?;
+        match value[start] {
+            BER_PPOLICY_TIME_BEFORE_EXPIRATION => policy.time_before_expiration = Some(warning),
+            BER_PPOLICY_GRACE_AUTHNS_REMAINING => policy.grace_authns_remaining = Some(warning),
+            _ => return None,
+        }
+        pos = ber_element_end(value, pos)?;
+    }
+    if pos < end && value[pos] == BER_PPOLICY_ERROR {
+        let (len, content) = ber_length(value, pos + 1)?;
+        policy.error = Some(match ber_uint(value.get(content..content + len)?)? {
+            0 => PasswordPolicyError::PasswordExpired,
+            1 => PasswordPolicyError::AccountLocked,
+            2 => PasswordPolicyError::ChangeAfterReset,
+            3 => PasswordPolicyError::PasswordModNotAllowed,
+            4 => PasswordPolicyError::MustSupplyOldPassword,
+            5 => PasswordPolicyError::InsufficientPasswordQuality,
+            6 => PasswordPolicyError::PasswordTooShort,
+            7 => PasswordPolicyError::PasswordTooYoung,
+            8 => PasswordPolicyError::PasswordInHistory,
+            _ => return None,
+        });
+    }
+    Some(policy)
+}
+
+/// Decode the SortResult of a server side sort response control.
+fn parse_sort_result(value: &[u8]) -> Option<ServerSortResult> {
+    if *value.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let pos = ber_length(value, 1)?.1;
+    let end = ber_element_end(value, 0)?;
+    if *value.get(pos)? != BER_ENUMERATED {
+        return None;
+    }
+    let (len, start) = ber_length(value, pos + 1)?;
+    if len == 0 || len > 4 {
+        return None;
+    }
+    let code = value
+        .get(start..start + len)?
+        .iter()
+        .fold(0i64, |acc, b| (acc << 8) | *b as i64);
+    let pos = start + len;
+
+    let attribute_type = if pos < end && value[pos] == BER_SORT_ATTRIBUTE_TYPE {
+        let (len, start) = ber_length(value, pos + 1)?;
+        Some(String::from_utf8(value.get(start..start + len)?.to_vec()).ok()?)
+    } else {
+        None
+    };
+    Some(ServerSortResult {
+        result_code: LdapResultCode::try_from(code).ok()?,
+        attribute_type,
+    })
+}
+
+/// Encode the SortResult of a server side sort response control. The
+/// attributeType is only present when set, and carries its [0] tag.
+fn sort_result_value(result: &ServerSortResult) -> Vec<u8> {
+    let code = (result.result_code.clone() as i64).to_be_bytes();
+    // The shortest two's complement form of the code.
+    let skip = (0..code.len() - 1)
+        .take_while(|i| code[*i] == 0 && code[i + 1] & 0x80 == 0)
+        .count();
+
+    let mut content = BytesMut::new();
+    put_ber_element(&mut content, BER_ENUMERATED, &code[skip..]);
+    if let Some(attribute_type) = &result.attribute_type {
+        put_ber_element(&mut content, BER_SORT_ATTRIBUTE_TYPE, attribute_type.as_bytes());
+    }
+    let mut value = BytesMut::new();
+    put_ber_element(&mut value, BER_SEQUENCE, &content);
+    value.to_vec()
+}
+
+/// Read the OCTET STRING at `pos`, returning its content and the position
+/// just past it.
+fn ber_octet_string(buf: &[u8], pos: usize) -> Option<(&[u8], usize)> {
+    if *buf.get(pos)? != BER_OCTET_STRING {
+        return None;
+    }
+    let (len, start) = ber_length(buf, pos + 1)?;
+    Some((buf.get(start..start + len)?, start + len))
+}
+
+struct SaslBind {
+    frame_len: usize,
+    frame: BytesMut,
+    creds: SaslCredentials,
+}
+
+/// If `buf` starts with a complete SASL bind request, rewrite it as a simple
+/// bind with empty credentials that `LdapCodec` can decode, keeping the SASL
+/// credentials aside.
+fn parse_sasl_bind(buf: &[u8]) -> Option<SaslBind> {
+    if *buf.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let (seq_len, body_start) = ber_length(buf, 1)?;
+    let frame_len = body_start + seq_len;
+    let buf = buf.get(..frame_len)?;
+    let msgid_end = ber_element_end(buf, body_start)?;
+    if *buf.get(msgid_end)? != BER_BIND_REQUEST {
+        return None;
+    }
+    let op_end = ber_element_end(buf, msgid_end)?;
+
+    // Skip the version and name.
+    let version_pos = ber_length(buf, msgid_end + 1)?.1;
+    let auth_pos = ber_element_end(buf, ber_element_end(buf, version_pos)?)?;
+    if *buf.get(auth_pos)? != BER_SASL_CREDENTIALS || ber_element_end(buf, auth_pos)? != op_end {
+        return None;
+    }
+
+    let pos = ber_length(buf, auth_pos + 1)?.1;
+    let (mechanism, pos) = ber_octet_string(buf, pos)?;
+    let credentials = if pos < op_end {
+        ber_octet_string(buf, pos)?.0.to_vec()
+    } else {
+        Vec::new()
+    };
+    let creds = SaslCredentials {
+        mechanism: String::from_utf8(mechanism.to_vec()).ok()?,
+        credentials,
+    };
+
+    let mut op = BytesMut::from(&buf[version_pos..auth_pos]);
+    put_ber_element(&mut op, BER_SIMPLE_CREDENTIALS, &[]);
+    let mut body = BytesMut::from(&buf[body_start..msgid_end]);
+    put_ber_element(&mut body, BER_BIND_REQUEST, &op);
+    body.extend_from_slice(&buf[op_end..]);
+    let mut frame = BytesMut::new();
+    put_ber_element(&mut frame, BER_SEQUENCE, &body);
+
+    Some(SaslBind {
+        frame_len,
+        frame,
+        creds,
+    })
+}
+
+/// Append an element with `tag` and `content` to the protocol op of the
+/// message in `encoded`.
+fn append_to_op(encoded: &[u8], tag: u8, content: &[u8]) -> Option<BytesMut> {
+    let (seq_len, body_start) = ber_length(encoded, 1)?;
+    let body_end = body_start + seq_len;
+    let msgid_end = ber_element_end(encoded, body_start)?;
+    let op_end = ber_element_end(encoded, msgid_end)?;
+    let op_start = ber_length(encoded, msgid_end + 1)?.1;
+
+    let mut op = BytesMut::from(&encoded[op_start..op_end]);
+    put_ber_element(&mut op, tag, content);
+    let mut body = BytesMut::from(&encoded[body_start..msgid_end]);
+    put_ber_element(&mut body, encoded[msgid_end], &op);
+    body.extend_from_slice(encoded.get(op_end..body_end)?);
+    let mut message = BytesMut::new();
+    put_ber_element(&mut message, BER_SEQUENCE, &body);
+    Some(message)
+}
+
+/// Append controls, given as oid and value, to any controls already on the
+/// message in `encoded`, writing the result to `buf`.
+fn append_controls(encoded: &[u8], extra: &[(String, Vec<u8>)], buf: &mut BytesMut) -> Option<()> {
+    let (seq_len, body_start) = ber_length(encoded, 1)?;
+    let body_end = body_start + seq_len;
+    let msgid_end = ber_element_end(encoded, body_start)?;
+    let op_end = ber_element_end(encoded, msgid_end)?;
+
+    let existing = if op_end < body_end && encoded[op_end] == BER_CONTROLS {
+        let (len, start) = ber_length(encoded, op_end + 1)?;
+        encoded.get(start..start + len)?
+    } else {
+        &[]
+    };
+
+    let mut controls = BytesMut::from(existing);
+    for (oid, value) in extra {
+        let mut control = BytesMut::new();
+        put_ber_element(&mut control, BER_OCTET_STRING, oid.as_bytes());
+        put_ber_element(&mut control, BER_OCTET_STRING, value);
+        put_ber_element(&mut controls, BER_SEQUENCE, &control);
+    }
+
+    let mut body = BytesMut::from(&encoded[body_start..op_end]);
+    put_ber_element(&mut body, BER_CONTROLS, &controls);
+
+    put_ber_element(buf, BER_SEQUENCE, &body);
+    Some(())
+}
+
+impl Decoder for ClientCodec {
+    type Item = LdapMsg;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if let Some(hdr) = parse_bind_header(buf) {
+            if hdr.version != LDAP_VERSION_3 {
+                if self.require_ldap_v3 {
+                    if buf.len() < hdr.frame_len {
+                        return Ok(None);
+                    }
+                    let _ = buf.split_to(hdr.frame_len);
+                    return Err(io::Error::other(UnsupportedBindVersion {
+                        msgid: hdr.msgid,
+                        version: hdr.version,
+                    }));
+                } else {
+                    buf[hdr.version_pos] = LDAP_VERSION_3;
+                }
+            }
+        }
+
+        let critical_controls: Vec<String> = raw_controls(buf)
+            .unwrap_or_default()
+            .into_iter()
+            .filter(|control| control.critical)
+            .map(|control| String::from_utf8_lossy(control.oid).into_owned())
+            .collect();
+        let manage_dsa_it_critical =
+            find_control(buf, OID_MANAGE_DSA_IT).map(|control| control.critical);
+        let sort_keys =
+            find_control(buf, OID_SORT_REQUEST).and_then(|control| parse_sort_keys(control.value?));
+        let mut msg = match parse_sasl_bind(buf) {
+            Some(mut bind) => {
+                let _ = buf.split_to(bind.frame_len);
+                let mut msg = self.inner.decode(&mut bind.frame)?;
+                if let Some(LdapOp::BindRequest(req)) = msg.as_mut().map(|msg| &mut msg.op) {
+                    req.cred = LdapBindCred::SASL(bind.creds);
+                }
+                msg
+            }
+            None => self.inner.decode(buf)?,
+        };
+        if let (Some(msg), Some(critical)) = (&mut msg, manage_dsa_it_critical) {
+            for ctrl in &mut msg.ctrl {
+                if let LdapControl::ManageDsaIT { criticality } = ctrl {
+                    *criticality = critical;
+                }
+            }
+        }
+        if let (Some(msg), Some(sort_requests)) = (&mut msg, sort_keys) {
+            for ctrl in &mut msg.ctrl {
+                if matches!(ctrl, LdapControl::Unknown { oid } if oid == OID_SORT_REQUEST) {
+                    *ctrl = LdapControl::ServerSort {
+                        sort_requests: sort_requests.clone(),
+                    };
+                }
+            }
+        }
+        self.critical_controls = msg
+            .as_ref()
+            .map(|msg| (msg.msgid, critical_controls));
+        Ok(msg)
+    }
+}
+
+impl ClientCodec {
+    /// Encode `msg` with `extra` controls appended, after taking out what
+    /// `LdapCodec` would encode wrongly to be written here instead.
+    fn encode_with_controls(
+        &mut self,
+        mut msg: LdapMsg,
+        mut extra: Vec<(String, Vec<u8>)>,
+        buf: &mut BytesMut,
+    ) -> io::Result<()> {
+        let saslcreds = match &mut msg.op {
+            LdapOp::BindResponse(resp) => resp.saslcreds.take(),
+            _ => None,
+        };
+        let mut sort_results = Vec::new();
+        msg.ctrl.retain(|ctrl| match ctrl {
+            LdapControl::ServerSortResult { sort_result } => {
+                sort_results.push((OID_SORT_RESULT.to_string(), sort_result_value(sort_result)));
+                false
+            }
+            _ => true,
+        });
+        extra.splice(0..0, sort_results);
+        if saslcreds.is_none() && extra.is_empty() {
+            return self.inner.encode(msg, buf);
+        }
+
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "malformed ldap message");
+        let mut encoded = BytesMut::new();
+        self.inner.encode(msg, &mut encoded)?;
+        if let Some(saslcreds) = saslcreds {
+            encoded =
+                append_to_op(&encoded, BER_SERVER_SASL_CREDS, &saslcreds).ok_or_else(invalid)?;
+        }
+        if extra.is_empty() {
+            buf.extend_from_slice(&encoded);
+            return Ok(());
+        }
+        append_controls(&encoded, &extra, buf).ok_or_else(invalid)
+    }
+}
+
+impl Encoder<LdapMsg> for ClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> io::Result<()> {
+        self.encode_with_controls(msg, Vec::new(), buf)
+    }
+}
+
+impl Encoder<ResponseWithControl> for ClientCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, resp: ResponseWithControl, buf: &mut BytesMut) -> io::Result<()> {
+        self.encode_with_controls(resp.msg, vec![(resp.oid, resp.value)], buf)
+    }
+}
+
+/// Codec for backend connections. `LdapCodec` drops the referral urls of
+/// results when decoding, so they are read from the frame here and restored.
+/// It also fails on a server side sort response naming the attribute that
+/// could not be sorted on, so that control is decoded here instead. The value
+/// of a password policy response control is lost by `LdapCodec`, so it is
+/// read from the frame and kept until taken with
+/// [BackendCodec::take_password_policy]. The max ber size applies to
+/// requests as well as responses, and exceeding it is reported as
+/// [MessageTooLarge].
+pub struct BackendCodec {
+    inner: LdapCodec,
+    max_ber_size: usize,
+    password_policy: Option<(i32, Vec<u8>)>,
+}
+
+impl BackendCodec {
+    pub fn new(max_ber_size: Option<usize>) -> Self {
+        BackendCodec {
+            inner: LdapCodec::new(max_ber_size),
+            max_ber_size: max_ber_size.unwrap_or(DEFAULT_MAX_BER_SIZE),
+            password_policy: None,
+        }
+    }
+
+    /// The value of the password policy response control on the message
+    /// `msgid`, if that is the last message decoded that carried one.
+    pub fn take_password_policy(&mut self, msgid: i32) -> Option<Vec<u8>> {
+        match self.password_policy.take() {
+            Some((id, value)) if id == msgid => Some(value),
+            _ => None,
+        }
+    }
+
+    fn too_large(&self) -> io::Error {
+        io::Error::other(MessageTooLarge {
+            max_ber_size: self.max_ber_size,
+        })
+    }
+}
+
+/// Read the referral urls of the result in `buf`, if `buf` starts with a
+/// complete response that has any.
+fn parse_referrals(buf: &[u8]) -> Option<Vec<String>> {
+    if *buf.first()? != BER_SEQUENCE {
+        return None;
+    }
+    let msgid_end = ber_element_end(buf, ber_length(buf, 1)?.1)?;
+    if !BER_RESULT_RESPONSES.contains(buf.get(msgid_end)?) {
+        return None;
+    }
+    let op_end = ber_element_end(buf, msgid_end)?;
+
+    // Skip the resultCode, matchedDN and diagnosticMessage.
+    let mut pos = ber_length(buf, msgid_end + 1)?.1;
+    for _ in 0..3 {
+        pos = ber_element_end(buf, pos)?;
+    }
+    if pos >= op_end || *buf.get(pos)? != BER_REFERRAL {
+        return None;
+    }
+
+    let end = ber_element_end(buf, pos)?;
+    if end > op_end {
+        return None;
+    }
+    let mut pos = ber_length(buf, pos + 1)?.1;
+    let mut referrals = Vec::new();
+    while pos < end {
+        let (url, next) = ber_octet_string(buf, pos)?;
+        if next > end {
+            return None;
+        }
+        referrals.push(String::from_utf8(url.to_vec()).ok()?);
+        pos = next;
+    }
+    Some(referrals)
+}
+
+fn result_mut(op: &mut LdapOp) -> Option<&mut LdapResult> {
+    match op {
+        LdapOp::BindResponse(resp) => Some(&mut resp.res),
+        LdapOp::ExtendedResponse(resp) => Some(&mut resp.res),
+        LdapOp::SearchResultDone(res)
+        | LdapOp::ModifyResponse(res)
+        | LdapOp::AddResponse(res)
+        | LdapOp::DelResponse(res)
+        | LdapOp::ModifyDNResponse(res)
+        | LdapOp::CompareResult(res) => Some(res),
+        _ => None,
+    }
+}
+
+impl Decoder for BackendCodec {
+    type Item = LdapMsg;
+    type Error = io::Error;
+
+    fn decode(&mut self, buf: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let referrals = parse_referrals(buf);
+        let password_policy = find_control(buf, OID_PASSWORD_POLICY)
+            .and_then(|control| control.value)
+            .map(<[u8]>::to_vec);
+        // Taken out of the frame, as `LdapCodec` fails on an attributeType.
+        let sort_result = match find_control(buf, OID_SORT_RESULT) {
+            Some(control) => {
+                let invalid =
+                    || io::Error::new(io::ErrorKind::InvalidData, "malformed ldap message");
+                let (seq_len, body_start) = ber_length(buf, 1).ok_or_else(invalid)?;
+                if body_start + seq_len > self.max_ber_size {
+                    return Err(self.too_large());
+                }
+                if buf.len() < body_start + seq_len {
+                    return Ok(None);
+                }
+                let (frame_len, frame) = without_control(buf, &control).ok_or_else(invalid)?;
+                let sort_result = control.value.and_then(parse_sort_result);
+                let _ = buf.split_to(frame_len);
+                Some((frame, sort_result))
+            }
+            None => None,
+        };
+        let (mut msg, sort_result) = match sort_result {
+            Some((mut frame, sort_result)) => (self.inner.decode(&mut frame)?, sort_result),
+            None => {
+                let msg = self.inner.decode(buf).map_err(|e| {
+                    if e.kind() == io::ErrorKind::OutOfMemory {
+                        self.too_large()
+                    } else {
+                        e
+                    }
+                })?;
+                (msg, None)
+            }
+        };
+        if let (Some(msg), Some(referrals)) = (&mut msg, referrals) {
+            if let Some(res) = result_mut(&mut msg.op) {
+                res.referral = referrals;
+            }
+        }
+        if let (Some(msg), Some(sort_result)) = (&mut msg, sort_result) {
+            msg.ctrl.push(LdapControl::ServerSortResult { sort_result });
+        }
+        if let (Some(msg), Some(value)) = (&msg, password_policy) {
+            self.password_policy = Some((msg.msgid, value));
+        }
+        Ok(msg)
+    }
+}
+
+impl Encoder<LdapMsg> for BackendCodec {
+    type Error = io::Error;
+
+    fn encode(&mut self, msg: LdapMsg, buf: &mut BytesMut) -> io::Result<()> {
+        // Encoded separately so that nothing is written if it is too large.
+        let mut encoded = BytesMut::new();
+        self.inner.encode(msg, &mut encoded)?;
+        if encoded.len() > self.max_ber_size {
+            return Err(self.too_large());
+        }
+        buf.extend_from_slice(&encoded);
+        Ok(())
+    }
+}
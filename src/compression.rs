@@ -0,0 +1,39 @@
+//! Compression of the values written to the Redis cache. Compressed values
+//! are recognised by the magic number their format starts with, which JSON
+//! never does, so values written with any setting can be read back.
+
+use crate::RedisCompression;
+use flate2::read::GzDecoder;
+use flate2::write::GzEncoder;
+use std::borrow::Cow;
+use std::io::{self, Read, Write};
+
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZSTD_LEVEL: i32 = 3;
+
+/// Compress a serialised value as `compression` says.
+pub fn compress(compression: RedisCompression, data: Vec<u8>) -> io::Result<Vec<u8>> {
+    match compression {
+        RedisCompression::None => Ok(data),
+        RedisCompression::Zstd => zstd::bulk::compress(&data, ZSTD_LEVEL),
+        RedisCompression::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+            encoder.write_all(&data)?;
+            encoder.finish()
+        }
+    }
+}
+
+/// Decompress a value read from Redis, however it was compressed.
+pub fn decompress(data: &[u8]) -> io::Result<Cow<'_, [u8]>> {
+    if data.starts_with(&ZSTD_MAGIC) {
+        zstd::stream::decode_all(data).map(Cow::Owned)
+    } else if data.starts_with(&GZIP_MAGIC) {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(data).read_to_end(&mut decompressed)?;
+        Ok(Cow::Owned(decompressed))
+    } else {
+        Ok(Cow::Borrowed(data))
+    }
+}
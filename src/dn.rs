@@ -0,0 +1,299 @@
+//! Parsing of distinguished names in their RFC 4514 string form, so that
+//! equivalent DNs can be compared.
+
+use serde_with::DeserializeFromStr;
+use std::fmt;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+enum AttributeValue {
+    String(String),
+    /// A `#` prefixed hex string holding the BER encoding of the value.
+    Ber(Vec<u8>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct AttributeTypeAndValue {
+    /// The attribute type, lower cased since attribute types are case
+    /// insensitive.
+    attr_type: String,
+    value: AttributeValue,
+}
+
+impl AttributeTypeAndValue {
+    fn eq_ignore_case(&self, other: &Self) -> bool {
+        self.attr_type == other.attr_type
+            && match (&self.value, &other.value) {
+                (AttributeValue::String(a), AttributeValue::String(b)) => a.eq_ignore_ascii_case(b),
+                (a, b) => a == b,
+            }
+    }
+}
+
+/// A parsed distinguished name. Attribute types are lower cased, spaces
+/// around separators are dropped and the values of multi-valued RDNs are
+/// sorted, so equivalent DNs parse to equal values. Attribute values keep
+/// their case, since matching them depends on the schema.
+#[derive(Debug, Clone, Default, PartialEq, Eq, DeserializeFromStr)]
+pub struct Dn {
+    rdns: Vec<Vec<AttributeTypeAndValue>>,
+}
+
+impl Dn {
+    /// Whether the last RDNs of this DN are those of `suffix`. String values
+    /// are compared ignoring ASCII case, as naming attributes such as `dc`
+    /// and `ou` usually are. Every DN ends with the empty DN.
+    pub fn ends_with(&self, suffix: &Dn) -> bool {
+        let Some(split) = self.rdns.len().checked_sub(suffix.rdns.len()) else {
+            return false;
+        };
+        self.rdns[split..].iter().zip(&suffix.rdns).all(|(rdn, suffix_rdn)| {
+            rdn.len() == suffix_rdn.len()
+                && rdn.iter().zip(suffix_rdn).all(|(a, b)| a.eq_ignore_case(b))
+        })
+    }
+
+    /// Whether this DN names the same entry as `other`, comparing string
+    /// values as [Self::ends_with] does.
+    pub fn eq_ignore_case(&self, other: &Dn) -> bool {
+        self.rdns.len() == other.rdns.len() && self.ends_with(other)
+    }
+
+    /// The value of the first attribute of the leftmost RDN, such as `alice`
+    /// for `uid=alice,ou=people`. None for the empty DN or a BER value.
+    pub fn leaf_value(&self) -> Option<&str> {
+        match &self.rdns.first()?.first()?.value {
+            AttributeValue::String(value) => Some(value),
+            AttributeValue::Ber(_) => None,
+        }
+    }
+
+    /// This DN followed by each of its ancestors, ending with the empty DN.
+    pub fn ancestors(&self) -> impl Iterator<Item = Dn> + '_ {
+        (0..=self.rdns.len()).map(|i| Dn {
+            rdns: self.rdns[i..].to_vec(),
+        })
+    }
+}
+
+/// Normalize `dn` to the string form of its parsed value.
+pub fn normalize_dn(dn: &str) -> Result<String, String> {
+    dn.parse::<Dn>().map(|dn| dn.to_string())
+}
+
+struct Parser<'a> {
+    input: &'a [u8],
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<u8> {
+        self.input.get(self.pos).copied()
+    }
+
+    fn skip_spaces(&mut self) {
+        while self.peek() == Some(b' ') {
+            self.pos += 1;
+        }
+    }
+
+    fn error(&self, msg: &str) -> String {
+        format!("{} at position {}", msg, self.pos)
+    }
+
+    fn attr_type(&mut self) -> Result<String, String> {
+        let start = self.pos;
+        while matches!(self.peek(), Some(c) if c.is_ascii_alphanumeric() || c == b'-' || c == b'.')
+        {
+            self.pos += 1;
+        }
+        let attr_type = std::str::from_utf8(&self.input[start..self.pos])
+            .map_err(|_| self.error("invalid attribute type"))?
+            .to_ascii_lowercase();
+        // RFC 2253 allowed numeric oids to be prefixed with "oid.".
+        let attr_type = match attr_type.strip_prefix("oid.") {
+            Some(oid) => oid.to_string(),
+            None => attr_type,
+        };
+
+        let bytes = attr_type.as_bytes();
+        let is_descr = bytes.first().is_some_and(u8::is_ascii_alphabetic)
+            && bytes.iter().all(|c| c.is_ascii_alphanumeric() || *c == b'-');
+        let is_numericoid = attr_type.contains('.')
+            && attr_type.split('.').all(|number| {
+                !number.is_empty()
+                    && number.bytes().all(|c| c.is_ascii_digit())
+                    && (number == "0" || !number.starts_with('0'))
+            });
+        if is_descr || is_numericoid {
+            Ok(attr_type)
+        } else {
+            Err(format!("invalid attribute type {:?}", attr_type))
+        }
+    }
+
+    fn hex_pair(&mut self) -> Option<u8> {
+        let pair = self.input.get(self.pos..self.pos + 2)?;
+        let pair = std::str::from_utf8(pair).ok()?;
+        let byte = u8::from_str_radix(pair, 16).ok()?;
+        self.pos += 2;
+        Some(byte)
+    }
+
+    fn ber_value(&mut self) -> Result<AttributeValue, String> {
+        let mut ber = Vec::new();
+        while matches!(self.peek(), Some(c) if c.is_ascii_hexdigit()) {
+            let byte = self
+                .hex_pair()
+                .ok_or_else(|| self.error("invalid hex string"))?;
+            ber.push(byte);
+        }
+        if ber.is_empty() {
+            return Err(self.error("empty hex string"));
+        }
+        Ok(AttributeValue::Ber(ber))
+    }
+
+    fn string_value(&mut self) -> Result<AttributeValue, String> {
+        let mut value = Vec::new();
+        // Unescaped trailing spaces are not part of the value.
+        let mut significant_len = 0;
+        while let Some(c) = self.peek() {
+            match c {
+                b',' | b';' | b'+' => break,
+                b'\\' => {
+                    self.pos += 1;
+                    match self.peek() {
+                        Some(
+                            c @ (b' ' | b'"' | b'#' | b'+' | b',' | b';' | b'<' | b'=' | b'>'
+                            | b'\\'),
+                        ) => {
+                            self.pos += 1;
+                            value.push(c);
+                        }
+                        _ => {
+                            let byte = self
+                                .hex_pair()
+                                .ok_or_else(|| self.error("invalid escape"))?;
+                            value.push(byte);
+                        }
+                    }
+                    significant_len = value.len();
+                }
+                b'"' | b'<' | b'>' | 0 => {
+                    return Err(self.error("unescaped special character"));
+                }
+                _ => {
+                    self.pos += 1;
+                    value.push(c);
+                    if c != b' ' {
+                        significant_len = value.len();
+                    }
+                }
+            }
+        }
+        value.truncate(significant_len);
+        String::from_utf8(value)
+            .map(AttributeValue::String)
+            .map_err(|_| "attribute value is not valid UTF-8".to_string())
+    }
+
+    fn attribute_type_and_value(&mut self) -> Result<AttributeTypeAndValue, String> {
+        self.skip_spaces();
+        let attr_type = self.attr_type()?;
+        self.skip_spaces();
+        if self.peek() != Some(b'=') {
+            return Err(self.error("expected '='"));
+        }
+        self.pos += 1;
+        self.skip_spaces();
+        let value = if self.peek() == Some(b'#') {
+            self.pos += 1;
+            let value = self.ber_value()?;
+            self.skip_spaces();
+            value
+        } else {
+            self.string_value()?
+        };
+        Ok(AttributeTypeAndValue { attr_type, value })
+    }
+}
+
+impl FromStr for Dn {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim_matches(' ').is_empty() {
+            return Ok(Dn { rdns: Vec::new() });
+        }
+
+        let mut parser = Parser {
+            input: s.as_bytes(),
+            pos: 0,
+        };
+        let mut rdns = Vec::new();
+        let mut rdn = Vec::new();
+        loop {
+            rdn.push(parser.attribute_type_and_value()?);
+            match parser.peek() {
+                Some(b'+') => {}
+                // ';' was accepted as an RDN separator by RFC 2253.
+                Some(b',' | b';') => {
+                    rdn.sort();
+                    rdns.push(std::mem::take(&mut rdn));
+                }
+                None => {
+                    rdn.sort();
+                    rdns.push(rdn);
+                    break;
+                }
+                Some(_) => return Err(parser.error("expected ',' or '+'")),
+            }
+            parser.pos += 1;
+        }
+        Ok(Dn { rdns })
+    }
+}
+
+fn write_value(f: &mut fmt::Formatter<'_>, value: &AttributeValue) -> fmt::Result {
+    match value {
+        AttributeValue::Ber(ber) => {
+            write!(f, "#")?;
+            for byte in ber {
+                write!(f, "{:02x}", byte)?;
+            }
+            Ok(())
+        }
+        AttributeValue::String(value) => {
+            let last = value.chars().count().saturating_sub(1);
+            for (i, c) in value.chars().enumerate() {
+                match c {
+                    '"' | '+' | ',' | ';' | '<' | '>' | '\\' => write!(f, "\\{}", c)?,
+                    '#' if i == 0 => write!(f, "\\#")?,
+                    ' ' if i == 0 || i == last => write!(f, "\\ ")?,
+                    '\0' => write!(f, "\\00")?,
+                    c => write!(f, "{}", c)?,
+                }
+            }
+            Ok(())
+        }
+    }
+}
+
+impl fmt::Display for Dn {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, rdn) in self.rdns.iter().enumerate() {
+            if i > 0 {
+                write!(f, ",")?;
+            }
+            for (j, ava) in rdn.iter().enumerate() {
+                if j > 0 {
+                    write!(f, "+")?;
+                }
+                write!(f, "{}=", ava.attr_type)?;
+                write_value(f, &ava.value)?;
+            }
+        }
+        Ok(())
+    }
+}
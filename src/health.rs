@@ -0,0 +1,220 @@
+use crate::backoff::Backoff;
+use crate::proxy::BasicLdapClient;
+use crate::{AppState, CacheBackend};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, trace, warn};
+
+const MAX_REQUEST_BYTES: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+const REDIS_PING_TIMEOUT: Duration = Duration::from_secs(2);
+const JSON: &str = "application/json";
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4";
+
+/// The backend addresses that were reachable at the last health check.
+#[derive(Default)]
+pub struct BackendHealth {
+    reachable: RwLock<HashSet<SocketAddr>>,
+}
+
+impl BackendHealth {
+    /// Connect to each current address of every backend and record which
+    /// succeed. Returns whether any did.
+    pub async fn check(&self, app_state: &AppState) -> bool {
+        let mut reachable = HashSet::new();
+        for backend in app_state.backends() {
+            for addr in backend.addrs() {
+                match BasicLdapClient::build(
+                    &[addr],
+                    backend.tls_params,
+                    backend.tls_server_name,
+                    app_state.max_proxy_ber_size,
+                    &app_state.connect_timeouts,
+                )
+                .await
+                {
+                    Ok(_) => {
+                        reachable.insert(addr);
+                    }
+                    Err(e) => debug!(?e, %addr, "Backend health check failed"),
+                }
+            }
+        }
+        let any_reachable = !reachable.is_empty();
+        *self.reachable.write().unwrap() = reachable;
+        any_reachable
+    }
+
+    fn is_reachable(&self, addr: &SocketAddr) -> bool {
+        self.reachable.read().unwrap().contains(addr)
+    }
+}
+
+#[derive(Serialize)]
+struct BackendStatus {
+    reachable: Vec<SocketAddr>,
+    unreachable: Vec<SocketAddr>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum CacheStatus {
+    Memory,
+    Custom,
+    Reachable,
+    Unreachable,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    backend: BackendStatus,
+    cache: CacheStatus,
+}
+
+async fn cache_status(cache: &CacheBackend) -> CacheStatus {
+    match cache {
+        CacheBackend::Memory(_) => CacheStatus::Memory,
+        CacheBackend::Custom(_) => CacheStatus::Custom,
+        CacheBackend::Redis(conn) => {
+            let mut conn = conn.clone();
+            let cmd = redis::cmd("PING");
+            let ping = cmd.query_async::<_, String>(&mut conn);
+            match tokio::time::timeout(REDIS_PING_TIMEOUT, ping).await {
+                Ok(Ok(_)) => CacheStatus::Reachable,
+                Ok(Err(e)) => {
+                    debug!(?e, "Redis health check failed");
+                    CacheStatus::Unreachable
+                }
+                Err(_) => {
+                    debug!("Redis health check timed out");
+                    CacheStatus::Unreachable
+                }
+            }
+        }
+    }
+}
+
+async fn readiness(app_state: &AppState, health: &BackendHealth) -> Readiness {
+    let (reachable, unreachable) = app_state
+        .backends()
+        .flat_map(|backend| backend.addrs())
+        .partition::<Vec<_>, _>(|addr| health.is_reachable(addr));
+    let cache = cache_status(&app_state.cache).await;
+    Readiness {
+        ready: !reachable.is_empty() && !matches!(cache, CacheStatus::Unreachable),
+        backend: BackendStatus {
+            reachable,
+            unreachable,
+        },
+        cache,
+    }
+}
+
+/// Read up to the end of the request headers and return the request path.
+async fn read_request_path(stream: &mut TcpStream) -> Option<String> {
+    let mut buf = Vec::with_capacity(512);
+    let mut chunk = [0u8; 512];
+    while !buf.windows(4).any(|w| w == b"\r\n\r\n") {
+        if buf.len() >= MAX_REQUEST_BYTES {
+            return None;
+        }
+        let n = stream.read(&mut chunk).await.ok()?;
+        if n == 0 {
+            return None;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+    }
+
+    let request_line = buf.split(|b| *b == b'\n').next()?;
+    let request_line = std::str::from_utf8(request_line).ok()?;
+    let mut parts = request_line.split_whitespace();
+    let _method = parts.next()?;
+    parts.next().map(str::to_string)
+}
+
+async fn handle_connection(mut stream: TcpStream, app_state: &AppState, health: &BackendHealth) {
+    let Ok(Some(path)) = tokio::time::timeout(REQUEST_TIMEOUT, read_request_path(&mut stream)).await
+    else {
+        trace!("Invalid or incomplete health request");
+        return;
+    };
+
+    let (status, content_type, body) = match path.as_str() {
+        "/livez" => ("200 OK", JSON, r#"{"live":true}"#.to_string()),
+        "/readyz" => {
+            let readiness = readiness(app_state, health).await;
+            let status = if readiness.ready {
+                "200 OK"
+            } else {
+                "503 Service Unavailable"
+            };
+            let body = serde_json::to_string(&readiness).unwrap_or_default();
+            (status, JSON, body)
+        }
+        "/metrics" => ("200 OK", PROMETHEUS_TEXT, app_state.metrics.render()),
+        _ => ("404 Not Found", JSON, r#"{"error":"not found"}"#.to_string()),
+    };
+
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        content_type,
+        body.len(),
+        body
+    );
+    if let Err(e) = stream.write_all(response.as_bytes()).await {
+        trace!(?e, "Unable to write health response");
+    }
+}
+
+/// Answer `/livez`, `/readyz` and `/metrics` over plain HTTP.
+pub async fn serve(listener: TcpListener, app_state: Arc<AppState>, health: Arc<BackendHealth>) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let app_state = app_state.clone();
+                let health = health.clone();
+                tokio::spawn(async move {
+                    handle_connection(stream, &app_state, &health).await;
+                });
+            }
+            Err(e) => {
+                warn!(?e, "Health acceptor error, continuing");
+            }
+        }
+    }
+}
+
+/// Periodically check that the backend addresses are reachable. While none
+/// are, the checks are spaced out by `backoff` instead of `interval`.
+pub async fn run_checks(
+    app_state: Arc<AppState>,
+    health: Arc<BackendHealth>,
+    interval: Duration,
+    backoff: Option<Backoff>,
+) {
+    let mut failures = 0;
+    loop {
+        if health.check(&app_state).await {
+            failures = 0;
+        } else {
+            failures += 1;
+        }
+        tokio::time::sleep(check_delay(interval, backoff.as_ref(), failures)).await;
+    }
+}
+
+/// The wait before the next health check after `failures` consecutive
+/// checks found no backend reachable.
+pub fn check_delay(interval: Duration, backoff: Option<&Backoff>, failures: u32) -> Duration {
+    match backoff {
+        Some(backoff) if failures > 0 => backoff.delay(failures - 1),
+        _ => interval,
+    }
+}
@@ -1,45 +1,853 @@
-use concread::arcache::ARCache;
+use concread::arcache::{ARCache, ARCacheBuilder};
 use hashbrown::HashSet;
 use ldap3_proto::parse_ldap_filter_str;
-use ldap3_proto::{LdapFilter, LdapSearchScope};
+use ldap3_proto::{LdapFilter, LdapResultCode, LdapSearchScope};
 use openssl::ssl::SslConnector;
 use redis::aio::ConnectionManager;
-use serde::Deserialize;
+use serde::{Deserialize, Deserializer};
 use serde_with::DeserializeFromStr;
 use std::collections::BTreeMap;
-use std::net::SocketAddr;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::num::{NonZeroU32, NonZeroU64, NonZeroUsize};
 use std::path::PathBuf;
 use std::str::FromStr;
-use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
 use url::Url;
 
+pub mod backoff;
+pub mod breaker;
+pub mod codec;
+pub mod compression;
+pub mod dn;
+pub mod health;
+pub mod metrics;
+pub mod offline;
+pub mod pool;
 pub mod proxy;
+pub mod proxy_header;
+pub mod redact;
+pub mod resolve;
+pub mod schema;
+pub mod sessions;
+pub mod telemetry;
+pub mod tls;
 
-use crate::proxy::{CachedValue, SearchCacheKey};
+use crate::backoff::Backoff;
+use crate::breaker::CircuitBreaker;
+use crate::dn::Dn;
+use crate::metrics::Metrics;
+use crate::offline::OfflineBindCache;
+use crate::pool::BackendPool;
+use crate::proxy::{CacheStore, CachedValue, InFlightSearches, SearchCacheKey, TieredCache};
+use crate::schema::SchemaSnapshot;
+use crate::sessions::SessionCounts;
 
 const MEGABYTES: usize = 1048576;
+const CACHE_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
 
 pub enum CacheBackend {
     Memory(Arc<ARCache<SearchCacheKey, CachedValue>>),
     Redis(ConnectionManager),
+    /// A store provided by an application embedding the proxy. It is never
+    /// built from the configuration.
+    Custom(Arc<dyn CacheStore>),
 }
 
 pub struct AppState {
     pub tls_params: SslConnector,
-    pub addrs: Vec<SocketAddr>,
+    /// The name sent as SNI to the backend, which its certificate is also
+    /// verified against.
+    pub tls_server_name: Option<String>,
+    /// Backend addresses in the order connections are attempted. These are
+    /// refreshed in the background when `resolve_refresh_secs` is set.
+    pub addrs: RwLock<Vec<SocketAddr>>,
+    /// Backends selected by the bind DN of a session, in the order they are
+    /// matched. Sessions matching none use the backend above.
+    pub routed_backends: Vec<RoutedBackend>,
     pub binddn_map: BTreeMap<String, DnConfig>,
     pub cache: CacheBackend,
     pub cache_ttl: Option<u64>,
+    /// The prefix of every Redis key, the `key_prefix` of a Redis cache.
+    pub cache_key_prefix: String,
     pub max_incoming_ber_size: Option<usize>,
     pub max_proxy_ber_size: Option<usize>,
+    pub max_buffered_entries: Option<usize>,
+    /// How many search result entries are written to the client between
+    /// flushes.
+    pub response_flush_entries: usize,
     pub allow_all_bind_dns: bool,
+    /// The configs of DNs without a bind map entry by suffix, in the order
+    /// they are matched.
+    pub dn_defaults: Vec<DnDefaultConfig>,
+    /// The config of DNs without a bind map entry, used when
+    /// `allow_all_bind_dns` is set.
+    pub default_dn_config: DnConfig,
+    pub allow_unauthenticated_bind: bool,
     pub remote_ip_addr_info: AddrInfoSource,
+    pub cacheable_result_codes: HashSet<LdapResultCode>,
+    pub degraded_result_codes: HashSet<LdapResultCode>,
+    /// Sort cached entries by DN, see [`CachedValue::sort_entries`].
+    pub sort_cached_entries: bool,
+    pub cache_mode: CacheMode,
+    /// Whether searches naming attributes are cached as all attributes, see
+    /// [`Config::cache_attribute_superset`].
+    pub cache_attribute_superset: bool,
+    /// Whether searches differing only in their size and time limits share
+    /// cache entries, see [`Config::cache_key_ignore_limits`].
+    pub cache_key_ignore_limits: bool,
+    pub read_through_max_age: Duration,
+    /// How long a search waits for the backend before it is answered from
+    /// the cache, see [`Config::cache_race_timeout_ms`].
+    pub cache_race_timeout: Option<Duration>,
+    pub require_ldap_v3: bool,
+    pub root_dse: Option<BTreeMap<String, Vec<String>>>,
+    /// The controls advertised in the `supportedControl` of backend root dse
+    /// entries, see [`Config::supported_controls`].
+    pub supported_controls: Option<Vec<String>>,
+    /// The backend's subschema subentry, when schema searches are answered
+    /// from a snapshot.
+    pub schema: Option<Arc<SchemaSnapshot>>,
+    /// The oid of the control attached to responses served from the fallback
+    /// cache, or None when they are not annotated.
+    pub cache_age_control_oid: Option<String>,
+    /// Note the age of cached data in the message of results served from
+    /// the fallback cache.
+    pub annotate_cached_message: bool,
+    /// The result code of results served from the fallback cache, in place
+    /// of the cached one.
+    pub fallback_result_code: Option<LdapResultCode>,
+    /// Prefixed to the message of results served from the fallback cache,
+    /// along with their age.
+    pub fallback_result_marker: Option<String>,
+    pub backend_pool: Option<BackendPool>,
+    pub dn_rewrite: Vec<DnRewrite>,
+    pub referral_rewrite: Vec<ReferralRewrite>,
+    pub strip_unmatched_referrals: bool,
+    /// Follow continuation references in search results instead of
+    /// relaying them.
+    pub chase_referrals: bool,
+    /// The connectors for the hosts references may be chased to, keyed by
+    /// the lower cased host name. Each verifies certificates against its
+    /// host.
+    pub referral_connectors: BTreeMap<String, SslConnector>,
+    /// Backend operations slower than this are logged as warnings.
+    pub slow_op_threshold: Option<Duration>,
+    pub denied_query_action: DeniedQueryAction,
+    pub no_fallback_action: NoFallbackAction,
+    pub max_dn_length: usize,
+    pub max_filter_depth: usize,
+    /// How many operations a client may have outstanding at once.
+    pub max_concurrent_ops: usize,
+    pub breaker: Option<CircuitBreaker>,
+    /// The in memory cache in front of Redis, shared by all sessions.
+    pub tiered_cache: Option<Arc<TieredCache>>,
+    /// Searches being fetched from the backend, when `coalesce_searches` is
+    /// set.
+    pub in_flight_searches: Option<InFlightSearches>,
+    /// How long to wait for the backend to answer a bind.
+    pub bind_timeout: Duration,
+    pub connect_timeouts: ConnectTimeouts,
+    /// Whether a dropped backend connection is replaced mid-session.
+    pub reconnect_backend: bool,
+    pub backend_mode: BackendMode,
+    pub whoami_format: WhoamiFormat,
+    /// Disconnect clients that send nothing for this long while no
+    /// operation is in progress.
+    pub client_idle_timeout: Option<Duration>,
+    pub metrics: Metrics,
+    /// Bound sessions per DN, counted for DNs with `max_sessions` set.
+    pub session_counts: Arc<SessionCounts>,
+    /// Credentials of successful binds, for validating binds while the
+    /// backend is unreachable.
+    pub offline_bind: Option<Arc<OfflineBindCache>>,
+    /// Credentials of recent successful binds, for answering repeated binds
+    /// without the backend.
+    pub bind_cache: Option<Arc<OfflineBindCache>>,
+    /// Set while the proxy is in maintenance mode, see
+    /// [`AppState::set_maintenance`].
+    pub maintenance: AtomicBool,
+    pub maintenance_bind_action: MaintenanceBindAction,
+    pub critical_control_action: CriticalControlAction,
 }
 
-#[derive(Debug, Clone, Deserialize, Default)]
+impl CacheBackend {
+    /// Build the cache described by `config`, along with the ttl of cached
+    /// values. If Redis is unreachable this fails when `require_cache` is set,
+    /// and otherwise falls back to a local memory cache.
+    pub async fn from_config(
+        config: &CacheConfig,
+        require_cache: bool,
+    ) -> Result<(Self, Option<u64>), String> {
+        match config {
+            CacheConfig::Memory { size_bytes } => {
+                info!("Memory cache configured with {} bytes", size_bytes);
+                Ok((Self::memory(*size_bytes)?, None))
+            }
+            CacheConfig::Redis {
+                url,
+                ttl_seconds,
+                key_prefix: _,
+            } => {
+                let client = redis::Client::open(url.as_str())
+                    .map_err(|e| format!("Unable to create Redis client -> {:?}", e))?;
+
+                let connected = tokio::time::timeout(
+                    CACHE_CONNECT_TIMEOUT,
+                    ConnectionManager::new(client),
+                )
+                .await
+                .map_err(|_| "timed out".to_string())
+                .and_then(|r| r.map_err(|e| e.to_string()));
+
+                match connected {
+                    Ok(conn_manager) => {
+                        info!(
+                            "Redis cache configured at {} with TTL: {:?}",
+                            url, ttl_seconds
+                        );
+                        Ok((CacheBackend::Redis(conn_manager), *ttl_seconds))
+                    }
+                    Err(e) if require_cache => {
+                        Err(format!("Unable to connect to Redis at {} -> {}", url, e))
+                    }
+                    Err(e) => {
+                        let size_bytes = default_fallback_cache_bytes();
+                        warn!(
+                            "Unable to connect to Redis at {} -> {}. CONTINUING WITH A LOCAL MEMORY CACHE OF {} BYTES ONLY: cached data is not shared and is lost on restart. Set require_cache = true to refuse to start instead.",
+                            url, e, size_bytes
+                        );
+                        Ok((Self::memory(size_bytes)?, *ttl_seconds))
+                    }
+                }
+            }
+        }
+    }
+
+    /// A memory cache holding up to `size_bytes` of search results.
+    pub fn memory(size_bytes: usize) -> Result<Self, String> {
+        ARCacheBuilder::new()
+            .set_size(size_bytes, 0)
+            .build()
+            .map(|cache| CacheBackend::Memory(Arc::new(cache)))
+            .ok_or_else(|| "Unable to build memory cache".to_string())
+    }
+}
+
+/// Why a config could not be loaded.
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The config is not valid toml, or does not have the expected options.
+    Toml(toml::de::Error),
+    /// A filter in the `allowed_queries` of `bind_dn` does not parse.
+    InvalidAllowedQuery {
+        bind_dn: String,
+        filter: String,
+        message: String,
+    },
+    /// A filter in a `cache_warm` query made as `bind_dn` does not parse.
+    InvalidWarmQuery {
+        bind_dn: String,
+        filter: String,
+        message: String,
+    },
+    /// A secret referenced by `field` could not be read.
+    Secret { field: String, message: String },
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::Toml(e) => write!(f, "{}", e),
+            ConfigError::InvalidAllowedQuery {
+                bind_dn,
+                filter,
+                message,
+            } => write!(
+                f,
+                "[{:?}] allowed_queries has invalid filter {:?} -> {}",
+                bind_dn, filter, message
+            ),
+            ConfigError::InvalidWarmQuery {
+                bind_dn,
+                filter,
+                message,
+            } => write!(
+                f,
+                "[cache_warm] query as {:?} has invalid filter {:?} -> {}",
+                bind_dn, filter, message
+            ),
+            ConfigError::Secret { field, message } => {
+                write!(f, "Unable to resolve the secret in {} -> {}", field, message)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Toml(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// The table holding the config of each bind DN.
+const BINDDN_TABLE: &str = "binddn";
+
+/// Remove every query with an invalid filter from the raw config, returning
+/// an error naming each. The deserializer would only report the first, and
+/// without the bind DN it belongs to.
+pub fn take_invalid_filters(raw: &mut toml::Table) -> Vec<ConfigError> {
+    let mut errors = Vec::new();
+
+    let parse = |query: &toml::Value, filter_idx: usize| {
+        let filter = query.get(filter_idx).and_then(|f| f.as_str())?;
+        parse_ldap_filter_str(filter)
+            .err()
+            .map(|e| (filter.to_string(), e.to_string()))
+    };
+
+    // Bind DNs are in the [binddn] table, or top level tables in the
+    // deprecated form.
+    let mut dnconfigs = Vec::new();
+    for (key, value) in raw.iter_mut() {
+        if key != BINDDN_TABLE {
+            dnconfigs.push((key, value));
+        } else if let Some(nested) = value.as_table_mut() {
+            dnconfigs.extend(nested.iter_mut());
+        }
+    }
+    for (bind_dn, value) in dnconfigs {
+        let Some(queries) = value.get_mut("allowed_queries").and_then(|q| q.as_array_mut()) else {
+            continue;
+        };
+        queries.retain(|query| match parse(query, 2) {
+            Some((filter, message)) => {
+                errors.push(ConfigError::InvalidAllowedQuery {
+                    bind_dn: bind_dn.clone(),
+                    filter,
+                    message,
+                });
+                false
+            }
+            None => true,
+        });
+    }
+
+    let warm_queries = raw
+        .get_mut("cache_warm")
+        .and_then(|cache_warm| cache_warm.get_mut("queries"))
+        .and_then(|q| q.as_array_mut());
+    if let Some(queries) = warm_queries {
+        queries.retain(|query| match parse(query, 3) {
+            Some((filter, message)) => {
+                let bind_dn = query.get(0).and_then(|dn| dn.as_str()).unwrap_or_default();
+                errors.push(ConfigError::InvalidWarmQuery {
+                    bind_dn: bind_dn.to_string(),
+                    filter,
+                    message,
+                });
+                false
+            }
+            None => true,
+        });
+    }
+
+    errors
+}
+
+/// Resolve the secrets a config value refers to. A value of the form
+/// `@file:<path>` is replaced by the contents of the file, without a trailing
+/// newline, and each `${NAME}` in any other value by the environment
+/// variable `NAME`. Secrets can then be kept out of the config file, such as
+/// the password in `redis://:${REDIS_PASSWORD}@localhost:6379`.
+pub fn resolve_secret(value: &str) -> Result<String, String> {
+    if let Some(path) = value.strip_prefix("@file:") {
+        return std::fs::read_to_string(path)
+            .map(|contents| contents.trim_end_matches(['\r', '\n']).to_string())
+            .map_err(|e| format!("unable to read {} -> {}", path, e));
+    }
+
+    let mut resolved = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find("${") {
+        resolved.push_str(&rest[..start]);
+        let Some(len) = rest[start + 2..].find('}') else {
+            return Err(format!("unterminated ${{ in {:?}", value));
+        };
+        let name = &rest[start + 2..start + 2 + len];
+        let secret = std::env::var(name).map_err(|e| match e {
+            std::env::VarError::NotPresent => format!("environment variable {} is not set", name),
+            std::env::VarError::NotUnicode(_) => {
+                format!("environment variable {} is not valid unicode", name)
+            }
+        })?;
+        resolved.push_str(&secret);
+        rest = &rest[start + 3 + len..];
+    }
+    resolved.push_str(rest);
+    Ok(resolved)
+}
+
+impl Config {
+    /// Load a config from its toml form. An invalid filter is reported along
+    /// with the bind DN whose queries it is in.
+    pub fn from_toml(contents: &str) -> Result<Self, ConfigError> {
+        let mut raw: toml::Table = toml::from_str(contents).map_err(ConfigError::Toml)?;
+        if let Some(e) = take_invalid_filters(&mut raw).into_iter().next() {
+            return Err(e);
+        }
+        let mut config: Config = toml::Value::Table(raw).try_into().map_err(ConfigError::Toml)?;
+        if let Some(e) = config.resolve_secrets().into_iter().next() {
+            return Err(e);
+        }
+        Ok(config)
+    }
+
+    /// Resolve the secrets referenced by the Redis `url` and the `cache_warm`
+    /// credentials, see [`resolve_secret`]. Returns the secrets that could
+    /// not be resolved, leaving those values as they were.
+    pub fn resolve_secrets(&mut self) -> Vec<ConfigError> {
+        let mut errors = Vec::new();
+        let mut resolve = |field: String, value: &mut String| match resolve_secret(value) {
+            Ok(resolved) => *value = resolved,
+            Err(message) => errors.push(ConfigError::Secret { field, message }),
+        };
+
+        if let Some(CacheConfig::Redis { url, .. }) = &mut self.cache {
+            resolve("cache.url".to_string(), url);
+        }
+        if let Some(cache_warm) = &mut self.cache_warm {
+            for (bind_dn, password) in cache_warm.credentials.iter_mut() {
+                resolve(format!("cache_warm.credentials.{:?}", bind_dn), password);
+            }
+        }
+        errors
+    }
+
+    /// The PROXY header handling of the listener on `addr`. Listeners not
+    /// in `proxy_protocol` require a header with `remote_ip_addr_info` set
+    /// to `ProxyV2`, and do not look for one otherwise.
+    pub fn proxy_protocol_for(&self, addr: &ListenAddr) -> Option<ProxyProtocolMode> {
+        match (self.proxy_protocol.get(addr), self.remote_ip_addr_info) {
+            (Some(mode), _) => Some(*mode),
+            (None, AddrInfoSource::ProxyV2) => Some(ProxyProtocolMode::Require),
+            (None, AddrInfoSource::None) => None,
+        }
+    }
+
+    /// The cache to build. The `[cache]` section takes precedence, and
+    /// without one a memory cache is sized by the deprecated
+    /// `fallback_cache_bytes`, or the default size.
+    pub fn cache_config(&self) -> CacheConfig {
+        match (&self.cache, self.fallback_cache_bytes) {
+            (Some(cache), _) => cache.clone(),
+            (None, Some(size_bytes)) => CacheConfig::Memory { size_bytes },
+            (None, None) => CacheConfig::default(),
+        }
+    }
+
+    /// The name the backend is asked for with SNI and its certificate is
+    /// verified against.
+    pub fn backend_tls_name(&self) -> Option<&str> {
+        self.ldap_tls_sni
+            .as_deref()
+            .or_else(|| self.ldap_url.host_str())
+    }
+
+    /// The circuit breaker described by this config, if it is enabled.
+    pub fn circuit_breaker(&self) -> Option<CircuitBreaker> {
+        self.breaker_threshold.map(|threshold| {
+            let breaker = CircuitBreaker::new(
+                threshold.get(),
+                Duration::from_secs(self.breaker_cooldown_secs),
+            );
+            match self.backoff() {
+                Some(backoff) => breaker.with_backoff(backoff),
+                None => breaker,
+            }
+        })
+    }
+
+    /// The circuit breaker degrading the Redis cache to its in memory level
+    /// while Redis fails.
+    pub fn redis_breaker(&self) -> CircuitBreaker {
+        CircuitBreaker::new(
+            self.redis_failure_threshold.get(),
+            Duration::from_secs(self.redis_cooldown_secs),
+        )
+        .for_service("Redis")
+    }
+
+    /// The connect timeout of each backend address.
+    pub fn connect_timeouts(&self) -> ConnectTimeouts {
+        ConnectTimeouts::new(
+            Duration::from_millis(self.backend_connect_timeout_ms.get()),
+            self.backend_connect_timeouts
+                .iter()
+                .map(|entry| (entry.addr, Duration::from_millis(entry.connect_timeout_ms.get())))
+                .collect(),
+        )
+    }
+
+    /// The backoff between attempts to reach a failing backend, if it is
+    /// enabled.
+    pub fn backoff(&self) -> Option<Backoff> {
+        self.backoff_initial_ms.map(|initial| {
+            Backoff::new(
+                Duration::from_millis(initial.get()),
+                Duration::from_millis(self.backoff_max_ms.get()),
+                self.backoff_jitter,
+            )
+        })
+    }
+
+    /// Whether DNs without a bind map entry may bind and then make any query.
+    pub fn is_default_dn_permissive(&self) -> bool {
+        self.allow_all_bind_dns
+            && self
+                .default_dn_config
+                .as_ref()
+                .is_none_or(|dnconfig| dnconfig.allowed_queries.is_empty())
+    }
+
+    pub fn offline_bind_cache(&self) -> Option<Arc<OfflineBindCache>> {
+        self.offline_bind
+            .as_ref()
+            .filter(|offline_bind| offline_bind.enabled)
+            .map(|offline_bind| {
+                Arc::new(OfflineBindCache::new(
+                    offline_bind.kdf,
+                    Duration::from_secs(offline_bind.ttl_secs.get()),
+                ))
+            })
+    }
+
+    pub fn bind_cache(&self) -> Option<Arc<OfflineBindCache>> {
+        let bind_cache = self.bind_cache.as_ref().filter(|bind_cache| bind_cache.enabled)?;
+        match OfflineBindCache::keyed(Duration::from_secs(bind_cache.ttl_secs.get())) {
+            Ok(cache) => Some(Arc::new(cache)),
+            Err(e) => {
+                warn!(?e, "Unable to create the bind cache key, the bind cache is disabled");
+                None
+            }
+        }
+    }
+}
+
+/// A backend that sessions bound with a DN ending in `dn_suffix` are sent
+/// to, instead of the one in `ldap_url`.
+pub struct RoutedBackend {
+    pub dn_suffix: Dn,
+    pub ldap_url: Url,
+    pub tls_params: SslConnector,
+    pub tls_server_name: Option<String>,
+    pub addrs: RwLock<Vec<SocketAddr>>,
+}
+
+impl RoutedBackend {
+    fn target(&self) -> BackendTarget<'_> {
+        BackendTarget {
+            addrs: &self.addrs,
+            tls_params: &self.tls_params,
+            tls_server_name: self.tls_server_name.as_deref(),
+        }
+    }
+}
+
+/// Where to connect for a backend, borrowed from the [`AppState`].
+pub struct BackendTarget<'a> {
+    pub addrs: &'a RwLock<Vec<SocketAddr>>,
+    pub tls_params: &'a SslConnector,
+    pub tls_server_name: Option<&'a str>,
+}
+
+impl BackendTarget<'_> {
+    /// A snapshot of the current addresses of this backend.
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.read().unwrap().clone()
+    }
+}
+
+impl AppState {
+    /// Whether the proxy is in maintenance mode.
+    pub fn in_maintenance(&self) -> bool {
+        self.maintenance.load(Ordering::Relaxed)
+    }
+
+    /// Enter or leave maintenance mode. While in it, searches are answered
+    /// only from the cache and binds as `maintenance_bind_action` says, so
+    /// that clients never contact the backend.
+    pub fn set_maintenance(&self, enabled: bool) {
+        if self.maintenance.swap(enabled, Ordering::SeqCst) == enabled {
+            return;
+        }
+        if enabled {
+            warn!("Entering maintenance mode, searches are answered only from the cache");
+        } else {
+            info!("Leaving maintenance mode, contacting the backend again");
+        }
+    }
+
+    /// A snapshot of the current backend addresses.
+    pub fn backend_addrs(&self) -> Vec<SocketAddr> {
+        self.addrs.read().unwrap().clone()
+    }
+
+    fn default_backend(&self) -> BackendTarget<'_> {
+        BackendTarget {
+            addrs: &self.addrs,
+            tls_params: &self.tls_params,
+            tls_server_name: self.tls_server_name.as_deref(),
+        }
+    }
+
+    /// The backend for sessions bound as `dn`: the first routed backend whose
+    /// suffix matches, or the default backend.
+    pub fn backend_for(&self, dn: &str) -> BackendTarget<'_> {
+        if self.routed_backends.is_empty() {
+            return self.default_backend();
+        }
+        let Ok(dn) = dn.parse::<Dn>() else {
+            return self.default_backend();
+        };
+        self.routed_backends
+            .iter()
+            .find(|backend| dn.ends_with(&backend.dn_suffix))
+            .map(RoutedBackend::target)
+            .unwrap_or_else(|| self.default_backend())
+    }
+
+    /// The config of sessions bound as `dn`: its bind map entry, or the
+    /// first `dn_default` whose suffix matches, or `default_dn_config` when
+    /// `allow_all_bind_dns` is set. None if `dn` may not bind.
+    pub fn dn_config(&self, dn: &str) -> Option<&DnConfig> {
+        if let Some(dnconfig) = self.binddn_map.get(dn) {
+            return Some(dnconfig);
+        }
+        let matched = match dn.parse::<Dn>() {
+            Ok(dn) if !self.dn_defaults.is_empty() => self
+                .dn_defaults
+                .iter()
+                .find(|default| dn.ends_with(&default.dn_suffix)),
+            _ => None,
+        };
+        match matched {
+            Some(default) => Some(&default.config),
+            None => self.allow_all_bind_dns.then_some(&self.default_dn_config),
+        }
+    }
+
+    /// The default backend followed by every routed backend.
+    pub fn backends(&self) -> impl Iterator<Item = BackendTarget<'_>> {
+        std::iter::once(self.default_backend())
+            .chain(self.routed_backends.iter().map(RoutedBackend::target))
+    }
+
+    /// Rewrite the referrals in a result from the backend before it is
+    /// relayed to the client.
+    pub fn rewrite_referrals(&self, referrals: &mut Vec<String>) {
+        *referrals = std::mem::take(referrals)
+            .into_iter()
+            .filter_map(|referral| {
+                match rewrite_referral(&self.referral_rewrite, &referral) {
+                    Some(rewritten) => Some(rewritten),
+                    None if self.strip_unmatched_referrals => {
+                        debug!(%referral, "Stripping referral that no rule matches");
+                        None
+                    }
+                    None => Some(referral),
+                }
+            })
+            .collect();
+    }
+}
+
+#[derive(Debug, Clone, Deserialize)]
 pub struct DnConfig {
     #[serde(default)]
     pub allowed_queries: HashSet<(String, LdapSearchScope, LdapFilterWrapper)>,
+    #[serde(default)]
+    pub allowed_bases: Vec<String>,
+    /// The scopes searches by this DN may use. Searches with any other
+    /// scope are rejected with `unwillingToPerform`, whatever the other
+    /// allow rules say. Any scope is allowed when empty.
+    #[serde(default)]
+    pub allowed_scopes: HashSet<LdapSearchScope>,
+    /// DNs in the same cache group share fallback cache entries. Only group
+    /// DNs that are guaranteed to see identical data from the backend.
+    #[serde(default)]
+    pub cache_group: Option<String>,
+    /// Never cache searches by this DN, nor serve them from the fallback
+    /// cache. Searches fail with `unavailable` during an outage instead.
+    #[serde(default)]
+    pub disable_cache: bool,
+    /// Only accept binds as this DN from clients in these networks. Any
+    /// source is accepted when empty.
+    #[serde(default)]
+    pub allowed_source_cidrs: Vec<IpCidr>,
+    /// The most sessions that may be bound as this DN at once. Further binds
+    /// are rejected with `adminLimitExceeded`. Unlimited when unset.
+    #[serde(default)]
+    pub max_sessions: Option<NonZeroUsize>,
+    /// When set, searches asking for all user attributes are sent to the
+    /// backend asking for only these, and cached that way.
+    #[serde(default)]
+    pub default_attributes: Option<Vec<String>>,
+    /// How searches naming attributes are treated when `default_attributes`
+    /// is set.
+    #[serde(default)]
+    pub explicit_attributes: ExplicitAttributes,
+    /// Give up on the backend when a search by this DN takes longer than
+    /// this, abandoning it and serving the fallback cache or
+    /// `timeLimitExceeded`. Unlimited when unset.
+    #[serde(default)]
+    pub search_deadline_ms: Option<NonZeroU64>,
+    /// Never serve fallback cache entries older than this many seconds to
+    /// this DN, as though none were cached. Unlike the cache TTL this does
+    /// not remove them. Any age is served when unset.
+    #[serde(default)]
+    pub max_fallback_age_secs: Option<u64>,
+    /// Reject modify, add, delete and modify DN requests by this DN with
+    /// `insufficientAccessRights` at the proxy, whatever the backend would
+    /// allow.
+    #[serde(default = "default_read_only")]
+    pub read_only: bool,
+}
+
+fn default_read_only() -> bool {
+    true
+}
+
+impl Default for DnConfig {
+    fn default() -> Self {
+        DnConfig {
+            allowed_queries: HashSet::new(),
+            allowed_bases: Vec::new(),
+            allowed_scopes: HashSet::new(),
+            cache_group: None,
+            disable_cache: false,
+            allowed_source_cidrs: Vec::new(),
+            max_sessions: None,
+            default_attributes: None,
+            explicit_attributes: ExplicitAttributes::default(),
+            search_deadline_ms: None,
+            max_fallback_age_secs: None,
+            read_only: default_read_only(),
+        }
+    }
+}
+
+/// How the attributes a search names are combined with `default_attributes`.
+#[derive(Debug, Clone, Copy, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExplicitAttributes {
+    /// Only the named attributes that are also in `default_attributes` are
+    /// requested.
+    #[default]
+    Intersect,
+    /// The named attributes are requested as they are.
+    Override,
+}
+
+impl DnConfig {
+    /// Returns true if a search with this base is permitted by `allowed_bases`.
+    /// The base must be equal to or below one of the listed bases, compared
+    /// RDN by RDN as [`Dn::ends_with`] does. A base that is not a valid DN is
+    /// never permitted.
+    pub fn is_base_allowed(&self, base: &str) -> bool {
+        if self.allowed_bases.is_empty() {
+            return true;
+        }
+
+        let Ok(base) = base.parse::<Dn>() else {
+            return false;
+        };
+        self.allowed_bases
+            .iter()
+            .filter_map(|allowed| allowed.parse::<Dn>().ok())
+            .any(|allowed| base.ends_with(&allowed))
+    }
+
+    /// Returns true if `allowed_source_cidrs` permits a bind from `addr`.
+    /// Clients without an IP address, such as those on a unix socket, are
+    /// only permitted when the list is empty.
+    pub fn is_source_allowed(&self, addr: Option<IpAddr>) -> bool {
+        if self.allowed_source_cidrs.is_empty() {
+            return true;
+        }
+        addr.is_some_and(|addr| self.allowed_source_cidrs.iter().any(|cidr| cidr.contains(addr)))
+    }
+
+    /// The identity used to partition the fallback cache for this DN. This is
+    /// the bind DN unless the DN belongs to a cache group. Group partitions
+    /// are not valid DNs so they can never collide with a bind DN.
+    pub fn cache_partition(&self, dn: &str) -> String {
+        match &self.cache_group {
+            Some(group) => format!("cache_group:{}", group),
+            None => dn.to_string(),
+        }
+    }
+
+    /// The attributes to request from the backend for a search asking for
+    /// `attrs`. An empty list or `*` stands for `default_attributes`, while
+    /// other attributes are kept according to `explicit_attributes`. Names
+    /// are compared case-insensitively.
+    pub fn project_attributes(&self, attrs: Vec<String>) -> Vec<String> {
+        let Some(defaults) = &self.default_attributes else {
+            return attrs;
+        };
+        let is_default = |attr: &str| defaults.iter().any(|d| d.eq_ignore_ascii_case(attr));
+
+        let mut projected: Vec<String> = Vec::new();
+        let mut push = |attr: &str| {
+            if !projected.iter().any(|p| p.eq_ignore_ascii_case(attr)) {
+                projected.push(attr.to_string());
+            }
+        };
+        if attrs.is_empty() || attrs.iter().any(|attr| attr == "*") {
+            defaults.iter().for_each(|attr| push(attr));
+        }
+        for attr in attrs.iter().filter(|attr| *attr != "*") {
+            if self.explicit_attributes == ExplicitAttributes::Override || is_default(attr) {
+                push(attr);
+            }
+        }
+
+        // An empty list would ask the backend for every attribute.
+        if projected.is_empty() {
+            projected.push("1.1".to_string());
+        }
+        projected
+    }
+
+    /// Returns true if a search with this scope is permitted by
+    /// `allowed_scopes`.
+    pub fn is_scope_allowed(&self, scope: &LdapSearchScope) -> bool {
+        self.allowed_scopes.is_empty() || self.allowed_scopes.contains(scope)
+    }
+
+    /// Returns true if the search is permitted by both `allowed_bases` and
+    /// `allowed_queries`.
+    pub fn is_search_allowed(&self, base: &str, scope: &LdapSearchScope, filter: &LdapFilter) -> bool {
+        if !self.is_base_allowed(base) {
+            return false;
+        }
+
+        self.allowed_queries.is_empty()
+            || self.allowed_queries.contains(&(
+                base.to_string(),
+                scope.clone(),
+                LdapFilterWrapper {
+                    inner: filter.clone(),
+                },
+            ))
+    }
 }
 
 #[derive(DeserializeFromStr, Debug, Clone, PartialEq, Eq, Hash)]
@@ -57,10 +865,241 @@ impl FromStr for LdapFilterWrapper {
     }
 }
 
+impl LdapFilterWrapper {
+    /// The filter in the string form accepted in `allowed_queries`.
+    pub fn to_filter_string(&self) -> String {
+        filter_to_string(&self.inner)
+    }
+}
+
+/// Write `filter` in its RFC 4515 string form. The filter parser takes values
+/// literally rather than unescaping them, so values holding characters it
+/// treats as operators are quoted instead of escaped, letting the result be
+/// parsed back to the same filter.
+pub fn filter_to_string(filter: &LdapFilter) -> String {
+    let mut out = String::new();
+    write_filter(&mut out, filter);
+    out
+}
+
+/// How deeply `filter` nests: 1 for a single item, and one more for each
+/// level of and, or and not around it.
+pub fn filter_depth(filter: &LdapFilter) -> usize {
+    match filter {
+        LdapFilter::And(filters) | LdapFilter::Or(filters) => {
+            1 + filters.iter().map(filter_depth).max().unwrap_or(0)
+        }
+        LdapFilter::Not(filter) => 1 + filter_depth(filter),
+        _ => 1,
+    }
+}
+
+fn write_filter_value(out: &mut String, value: &str) {
+    let needs_quotes = value.is_empty()
+        || value.chars().any(|c| {
+            matches!(
+                c,
+                '=' | '(' | ')' | '~' | '>' | '<' | '!' | '&' | '|' | ' ' | '\t' | '\n'
+            )
+        });
+    if needs_quotes {
+        out.push('"');
+        out.push_str(value);
+        out.push('"');
+    } else {
+        out.push_str(value);
+    }
+}
+
+fn write_filter(out: &mut String, filter: &LdapFilter) {
+    out.push('(');
+    match filter {
+        LdapFilter::And(filters) => {
+            out.push('&');
+            filters.iter().for_each(|filter| write_filter(out, filter));
+        }
+        LdapFilter::Or(filters) => {
+            out.push('|');
+            filters.iter().for_each(|filter| write_filter(out, filter));
+        }
+        LdapFilter::Not(filter) => {
+            out.push('!');
+            write_filter(out, filter);
+        }
+        LdapFilter::Equality(attr, value) => {
+            out.push_str(attr);
+            out.push('=');
+            write_filter_value(out, value);
+        }
+        LdapFilter::Substring(attr, substring) => {
+            out.push_str(attr);
+            out.push('=');
+            if let Some(initial) = &substring.initial {
+                write_filter_value(out, initial);
+            }
+            out.push('*');
+            for any in &substring.any {
+                write_filter_value(out, any);
+                out.push('*');
+            }
+            if let Some(final_) = &substring.final_ {
+                write_filter_value(out, final_);
+            }
+        }
+        LdapFilter::GreaterOrEqual(attr, value) => {
+            out.push_str(attr);
+            out.push_str(">=");
+            write_filter_value(out, value);
+        }
+        LdapFilter::LessOrEqual(attr, value) => {
+            out.push_str(attr);
+            out.push_str("<=");
+            write_filter_value(out, value);
+        }
+        LdapFilter::Present(attr) => {
+            out.push_str(attr);
+            out.push_str("=*");
+        }
+        LdapFilter::Approx(attr, value) => {
+            out.push_str(attr);
+            out.push_str("~=");
+            write_filter_value(out, value);
+        }
+        LdapFilter::Extensible(assertion) => {
+            if let Some(type_) = &assertion.type_ {
+                out.push_str(type_);
+            }
+            if assertion.dn_attributes {
+                out.push_str(":dn");
+            }
+            if let Some(matching_rule) = &assertion.matching_rule {
+                out.push(':');
+                out.push_str(matching_rule);
+            }
+            out.push_str(":=");
+            write_filter_value(out, &assertion.match_value);
+        }
+    }
+    out.push(')');
+}
+
 fn default_fallback_cache_bytes() -> usize {
     256 * MEGABYTES
 }
 
+fn default_cacheable_result_codes() -> HashSet<LdapResultCode> {
+    HashSet::from([LdapResultCode::Success])
+}
+
+fn default_degraded_result_codes() -> HashSet<LdapResultCode> {
+    HashSet::from([LdapResultCode::Busy, LdapResultCode::Unavailable])
+}
+
+fn default_require_ldap_v3() -> bool {
+    true
+}
+
+fn default_listen_backlog() -> u32 {
+    1024
+}
+
+/// A network in CIDR notation, such as `10.0.0.0/8` or `2001:db8::/32`. A
+/// bare address is a network of just that address.
+#[derive(DeserializeFromStr, Debug, Clone, PartialEq, Eq)]
+pub struct IpCidr {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpCidr {
+    pub fn contains(&self, addr: IpAddr) -> bool {
+        // IPv4 clients of a dual stack listener appear as mapped addresses.
+        match (self.addr, addr.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(addr)) => {
+                let mask = u32::MAX.checked_shl(32 - u32::from(self.prefix_len)).unwrap_or(0);
+                u32::from(net) & mask == u32::from(addr) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(addr)) => {
+                let mask = u128::MAX.checked_shl(128 - u32::from(self.prefix_len)).unwrap_or(0);
+                u128::from(net) & mask == u128::from(addr) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl FromStr for IpCidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match s.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (s, None),
+        };
+        let addr = IpAddr::from_str(addr).map_err(|err| format!("{} -> {}", s, err))?;
+        let max_len = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len
+                .parse::<u8>()
+                .ok()
+                .filter(|len| *len <= max_len)
+                .ok_or_else(|| format!("{} -> invalid prefix length", s))?,
+            None => max_len,
+        };
+        Ok(IpCidr { addr, prefix_len })
+    }
+}
+
+/// A single address the proxy accepts connections on. Unix domain sockets
+/// are given as `unix:/path/to/socket`.
+#[derive(DeserializeFromStr, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Some(path) = s.strip_prefix("unix:") {
+            if path.is_empty() {
+                return Err("unix listen address requires a path".to_string());
+            }
+            Ok(ListenAddr::Unix(PathBuf::from(path)))
+        } else {
+            SocketAddr::from_str(s)
+                .map(ListenAddr::Tcp)
+                .map_err(|err| err.to_string())
+        }
+    }
+}
+
+impl fmt::Display for ListenAddr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ListenAddr::Tcp(addr) => write!(f, "{}", addr),
+            ListenAddr::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum BindConfig {
+    Single(ListenAddr),
+    Multiple(Vec<ListenAddr>),
+}
+
+impl BindConfig {
+    pub fn addrs(&self) -> &[ListenAddr] {
+        match self {
+            BindConfig::Single(addr) => std::slice::from_ref(addr),
+            BindConfig::Multiple(addrs) => addrs.as_slice(),
+        }
+    }
+}
+
 #[derive(Debug, Deserialize, Default, Clone, Copy)]
 pub enum AddrInfoSource {
     #[default]
@@ -68,7 +1107,290 @@ pub enum AddrInfoSource {
     ProxyV2,
 }
 
+/// The PROXY header handling of a listener.
+#[derive(Debug, Deserialize, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ProxyProtocolMode {
+    /// Read the client address from a PROXY v2 header, and refuse
+    /// connections without a valid one.
+    Require,
+    /// Refuse connections that start with a PROXY header, so that a client
+    /// connecting directly cannot claim another address.
+    Forbid,
+}
+
+/// A `[[backend]]` entry. Sessions bound with a DN ending in `dn_suffix` use
+/// this backend rather than the one in `ldap_url`. The first matching entry
+/// applies. `ldap_tls_insecure_skip_verify` and `address_preference` apply to
+/// every backend.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BackendConfig {
+    pub ldap_url: Url,
+    pub ldap_ca: PathBuf,
+    pub dn_suffix: Dn,
+}
+
+/// A `[[dn_default]]` entry. DNs without a bind map entry that end in
+/// `dn_suffix` may bind, with the options of a bind map entry given here.
+/// The first matching entry applies.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DnDefaultConfig {
+    pub dn_suffix: Dn,
+    #[serde(flatten)]
+    pub config: DnConfig,
+}
+
+/// A `[[backend_connect_timeout]]` entry, overriding the connect timeout for
+/// one backend address.
 #[derive(Debug, Deserialize, Clone)]
+pub struct ConnectTimeoutConfig {
+    pub addr: SocketAddr,
+    pub connect_timeout_ms: NonZeroU64,
+}
+
+/// How long to wait for a TCP connection to each backend address before
+/// trying the next.
+#[derive(Debug, Clone)]
+pub struct ConnectTimeouts {
+    default: Duration,
+    by_addr: BTreeMap<SocketAddr, Duration>,
+}
+
+impl ConnectTimeouts {
+    pub fn new(default: Duration, by_addr: BTreeMap<SocketAddr, Duration>) -> Self {
+        ConnectTimeouts { default, by_addr }
+    }
+
+    pub fn for_addr(&self, addr: &SocketAddr) -> Duration {
+        self.by_addr.get(addr).copied().unwrap_or(self.default)
+    }
+}
+
+impl Default for ConnectTimeouts {
+    fn default() -> Self {
+        ConnectTimeouts::new(
+            Duration::from_millis(default_backend_connect_timeout_ms().get()),
+            BTreeMap::new(),
+        )
+    }
+}
+
+/// Rewrites DNs ending in `client_suffix` to end in `backend_suffix` instead,
+/// so that clients can use different DNs to those the backend expects. An
+/// empty `client_suffix` matches every non-empty DN.
+#[derive(Debug, Deserialize, Clone)]
+pub struct DnRewrite {
+    pub client_suffix: String,
+    pub backend_suffix: String,
+}
+
+impl DnRewrite {
+    fn apply(&self, dn: &str) -> Option<String> {
+        let prefix = if self.client_suffix.is_empty() {
+            dn
+        } else {
+            let split = dn.len().checked_sub(self.client_suffix.len())?;
+            if !dn.is_char_boundary(split)
+                || !dn[split..].eq_ignore_ascii_case(&self.client_suffix)
+            {
+                return None;
+            }
+            let prefix = &dn[..split];
+            if prefix.is_empty() {
+                prefix
+            } else {
+                // The suffix must match whole RDNs.
+                prefix.strip_suffix(',')?
+            }
+        };
+
+        Some(match (prefix.is_empty(), self.backend_suffix.is_empty()) {
+            (true, _) => self.backend_suffix.clone(),
+            (false, true) => prefix.to_string(),
+            (false, false) => format!("{},{}", prefix, self.backend_suffix),
+        })
+    }
+}
+
+/// Rewrite `dn` with the first matching rule. The empty DN, used for
+/// anonymous binds and the root dse, is never rewritten.
+pub fn rewrite_dn(rules: &[DnRewrite], dn: &str) -> String {
+    if dn.is_empty() {
+        return String::new();
+    }
+    rules
+        .iter()
+        .find_map(|rule| rule.apply(dn))
+        .unwrap_or_else(|| dn.to_string())
+}
+
+/// Rewrites referral urls from the backend so that clients are sent
+/// somewhere they can reach. `backend_url` matches the scheme, host and port
+/// of a referral, which are replaced with `client_url`. The rest of the url,
+/// such as the DN, is kept.
+#[derive(Debug, Deserialize, Clone)]
+pub struct ReferralRewrite {
+    pub backend_url: String,
+    pub client_url: String,
+}
+
+impl ReferralRewrite {
+    fn apply(&self, referral: &str) -> Option<String> {
+        let backend_url = self.backend_url.trim_end_matches('/');
+        let rest = referral
+            .get(..backend_url.len())
+            .filter(|prefix| prefix.eq_ignore_ascii_case(backend_url))
+            .map(|_| &referral[backend_url.len()..])?;
+        // The rule must match the whole host and port.
+        if !rest.is_empty() && !rest.starts_with(['/', '?']) {
+            return None;
+        }
+        Some(format!("{}{}", self.client_url.trim_end_matches('/'), rest))
+    }
+}
+
+/// Rewrite `referral` with the first matching rule, or None if no rule
+/// matches.
+pub fn rewrite_referral(rules: &[ReferralRewrite], referral: &str) -> Option<String> {
+    rules.iter().find_map(|rule| rule.apply(referral))
+}
+
+/// How to answer a search that the bind map does not allow.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DeniedQueryAction {
+    /// Reply as if the search succeeded with no results.
+    EmptySuccess,
+    /// Reply with insufficientAccessRights and keep the connection open.
+    #[default]
+    AccessDenied,
+    /// Reply with insufficientAccessRights and close the connection.
+    Disconnect,
+}
+
+/// How long a session holds its backend connection.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BackendMode {
+    /// From the bind until the session ends.
+    #[default]
+    PerSession,
+    /// Only while a search runs. Each search binds a connection again, or
+    /// takes one from the backend pool.
+    PerOperation,
+}
+
+/// The authzId form of the Who Am I? extended operation's response.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum WhoamiFormat {
+    /// `dn:` followed by the bind DN, as RFC 4532 specifies.
+    #[default]
+    Dn,
+    /// The bind DN without a prefix.
+    Raw,
+    /// `u:` followed by the value of the bind DN's leftmost RDN.
+    U,
+}
+
+impl WhoamiFormat {
+    /// The authzId of a session bound as `dn`. Anonymous sessions have an
+    /// empty authzId, and DNs without a usable RDN value fall back to `dn:`.
+    pub fn authz_id(self, dn: &str) -> String {
+        if dn.is_empty() {
+            return String::new();
+        }
+        match self {
+            WhoamiFormat::Raw => dn.to_string(),
+            WhoamiFormat::U => {
+                let parsed = dn.parse::<Dn>().ok();
+                match parsed.as_ref().and_then(Dn::leaf_value) {
+                    Some(user) => format!("u:{user}"),
+                    None => format!("dn:{dn}"),
+                }
+            }
+            WhoamiFormat::Dn => format!("dn:{dn}"),
+        }
+    }
+}
+
+/// What to do at startup when `allow_all_bind_dns` lets unknown DNs make any
+/// query.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum PermissiveDefaultAction {
+    /// Log a warning and start.
+    #[default]
+    Warn,
+    /// Refuse to start.
+    Error,
+}
+
+/// When the search cache is consulted relative to the backend.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CacheMode {
+    /// Always query the backend, using the cache only when it fails.
+    #[default]
+    Fallback,
+    /// Serve entries younger than `read_through_max_age_secs` without
+    /// querying the backend, and query it on a miss.
+    ReadThrough,
+    /// As `Fallback`, but write every cacheable result to the cache even if
+    /// it is unchanged, refreshing its age and Redis ttl.
+    WriteThrough,
+}
+
+/// How to answer a search when the backend is unreachable and the fallback
+/// cache holds nothing for it.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum NoFallbackAction {
+    /// Reply with unavailable and close the connection.
+    #[default]
+    Unavailable,
+    /// Reply as if the search succeeded with no results.
+    EmptySuccess,
+}
+
+/// How to answer a bind while the proxy is in maintenance mode.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum MaintenanceBindAction {
+    /// Accept credentials that match the offline bind cache, and reject
+    /// the rest with unavailable.
+    #[default]
+    Offline,
+    /// Reject every bind with unavailable.
+    Reject,
+}
+
+/// How to answer a request with a critical control the proxy cannot honor,
+/// as it would relay the control without its criticality or answer the
+/// request itself.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum CriticalControlAction {
+    /// Reply with unavailableCriticalExtension.
+    #[default]
+    Reject,
+    /// Proceed as if the control were not critical.
+    Ignore,
+}
+
+/// Which address family to try first when the backend resolves to both IPv4
+/// and IPv6 addresses.
+#[derive(Debug, Deserialize, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AddressPreference {
+    /// Keep the order returned by the system resolver.
+    #[default]
+    System,
+    Ipv4First,
+    Ipv6First,
+}
+
+#[derive(Deserialize, Clone)]
 #[serde(tag = "type", rename_all = "lowercase")]
 pub enum CacheConfig {
     Memory {
@@ -84,6 +1406,235 @@ pub enum CacheConfig {
     },
 }
 
+impl CacheConfig {
+    /// The prefix of the Redis keys of the cache. Other caches ignore it, and
+    /// custom stores are handed the default.
+    pub fn key_prefix(&self) -> String {
+        match self {
+            CacheConfig::Redis { key_prefix, .. } => key_prefix.clone(),
+            CacheConfig::Memory { .. } => default_redis_key_prefix(),
+        }
+    }
+}
+
+// Implement by hand to avoid printing the Redis password.
+impl fmt::Debug for CacheConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheConfig::Memory { size_bytes } => f
+                .debug_struct("Memory")
+                .field("size_bytes", size_bytes)
+                .finish(),
+            CacheConfig::Redis {
+                url,
+                ttl_seconds,
+                key_prefix,
+            } => {
+                let url = match Url::parse(url) {
+                    Ok(mut url) if url.password().is_some() => {
+                        let _ = url.set_password(Some("***"));
+                        url.to_string()
+                    }
+                    Ok(url) => url.to_string(),
+                    Err(_) => "<invalid>".to_string(),
+                };
+                f.debug_struct("Redis")
+                    .field("url", &url)
+                    .field("ttl_seconds", ttl_seconds)
+                    .field("key_prefix", key_prefix)
+                    .finish()
+            }
+        }
+    }
+}
+
+/// Queries executed against the backend at startup to prime the fallback
+/// cache. Each query is `[bind_dn, base, scope, filter]` and is bound with
+/// the matching entry from `credentials` (or anonymously for `""`).
+#[derive(Deserialize, Clone, Default)]
+pub struct CacheWarmConfig {
+    #[serde(default)]
+    pub credentials: BTreeMap<String, String>,
+    #[serde(default)]
+    pub queries: Vec<(String, String, LdapSearchScope, LdapFilterWrapper)>,
+}
+
+// Implement by hand to avoid printing the passwords.
+impl fmt::Debug for CacheWarmConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CacheWarmConfig")
+            .field("credentials", &self.credentials.keys().collect::<Vec<_>>())
+            .field("queries", &self.queries)
+            .finish()
+    }
+}
+
+/// Keep connections to the backend that are already bound so that clients
+/// binding repeatedly as the same dn skip the connect and bind.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BackendPoolConfig {
+    pub per_dn_idle: usize,
+    #[serde(default = "default_pool_idle_timeout_seconds")]
+    pub idle_timeout_seconds: u64,
+    #[serde(default = "default_pool_ping_interval_seconds")]
+    pub ping_interval_seconds: NonZeroU64,
+}
+
+/// Cache a salted hash of the password of each successful simple bind, so
+/// that while the backend is unreachable a client presenting the same
+/// password can still bind and be answered from the fallback cache.
+#[derive(Debug, Deserialize, Clone)]
+pub struct OfflineBindConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    /// How long after a successful bind its password is accepted offline.
+    #[serde(default = "default_offline_bind_ttl_secs")]
+    pub ttl_secs: NonZeroU64,
+    #[serde(default)]
+    pub kdf: OfflineBindKdf,
+}
+
+/// Answer a simple bind repeating the dn and password of a successful bind
+/// within the last `ttl_secs` without contacting the backend. Only an
+/// HMAC-SHA256 of the password under a random key of this process is kept,
+/// and binds the backend rejects are never cached.
+#[derive(Debug, Deserialize, Clone)]
+pub struct BindCacheConfig {
+    #[serde(default)]
+    pub enabled: bool,
+    #[serde(default = "default_bind_cache_ttl_secs")]
+    pub ttl_secs: NonZeroU64,
+}
+
+/// The key derivation function used to hash cached bind passwords.
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum OfflineBindKdf {
+    #[default]
+    Scrypt,
+    Pbkdf2Sha256,
+}
+
+/// The compression of values written to the Redis cache. Values are read
+/// whichever way they were written, see [`compression::decompress`].
+#[derive(Debug, Deserialize, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RedisCompression {
+    #[default]
+    None,
+    Zstd,
+    Gzip,
+}
+
+/// Export a span for each client operation to an OpenTelemetry collector.
+#[derive(Debug, Deserialize, Clone)]
+pub struct TracingConfig {
+    /// The OTLP/HTTP traces endpoint, e.g. `http://localhost:4318/v1/traces`.
+    pub otlp_endpoint: Url,
+    #[serde(default = "default_tracing_service_name")]
+    pub service_name: String,
+    /// How long to wait for the collector to accept a batch of spans.
+    #[serde(default = "default_tracing_export_timeout_secs")]
+    pub export_timeout_secs: u64,
+}
+
+/// Serve `/livez` and `/readyz` over plain HTTP for orchestrators.
+#[derive(Debug, Deserialize, Clone)]
+pub struct HealthConfig {
+    pub bind: SocketAddr,
+    /// How often the backend addresses are checked for readiness.
+    #[serde(default = "default_health_check_interval_seconds")]
+    pub check_interval_seconds: NonZeroU64,
+}
+
+fn default_health_check_interval_seconds() -> NonZeroU64 {
+    NonZeroU64::new(10).unwrap()
+}
+
+fn default_tracing_service_name() -> String {
+    "ldap-proxy".to_string()
+}
+
+fn default_tracing_export_timeout_secs() -> u64 {
+    10
+}
+
+fn default_breaker_cooldown_secs() -> u64 {
+    30
+}
+
+fn default_redis_failure_threshold() -> NonZeroU32 {
+    NonZeroU32::new(5).unwrap()
+}
+
+fn default_backoff_max_ms() -> NonZeroU64 {
+    NonZeroU64::new(60_000).unwrap()
+}
+
+fn default_backoff_jitter() -> f64 {
+    0.2
+}
+
+fn default_backend_bind_timeout_secs() -> NonZeroU64 {
+    NonZeroU64::new(10).unwrap()
+}
+
+fn default_backend_connect_timeout_ms() -> NonZeroU64 {
+    NonZeroU64::new(5000).unwrap()
+}
+
+fn default_response_flush_entries() -> NonZeroUsize {
+    NonZeroUsize::new(64).unwrap()
+}
+
+fn default_schema_refresh_secs() -> NonZeroU64 {
+    NonZeroU64::new(3600).unwrap()
+}
+
+fn default_quiesce_interval_secs() -> NonZeroU64 {
+    NonZeroU64::new(5).unwrap()
+}
+
+fn default_require_cache() -> bool {
+    true
+}
+
+fn default_read_through_max_age_secs() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
+fn default_bind_cache_ttl_secs() -> NonZeroU64 {
+    NonZeroU64::new(5).unwrap()
+}
+
+fn default_offline_bind_ttl_secs() -> NonZeroU64 {
+    NonZeroU64::new(900).unwrap()
+}
+
+fn default_ldap_tls_session_resumption() -> bool {
+    true
+}
+
+fn default_max_dn_length() -> NonZeroUsize {
+    NonZeroUsize::new(4096).unwrap()
+}
+
+fn default_max_filter_depth() -> NonZeroUsize {
+    NonZeroUsize::new(64).unwrap()
+}
+
+fn default_max_concurrent_ops() -> NonZeroUsize {
+    NonZeroUsize::MIN
+}
+
+fn default_pool_idle_timeout_seconds() -> u64 {
+    300
+}
+
+fn default_pool_ping_interval_seconds() -> NonZeroU64 {
+    NonZeroU64::new(60).unwrap()
+}
+
 fn default_redis_key_prefix() -> String {
     "ldap_proxy:".to_string()
 }
@@ -98,29 +1649,438 @@ impl Default for CacheConfig {
 
 #[derive(Debug, Deserialize)]
 pub struct Config {
-    pub bind: SocketAddr,
+    pub bind: BindConfig,
+    #[serde(default = "default_listen_backlog")]
+    pub listen_backlog: u32,
+    /// Tenant labels keyed by listen address. Searches made on a listener
+    /// with a tenant are cached apart from those of other tenants and of
+    /// listeners without one.
+    #[serde(default)]
+    pub tenants: BTreeMap<ListenAddr, String>,
+    /// Whether connections to a listener must or must not start with a
+    /// PROXY header, keyed by listen address, see
+    /// [`Config::proxy_protocol_for`].
+    #[serde(default)]
+    pub proxy_protocol: BTreeMap<ListenAddr, ProxyProtocolMode>,
     pub tls_key: PathBuf,
     pub tls_chain: PathBuf,
 
+    /// The `[cache]` section, see [`Config::cache_config`].
+    #[serde(default)]
+    pub cache: Option<CacheConfig>,
+
+    /// Refuse to start if the Redis cache is unreachable. When false, a
+    /// local memory cache is used instead.
+    #[serde(default = "default_require_cache")]
+    pub require_cache: bool,
+
+    /// How often the memory cache applies the hits recorded by readers and
+    /// evicts entries to stay within its size.
+    #[serde(default = "default_quiesce_interval_secs")]
+    pub quiesce_interval_secs: NonZeroU64,
+
+    /// How values written to the Redis cache are compressed.
     #[serde(default)]
-    pub cache: CacheConfig,
+    pub redis_compression: RedisCompression,
+
+    /// After this many consecutive failed Redis operations the cache is
+    /// degraded to its in memory level, and Redis is not used until a probe
+    /// after `redis_cooldown_secs` succeeds.
+    #[serde(default = "default_redis_failure_threshold")]
+    pub redis_failure_threshold: NonZeroU32,
+
+    /// How long the Redis cache stays degraded before Redis is probed.
+    #[serde(default = "default_breaker_cooldown_secs")]
+    pub redis_cooldown_secs: u64,
 
-    // Deprecated: use cache.size_bytes instead
-    #[serde(default = "default_fallback_cache_bytes")]
-    pub fallback_cache_bytes: usize,
+    /// Deprecated, use `size_bytes` in the `[cache]` section instead. Only
+    /// sizes the memory cache when there is no `[cache]` section.
+    #[serde(default)]
+    pub fallback_cache_bytes: Option<usize>,
 
     pub ldap_ca: PathBuf,
     pub ldap_url: Url,
 
+    /// The name sent as SNI to the backend and checked against its
+    /// certificate, when it differs from the host of `ldap_url`.
+    #[serde(default)]
+    pub ldap_tls_sni: Option<String>,
+
+    /// Accept any backend certificate. Only for lab setups.
+    #[serde(default)]
+    pub ldap_tls_insecure_skip_verify: bool,
+
+    /// Resume TLS sessions when reconnecting to a backend, skipping the full
+    /// handshake.
+    #[serde(default = "default_ldap_tls_session_resumption")]
+    pub ldap_tls_session_resumption: bool,
+
+    #[serde(default)]
+    pub address_preference: AddressPreference,
+
+    /// Further backends, each serving the bind DNs under its `dn_suffix`.
+    #[serde(default, rename = "backend")]
+    pub backends: Vec<BackendConfig>,
+
+    /// Re-resolve `ldap_url` this often. When unset the addresses resolved at
+    /// startup are used for the life of the process.
+    #[serde(default)]
+    pub resolve_refresh_secs: Option<NonZeroU64>,
+
     #[serde(default)]
     pub remote_ip_addr_info: AddrInfoSource,
 
     pub max_incoming_ber_size: Option<usize>,
     pub max_proxy_ber_size: Option<usize>,
 
+    /// The most entries of a single search held in memory. Larger results are
+    /// streamed to the client and not cached.
+    #[serde(alias = "stream_threshold_entries")]
+    pub max_buffered_entries: Option<usize>,
+
+    /// How many search result entries are written to the client between
+    /// flushes. The result that ends a search is always flushed.
+    #[serde(default = "default_response_flush_entries")]
+    pub response_flush_entries: NonZeroUsize,
+
+    /// Binds with a DN longer than this many bytes are rejected with
+    /// invalidDNSyntax without reaching the backend.
+    #[serde(default = "default_max_dn_length")]
+    pub max_dn_length: NonZeroUsize,
+
+    /// Searches with filters nested deeper than this are rejected with
+    /// protocolError without reaching the backend.
+    #[serde(default = "default_max_filter_depth")]
+    pub max_filter_depth: NonZeroUsize,
+
     #[serde(default)]
     pub allow_all_bind_dns: bool,
 
-    #[serde(flatten)]
+    /// Configs for DNs without a bind map entry under a suffix, consulted
+    /// before `allow_all_bind_dns`.
+    #[serde(default, rename = "dn_default")]
+    pub dn_defaults: Vec<DnDefaultConfig>,
+
+    /// The config of DNs without a bind map entry when `allow_all_bind_dns`
+    /// is set. When unset they may make any query.
+    #[serde(default)]
+    pub default_dn_config: Option<DnConfig>,
+
+    /// Whether `allow_all_bind_dns` without restrictive `allowed_queries` in
+    /// `default_dn_config` is warned about or refused.
+    #[serde(default)]
+    pub permissive_default_action: PermissiveDefaultAction,
+
+    /// Accept simple binds with an empty DN and a non-empty password, using
+    /// the anonymous ("") bind map entry. They are rejected with
+    /// `invalidCredentials` otherwise.
+    #[serde(default)]
+    pub allow_unauthenticated_bind: bool,
+
+    #[serde(default)]
+    pub cache_warm: Option<CacheWarmConfig>,
+
+    /// Search result codes that may be stored in the fallback cache. Transient
+    /// codes such as `busy` should never be listed here.
+    #[serde(default = "default_cacheable_result_codes")]
+    pub cacheable_result_codes: HashSet<LdapResultCode>,
+
+    /// Search result codes that indicate the backend is overloaded or
+    /// partially available. These are answered from the fallback cache when
+    /// possible, as if the backend were unreachable.
+    #[serde(default = "default_degraded_result_codes")]
+    pub degraded_result_codes: HashSet<LdapResultCode>,
+
+    /// Sort search results by DN before they are compared with and written to
+    /// the fallback cache, so that a backend returning entries in a varying
+    /// order does not cause a cache write on every search. Results served
+    /// from the cache are then in DN order rather than the backend's.
+    #[serde(default)]
+    pub sort_cached_entries: bool,
+
+    #[serde(default)]
+    pub cache_mode: CacheMode,
+
+    /// How old a cached search may be and still be served without querying
+    /// the backend when `cache_mode` is `read_through`.
+    #[serde(default = "default_read_through_max_age_secs")]
+    pub read_through_max_age_secs: NonZeroU64,
+
+    /// Answer a search from the cache when the backend has not answered it
+    /// within this many milliseconds and a cached result exists. The backend
+    /// search still completes afterwards and refreshes the cache, on the
+    /// session's own connection, so only with `max_concurrent_ops` above 1
+    /// does the session carry on meanwhile. Searches wait for the backend
+    /// when unset.
+    #[serde(default)]
+    pub cache_race_timeout_ms: Option<NonZeroU64>,
+
+    /// Ask the backend for every attribute of searches that name the
+    /// attributes they want, and cache that superset once per base, scope and
+    /// filter. Each search is then answered with the attributes it named.
+    #[serde(default)]
+    pub cache_attribute_superset: bool,
+
+    /// Leave the size and time limits of searches out of their cache keys,
+    /// so that searches differing only in those share a cache entry. The
+    /// backend is still sent the client's limits, and a cached result is cut
+    /// to the size limit of the search it answers.
+    #[serde(default)]
+    pub cache_key_ignore_limits: bool,
+
+    /// Let a search wait for an identical one that is already being fetched
+    /// from the backend, and answer it with that result, rather than sending
+    /// its own. Searches share a result when they would share a cache entry.
+    #[serde(default)]
+    pub coalesce_searches: bool,
+
+    /// Reject binds from clients that do not request LDAPv3. When false these
+    /// binds are forwarded to the backend as LDAPv3.
+    #[serde(default = "default_require_ldap_v3")]
+    pub require_ldap_v3: bool,
+
+    /// When set, root dse searches are answered with these attributes rather
+    /// than being proxied to the backend.
+    #[serde(default)]
+    pub root_dse: Option<BTreeMap<String, Vec<String>>>,
+
+    /// When set, the `supportedControl` values of root dse entries from the
+    /// backend are limited to these OIDs, so clients are not told of
+    /// controls the proxy does not relay faithfully. Every control the
+    /// backend lists is advertised when unset.
+    #[serde(default)]
+    pub supported_controls: Option<Vec<String>>,
+
+    /// Fetch the backend's subschema subentry at startup and answer schema
+    /// searches from it, including those of clients that have not bound.
+    #[serde(default)]
+    pub cache_schema_at_startup: bool,
+
+    /// How often the schema snapshot is fetched again.
+    #[serde(default = "default_schema_refresh_secs")]
+    pub schema_refresh_secs: NonZeroU64,
+
+    /// Attach a control carrying the age in seconds of the cached data to
+    /// search responses served from the fallback cache.
+    #[serde(default)]
+    pub annotate_cached_responses: bool,
+
+    /// Replace the diagnostic message of results served from the fallback
+    /// cache with one giving the age of the cached data, followed by the
+    /// backend's original message.
+    #[serde(default)]
+    pub annotate_cached_message: bool,
+
+    /// The oid of the cache age control. Required when
+    /// `annotate_cached_responses` is enabled.
+    #[serde(default)]
+    pub cache_age_control_oid: Option<String>,
+
+    /// Answer searches served from the fallback cache with this result code
+    /// rather than the one cached, for clients telling stale data apart.
+    #[serde(default)]
+    pub fallback_result_code: Option<LdapResultCode>,
+
+    /// Begin the diagnostic message of results served from the fallback
+    /// cache with this marker and the age of the cached data, as
+    /// `<marker> age=<secs>`.
+    #[serde(default)]
+    pub fallback_result_marker: Option<String>,
+
+    #[serde(default)]
+    pub backend_pool: Option<BackendPoolConfig>,
+
+    /// Validate binds locally while the backend is unreachable. Disabled
+    /// unless `enabled` is set.
+    #[serde(default)]
+    pub offline_bind: Option<OfflineBindConfig>,
+
+    /// Answer repeated identical simple binds locally for a few seconds.
+    /// Disabled unless `enabled` is set.
+    #[serde(default)]
+    pub bind_cache: Option<BindCacheConfig>,
+
+    /// How binds are answered in maintenance mode, which SIGUSR2 toggles.
+    #[serde(default)]
+    pub maintenance_bind_action: MaintenanceBindAction,
+
+    /// How requests with critical controls the proxy cannot honor are
+    /// answered.
+    #[serde(default)]
+    pub critical_control_action: CriticalControlAction,
+
+    /// Rules for rewriting client bind DNs and search bases before they are
+    /// sent to the backend. The first matching rule applies.
+    #[serde(default)]
+    pub dn_rewrite: Vec<DnRewrite>,
+
+    /// Rules for rewriting referrals returned by the backend. The first
+    /// matching rule applies.
+    #[serde(default)]
+    pub referral_rewrite: Vec<ReferralRewrite>,
+
+    /// Drop referrals that no `referral_rewrite` rule matches, instead of
+    /// relaying them unchanged.
+    #[serde(default)]
+    pub strip_unmatched_referrals: bool,
+
+    /// Follow the continuation references in search results to the servers
+    /// they name, bound as the session's dn, and merge the entries found
+    /// there into the result. References that cannot be followed, and all
+    /// references when this is off, are relayed to the client.
+    #[serde(default)]
+    pub chase_referrals: bool,
+
+    /// The hosts `chase_referrals` may follow references to. The session's
+    /// bind, password included, is sent to them, so list only servers
+    /// trusted with the credentials of every DN. Their certificates must
+    /// chain to `ldap_ca` and match the host. References to any other host
+    /// are relayed.
+    #[serde(default)]
+    pub referral_hosts: Vec<String>,
+
+    /// Log backend binds and searches that take longer than this many
+    /// milliseconds as warnings, with the DN, filter and time taken. Not
+    /// logged when unset.
+    #[serde(default)]
+    pub slow_op_threshold_ms: Option<NonZeroU64>,
+
+    #[serde(default)]
+    pub denied_query_action: DeniedQueryAction,
+
+    #[serde(default)]
+    pub no_fallback_action: NoFallbackAction,
+
+    /// The number of searches a client may have outstanding at once. Each
+    /// outstanding search uses its own backend connection.
+    #[serde(default = "default_max_concurrent_ops")]
+    pub max_concurrent_ops: NonZeroUsize,
+
+    pub health: Option<HealthConfig>,
+
+    /// Export operation spans over OTLP. No exporter is installed when unset.
+    #[serde(default)]
+    pub tracing: Option<TracingConfig>,
+
+    /// Stop contacting the backend after this many consecutive connect or
+    /// bind failures, answering from the fallback cache instead. Disabled
+    /// when unset.
+    pub breaker_threshold: Option<NonZeroU32>,
+
+    /// How long the circuit breaker stays open before probing the backend.
+    #[serde(default = "default_breaker_cooldown_secs")]
+    pub breaker_cooldown_secs: u64,
+
+    /// Back off exponentially between attempts to reach a failing backend,
+    /// starting with this many milliseconds. Applies to the health checks
+    /// and to the probes of the circuit breaker, replacing its fixed
+    /// cooldown. Disabled when unset.
+    #[serde(default)]
+    pub backoff_initial_ms: Option<NonZeroU64>,
+
+    /// The longest wait between attempts when backing off.
+    #[serde(default = "default_backoff_max_ms")]
+    pub backoff_max_ms: NonZeroU64,
+
+    /// The fraction of each backoff wait that is randomly cut, from 0 to 1.
+    #[serde(
+        default = "default_backoff_jitter",
+        deserialize_with = "deserialize_backoff_jitter"
+    )]
+    pub backoff_jitter: f64,
+
+    /// How long to wait for the backend to answer a bind before giving up on
+    /// the connection.
+    #[serde(default = "default_backend_bind_timeout_secs")]
+    pub backend_bind_timeout_secs: NonZeroU64,
+
+    /// How long to wait for a connection to a backend address before trying
+    /// the next one.
+    #[serde(default = "default_backend_connect_timeout_ms")]
+    pub backend_connect_timeout_ms: NonZeroU64,
+
+    /// Connect timeouts for particular backend addresses, overriding
+    /// `backend_connect_timeout_ms`.
+    #[serde(default, rename = "backend_connect_timeout")]
+    pub backend_connect_timeouts: Vec<ConnectTimeoutConfig>,
+
+    /// Reconnect and re-bind as the session's dn when the backend connection
+    /// drops during a session, then retry the search once. This keeps the
+    /// client's bind in memory for the session, so it is off by default.
+    #[serde(default)]
+    pub reconnect_backend: bool,
+
+    /// Whether sessions hold a backend connection between searches.
+    #[serde(default)]
+    pub backend_mode: BackendMode,
+
+    /// The authzId form returned by the Who Am I? extended operation.
+    #[serde(default)]
+    pub whoami_format: WhoamiFormat,
+
+    /// Disconnect clients that send nothing for this long while no operation
+    /// is in progress. Disabled when unset.
+    #[serde(default)]
+    pub client_idle_timeout_secs: Option<NonZeroU64>,
+
+    /// Attribute types whose values are masked when messages are logged.
+    #[serde(default = "redact::default_sensitive_attributes")]
+    pub sensitive_attributes: Vec<String>,
+
+    /// Keyed by the normalized bind DN, see [`dn::normalize_dn`]. Read from
+    /// the `[binddn]` table, and from the deprecated top level tables.
+    #[serde(flatten, deserialize_with = "deserialize_binddn_map")]
     pub binddn_map: BTreeMap<String, DnConfig>,
+}
+
+/// Read `backoff_jitter`, which must be a number. TOML also has nan and inf,
+/// which clamping would not bring into range.
+fn deserialize_backoff_jitter<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+    use serde::de::Error;
+
+    let jitter = f64::deserialize(deserializer)?;
+    if !jitter.is_finite() {
+        return Err(D::Error::custom(format!(
+            "backoff_jitter must be a finite number, not {}",
+            jitter
+        )));
+    }
+    Ok(jitter)
+}
+
+/// Read the bind DNs of the `[binddn]` table, along with any configured as top
+/// level tables, as they were before `[binddn]`. There any key that is not an
+/// option is taken for a bind DN, and a DN named like an option cannot be
+/// configured, so that form is only accepted with a warning.
+fn deserialize_binddn_map<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<BTreeMap<String, DnConfig>, D::Error> {
+    use serde::de::Error;
+
+    let mut legacy = BTreeMap::<String, toml::Value>::deserialize(deserializer)?;
+    let nested = match legacy.remove(BINDDN_TABLE) {
+        Some(toml::Value::Table(table)) => table,
+        Some(_) => return Err(D::Error::custom("binddn must be a table of bind dns")),
+        None => toml::Table::new(),
+    };
+    if !legacy.is_empty() {
+        warn!(
+            bind_dns = ?legacy.keys().collect::<Vec<_>>(),
+            "Bind dns configured as top level tables are deprecated, move them under [binddn]"
+        );
+    }
+
+    let mut binddn_map = BTreeMap::new();
+    for (bind_dn, value) in nested.into_iter().chain(legacy) {
+        let dnconfig = DnConfig::deserialize(value).map_err(D::Error::custom)?;
+        let normalized = dn::normalize_dn(&bind_dn)
+            .map_err(|e| D::Error::custom(format!("invalid bind dn {:?} -> {}", bind_dn, e)))?;
+        if binddn_map.insert(normalized, dnconfig).is_some() {
+            return Err(D::Error::custom(format!(
+                "bind dn {:?} is configured more than once",
+                bind_dn
+            )));
+        }
+    }
+    Ok(binddn_map)
 }
\ No newline at end of file
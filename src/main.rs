@@ -13,20 +13,36 @@
 #[global_allocator]
 static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
 
+mod check;
+
 use clap::Parser;
-use concread::arcache::ARCacheBuilder;
-use ldap3_proto::LdapCodec;
-use ldap_proxy::{proxy, AddrInfoSource, AppState, Config};
-use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use ldap_proxy::codec::ClientCodec;
+use ldap_proxy::health::{self, BackendHealth};
+use ldap_proxy::pool::{self, BackendPool};
+use ldap_proxy::proxy::{ClientAddress, TieredCache};
+use ldap_proxy::schema::{self, SchemaSnapshot};
+use ldap_proxy::{proxy_header, redact, resolve, telemetry, tls};
+use ldap_proxy::{
+    proxy, AddressPreference, AppState, BackendConfig, CacheBackend, Config, ListenAddr,
+    PermissiveDefaultAction, ProxyProtocolMode, RoutedBackend,
+};
+use opentelemetry::trace::TracerProvider;
+use openssl::ssl::{Ssl, SslAcceptor, SslConnector, SslMethod, SslVerifyMode};
 use openssl::x509::X509;
+use socket2::{Domain, Protocol, Socket, Type};
+use std::collections::BTreeMap;
 use std::fs::File;
 use std::io::Read;
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::os::unix::fs::FileTypeExt;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
-use std::sync::Arc;
-use tokio::net::TcpListener;
-use tokio::net::TcpStream;
+use std::process::ExitCode;
+use std::sync::atomic::AtomicBool;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite, BufReader};
+use tokio::net::{TcpListener, UnixListener, UnixSocket};
 use tokio::sync::broadcast;
 use tokio_openssl::SslStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
@@ -42,25 +58,44 @@ struct Opt {
 
     #[clap(value_parser, short, long, default_value_os_t = DEFAULT_CONFIG_PATH.into(), env="LDAP_PROXY_CONFIG_PATH")]
     config: PathBuf,
+
+    /// Validate the config and exit without starting the proxy.
+    #[clap(long)]
+    check_config: bool,
+
+    /// With --check-config, also check the backend and cache are reachable.
+    #[clap(long, requires = "check_config")]
+    check_connectivity: bool,
+}
+
+enum Listener {
+    Tcp(TcpListener),
+    Unix(UnixListener, PathBuf),
 }
 
-async fn ldaps_tls_acceptor(
-    tcpstream: TcpStream,
-    client_socket_addr: SocketAddr,
+async fn ldaps_tls_acceptor<S: AsyncRead + AsyncWrite + Unpin + Send + 'static>(
+    tcpstream: S,
+    client_socket_addr: ClientAddress,
     tls_parms: SslAcceptor,
+    tenant: Option<String>,
+    proxy_protocol: Option<ProxyProtocolMode>,
     app_state: Arc<AppState>,
 ) {
-    use haproxy_protocol::{ProxyHdrV2, RemoteAddress};
+    use haproxy_protocol::RemoteAddress;
+    use proxy_header::RefusedHeader;
     let span = span!(Level::DEBUG, "tls_accept");
     let _enter = span.enter();
 
     let max_incoming_ber_size = app_state.max_incoming_ber_size;
 
-    let (tcpstream, reported_socket_addr) = match app_state.remote_ip_addr_info {
-        AddrInfoSource::None => (tcpstream, None),
-        AddrInfoSource::ProxyV2 => match ProxyHdrV2::parse_from_read(tcpstream).await {
-            Ok((tcpstream, hdr)) => {
-                let remote_socket_addr = match hdr.to_remote_addr() {
+    // Buffered so that a forbidden PROXY header can be looked for without
+    // consuming the start of the TLS handshake.
+    let mut tcpstream = BufReader::new(tcpstream);
+    let (reported_socket_addr, reported_client_cn) =
+        match proxy_header::accept_proxy_header(&mut tcpstream, proxy_protocol).await {
+            Ok(None) => (None, None),
+            Ok(Some(hdr)) => {
+                let remote_socket_addr = match hdr.remote_addr {
                     RemoteAddress::Local => {
                         debug!("haproxy check");
                         return;
@@ -73,16 +108,23 @@ async fn ldaps_tls_acceptor(
                     }
                 };
 
-                (tcpstream, Some(remote_socket_addr))
+                (Some(remote_socket_addr), hdr.ssl_client_cn)
             }
-            Err(err) => {
+            Err(RefusedHeader::Read(err)) => {
                 error!(?err, "Unable to process proxy v2 header");
                 return;
             }
-        },
-    };
+            Err(RefusedHeader::Forbidden) => {
+                error!(%client_socket_addr, "Refusing proxy header on a listener that forbids it");
+                return;
+            }
+        };
 
-    debug!(remote_addr_source = ?app_state.remote_ip_addr_info, ?reported_socket_addr);
+    debug!(
+        ?proxy_protocol,
+        ?reported_socket_addr,
+        ?reported_client_cn
+    );
 
     let mut tlsstream = match Ssl::new(tls_parms.context())
         .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
@@ -98,46 +140,194 @@ async fn ldaps_tls_acceptor(
         return;
     };
     let (r, w) = tokio::io::split(tlsstream);
-    let r = FramedRead::new(r, LdapCodec::new(max_incoming_ber_size));
-    let w = FramedWrite::new(w, LdapCodec::new(max_incoming_ber_size));
+    let r = FramedRead::new(
+        r,
+        ClientCodec::new(max_incoming_ber_size, app_state.require_ldap_v3),
+    );
+    let w = FramedWrite::new(
+        w,
+        ClientCodec::new(max_incoming_ber_size, app_state.require_ldap_v3),
+    );
 
     tokio::spawn(proxy::client_process(
         r,
         w,
         client_socket_addr,
         reported_socket_addr,
+        reported_client_cn,
+        tenant,
         app_state,
     ));
 }
 
 async fn ldaps_acceptor(
-    listener: TcpListener,
+    listener: Listener,
     tls_parms: SslAcceptor,
+    tenant: Option<String>,
+    proxy_protocol: Option<ProxyProtocolMode>,
     mut broadcast_rx: broadcast::Receiver<bool>,
     app_state: Arc<AppState>,
 ) {
     loop {
-        tokio::select! {
-            _ = broadcast_rx.recv() => {
-                break;
-            }
-            accept_result = listener.accept() => {
-                match accept_result {
-                    Ok((tcpstream, client_socket_addr)) => {
-                        let c_app_state = app_state.clone();
-                        tokio::spawn(ldaps_tls_acceptor( tcpstream, client_socket_addr, tls_parms.clone(), c_app_state ));
+        let c_app_state = app_state.clone();
+        match &listener {
+            Listener::Tcp(listener) => tokio::select! {
+                _ = broadcast_rx.recv() => {
+                    break;
+                }
+                accept_result = listener.accept() => {
+                    match accept_result {
+                        Ok((tcpstream, client_socket_addr)) => {
+                            let client_address = ClientAddress::Tcp(client_socket_addr);
+                            tokio::spawn(ldaps_tls_acceptor( tcpstream, client_address, tls_parms.clone(), tenant.clone(), proxy_protocol, c_app_state ));
+                        }
+                        Err(e) => {
+                            error!("LDAP acceptor error, continuing -> {:?}", e);
+                        }
                     }
-                    Err(e) => {
-                        error!("LDAP acceptor error, continuing -> {:?}", e);
+                }
+            },
+            Listener::Unix(listener, path) => tokio::select! {
+                _ = broadcast_rx.recv() => {
+                    break;
+                }
+                accept_result = listener.accept() => {
+                    match accept_result {
+                        Ok((unixstream, _)) => {
+                            let client_address = ClientAddress::Unix(path.clone());
+                            tokio::spawn(ldaps_tls_acceptor( unixstream, client_address, tls_parms.clone(), tenant.clone(), proxy_protocol, c_app_state ));
+                        }
+                        Err(e) => {
+                            error!("LDAP acceptor error, continuing -> {:?}", e);
+                        }
                     }
                 }
-            }
+            },
         }
     }
     debug!("Stopped ldaps acceptor");
 }
 
-async fn setup(opt: &Opt) {
+fn bind_tcp_listener(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, Some(Protocol::TCP))?;
+    // Otherwise a v6 wildcard listener also takes the port for v4, and a v4
+    // listener on the same port fails to bind.
+    if addr.is_ipv6() {
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    socket.listen(i32::try_from(backlog).unwrap_or(i32::MAX))?;
+    TcpListener::from_std(socket.into())
+}
+
+fn bind_unix_listener(path: &Path, backlog: u32) -> std::io::Result<UnixListener> {
+    // Clean up a stale socket left behind by a previous run, but never
+    // remove anything that isn't a socket.
+    if let Ok(metadata) = std::fs::symlink_metadata(path) {
+        if metadata.file_type().is_socket() {
+            std::fs::remove_file(path)?;
+        }
+    }
+    let socket = UnixSocket::new_stream()?;
+    socket.bind(path)?;
+    socket.listen(backlog)
+}
+
+fn bind_listener(addr: &ListenAddr, backlog: u32) -> std::io::Result<Listener> {
+    match addr {
+        ListenAddr::Tcp(addr) => bind_tcp_listener(*addr, backlog).map(Listener::Tcp),
+        ListenAddr::Unix(path) => {
+            bind_unix_listener(path, backlog).map(|l| Listener::Unix(l, path.clone()))
+        }
+    }
+}
+
+/// Build the connector used for backend connections, trusting only
+/// `ldap_ca` and verifying the certificate against `hostname`. With
+/// `insecure_skip_verify` the certificate is not verified at all.
+fn backend_tls_connector(
+    ldap_ca: &Path,
+    hostname: &str,
+    insecure_skip_verify: bool,
+    session_resumption: bool,
+) -> Result<SslConnector, String> {
+    let mut tls_builder = SslConnector::builder(SslMethod::tls_client())
+        .map_err(|e| format!("Unable to create tls client -> {:?}", e))?;
+
+    if session_resumption {
+        tls::enable_session_resumption(&mut tls_builder)
+            .map_err(|e| format!("Unable to enable tls session resumption -> {:?}", e))?;
+    }
+
+    let mut file = File::open(ldap_ca).map_err(|e| format!("Unable to open {:?} -> {:?}", ldap_ca, e))?;
+
+    let mut pem = Vec::new();
+    file.read_to_end(&mut pem)
+        .map_err(|e| format!("Unable to read {:?} -> {:?}", ldap_ca, e))?;
+
+    let ca_cert = X509::from_pem(pem.as_slice())
+        .map_err(|e| format!("Unable to parse {:?} -> {:?}", ldap_ca, e))?;
+
+    tls_builder
+        .cert_store_mut()
+        .add_cert(ca_cert)
+        .map_err(|e| format!("Unable to add {:?} to cert store -> {:?}", ldap_ca, e))?;
+    debug!("Added {:?} to cert store", ldap_ca);
+
+    if insecure_skip_verify {
+        warn!("ldap_tls_insecure_skip_verify is set, the backend certificate will NOT be verified");
+        tls_builder.set_verify(SslVerifyMode::NONE);
+        return Ok(tls_builder.build());
+    }
+
+    tls_builder
+        .verify_param_mut()
+        .set_host(hostname)
+        .map_err(|e| format!("Unable to set verify hostname -> {:?}", e))?;
+
+    tls_builder.set_verify(SslVerifyMode::PEER);
+
+    Ok(tls_builder.build())
+}
+
+/// Resolve and set up the TLS connector of a `[[backend]]` entry.
+fn routed_backend(
+    config: &BackendConfig,
+    preference: AddressPreference,
+    insecure_skip_verify: bool,
+    session_resumption: bool,
+) -> Result<RoutedBackend, String> {
+    if config.ldap_url.scheme() != "ldaps" {
+        return Err(format!("LDAPS is required in backend ldap_url {}", config.ldap_url));
+    }
+    let hostname = config
+        .ldap_url
+        .host_str()
+        .ok_or_else(|| format!("Unable to determine hostname from {}", config.ldap_url))?;
+    let addrs = resolve::resolve_backend_addrs(&config.ldap_url, preference)
+        .map_err(|e| format!("Unable to resolve {} -> {:?}", config.ldap_url, e))?;
+    if addrs.is_empty() {
+        return Err(format!("{} resolved to no addresses", config.ldap_url));
+    }
+    let tls_params = backend_tls_connector(
+        &config.ldap_ca,
+        hostname,
+        insecure_skip_verify,
+        session_resumption,
+    )?;
+    info!(dn_suffix = %config.dn_suffix, ?addrs, "Resolved routed backend addresses");
+    Ok(RoutedBackend {
+        dn_suffix: config.dn_suffix.clone(),
+        ldap_url: config.ldap_url.clone(),
+        tls_params,
+        tls_server_name: Some(hostname.to_string()),
+        addrs: RwLock::new(addrs),
+    })
+}
+
+async fn setup(opt: &Opt) -> ExitCode {
     info!("Starting ldap-proxy (fallback mode)");
 
     let mut f = match File::open(&opt.config) {
@@ -148,7 +338,7 @@ async fn setup(opt: &Opt) {
                 &opt.config.display(),
                 e
             );
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
@@ -159,200 +349,358 @@ async fn setup(opt: &Opt) {
             &opt.config.display(),
             e
         );
-        return;
+        return ExitCode::FAILURE;
     };
 
-    let sync_config: Config = match toml::from_str(contents.as_str()) {
+    let sync_config = match Config::from_toml(contents.as_str()) {
         Ok(c) => c,
         Err(e) => {
             eprintln!(
-                "unable to parse config from '{}' {:?}",
+                "unable to parse config from '{}' -> {}",
                 &opt.config.display(),
                 e
             );
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
     debug!(?sync_config);
 
-    let (broadcast_tx, broadcast_rx) = broadcast::channel(1);
+    redact::set_sensitive_attributes(&sync_config.sensitive_attributes);
 
-    let listener = match TcpListener::bind(&sync_config.bind).await {
-        Ok(l) => l,
-        Err(e) => {
-            error!(
-                "Could not bind to LDAP server address {} -> {:?}",
-                sync_config.bind, e
-            );
-            return;
+    // Setup the TLS server parameters. A bad certificate or key is reported
+    // before any address is bound.
+    let tls_server_params =
+        match tls::server_tls_acceptor(&sync_config.tls_chain, &sync_config.tls_key) {
+            Ok(t) => t,
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        };
+
+    let (broadcast_tx, _) = broadcast::channel(1);
+
+    let mut listeners = Vec::with_capacity(sync_config.bind.addrs().len());
+    for addr in sync_config.bind.addrs() {
+        match bind_listener(addr, sync_config.listen_backlog) {
+            Ok(l) => {
+                let tenant = sync_config.tenants.get(addr).cloned();
+                let proxy_protocol = sync_config.proxy_protocol_for(addr);
+                info!(?tenant, ?proxy_protocol, "Listening on {}", addr);
+                listeners.push((l, tenant, proxy_protocol));
+            }
+            Err(e) => {
+                error!("Could not bind to LDAP server address {} -> {:?}", addr, e);
+                return ExitCode::FAILURE;
+            }
         }
-    };
+    }
 
-    let url = sync_config.ldap_url;
+    if listeners.is_empty() {
+        error!("No bind addresses configured");
+        return ExitCode::FAILURE;
+    }
+
+    let url = sync_config.ldap_url.clone();
 
     match url.scheme() {
         "ldaps" => {}
         _ => {
             error!("Unable to proceed. LDAPS is required in remote ldap_url");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    let hostname = match url.host_str() {
-        Some(s) => s,
+    let hostname = match sync_config.backend_tls_name() {
+        Some(s) => s.to_string(),
         None => {
             error!("Unable to determine hostname from url");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
-    let addrs = match url.socket_addrs(|| Some(636)) {
+    let addrs = match resolve::resolve_backend_addrs(&url, sync_config.address_preference) {
         Ok(a) => a,
         Err(e) => {
             error!(?e, "url address resolver error");
-            return;
+            return ExitCode::FAILURE;
         }
     };
 
     if addrs.is_empty() {
         error!("url address resolved to no addresses");
-        return;
+        return ExitCode::FAILURE;
     }
-
-    let mut tls_builder = match SslConnector::builder(SslMethod::tls_client()) {
+    info!(?addrs, "Resolved backend addresses");
+
+    let tls_params = match backend_tls_connector(
+        &sync_config.ldap_ca,
+        &hostname,
+        sync_config.ldap_tls_insecure_skip_verify,
+        sync_config.ldap_tls_session_resumption,
+    ) {
         Ok(t) => t,
         Err(e) => {
-            error!("Unable to create tls client -> {:?}", e);
-            return;
+            error!("{}", e);
+            return ExitCode::FAILURE;
         }
     };
 
-    let cert_store = tls_builder.cert_store_mut();
-    let mut file = match File::open(&sync_config.ldap_ca) {
-        Ok(f) => f,
-        Err(e) => {
-            error!(?e, "Unable to open {:?}", &sync_config.ldap_ca);
-            return;
+    let mut routed_backends = Vec::with_capacity(sync_config.backends.len());
+    for backend in &sync_config.backends {
+        match routed_backend(
+            backend,
+            sync_config.address_preference,
+            sync_config.ldap_tls_insecure_skip_verify,
+            sync_config.ldap_tls_session_resumption,
+        ) {
+            Ok(b) => routed_backends.push(b),
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
         }
-    };
-
-    let mut pem = Vec::new();
-    if let Err(e) = file.read_to_end(&mut pem) {
-        error!(?e, "Unable to read {:?}", &sync_config.ldap_ca);
-        return;
     }
 
-    let ca_cert = match X509::from_pem(pem.as_slice()) {
-        Ok(c) => c,
-        Err(e) => {
-            error!(?e, "openssl");
-            return;
+    let mut referral_connectors = BTreeMap::new();
+    for host in &sync_config.referral_hosts {
+        match backend_tls_connector(
+            &sync_config.ldap_ca,
+            host,
+            sync_config.ldap_tls_insecure_skip_verify,
+            sync_config.ldap_tls_session_resumption,
+        ) {
+            Ok(connector) => {
+                referral_connectors.insert(host.to_lowercase(), connector);
+            }
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
         }
-    };
-
-    if let Err(e) = cert_store.add_cert(ca_cert).map(|()| {
-        debug!("Added {:?} to cert store", &sync_config.ldap_ca);
-    }) {
-        error!(?e, "openssl");
-        return;
-    };
-
-    let verify_param = tls_builder.verify_param_mut();
-    if let Err(e) = verify_param.set_host(hostname) {
-        error!(?e, "openssl");
-        return;
     }
-
-    tls_builder.set_verify(SslVerifyMode::PEER);
-
-    let tls_params = tls_builder.build();
+    if sync_config.chase_referrals && referral_connectors.is_empty() {
+        warn!("chase_referrals is set without referral_hosts, no references will be chased");
+    }
+    if sync_config.cache_race_timeout_ms.is_some() && sync_config.max_concurrent_ops.get() == 1 {
+        warn!(
+            "cache_race_timeout_ms is set with max_concurrent_ops = 1, each session waits for \
+             the backend after a search is answered from the cache"
+        );
+    }
 
     // Initialize cache based on configuration
-    let (cache, cache_ttl) = match &sync_config.cache {
-        ldap_proxy::CacheConfig::Memory { size_bytes } => {
-            let Some(cache) = ARCacheBuilder::new().set_size(*size_bytes, 0).build() else {
-                error!("Unable to build memory cache");
-                return;
-            };
-            info!("Memory cache configured with {} bytes", size_bytes);
-            (ldap_proxy::CacheBackend::Memory(Arc::new(cache)), None)
-        }
-        ldap_proxy::CacheConfig::Redis {
-            url,
-            ttl_seconds,
-            key_prefix: _,
-        } => {
-            let client = match redis::Client::open(url.as_str()) {
-                Ok(c) => c,
-                Err(e) => {
-                    error!(?e, "Unable to create Redis client");
-                    return;
-                }
-            };
-
-            let conn_manager = match redis::aio::ConnectionManager::new(client).await {
-                Ok(cm) => cm,
-                Err(e) => {
-                    error!(?e, "Unable to connect to Redis");
-                    return;
-                }
-            };
-
-            info!(
-                "Redis cache configured at {} with TTL: {:?}",
-                url, ttl_seconds
-            );
-            (ldap_proxy::CacheBackend::Redis(conn_manager), *ttl_seconds)
-        }
-    };
+    match (&sync_config.cache, sync_config.fallback_cache_bytes) {
+        (Some(_), Some(_)) => warn!(
+            "fallback_cache_bytes is deprecated and ignored, as the [cache] section configures \
+             the cache"
+        ),
+        (None, Some(_)) => warn!(
+            "fallback_cache_bytes is deprecated, set size_bytes in a [cache] section of type \
+             \"memory\" instead"
+        ),
+        (_, None) => {}
+    }
+    let cache_key_prefix = sync_config.cache_config().key_prefix();
+    let (cache, cache_ttl) =
+        match CacheBackend::from_config(&sync_config.cache_config(), sync_config.require_cache)
+            .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                error!("{}", e);
+                return ExitCode::FAILURE;
+            }
+        };
 
     let max_incoming_ber_size = sync_config.max_incoming_ber_size;
     let max_proxy_ber_size = sync_config.max_proxy_ber_size;
+    let max_buffered_entries = sync_config.max_buffered_entries;
     let allow_all_bind_dns = sync_config.allow_all_bind_dns;
+    if sync_config.is_default_dn_permissive() {
+        match sync_config.permissive_default_action {
+            PermissiveDefaultAction::Warn => warn!(
+                "allow_all_bind_dns is set without allowed_queries in default_dn_config, \
+                 so DNs without a bind map entry may make any query"
+            ),
+            PermissiveDefaultAction::Error => {
+                error!(
+                    "allow_all_bind_dns requires allowed_queries in default_dn_config when \
+                     permissive_default_action is \"error\""
+                );
+                return ExitCode::FAILURE;
+            }
+        }
+    }
     let remote_ip_addr_info = sync_config.remote_ip_addr_info;
+    let cacheable_result_codes = sync_config.cacheable_result_codes.clone();
+    let degraded_result_codes = sync_config.degraded_result_codes.clone();
+    let require_ldap_v3 = sync_config.require_ldap_v3;
+    let root_dse = sync_config.root_dse.clone();
+    let schema = sync_config
+        .cache_schema_at_startup
+        .then(|| Arc::new(SchemaSnapshot::default()));
+
+    let cache_age_control_oid = if sync_config.annotate_cached_responses {
+        match &sync_config.cache_age_control_oid {
+            Some(oid) => Some(oid.clone()),
+            None => {
+                error!("cache_age_control_oid must be set when annotate_cached_responses is enabled");
+                return ExitCode::FAILURE;
+            }
+        }
+    } else {
+        None
+    };
+
+    let cache_warm = sync_config.cache_warm.clone();
+    let backend_pool = sync_config.backend_pool.as_ref().map(BackendPool::new);
+
+    let tiered_cache = TieredCache::for_backend(
+        &cache,
+        &cache_key_prefix,
+        sync_config.redis_compression,
+        sync_config.redis_breaker(),
+    );
 
     let app_state = Arc::new(AppState {
         tls_params,
-        addrs,
+        tls_server_name: Some(hostname),
+        addrs: RwLock::new(addrs),
+        routed_backends,
         binddn_map: sync_config.binddn_map.clone(),
         cache,
         cache_ttl,
+        cache_key_prefix,
         max_incoming_ber_size,
         max_proxy_ber_size,
+        max_buffered_entries,
+        response_flush_entries: sync_config.response_flush_entries.get(),
         allow_all_bind_dns,
+        dn_defaults: sync_config.dn_defaults.clone(),
+        default_dn_config: sync_config.default_dn_config.clone().unwrap_or_default(),
+        allow_unauthenticated_bind: sync_config.allow_unauthenticated_bind,
         remote_ip_addr_info,
+        cacheable_result_codes,
+        degraded_result_codes,
+        sort_cached_entries: sync_config.sort_cached_entries,
+        cache_mode: sync_config.cache_mode,
+        cache_attribute_superset: sync_config.cache_attribute_superset,
+        cache_key_ignore_limits: sync_config.cache_key_ignore_limits,
+        read_through_max_age: Duration::from_secs(sync_config.read_through_max_age_secs.get()),
+        cache_race_timeout: sync_config
+            .cache_race_timeout_ms
+            .map(|ms| Duration::from_millis(ms.get())),
+        require_ldap_v3,
+        root_dse,
+        supported_controls: sync_config.supported_controls.clone(),
+        schema,
+        cache_age_control_oid,
+        annotate_cached_message: sync_config.annotate_cached_message,
+        fallback_result_code: sync_config.fallback_result_code.clone(),
+        fallback_result_marker: sync_config.fallback_result_marker.clone(),
+        backend_pool,
+        dn_rewrite: sync_config.dn_rewrite.clone(),
+        referral_rewrite: sync_config.referral_rewrite.clone(),
+        strip_unmatched_referrals: sync_config.strip_unmatched_referrals,
+        chase_referrals: sync_config.chase_referrals,
+        referral_connectors,
+        slow_op_threshold: sync_config
+            .slow_op_threshold_ms
+            .map(|ms| Duration::from_millis(ms.get())),
+        denied_query_action: sync_config.denied_query_action,
+        no_fallback_action: sync_config.no_fallback_action,
+        max_dn_length: sync_config.max_dn_length.get(),
+        max_filter_depth: sync_config.max_filter_depth.get(),
+        max_concurrent_ops: sync_config.max_concurrent_ops.get(),
+        breaker: sync_config.circuit_breaker(),
+        tiered_cache,
+        in_flight_searches: sync_config.coalesce_searches.then(Default::default),
+        bind_timeout: Duration::from_secs(sync_config.backend_bind_timeout_secs.get()),
+        connect_timeouts: sync_config.connect_timeouts(),
+        reconnect_backend: sync_config.reconnect_backend,
+        backend_mode: sync_config.backend_mode,
+        whoami_format: sync_config.whoami_format,
+        client_idle_timeout: sync_config
+            .client_idle_timeout_secs
+            .map(|secs| Duration::from_secs(secs.get())),
+        metrics: Default::default(),
+        session_counts: Default::default(),
+        offline_bind: sync_config.offline_bind_cache(),
+        bind_cache: sync_config.bind_cache(),
+        maintenance: AtomicBool::new(false),
+        maintenance_bind_action: sync_config.maintenance_bind_action,
+        critical_control_action: sync_config.critical_control_action,
     });
 
-    // Setup the TLS server parameters
-    let mut tls_builder = match SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()) {
-        Ok(t) => t,
-        Err(e) => {
-            error!("Unable to create tls acceptor -> {:?}", e);
-            return;
-        }
-    };
+    if let Some(cache_warm) = cache_warm {
+        let tenants = sync_config.tenants.values().cloned().collect();
+        tokio::spawn(proxy::warm_cache(app_state.clone(), cache_warm, tenants));
+    }
 
-    if let Err(e) = tls_builder.set_certificate_chain_file(&sync_config.tls_chain) {
-        error!("Unable to load certificate chain -> {:?}", e);
-        return;
+    if let Some(schema) = &app_state.schema {
+        tokio::spawn(schema::run_refresh(
+            app_state.clone(),
+            schema.clone(),
+            Duration::from_secs(sync_config.schema_refresh_secs.get()),
+        ));
     }
 
-    if let Err(e) = tls_builder.set_private_key_file(&sync_config.tls_key, SslFiletype::PEM) {
-        error!("Unable to load private key -> {:?}", e);
-        return;
+    if let Some(refresh) = sync_config.resolve_refresh_secs {
+        tokio::spawn(resolve::run_refresh(
+            app_state.clone(),
+            url.clone(),
+            sync_config.address_preference,
+            Duration::from_secs(refresh.get()),
+        ));
     }
 
-    if let Err(e) = tls_builder.check_private_key() {
-        error!("Unable to validate private key -> {:?}", e);
-        return;
+    tokio::spawn(proxy::run_cache_quiesce(
+        app_state.clone(),
+        Duration::from_secs(sync_config.quiesce_interval_secs.get()),
+    ));
+
+    if app_state.backend_pool.is_some() {
+        tokio::spawn(pool::run_maintenance(app_state.clone()));
     }
 
-    let tls_server_params = tls_builder.build();
+    if let Some(health_config) = &sync_config.health {
+        let listener = match TcpListener::bind(health_config.bind).await {
+            Ok(l) => l,
+            Err(e) => {
+                error!("Could not bind to health address {} -> {:?}", health_config.bind, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        info!("Serving health checks on {}", health_config.bind);
+        let backend_health = Arc::new(BackendHealth::default());
+        tokio::spawn(health::run_checks(
+            app_state.clone(),
+            backend_health.clone(),
+            Duration::from_secs(health_config.check_interval_seconds.get()),
+            sync_config.backoff(),
+        ));
+        tokio::spawn(proxy::run_cache_metrics(
+            app_state.clone(),
+            Duration::from_secs(health_config.check_interval_seconds.get()),
+        ));
+        tokio::spawn(health::serve(listener, app_state.clone(), backend_health));
+    }
 
-    let acceptor = tokio::spawn(async move {
-        ldaps_acceptor(listener, tls_server_params, broadcast_rx, app_state).await
-    });
+    let acceptors: Vec<_> = listeners
+        .into_iter()
+        .map(|(listener, tenant, proxy_protocol)| {
+            tokio::spawn(ldaps_acceptor(
+                listener,
+                tls_server_params.clone(),
+                tenant,
+                proxy_protocol,
+                broadcast_tx.subscribe(),
+                app_state.clone(),
+            ))
+        })
+        .collect();
 
     loop {
         tokio::select! {
@@ -385,14 +733,14 @@ async fn setup(opt: &Opt) {
                 #[allow(clippy::unwrap_used)]
                 tokio::signal::unix::signal(sigterm).unwrap().recv().await
             } => {
-                // Ignore
+                tokio::spawn(proxy::log_cache_summary(app_state.clone()));
             }
             Some(()) = async move {
                 let sigterm = tokio::signal::unix::SignalKind::user_defined2();
                 #[allow(clippy::unwrap_used)]
                 tokio::signal::unix::signal(sigterm).unwrap().recv().await
             } => {
-                // Ignore
+                app_state.set_maintenance(!app_state.in_maintenance());
             }
         }
     }
@@ -401,23 +749,60 @@ async fn setup(opt: &Opt) {
         error!("Unable to shutdown workers {:?}", e);
     }
 
-    let _ = acceptor.await;
+    for acceptor in acceptors {
+        let _ = acceptor.await;
+    }
+
+    ExitCode::SUCCESS
 }
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let opt = Opt::parse();
 
+    if opt.check_config {
+        return check::check_config(&opt.config, opt.check_connectivity).await;
+    }
+
     let level = if opt.debug {
         LevelFilter::TRACE
     } else {
         LevelFilter::INFO
     };
 
-    tracing_forest::worker_task()
+    // Installed before the config is loaded in full, so a config that cannot
+    // be read is reported by setup instead.
+    let tracing_config = std::fs::read_to_string(&opt.config)
+        .ok()
+        .and_then(|contents| telemetry::tracing_config(&contents).ok().flatten());
+    let tracer_provider = match tracing_config.as_ref().map(telemetry::tracer_provider) {
+        Some(Ok(provider)) => Some(provider),
+        Some(Err(e)) => {
+            eprintln!("unable to set up the otlp exporter {:?}", e);
+            return ExitCode::FAILURE;
+        }
+        None => None,
+    };
+    let tracer = tracer_provider
+        .as_ref()
+        .map(|provider| provider.tracer("ldap-proxy"));
+
+    let exit_code = tracing_forest::worker_task()
         .set_global(true)
         .map_sender(|sender| sender.or_stderr())
-        .build_on(|subscriber| subscriber.with(level))
+        .build_on(|subscriber| {
+            subscriber
+                .with(level)
+                .with(tracer.map(|tracer| tracing_opentelemetry::layer().with_tracer(tracer)))
+        })
         .on(setup(&opt))
         .await;
-}
\ No newline at end of file
+
+    if let Some(provider) = tracer_provider {
+        if let Err(e) = provider.shutdown() {
+            eprintln!("unable to flush the otlp exporter {:?}", e);
+        }
+    }
+
+    exit_code
+}
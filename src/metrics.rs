@@ -0,0 +1,242 @@
+//! Counters and gauges served in the Prometheus text format at `/metrics` on the health
+//! listener.
+
+use crate::proxy::DisconnectReason;
+use ldap3_proto::LdapResultCode;
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The backend operations whose results are counted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum BackendOp {
+    Bind,
+    Search,
+}
+
+impl BackendOp {
+    pub fn label(self) -> &'static str {
+        match self {
+            BackendOp::Bind => "bind",
+            BackendOp::Search => "search",
+        }
+    }
+}
+
+/// The label for `code`. These are part of the metrics interface, so they
+/// must not change even if the variant names in ldap3_proto do.
+pub fn result_code_label(code: &LdapResultCode) -> &'static str {
+    match code {
+        LdapResultCode::Success => "success",
+        LdapResultCode::OperationsError => "operations_error",
+        LdapResultCode::ProtocolError => "protocol_error",
+        LdapResultCode::TimeLimitExceeded => "time_limit_exceeded",
+        LdapResultCode::SizeLimitExceeded => "size_limit_exceeded",
+        LdapResultCode::CompareFalse => "compare_false",
+        LdapResultCode::CompareTrue => "compare_true",
+        LdapResultCode::AuthMethodNotSupported => "auth_method_not_supported",
+        LdapResultCode::StrongerAuthRequired => "stronger_auth_required",
+        LdapResultCode::Referral => "referral",
+        LdapResultCode::AdminLimitExceeded => "admin_limit_exceeded",
+        LdapResultCode::UnavailableCriticalExtension => "unavailable_critical_extension",
+        LdapResultCode::ConfidentialityRequired => "confidentiality_required",
+        LdapResultCode::SaslBindInProgress => "sasl_bind_in_progress",
+        LdapResultCode::NoSuchAttribute => "no_such_attribute",
+        LdapResultCode::UndefinedAttributeType => "undefined_attribute_type",
+        LdapResultCode::InappropriateMatching => "inappropriate_matching",
+        LdapResultCode::ConstraintViolation => "constraint_violation",
+        LdapResultCode::AttributeOrValueExists => "attribute_or_value_exists",
+        LdapResultCode::InvalidAttributeSyntax => "invalid_attribute_syntax",
+        LdapResultCode::NoSuchObject => "no_such_object",
+        LdapResultCode::AliasProblem => "alias_problem",
+        LdapResultCode::InvalidDNSyntax => "invalid_dn_syntax",
+        LdapResultCode::AliasDereferencingProblem => "alias_dereferencing_problem",
+        LdapResultCode::InappropriateAuthentication => "inappropriate_authentication",
+        LdapResultCode::InvalidCredentials => "invalid_credentials",
+        LdapResultCode::InsufficentAccessRights => "insufficient_access_rights",
+        LdapResultCode::Busy => "busy",
+        LdapResultCode::Unavailable => "unavailable",
+        LdapResultCode::UnwillingToPerform => "unwilling_to_perform",
+        LdapResultCode::LoopDetect => "loop_detect",
+        LdapResultCode::NamingViolation => "naming_violation",
+        LdapResultCode::ObjectClassViolation => "object_class_violation",
+        LdapResultCode::NotAllowedOnNonLeaf => "not_allowed_on_non_leaf",
+        LdapResultCode::NotALlowedOnRDN => "not_allowed_on_rdn",
+        LdapResultCode::EntryAlreadyExists => "entry_already_exists",
+        LdapResultCode::ObjectClassModsProhibited => "object_class_mods_prohibited",
+        LdapResultCode::AffectsMultipleDSAs => "affects_multiple_dsas",
+        LdapResultCode::Other => "other",
+        LdapResultCode::EsyncRefreshRequired => "esync_refresh_required",
+    }
+}
+
+#[derive(Default)]
+pub struct Metrics {
+    backend_results: Mutex<BTreeMap<(BackendOp, &'static str), u64>>,
+    disconnects: Mutex<BTreeMap<DisconnectReason, u64>>,
+    /// The entries and bytes held by each in memory cache, as last sampled.
+    cache_sizes: Mutex<BTreeMap<&'static str, (usize, usize)>>,
+    /// The number of keys under the Redis prefix, as last sampled.
+    redis_keys: Mutex<Option<usize>>,
+    /// Whether the Redis cache was degraded to its in memory level, as last
+    /// sampled.
+    redis_degraded: Mutex<Option<bool>>,
+    /// Entries the memory cache evicted to stay within its size.
+    cache_evictions: Mutex<u64>,
+    /// Entries larger than the whole memory cache.
+    cache_oversized_entries: Mutex<u64>,
+    /// The evictions not yet reported, and when they last were.
+    unreported_evictions: Mutex<(u64, Option<Instant>)>,
+}
+
+impl Metrics {
+    /// Count a result the backend sent for `op`.
+    pub fn record_backend_result(&self, op: BackendOp, code: &LdapResultCode) {
+        *self
+            .backend_results
+            .lock()
+            .unwrap()
+            .entry((op, result_code_label(code)))
+            .or_default() += 1;
+    }
+
+    pub fn backend_result_count(&self, op: BackendOp, code: &LdapResultCode) -> u64 {
+        self.backend_results
+            .lock()
+            .unwrap()
+            .get(&(op, result_code_label(code)))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Count a client session that ended for `reason`.
+    pub fn record_disconnect(&self, reason: DisconnectReason) {
+        *self.disconnects.lock().unwrap().entry(reason).or_default() += 1;
+    }
+
+    pub fn disconnect_count(&self, reason: DisconnectReason) -> u64 {
+        self.disconnects
+            .lock()
+            .unwrap()
+            .get(&reason)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Record the size of the in memory `cache`, "memory" or "l1".
+    pub fn set_cache_size(&self, cache: &'static str, entries: usize, bytes: usize) {
+        self.cache_sizes
+            .lock()
+            .unwrap()
+            .insert(cache, (entries, bytes));
+    }
+
+    /// The entries and bytes last recorded for `cache`.
+    pub fn cache_size(&self, cache: &str) -> Option<(usize, usize)> {
+        self.cache_sizes.lock().unwrap().get(cache).copied()
+    }
+
+    /// Count entries the memory cache evicted, returning the evictions to
+    /// report if none have been for `interval`.
+    pub fn record_cache_evictions(&self, evicted: u64, interval: Duration) -> Option<u64> {
+        *self.cache_evictions.lock().unwrap() += evicted;
+        let mut unreported = self.unreported_evictions.lock().unwrap();
+        unreported.0 += evicted;
+        if unreported.1.is_some_and(|at| at.elapsed() < interval) {
+            return None;
+        }
+        let report = std::mem::take(&mut unreported.0);
+        unreported.1 = Some(Instant::now());
+        Some(report)
+    }
+
+    pub fn cache_eviction_count(&self) -> u64 {
+        *self.cache_evictions.lock().unwrap()
+    }
+
+    /// Count an entry too large for the memory cache to hold.
+    pub fn record_cache_oversized_entry(&self) {
+        *self.cache_oversized_entries.lock().unwrap() += 1;
+    }
+
+    pub fn cache_oversized_entry_count(&self) -> u64 {
+        *self.cache_oversized_entries.lock().unwrap()
+    }
+
+    pub fn set_redis_keys(&self, keys: usize) {
+        *self.redis_keys.lock().unwrap() = Some(keys);
+    }
+
+    pub fn set_redis_degraded(&self, degraded: bool) {
+        *self.redis_degraded.lock().unwrap() = Some(degraded);
+    }
+
+    /// Render every counter and gauge in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP backend_result_total Results received from the backend, by operation and result code.\n");
+        out.push_str("# TYPE backend_result_total counter\n");
+        for ((op, code), count) in self.backend_results.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "backend_result_total{{op=\"{}\",code=\"{}\"}} {}",
+                op.label(),
+                code,
+                count
+            );
+        }
+
+        out.push_str("# HELP disconnects_total Client sessions that ended, by reason.\n");
+        out.push_str("# TYPE disconnects_total counter\n");
+        for (reason, count) in self.disconnects.lock().unwrap().iter() {
+            let _ = writeln!(
+                out,
+                "disconnects_total{{reason=\"{}\"}} {}",
+                reason.label(),
+                count
+            );
+        }
+
+        let cache_sizes = self.cache_sizes.lock().unwrap();
+        if !cache_sizes.is_empty() {
+            out.push_str("# HELP cache_entries Searches held in the memory cache, or the L1 cache in front of Redis.\n");
+            out.push_str("# TYPE cache_entries gauge\n");
+            for (cache, (entries, _)) in cache_sizes.iter() {
+                let _ = writeln!(out, "cache_entries{{cache=\"{}\"}} {}", cache, entries);
+            }
+            out.push_str("# HELP cache_bytes Approximate size of the searches held in the memory cache, or the L1 cache in front of Redis.\n");
+            out.push_str("# TYPE cache_bytes gauge\n");
+            for (cache, (_, bytes)) in cache_sizes.iter() {
+                let _ = writeln!(out, "cache_bytes{{cache=\"{}\"}} {}", cache, bytes);
+            }
+        }
+
+        // Only once there is something to count, as they only apply to the
+        // memory cache.
+        let evictions = *self.cache_evictions.lock().unwrap();
+        if evictions > 0 {
+            out.push_str("# HELP cache_evictions_total Searches the memory cache evicted to stay within size_bytes.\n");
+            out.push_str("# TYPE cache_evictions_total counter\n");
+            let _ = writeln!(out, "cache_evictions_total {}", evictions);
+        }
+        let oversized = *self.cache_oversized_entries.lock().unwrap();
+        if oversized > 0 {
+            out.push_str("# HELP cache_oversized_entries_total Searches larger than the whole memory cache.\n");
+            out.push_str("# TYPE cache_oversized_entries_total counter\n");
+            let _ = writeln!(out, "cache_oversized_entries_total {}", oversized);
+        }
+
+        if let Some(keys) = *self.redis_keys.lock().unwrap() {
+            out.push_str("# HELP redis_keys Cached searches under the Redis cache prefix.\n");
+            out.push_str("# TYPE redis_keys gauge\n");
+            let _ = writeln!(out, "redis_keys {}", keys);
+        }
+        if let Some(degraded) = *self.redis_degraded.lock().unwrap() {
+            out.push_str("# HELP redis_degraded Whether Redis is failing and only the in memory cache is used.\n");
+            out.push_str("# TYPE redis_degraded gauge\n");
+            let _ = writeln!(out, "redis_degraded {}", u8::from(degraded));
+        }
+        out
+    }
+}
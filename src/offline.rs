@@ -0,0 +1,247 @@
+//! Validation of simple binds against a salted hash of the password of the
+//! last successful bind as each DN, while the backend is unreachable or, for
+//! a short while, in place of asking it again.
+
+use crate::OfflineBindKdf;
+use openssl::error::ErrorStack;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::sign::Signer;
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+const SALT_LEN: usize = 16;
+const KEY_LEN: usize = 32;
+
+// Work factors from the OWASP password storage recommendations, taking 32MiB
+// of memory for each scrypt derivation.
+const SCRYPT_N: u64 = 1 << 15;
+const SCRYPT_R: u64 = 8;
+const SCRYPT_P: u64 = 3;
+const SCRYPT_MAX_MEM: u64 = 64 * 1024 * 1024;
+const PBKDF2_ITERATIONS: usize = 600_000;
+
+// Derivations that may run at once. Binds beyond this are not validated by
+// the cache, instead of queueing on the blocking pool.
+const MAX_CONCURRENT_DERIVATIONS: usize = 4;
+
+struct CachedCredential {
+    salt: [u8; SALT_LEN],
+    key: [u8; KEY_LEN],
+    cached_at: Instant,
+}
+
+/// How the passwords of cached credentials are hashed.
+#[derive(Clone, Copy)]
+enum Hasher {
+    /// A deliberately slow kdf, for credentials kept long enough to be worth
+    /// attacking.
+    Kdf(OfflineBindKdf),
+    /// HMAC-SHA256 under a key that only this process knows, cheap enough to
+    /// run on every bind.
+    Keyed([u8; KEY_LEN]),
+}
+
+/// The credentials of successful simple binds, keyed by the normalized bind
+/// DN. Passwords are never kept, only a key derived from them.
+pub struct OfflineBindCache {
+    hasher: Hasher,
+    ttl: Duration,
+    credentials: Mutex<HashMap<String, CachedCredential>>,
+    derivations: Arc<Semaphore>,
+    /// DNs with a verification running, so that repeated binds as one DN
+    /// cannot take every derivation.
+    verifying: Mutex<HashSet<String>>,
+}
+
+/// Marks a DN as being verified until dropped.
+struct Verifying {
+    cache: Arc<OfflineBindCache>,
+    dn: String,
+}
+
+impl Drop for Verifying {
+    fn drop(&mut self) {
+        self.cache.verifying.lock().unwrap().remove(&self.dn);
+    }
+}
+
+impl OfflineBindCache {
+    pub fn new(kdf: OfflineBindKdf, ttl: Duration) -> Self {
+        Self::with_hasher(Hasher::Kdf(kdf), ttl)
+    }
+
+    /// A cache hashing passwords with HMAC-SHA256 under a random key, for
+    /// credentials kept only briefly. Its keys are worthless outside this
+    /// process, so a slow kdf buys nothing.
+    pub fn keyed(ttl: Duration) -> Result<Self, ErrorStack> {
+        let mut key = [0; KEY_LEN];
+        openssl::rand::rand_bytes(&mut key)?;
+        Ok(Self::with_hasher(Hasher::Keyed(key), ttl))
+    }
+
+    fn with_hasher(hasher: Hasher, ttl: Duration) -> Self {
+        OfflineBindCache {
+            hasher,
+            ttl,
+            credentials: Mutex::new(HashMap::new()),
+            derivations: Arc::new(Semaphore::new(MAX_CONCURRENT_DERIVATIONS)),
+            verifying: Mutex::new(HashSet::new()),
+        }
+    }
+
+    fn insert(&self, dn: String, password: &str) -> Result<(), ErrorStack> {
+        let mut salt = [0; SALT_LEN];
+        openssl::rand::rand_bytes(&mut salt)?;
+        let key = derive_key(self.hasher, password, &salt)?;
+        debug!(%dn, "Caching bind credentials");
+        self.credentials.lock().unwrap().insert(
+            dn,
+            CachedCredential {
+                salt,
+                key,
+                cached_at: Instant::now(),
+            },
+        );
+        Ok(())
+    }
+
+    /// A permit to run a derivation, or None if too many are running. It is
+    /// held by the blocking task, so that it is only released once the
+    /// derivation ends, even if the bind that started it has gone.
+    fn derivation_permit(&self) -> Option<OwnedSemaphorePermit> {
+        let permit = self.derivations.clone().try_acquire_owned().ok();
+        if permit.is_none() {
+            warn!("Too many bind credential derivations running, skipping one");
+        }
+        permit
+    }
+
+    /// Remember that `password` was accepted by the backend for `dn`,
+    /// replacing any earlier credentials. A kdf key is derived on the
+    /// blocking pool as the kdf is deliberately slow, and not at all while
+    /// too many derivations are running.
+    pub async fn store(self: &Arc<Self>, dn: &str, password: &str) {
+        if let Hasher::Keyed(_) = self.hasher {
+            if let Err(e) = self.insert(dn.to_string(), password) {
+                warn!(?e, "Unable to hash the bind password");
+            }
+            return;
+        }
+        let Some(permit) = self.derivation_permit() else {
+            return;
+        };
+        let cache = self.clone();
+        let dn = dn.to_string();
+        let password = password.to_string();
+        let stored = tokio::task::spawn_blocking(move || {
+            let _permit = permit;
+            cache.insert(dn, &password)
+        })
+        .await;
+        match stored {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => warn!(?e, "Unable to derive the offline bind key"),
+            Err(e) => warn!(?e, "Offline bind key derivation did not complete"),
+        }
+    }
+
+    /// Whether `password` matches the unexpired credentials cached for `dn`.
+    /// False without deriving a kdf key if another bind as `dn` is being
+    /// verified, or too many derivations are running.
+    pub async fn verify(self: &Arc<Self>, dn: &str, password: &str) -> bool {
+        let (salt, expected) = {
+            let mut credentials = self.credentials.lock().unwrap();
+            match credentials.get(dn) {
+                Some(cached) if cached.cached_at.elapsed() < self.ttl => (cached.salt, cached.key),
+                Some(_) => {
+                    debug!(%dn, "Cached bind credentials have expired");
+                    credentials.remove(dn);
+                    return false;
+                }
+                None => return false,
+            }
+        };
+
+        if let Hasher::Keyed(_) = self.hasher {
+            return derive_key(self.hasher, password, &salt)
+                .is_ok_and(|key| openssl::memcmp::eq(&key, &expected));
+        }
+        if !self.verifying.lock().unwrap().insert(dn.to_string()) {
+            debug!(%dn, "Another bind as this dn is being verified");
+            return false;
+        }
+        let verifying = Verifying {
+            cache: self.clone(),
+            dn: dn.to_string(),
+        };
+        let Some(permit) = self.derivation_permit() else {
+            return false;
+        };
+
+        let hasher = self.hasher;
+        let password = password.to_string();
+        let derived = tokio::task::spawn_blocking(move || {
+            let _held = (permit, verifying);
+            derive_key(hasher, &password, &salt)
+        });
+        match derived.await {
+            Ok(Ok(key)) => openssl::memcmp::eq(&key, &expected),
+            Ok(Err(e)) => {
+                warn!(?e, "Unable to derive the offline bind key");
+                false
+            }
+            Err(e) => {
+                warn!(?e, "Offline bind key derivation did not complete");
+                false
+            }
+        }
+    }
+
+    /// Drop the cached credentials for `dn`, once the backend has rejected
+    /// them.
+    pub fn forget(&self, dn: &str) {
+        if self.credentials.lock().unwrap().remove(dn).is_some() {
+            debug!(%dn, "Dropped cached bind credentials");
+        }
+    }
+
+    /// Whether credentials are cached for `dn`, expired or not.
+    pub fn contains(&self, dn: &str) -> bool {
+        self.credentials.lock().unwrap().contains_key(dn)
+    }
+}
+
+fn derive_key(hasher: Hasher, password: &str, salt: &[u8]) -> Result<[u8; KEY_LEN], ErrorStack> {
+    let mut key = [0; KEY_LEN];
+    match hasher {
+        Hasher::Keyed(secret) => {
+            let secret = PKey::hmac(&secret)?;
+            let mut signer = Signer::new(MessageDigest::sha256(), &secret)?;
+            signer.update(salt)?;
+            signer.update(password.as_bytes())?;
+            signer.sign(&mut key)?;
+        }
+        Hasher::Kdf(OfflineBindKdf::Scrypt) => openssl::pkcs5::scrypt(
+            password.as_bytes(),
+            salt,
+            SCRYPT_N,
+            SCRYPT_R,
+            SCRYPT_P,
+            SCRYPT_MAX_MEM,
+            &mut key,
+        )?,
+        Hasher::Kdf(OfflineBindKdf::Pbkdf2Sha256) => openssl::pkcs5::pbkdf2_hmac(
+            password.as_bytes(),
+            salt,
+            PBKDF2_ITERATIONS,
+            MessageDigest::sha256(),
+            &mut key,
+        )?,
+    }
+    Ok(key)
+}
@@ -0,0 +1,123 @@
+use crate::proxy::BasicLdapClient;
+use crate::{AppState, BackendPoolConfig};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::time::Instant;
+use tracing::{debug, trace};
+
+/// Digest of the password a pooled connection was bound with. A connection is
+/// only handed to a client that presents the same credentials.
+pub type CredentialDigest = [u8; 32];
+
+pub fn credential_digest(password: &str) -> CredentialDigest {
+    openssl::sha::sha256(password.as_bytes())
+}
+
+struct IdleClient {
+    client: BasicLdapClient,
+    credentials: CredentialDigest,
+    idle_since: Instant,
+}
+
+/// Idle backend connections that are already bound, keyed by bind dn.
+pub struct BackendPool {
+    per_dn_idle: usize,
+    idle_timeout: Duration,
+    ping_interval: Duration,
+    idle: Mutex<HashMap<String, Vec<IdleClient>>>,
+}
+
+impl BackendPool {
+    pub fn new(config: &BackendPoolConfig) -> Self {
+        BackendPool {
+            per_dn_idle: config.per_dn_idle,
+            idle_timeout: Duration::from_secs(config.idle_timeout_seconds),
+            ping_interval: Duration::from_secs(config.ping_interval_seconds.get()),
+            idle: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take an idle connection bound as `dn` with `credentials`, checking that
+    /// it is still alive first.
+    pub async fn checkout(
+        &self,
+        dn: &str,
+        credentials: &CredentialDigest,
+    ) -> Option<BasicLdapClient> {
+        loop {
+            let candidate = {
+                let mut idle = self.idle.lock().unwrap();
+                let clients = idle.get_mut(dn)?;
+                clients.retain(|c| c.idle_since.elapsed() < self.idle_timeout);
+                let idx = clients.iter().rposition(|c| &c.credentials == credentials)?;
+                clients.swap_remove(idx)
+            };
+
+            let mut client = candidate.client;
+            match client.whoami().await {
+                Ok(_) => {
+                    debug!(%dn, "Reusing pooled backend connection");
+                    return Some(client);
+                }
+                Err(e) => {
+                    debug!(?e, %dn, "Discarding dead pooled backend connection");
+                }
+            }
+        }
+    }
+
+    /// Return a bound connection to the pool, dropping it if the pool for `dn`
+    /// is already full.
+    pub fn checkin(&self, dn: &str, credentials: CredentialDigest, client: BasicLdapClient) {
+        let mut idle = self.idle.lock().unwrap();
+        let clients = idle.entry(dn.to_string()).or_default();
+        if clients.len() < self.per_dn_idle {
+            trace!(%dn, "Returning backend connection to pool");
+            clients.push(IdleClient {
+                client,
+                credentials,
+                idle_since: Instant::now(),
+            });
+        }
+    }
+
+    /// Close connections idle for longer than the timeout and ping the rest,
+    /// discarding any that have gone away.
+    async fn maintain(&self) {
+        let drained: Vec<(String, Vec<IdleClient>)> = {
+            let mut idle = self.idle.lock().unwrap();
+            idle.drain().collect()
+        };
+
+        for (dn, clients) in drained {
+            for mut idle_client in clients {
+                if idle_client.idle_since.elapsed() >= self.idle_timeout {
+                    trace!(%dn, "Recycling idle backend connection");
+                    continue;
+                }
+                if let Err(e) = idle_client.client.whoami().await {
+                    debug!(?e, %dn, "Discarding dead pooled backend connection");
+                    continue;
+                }
+                let mut idle = self.idle.lock().unwrap();
+                let clients = idle.entry(dn.clone()).or_default();
+                if clients.len() < self.per_dn_idle {
+                    clients.push(idle_client);
+                }
+            }
+        }
+    }
+}
+
+/// Periodically health check the idle connections of the backend pool.
+pub async fn run_maintenance(app_state: Arc<AppState>) {
+    let Some(pool) = &app_state.backend_pool else {
+        return;
+    };
+    let mut interval = tokio::time::interval(pool.ping_interval);
+    loop {
+        interval.tick().await;
+        pool.maintain().await;
+    }
+}
@@ -1,41 +1,115 @@
-use crate::{AppState, CacheBackend, DnConfig, LdapFilterWrapper};
+use crate::breaker::CircuitBreaker;
+use crate::codec::{
+    parse_password_policy, BackendCodec, ClientCodec, MessageTooLarge, PasswordPolicyResponse,
+    ResponseWithControl, UnsupportedBindVersion, OID_PASSWORD_POLICY,
+};
+use crate::compression;
+use crate::dn::{normalize_dn, Dn};
+use crate::metrics::{BackendOp, Metrics};
+use crate::pool::{credential_digest, CredentialDigest};
+use crate::redact::redact;
+use crate::sessions::SessionGuard;
+use crate::telemetry;
+use crate::tls;
+use crate::{
+    filter_depth, filter_to_string, rewrite_dn, AppState, BackendMode, CacheBackend, CacheMode,
+    CacheWarmConfig, ConnectTimeouts, CriticalControlAction, DeniedQueryAction, DnConfig,
+    MaintenanceBindAction, NoFallbackAction, RedisCompression,
+};
+use concread::arcache::stats::ARCacheWriteStat;
+use concread::arcache::ARCache;
+use futures_util::future::BoxFuture;
 use futures_util::sink::SinkExt;
-use futures_util::stream::StreamExt;
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use ldap3_proto::control::LdapControl;
 use ldap3_proto::proto::*;
-use ldap3_proto::LdapCodec;
+use ldap3_proto::DisconnectionNotice;
 use openssl::ssl::{Ssl, SslConnector};
 use redis::AsyncCommands;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt;
 use std::hash::Hash;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::num::NonZeroUsize;
+use std::path::PathBuf;
 use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio::io::{ReadHalf, WriteHalf};
 use tokio::net::TcpStream;
+use tokio::sync::watch;
 use tokio_openssl::SslStream;
 use tokio_util::codec::{FramedRead, FramedWrite};
-use tracing::{debug, error, info, span, trace, warn, Level};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, error, field, info, span, trace, warn, Instrument, Level, Span};
+use url::Url;
 
 type CR = ReadHalf<SslStream<TcpStream>>;
 type CW = WriteHalf<SslStream<TcpStream>>;
 
 #[derive(Debug, Clone, Hash, PartialOrd, Ord, Eq, PartialEq, serde::Serialize, serde::Deserialize)]
 pub struct SearchCacheKey {
+    /// The tenant of the listener the search arrived on. Left out of the
+    /// serialisation when unset, so untenanted Redis keys are unchanged.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    tenant: Option<String>,
     bind_dn: String,
     search: LdapSearchRequest,
     ctrl: Vec<LdapControl>,
 }
 
 impl SearchCacheKey {
-    pub fn to_redis_key(&self, prefix: &str) -> String {
-        use std::hash::{Hash, Hasher};
-        let mut hasher = std::collections::hash_map::DefaultHasher::new();
-        self.hash(&mut hasher);
-        format!("{}{:x}", prefix, hasher.finish())
+    pub fn new(bind_dn: String, search: LdapSearchRequest, ctrl: Vec<LdapControl>) -> Self {
+        SearchCacheKey {
+            tenant: None,
+            bind_dn,
+            search,
+            ctrl,
+        }
+    }
+
+    /// Whether the search base is `dn` or one of its ancestors, so that a
+    /// write to `dn` may change the result. Bases that do not parse never are.
+    fn is_under(&self, dn: &Dn) -> bool {
+        self.search
+            .base
+            .parse::<Dn>()
+            .is_ok_and(|base| dn.ends_with(&base))
+    }
+
+    /// The same search without its size and time limits, so that it shares
+    /// an entry with searches differing only in those. Whether aliases are
+    /// dereferenced and values are returned changes the result, so those stay.
+    pub fn without_limits(self) -> Self {
+        SearchCacheKey {
+            search: LdapSearchRequest {
+                sizelimit: 0,
+                timelimit: 0,
+                ..self.search
+            },
+            ..self
+        }
+    }
+
+    /// The same search made on a listener of `tenant`.
+    pub fn with_tenant(self, tenant: Option<String>) -> Self {
+        SearchCacheKey { tenant, ..self }
+    }
+
+    /// Derive the Redis key for this search. The key is the prefix followed by
+    /// the hex encoded SHA-256 digest of the JSON serialisation of the key, so
+    /// it is stable across processes and toolchain upgrades.
+    pub fn to_redis_key(&self, prefix: &str) -> Result<String, serde_json::Error> {
+        let canonical = serde_json::to_vec(self)?;
+        let digest = openssl::sha::sha256(&canonical);
+        let mut redis_key = String::with_capacity(prefix.len() + digest.len() * 2);
+        redis_key.push_str(prefix);
+        for byte in digest {
+            redis_key.push_str(&format!("{:02x}", byte));
+        }
+        Ok(redis_key)
     }
 }
 
@@ -45,29 +119,196 @@ pub struct CachedValue {
     pub entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
     pub result: LdapResult,
     pub ctrl: Vec<LdapControl>,
+    /// The backend that returned this result. Absent in entries cached by
+    /// older versions.
+    #[serde(default)]
+    pub source_addr: Option<SocketAddr>,
 }
 
 impl CachedValue {
     pub fn size(&self) -> usize {
         std::mem::size_of::<Self>() + self.entries.iter().map(|(e, _)| e.size()).sum::<usize>()
     }
+
+    /// Returns true if the cached data differs from `other`. The time it was
+    /// cached and where it came from are ignored.
+    pub fn data_differs(&self, other: &CachedValue) -> bool {
+        self.entries != other.entries
+            || self.result.code != other.result.code
+            || self.result.message != other.result.message
+            || self.ctrl != other.ctrl
+    }
+
+    /// Sort the entries by DN, so that results which only differ in the
+    /// order the backend returned them compare equal.
+    pub fn sort_entries(&mut self) {
+        self.entries.sort_by(|(a, _), (b, _)| a.dn.cmp(&b.dn));
+    }
+
+    /// Seconds since the value was cached.
+    pub fn age_secs(&self) -> u64 {
+        self.cached_at.elapsed().unwrap_or_default().as_secs()
+    }
+}
+
+/// Searches being fetched from the backend, so that identical searches
+/// arriving meanwhile wait for that result instead of sending their own.
+#[derive(Default)]
+pub struct InFlightSearches {
+    searches: Mutex<HashMap<SearchCacheKey, watch::Receiver<Option<Arc<CachedValue>>>>>,
+}
+
+/// How a search takes part in coalescing.
+enum InFlight<'a> {
+    /// No identical search is in flight, so this one fetches the result for
+    /// those that arrive meanwhile.
+    Leader(Box<InFlightGuard<'a>>),
+    /// An identical search is in flight.
+    Waiter(watch::Receiver<Option<Arc<CachedValue>>>),
+}
+
+/// Held while a search is fetched. Dropped without `complete`, the waiting
+/// searches go to the backend themselves.
+struct InFlightGuard<'a> {
+    searches: &'a InFlightSearches,
+    key: SearchCacheKey,
+    tx: watch::Sender<Option<Arc<CachedValue>>>,
+}
+
+impl InFlightSearches {
+    fn join(&self, key: &SearchCacheKey) -> InFlight<'_> {
+        let mut searches = self.searches.lock().unwrap();
+        if let Some(rx) = searches.get(key) {
+            return InFlight::Waiter(rx.clone());
+        }
+        let (tx, rx) = watch::channel(None);
+        searches.insert(key.clone(), rx);
+        InFlight::Leader(Box::new(InFlightGuard {
+            searches: self,
+            key: key.clone(),
+            tx,
+        }))
+    }
+}
+
+impl InFlightGuard<'_> {
+    /// Hand the result to the waiting searches.
+    fn complete(self, value: Arc<CachedValue>) {
+        self.tx.send_replace(Some(value));
+    }
+}
+
+impl Drop for InFlightGuard<'_> {
+    fn drop(&mut self) {
+        self.searches.searches.lock().unwrap().remove(&self.key);
+    }
+}
+
+/// Where a client connection was accepted from. Unix domain socket peers
+/// have no IP address, so only the listening socket path is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ClientAddress {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl fmt::Display for ClientAddress {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ClientAddress::Tcp(addr) => write!(f, "{}", addr),
+            ClientAddress::Unix(path) => write!(f, "unix:{}", path.display()),
+        }
+    }
 }
 
 enum ClientState {
     Unbound,
     Authenticated {
         dn: String,
-        config: DnConfig,
+        config: Box<DnConfig>,
+        /// Idle backend connections bound as `dn`. There is one per
+        /// operation that may be outstanding at once.
+        clients: Vec<BasicLdapClient>,
+        /// The bind sent to the backend, kept only when more connections may
+        /// need to be bound, for concurrent operations or to replace one that
+        /// dropped.
+        backend_bind: Option<Box<(LdapBindRequest, Vec<LdapControl>)>>,
+        pool_credentials: Option<CredentialDigest>,
+        /// Counts this session towards the `max_sessions` of `dn`.
+        session: Option<SessionGuard>,
+    },
+    /// The backend answered a SASL bind with `saslBindInProgress`. The
+    /// connection is kept for the client's next round of the exchange.
+    SaslInProgress {
+        dn: String,
         client: BasicLdapClient,
+        session: Option<SessionGuard>,
     },
+    /// Bound by the proxy against cached credentials while the backend was
+    /// unreachable. Searches are only answered from the fallback cache.
+    Offline {
+        dn: String,
+        config: Box<DnConfig>,
+        session: Option<SessionGuard>,
+    },
+}
+
+impl ClientState {
+    /// Return a backend connection once the operation using it completes.
+    fn release_connection(&mut self, app_state: &AppState, client: BasicLdapClient) {
+        if let ClientState::Authenticated {
+            dn,
+            clients,
+            backend_bind,
+            pool_credentials,
+            ..
+        } = self
+        {
+            release_connection(
+                app_state,
+                dn,
+                clients,
+                backend_bind.is_some(),
+                pool_credentials.as_ref(),
+                client,
+            );
+        }
+    }
+}
+
+/// Keep a backend connection bound as `dn` for the session's next operation.
+/// In per operation mode it is instead returned to the backend pool, or
+/// closed, unless the session cannot bind another one.
+fn release_connection(
+    app_state: &AppState,
+    dn: &str,
+    clients: &mut Vec<BasicLdapClient>,
+    can_rebind: bool,
+    pool_credentials: Option<&CredentialDigest>,
+    client: BasicLdapClient,
+) {
+    if app_state.backend_mode == BackendMode::PerOperation && can_rebind {
+        match (&app_state.backend_pool, pool_credentials) {
+            (Some(pool), Some(credentials)) => pool.checkin(dn, *credentials, client),
+            _ => trace!(%dn, "Closing backend connection"),
+        }
+        return;
+    }
+    clients.push(client);
 }
 
 fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
+    bind_error(msgid, LdapResultCode::OperationsError, msg)
+}
+
+/// A bind response with `code`, which is also recorded on the current span.
+fn bind_error(msgid: i32, code: LdapResultCode, msg: &str) -> LdapMsg {
+    telemetry::record_result(&code);
     LdapMsg {
         msgid,
         op: LdapOp::BindResponse(LdapBindResponse {
             res: LdapResult {
-                code: LdapResultCode::OperationsError,
+                code,
                 matcheddn: "".to_string(),
                 message: msg.to_string(),
                 referral: vec![],
@@ -78,22 +319,662 @@ fn bind_operror(msgid: i32, msg: &str) -> LdapMsg {
     }
 }
 
+/// The response rejecting a bind DN longer than `max_dn_length` or a search
+/// filter nested deeper than `max_filter_depth`, before either is parsed
+/// further or sent to the backend.
+fn exceeds_request_limits(app_state: &AppState, msg: &LdapMsg) -> Option<LdapMsg> {
+    match &msg.op {
+        LdapOp::BindRequest(lbr) if lbr.dn.len() > app_state.max_dn_length => {
+            warn!(length = lbr.dn.len(), "Rejecting bind with a dn over max_dn_length");
+            Some(bind_error(msg.msgid, LdapResultCode::InvalidDNSyntax, "dn is too long"))
+        }
+        LdapOp::SearchRequest(sr) => {
+            let depth = filter_depth(&sr.filter);
+            if depth <= app_state.max_filter_depth {
+                return None;
+            }
+            warn!(depth, "Rejecting search with a filter over max_filter_depth");
+            telemetry::record_result(&LdapResultCode::ProtocolError);
+            Some(LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::ProtocolError,
+                    matcheddn: "".to_string(),
+                    message: "filter is nested too deeply".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// The oids of the controls relayed to the backend with their criticality.
+/// Others are relayed as not critical, and unknown ones also lose their
+/// value.
+const CRITICALITY_RELAYED: [&str; 4] = [
+    // Sync request
+    "1.3.6.1.4.1.4203.1.9.1.1",
+    // AD dirsync
+    "1.2.840.113556.1.4.841",
+    // ManageDsaIT
+    "2.16.840.1.113730.3.4.2",
+    OID_PASSWORD_POLICY,
+];
+
+/// The response rejecting a bind or search with a critical control that the
+/// proxy would relay without its criticality, or any critical control on a
+/// search the proxy answers itself.
+fn unhonored_critical_control(
+    app_state: &AppState,
+    msg: &LdapMsg,
+    critical: &[String],
+) -> Option<LdapMsg> {
+    if app_state.critical_control_action == CriticalControlAction::Ignore {
+        return None;
+    }
+    let synthetic = match &msg.op {
+        LdapOp::BindRequest(_) => false,
+        LdapOp::SearchRequest(sr) => {
+            (app_state.root_dse.is_some() && is_root_dse_search(sr))
+                || app_state.schema.as_ref().is_some_and(|schema| schema.search(sr).is_some())
+        }
+        _ => return None,
+    };
+    let oid = critical
+        .iter()
+        .find(|oid| synthetic || !CRITICALITY_RELAYED.contains(&oid.as_str()))?;
+
+    warn!(%oid, "Rejecting request with a critical control the proxy cannot honor");
+    let code = LdapResultCode::UnavailableCriticalExtension;
+    let message = format!("critical control {} is not supported", oid);
+    if matches!(msg.op, LdapOp::BindRequest(_)) {
+        return Some(bind_error(msg.msgid, code, &message));
+    }
+    telemetry::record_result(&code);
+    Some(LdapMsg {
+        msgid: msg.msgid,
+        op: LdapOp::SearchResultDone(LdapResult {
+            code,
+            matcheddn: "".to_string(),
+            message,
+            referral: vec![],
+        }),
+        ctrl: vec![],
+    })
+}
+
+/// A base scoped search of the empty DN for `(objectClass=*)`.
+fn is_root_dse_search(sr: &LdapSearchRequest) -> bool {
+    sr.base.is_empty()
+        && sr.scope == LdapSearchScope::Base
+        && matches!(&sr.filter, LdapFilter::Present(attr) if attr.eq_ignore_ascii_case("objectclass"))
+}
+
+/// Build the root dse entry from the configured attributes, honouring the
+/// attribute selection of the request.
+fn root_dse_entry(
+    root_dse: &BTreeMap<String, Vec<String>>,
+    requested: &[String],
+) -> LdapSearchResultEntry {
+    let all = requested.is_empty() || requested.iter().any(|a| a == "*" || a == "+");
+    let attributes = root_dse
+        .iter()
+        .filter(|(atype, _)| all || requested.iter().any(|a| a.eq_ignore_ascii_case(atype)))
+        .map(|(atype, vals)| LdapPartialAttribute {
+            atype: atype.clone(),
+            vals: vals.iter().map(|v| v.as_bytes().to_vec()).collect(),
+        })
+        .collect();
+
+    LdapSearchResultEntry {
+        dn: "".to_string(),
+        attributes,
+    }
+}
+
+/// The attributes asked of the backend for a search under
+/// `cache_attribute_superset`, all user and operational attributes.
+const ATTRIBUTE_SUPERSET: [&str; 2] = ["*", "+"];
+
+/// Whether a search asking for `attrs` can be answered from the attribute
+/// superset, as it names the attributes it wants rather than asking for all
+/// user or operational attributes, or none.
+fn is_superset_selectable(attrs: &[String]) -> bool {
+    !attrs.is_empty() && attrs.iter().all(|a| a != "*" && a != "+" && a != "1.1")
+}
+
+/// Keep the attributes of `entry` that `attrs` names. Names are compared
+/// case-insensitively, and also match the attribute with options, so `cn`
+/// keeps `cn;lang-en`.
+fn select_attributes(entry: &mut LdapSearchResultEntry, attrs: &[String]) {
+    entry.attributes.retain(|attr| {
+        let name = attr.atype.split(';').next().unwrap_or_default();
+        attrs.iter().any(|a| a.eq_ignore_ascii_case(name))
+    });
+}
+
+/// Keep the `supportedControl` values of a root dse `entry` that are in
+/// `allowed`.
+fn filter_supported_controls(entry: &mut LdapSearchResultEntry, allowed: &[String]) {
+    for attr in entry.attributes.iter_mut() {
+        if attr.atype.eq_ignore_ascii_case("supportedControl") {
+            attr.vals
+                .retain(|oid| allowed.iter().any(|a| a.as_bytes() == oid.as_slice()));
+        }
+    }
+}
+
+/// Answer a search with a single entry the proxy holds itself. Returns the
+/// reason if the session should end.
+async fn send_synthetic_entry<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, ClientCodec>,
+    msgid: i32,
+    entry: LdapSearchResultEntry,
+) -> Result<(), DisconnectReason> {
+    telemetry::record_result(&LdapResultCode::Success);
+    let msgs = [
+        LdapOp::SearchResultEntry(entry),
+        LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        }),
+    ];
+    for op in msgs {
+        if w.send(LdapMsg {
+            msgid,
+            op,
+            ctrl: vec![],
+        })
+        .await
+        .is_err()
+        {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+    }
+    Ok(())
+}
+
+/// Send a search response, attaching the cache age control when the response
+/// was served from the fallback cache. The control value is the age in whole
+/// seconds as a decimal string. Unless `flush` is set the response may stay
+/// buffered until a later one is flushed.
+async fn send_search_response<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, ClientCodec>,
+    msg: LdapMsg,
+    cache_age_control: Option<(&str, u64)>,
+    flush: bool,
+) -> Result<(), std::io::Error> {
+    match cache_age_control {
+        Some((oid, age)) => {
+            let msg = ResponseWithControl {
+                msg,
+                oid: oid.to_string(),
+                value: age.to_string().into_bytes(),
+            };
+            if flush {
+                w.send(msg).await
+            } else {
+                w.feed(msg).await
+            }
+        }
+        None if flush => w.send(msg).await,
+        None => w.feed(msg).await,
+    }
+}
+
+/// Whether the entry numbered `sent` (from 1) of a search is flushed.
+fn flush_entry(app_state: &AppState, sent: usize) -> bool {
+    sent.is_multiple_of(app_state.response_flush_entries)
+}
+
+/// Forward a search that exceeded the buffer limit: first the entries that
+/// were buffered, then the rest as they arrive from the backend. Returns the
+/// reason if the session should end.
+async fn stream_spilled_search<W: AsyncWrite + Unpin>(
+    app_state: &AppState,
+    w: &mut FramedWrite<W, ClientCodec>,
+    client: &mut BasicLdapClient,
+    msgid: i32,
+    backend_msgid: i32,
+    entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    selected_attrs: Option<&[String]>,
+) -> Result<(), DisconnectReason> {
+    let mut sent = 0;
+    for (mut entry, ctrl) in entries {
+        if let Some(attrs) = selected_attrs {
+            select_attributes(&mut entry, attrs);
+        }
+        sent += 1;
+        let msg = LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultEntry(entry),
+            ctrl,
+        };
+        if send_search_response(w, msg, None, flush_entry(app_state, sent))
+            .await
+            .is_err()
+        {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+    }
+
+    loop {
+        let (op, ctrl, done) = match client.next_search_item(backend_msgid).await {
+            Ok(SearchItem::Entry(mut entry, ctrl)) => {
+                if let Some(attrs) = selected_attrs {
+                    select_attributes(&mut entry, attrs);
+                }
+                (LdapOp::SearchResultEntry(entry), ctrl, false)
+            }
+            Ok(SearchItem::Intermediate(response, ctrl)) => {
+                (LdapOp::IntermediateResponse(response), ctrl, false)
+            }
+            Ok(SearchItem::Reference(mut reference, ctrl)) => {
+                app_state.rewrite_referrals(&mut reference.uris);
+                (LdapOp::SearchResultReference(reference), ctrl, false)
+            }
+            Ok(SearchItem::Done(mut result, ctrl)) => {
+                app_state
+                    .metrics
+                    .record_backend_result(BackendOp::Search, &result.code);
+                telemetry::record_result(&result.code);
+                app_state.rewrite_referrals(&mut result.referral);
+                (LdapOp::SearchResultDone(result), ctrl, true)
+            }
+            Err(LdapError::MessageTooLarge) => {
+                warn!("Search result exceeded max_proxy_ber_size while streaming");
+                telemetry::record_result(&LdapResultCode::AdminLimitExceeded);
+                let result = LdapResult {
+                    code: LdapResultCode::AdminLimitExceeded,
+                    matcheddn: "".to_string(),
+                    message: "Message from the backend exceeds the proxy's max ber size"
+                        .to_string(),
+                    referral: vec![],
+                };
+                (LdapOp::SearchResultDone(result), Vec::new(), true)
+            }
+            Err(e) => {
+                error!(?e, "Backend failed while streaming search results");
+                telemetry::record_result(&LdapResultCode::Unavailable);
+                let resp_msg = LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchResultDone(LdapResult {
+                        code: LdapResultCode::Unavailable,
+                        matcheddn: "".to_string(),
+                        message: "Backend LDAP server unavailable".to_string(),
+                        referral: vec![],
+                    }),
+                    ctrl: vec![],
+                };
+                if w.send(resp_msg).await.is_err() {
+                    error!("Unable to send response");
+                }
+                send_disconnect_notice(
+                    w,
+                    LdapResultCode::Unavailable,
+                    "backend ldap server unavailable",
+                )
+                .await;
+                return Err(DisconnectReason::BackendUnavailable);
+            }
+        };
+
+        sent += 1;
+        let flush = done || flush_entry(app_state, sent);
+        if send_search_response(w, LdapMsg { msgid, op, ctrl }, None, flush)
+            .await
+            .is_err()
+        {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+/// Relay the continuation references of a search, with their urls
+/// rewritten as referrals are.
+async fn send_search_references<W: AsyncWrite + Unpin>(
+    app_state: &AppState,
+    w: &mut FramedWrite<W, ClientCodec>,
+    msgid: i32,
+    references: Vec<SearchReference>,
+) -> Result<(), DisconnectReason> {
+    for (mut reference, ctrl) in references {
+        app_state.rewrite_referrals(&mut reference.uris);
+        if reference.uris.is_empty() {
+            continue;
+        }
+        let msg = LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultReference(reference),
+            ctrl,
+        };
+        if send_search_response(w, msg, None, false).await.is_err() {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+    }
+    Ok(())
+}
+
+/// Record how long the backend took over an operation by `dn` started at
+/// `started`, logging it as a warning when that is over
+/// `slow_op_threshold_ms`.
+fn observe_backend_latency(
+    app_state: &AppState,
+    op: BackendOp,
+    dn: &str,
+    filter: Option<&LdapFilter>,
+    started: Instant,
+) {
+    let elapsed = started.elapsed();
+    telemetry::record_backend_latency(elapsed);
+    let elapsed_ms = elapsed.as_millis() as u64;
+    match app_state.slow_op_threshold {
+        Some(threshold) if elapsed > threshold => {
+            let filter = filter.map(filter_to_string).unwrap_or_default();
+            warn!(op = op.label(), %dn, %filter, elapsed_ms, "Slow backend operation");
+        }
+        _ => trace!(op = op.label(), %dn, elapsed_ms, "Backend operation complete"),
+    }
+}
+
+/// Whether `ctrl` holds an RFC 4533 sync request.
+fn is_sync_search(ctrl: &[LdapControl]) -> bool {
+    ctrl.iter()
+        .any(|ctrl| matches!(ctrl, LdapControl::SyncRequest { .. }))
+}
+
+/// Whether `ctrl` holds an RFC 4533 sync request in refreshAndPersist mode.
+fn is_persist_search(ctrl: &[LdapControl]) -> bool {
+    ctrl.iter().any(|ctrl| {
+        matches!(
+            ctrl,
+            LdapControl::SyncRequest {
+                mode: SyncRequestMode::RefreshAndPersist,
+                ..
+            }
+        )
+    })
+}
+
+/// Relay an RFC 4533 sync search, whose results follow the live state of the
+/// backend. Each response is sent as soon as it arrives, sync info
+/// intermediate responses included, and nothing is cached. In
+/// refreshAndPersist mode this lasts until the search is abandoned or the
+/// backend ends it, so it is refused unless searches run concurrently, as the
+/// abandon would otherwise never be read. The writer is only held for each
+/// response, so that other operations of the session can still be answered.
+/// Returns the reason if the session should end.
+async fn relay_sync_search<W: AsyncWrite + Unpin>(
+    app_state: &AppState,
+    w: &tokio::sync::Mutex<FramedWrite<W, ClientCodec>>,
+    client: Option<&mut BasicLdapClient>,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+) -> Result<(), DisconnectReason> {
+    let breaker_open = app_state
+        .breaker
+        .as_ref()
+        .is_some_and(|breaker| breaker.is_open());
+    let unavailable = |message: &str| LdapMsg {
+        msgid,
+        op: LdapOp::SearchResultDone(LdapResult {
+            code: LdapResultCode::Unavailable,
+            matcheddn: "".to_string(),
+            message: message.to_string(),
+            referral: vec![],
+        }),
+        ctrl: vec![],
+    };
+
+    if app_state.max_concurrent_ops <= 1 && is_persist_search(&ctrl) {
+        warn!("Refusing a persistent sync search with serial operations");
+        telemetry::record_result(&LdapResultCode::UnwillingToPerform);
+        let resp_msg = LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::UnwillingToPerform,
+                matcheddn: "".to_string(),
+                message: "refreshAndPersist requires max_concurrent_ops above 1".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        };
+        if w.lock().await.send(resp_msg).await.is_err() {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+        return Ok(());
+    }
+
+    let client = match client {
+        Some(client) if !breaker_open && !app_state.in_maintenance() => client,
+        _ => {
+            warn!("Backend is unavailable, refusing a sync search");
+            telemetry::record_result(&LdapResultCode::Unavailable);
+            let resp_msg = unavailable("sync searches require the backend ldap server");
+            if w.lock().await.send(resp_msg).await.is_err() {
+                error!("Unable to send response");
+                return Err(DisconnectReason::WriteFailed);
+            }
+            return Ok(());
+        }
+    };
+
+    let sr = LdapSearchRequest {
+        base: rewrite_dn(&app_state.dn_rewrite, &sr.base),
+        ..sr
+    };
+    let started = Instant::now();
+    let failure = 'relay: {
+        let backend_msgid = match client.send_search(sr, ctrl).await {
+            Ok(backend_msgid) => backend_msgid,
+            Err(e) => break 'relay e,
+        };
+        loop {
+            let (op, ctrl, done) = match client.next_search_item(backend_msgid).await {
+                Ok(SearchItem::Entry(entry, ctrl)) => {
+                    (LdapOp::SearchResultEntry(entry), ctrl, false)
+                }
+                Ok(SearchItem::Intermediate(response, ctrl)) => {
+                    (LdapOp::IntermediateResponse(response), ctrl, false)
+                }
+                Ok(SearchItem::Reference(mut reference, ctrl)) => {
+                    app_state.rewrite_referrals(&mut reference.uris);
+                    (LdapOp::SearchResultReference(reference), ctrl, false)
+                }
+                Ok(SearchItem::Done(mut result, ctrl)) => {
+                    telemetry::record_backend_latency(started.elapsed());
+                    app_state
+                        .metrics
+                        .record_backend_result(BackendOp::Search, &result.code);
+                    telemetry::record_result(&result.code);
+                    app_state.rewrite_referrals(&mut result.referral);
+                    (LdapOp::SearchResultDone(result), ctrl, true)
+                }
+                Err(e) => break 'relay e,
+            };
+
+            if w.lock().await.send(LdapMsg { msgid, op, ctrl }).await.is_err() {
+                error!("Unable to send response");
+                return Err(DisconnectReason::WriteFailed);
+            }
+            if done {
+                return Ok(());
+            }
+        }
+    };
+
+    error!(e = ?failure, "Backend failed during a sync search");
+    telemetry::record_result(&LdapResultCode::Unavailable);
+    let mut w = w.lock().await;
+    if w.send(unavailable("Backend LDAP server unavailable")).await.is_err() {
+        error!("Unable to send response");
+    }
+    send_disconnect_notice(
+        &mut w,
+        LdapResultCode::Unavailable,
+        "backend ldap server unavailable",
+    )
+    .await;
+    Err(DisconnectReason::BackendUnavailable)
+}
+
+/// Send the RFC 4511 unsolicited notice of disconnection. This is best-effort
+/// since the connection is about to be closed regardless.
+async fn send_disconnect_notice<W: AsyncWrite + Unpin>(
+    w: &mut FramedWrite<W, ClientCodec>,
+    code: LdapResultCode,
+    msg: &str,
+) {
+    if w.send(DisconnectionNotice::gen(code, msg)).await.is_err() {
+        debug!("Unable to send notice of disconnection");
+    }
+}
+
+/// Completes after `timeout`, or never if there is none.
+async fn idle(timeout: Option<Duration>) {
+    match timeout {
+        Some(timeout) => tokio::time::sleep(timeout).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// The number of entries held in memory in front of Redis.
+const L1_CACHE_ENTRIES: usize = 1000;
+
+/// The Redis sorted set holding the keys of the searches cached with `base`,
+/// so that they can be invalidated without a scan. It is under `key_prefix`
+/// rather than a tenant's prefix as a write changes the results of every
+/// tenant.
+fn redis_base_index_key(key_prefix: &str, base: &Dn) -> String {
+    format!("{}index:base:{}", key_prefix, base.to_string().to_ascii_lowercase())
+}
+
+/// The current time in seconds since the epoch, which index members are
+/// scored against.
+fn unix_now() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |now| now.as_secs())
+}
+
+/// The number of oldest entries listed by [log_cache_summary].
+const CACHE_SUMMARY_OLDEST: usize = 10;
+
+/// How often evictions from the memory cache are logged at most.
+const CACHE_EVICTION_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The in memory level of a [`TieredCache`]. Once full, an arbitrary entry
+/// is evicted for each insert.
+pub struct L1Cache {
+    entries: Mutex<HashMap<SearchCacheKey, CachedValue>>,
+    max_entries: usize,
+}
+
+impl L1Cache {
+    pub fn new(max_entries: usize) -> Self {
+        Self {
+            entries: Mutex::new(HashMap::new()),
+            max_entries,
+        }
+    }
+
+    pub fn get(&self, key: &SearchCacheKey) -> Option<CachedValue> {
+        self.entries.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert(&self, key: SearchCacheKey, value: CachedValue) {
+        let mut cache = self.entries.lock().unwrap();
+
+        // Simple eviction if over size
+        if cache.len() >= self.max_entries {
+            // Remove oldest entry (simple FIFO eviction)
+            if let Some(first_key) = cache.keys().next().cloned() {
+                cache.remove(&first_key);
+            }
+        }
+
+        cache.insert(key, value);
+    }
+
+    /// Whether `value` differs from the entry for `key`, compared in place,
+    /// or None if there is no entry.
+    fn data_differs(&self, key: &SearchCacheKey, value: &CachedValue) -> Option<bool> {
+        let cache = self.entries.lock().unwrap();
+        cache.get(key).map(|cached| cached.data_differs(value))
+    }
+
+    fn summary(&self) -> CacheSummary {
+        CacheSummary::new(self.entries.lock().unwrap().iter())
+    }
+
+    /// Remove the entries of searches under `dn`, returning how many.
+    fn invalidate(&self, dn: &Dn) -> usize {
+        let mut cache = self.entries.lock().unwrap();
+        let before = cache.len();
+        cache.retain(|key, _| !key.is_under(dn));
+        before - cache.len()
+    }
+}
+
 // Tiered cache structure for Redis backend
-struct TieredCache {
-    l1_cache: Arc<Mutex<HashMap<SearchCacheKey, CachedValue>>>,
+pub struct TieredCache {
+    l1: L1Cache,
     redis_conn: redis::aio::ConnectionManager,
-    max_l1_size: usize,
+    /// The prefix of every Redis key, that of the cache index included.
+    key_prefix: String,
+    compression: RedisCompression,
+    /// Open while Redis fails, when only L1 is used.
+    breaker: CircuitBreaker,
 }
 
 impl TieredCache {
     fn new(
         redis_conn: redis::aio::ConnectionManager,
+        key_prefix: &str,
         max_l1_size: usize,
+        compression: RedisCompression,
+        breaker: CircuitBreaker,
     ) -> Self {
         Self {
-            l1_cache: Arc::new(Mutex::new(HashMap::new())),
+            l1: L1Cache::new(max_l1_size),
             redis_conn,
-            max_l1_size,
+            key_prefix: key_prefix.to_string(),
+            compression,
+            breaker,
+        }
+    }
+
+    /// The in memory cache shared by all sessions in front of `cache`, if it
+    /// is Redis. Values are written to Redis under `key_prefix` compressed
+    /// with `compression`, and Redis is left alone while `breaker` is open.
+    pub fn for_backend(
+        cache: &CacheBackend,
+        key_prefix: &str,
+        compression: RedisCompression,
+        breaker: CircuitBreaker,
+    ) -> Option<Arc<Self>> {
+        match cache {
+            CacheBackend::Redis(conn) => Some(Arc::new(Self::new(
+                conn.clone(),
+                key_prefix,
+                L1_CACHE_ENTRIES,
+                compression,
+                breaker,
+            ))),
+            CacheBackend::Memory(_) | CacheBackend::Custom(_) => None,
         }
     }
 
@@ -103,36 +984,39 @@ impl TieredCache {
         redis_prefix: &str,
     ) -> Option<CachedValue> {
         // Check L1 cache first
-        {
-            let cache = self.l1_cache.lock().unwrap();
-            if let Some(value) = cache.get(key) {
-                trace!("L1 cache hit");
-                return Some(value.clone());
-            }
+        if let Some(value) = self.l1.get(key) {
+            trace!("L1 cache hit");
+            return Some(value);
         }
 
         // L1 miss, check Redis (L2)
-        let redis_key = key.to_redis_key(redis_prefix);
+        let redis_key = match key.to_redis_key(redis_prefix) {
+            Ok(k) => k,
+            Err(e) => {
+                error!(?e, "Unable to derive Redis key");
+                return None;
+            }
+        };
+        if !self.breaker.allow() {
+            trace!("Redis is degraded, L1 cache miss");
+            return None;
+        }
         let mut conn = self.redis_conn.clone();
         
-        match conn.get::<_, Vec<u8>>(&redis_key).await {
-            Ok(data) => match serde_json::from_slice::<CachedValue>(&data) {
+        let result = conn.get::<_, Vec<u8>>(&redis_key).await;
+        match &result {
+            Err(e) if e.kind() != redis::ErrorKind::TypeError => self.breaker.record_failure(),
+            _ => self.breaker.record_success(),
+        }
+        match result {
+            Ok(data) => match compression::decompress(&data)
+                .map_err(serde_json::Error::io)
+                .and_then(|data| serde_json::from_slice::<CachedValue>(&data))
+            {
                 Ok(value) => {
                     trace!("L2 (Redis) cache hit, promoting to L1");
                     // Promote to L1 cache
-                    {
-                        let mut cache = self.l1_cache.lock().unwrap();
-                        
-                        // Simple eviction if over size
-                        if cache.len() >= self.max_l1_size {
-                            // Remove oldest entry (simple FIFO eviction)
-                            if let Some(first_key) = cache.keys().next().cloned() {
-                                cache.remove(&first_key);
-                            }
-                        }
-                        
-                        cache.insert(key.clone(), value.clone());
-                    }
+                    self.l1.insert(key.clone(), value.clone());
                     Some(value)
                 }
                 Err(e) => {
@@ -161,50 +1045,153 @@ impl TieredCache {
         redis_prefix: &str,
         ttl: Option<u64>,
     ) {
+        let redis_key = key.to_redis_key(redis_prefix);
+        let index_key = key
+            .search
+            .base
+            .parse::<Dn>()
+            .ok()
+            .map(|base| redis_base_index_key(&self.key_prefix, &base));
+        let data = serde_json::to_vec(&value).and_then(|data| {
+            compression::compress(self.compression, data).map_err(serde_json::Error::io)
+        });
+
         // Write to L1 cache immediately
-        {
-            let mut cache = self.l1_cache.lock().unwrap();
-            
-            // Simple eviction if over size
-            if cache.len() >= self.max_l1_size {
-                if let Some(first_key) = cache.keys().next().cloned() {
-                    cache.remove(&first_key);
-                }
-            }
-            
-            cache.insert(key.clone(), value.clone());
-        }
+        self.l1.insert(key, value);
 
         // Write to Redis synchronously with timeout
-        let redis_key = key.to_redis_key(redis_prefix);
+        let redis_key = match redis_key {
+            Ok(k) => k,
+            Err(e) => {
+                error!(?e, "Unable to derive Redis key");
+                return;
+            }
+        };
+        let data = match data {
+            Ok(data) => data,
+            Err(e) => {
+                error!(?e, "Failed to serialize value for Redis");
+                return;
+            }
+        };
+        if !self.breaker.allow() {
+            trace!("Redis is degraded, written to L1 cache only");
+            return;
+        }
         let mut conn = self.redis_conn.clone();
         
         let timeout = Duration::from_millis(100);
         let redis_write = async {
-            match serde_json::to_vec(&value) {
-                Ok(data) => {
-                    let result = if let Some(ttl_seconds) = ttl {
-                        conn.set_ex::<_, _, ()>(&redis_key, data, ttl_seconds).await
-                    } else {
-                        conn.set::<_, _, ()>(&redis_key, data).await
-                    };
-                    
-                    if let Err(e) = result {
-                        debug!(?e, "Redis write failed");
-                    } else {
-                        trace!("Redis write completed");
-                    }
-                }
-                Err(e) => {
-                    error!(?e, "Failed to serialize value for Redis");
+            let mut pipe = redis::pipe();
+            if let Some(ttl_seconds) = ttl {
+                pipe.set_ex(&redis_key, data, ttl_seconds).ignore();
+            } else {
+                pipe.set(&redis_key, data).ignore();
+            }
+            if let Some(index_key) = &index_key {
+                // Each member is scored by when its search expires, and those
+                // already expired are dropped, so that the index only holds
+                // the searches still cached.
+                let now = unix_now();
+                let expires = ttl.map_or("+inf".to_string(), |ttl| (now + ttl).to_string());
+                pipe.zadd(index_key, &redis_key, expires).ignore();
+                pipe.zrembyscore(index_key, "-inf", format!("({}", now)).ignore();
+                // Outlives every key it holds, as each add pushes it back.
+                if let Some(ttl_seconds) = ttl {
+                    pipe.expire(index_key, ttl_seconds as i64).ignore();
                 }
             }
+            pipe.query_async::<_, ()>(&mut conn).await
         };
 
-        // Wait for Redis write with timeout
-        if tokio::time::timeout(timeout, redis_write).await.is_err() {
-            warn!("Redis write timed out, continuing with L1 cache only");
+        // Wait for Redis write with timeout. Failures are only logged at debug
+        // level, the breaker warns once Redis keeps failing.
+        match tokio::time::timeout(timeout, redis_write).await {
+            Ok(Ok(())) => {
+                self.breaker.record_success();
+                trace!("Redis write completed");
+            }
+            Ok(Err(e)) => {
+                self.breaker.record_failure();
+                debug!(?e, "Redis write failed");
+            }
+            Err(_) => {
+                self.breaker.record_failure();
+                debug!("Redis write timed out, continuing with L1 cache only");
+            }
+        }
+    }
+
+    /// Whether Redis is failing and only L1 is used.
+    pub fn is_degraded(&self) -> bool {
+        self.breaker.is_open()
+    }
+
+    /// Compare against the L1 entry in place, falling back to Redis only when
+    /// L1 has no entry for the key.
+    async fn is_changed(&self, key: &SearchCacheKey, value: &CachedValue, redis_prefix: &str) -> bool {
+        match self.l1.data_differs(key, value) {
+            Some(changed) => changed,
+            None => match self.get(key, redis_prefix).await {
+                Some(cached) => cached.data_differs(value),
+                // No existing cache, definitely changed
+                None => true,
+            },
+        }
+    }
+
+    /// Remove the searches under `dn` from L1 and Redis, returning how many
+    /// were removed from Redis. An error if Redis is degraded or fails, as
+    /// its stale entries would then be served once L1 no longer holds them.
+    async fn invalidate(&self, dn: &Dn) -> Result<usize, String> {
+        self.l1.invalidate(dn);
+
+        if !self.breaker.allow() {
+            warn!(%dn, "Redis is degraded, only the L1 cache was invalidated");
+            return Err("Redis is degraded, only the memory cache was invalidated".to_string());
+        }
+        let mut conn = self.redis_conn.clone();
+        let mut removed = 0;
+        let mut failed = false;
+        for base in dn.ancestors() {
+            let index_key = redis_base_index_key(&self.key_prefix, &base);
+            let live = conn.zrangebyscore(&index_key, unix_now(), "+inf").await;
+            let keys: Vec<String> = match live {
+                Ok(keys) => keys,
+                Err(e) => {
+                    warn!(?e, base = %base, "Unable to read the Redis cache index");
+                    failed = true;
+                    continue;
+                }
+            };
+            if keys.is_empty() {
+                continue;
+            }
+            // Only the keys read are removed from the index, so that searches
+            // cached meanwhile stay indexed.
+            let result = redis::pipe()
+                .del(&keys)
+                .zrem(&index_key, &keys)
+                .ignore()
+                .query_async::<_, (usize,)>(&mut conn)
+                .await;
+            match result {
+                Ok((deleted,)) => removed += deleted,
+                Err(e) => {
+                    warn!(?e, base = %base, "Unable to invalidate Redis cache entries");
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            self.breaker.record_failure();
+            return Err(format!(
+                "Redis failed, {} cached searches were invalidated but others may remain",
+                removed
+            ));
         }
+        self.breaker.record_success();
+        Ok(removed)
     }
 
     async fn set_if_changed(
@@ -215,66 +1202,283 @@ impl TieredCache {
         ttl: Option<u64>,
     ) {
         // Check if data has changed by comparing with existing cache
-        let existing = self.get(&key, redis_prefix).await;
-        
-        let has_changed = match existing {
-            Some(cached) => {
-                // Compare the actual data (entries and result)
-                // We ignore cached_at timestamp for comparison
-                cached.entries != value.entries 
-                    || cached.result.code != value.result.code
-                    || cached.result.message != value.result.message
-                    || cached.ctrl != value.ctrl
-            }
-            None => {
-                // No existing cache, definitely changed
-                true
-            }
-        };
-
-        if has_changed {
+        if self.is_changed(&key, &value, redis_prefix).await {
             debug!("Cache data has changed, updating");
             self.set(key, value, redis_prefix, ttl).await;
         } else {
             debug!("Cache data unchanged, skipping Redis write");
             // Still update L1 to refresh the entry
-            let mut cache = self.l1_cache.lock().unwrap();
-            
-            // Simple eviction if over size
-            if cache.len() >= self.max_l1_size {
-                if let Some(first_key) = cache.keys().next().cloned() {
-                    cache.remove(&first_key);
-                }
-            }
-            
-            cache.insert(key, value);
+            self.l1.insert(key, value);
+        }
+    }
+}
+
+/// A store for cached search results. The memory cache and Redis are
+/// provided by [MemoryStore] and [TieredCache], and other stores can be
+/// used through [CacheBackend::Custom].
+///
+/// `prefix` namespaces the keys of the tenant a search arrived for, in
+/// stores shared between proxies. `ttl` is the lifetime of an entry in
+/// seconds, for stores that expire them.
+pub trait CacheStore: Send + Sync {
+    fn get<'a>(
+        &'a self,
+        key: &'a SearchCacheKey,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Option<CachedValue>>;
+
+    fn set<'a>(
+        &'a self,
+        key: SearchCacheKey,
+        value: CachedValue,
+        prefix: &'a str,
+        ttl: Option<u64>,
+    ) -> BoxFuture<'a, ()>;
+
+    /// As [CacheStore::set], but the store may skip writing a value equal to
+    /// the one it already holds. By default it is always written.
+    fn set_if_changed<'a>(
+        &'a self,
+        key: SearchCacheKey,
+        value: CachedValue,
+        prefix: &'a str,
+        ttl: Option<u64>,
+    ) -> BoxFuture<'a, ()> {
+        self.set(key, value, prefix, ttl)
+    }
+
+    /// Remove every search whose base is `dn` or one of its ancestors,
+    /// returning how many were removed, or an error if some may remain. By
+    /// default this is unsupported, which is an error rather than a claim
+    /// that nothing was stale.
+    fn invalidate<'a>(&'a self, _dn: &'a Dn) -> BoxFuture<'a, Result<usize, String>> {
+        Box::pin(std::future::ready(Err(
+            "invalidation is not supported by this cache store".to_string(),
+        )))
+    }
+
+    /// Apply pending housekeeping, which is called periodically. By default
+    /// there is none.
+    fn try_quiesce(&self) -> BoxFuture<'_, ()> {
+        Box::pin(std::future::ready(()))
+    }
+}
+
+impl CacheStore for TieredCache {
+    fn get<'a>(
+        &'a self,
+        key: &'a SearchCacheKey,
+        prefix: &'a str,
+    ) -> BoxFuture<'a, Option<CachedValue>> {
+        Box::pin(TieredCache::get(self, key, prefix))
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: SearchCacheKey,
+        value: CachedValue,
+        prefix: &'a str,
+        ttl: Option<u64>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(TieredCache::set(self, key, value, prefix, ttl))
+    }
+
+    fn set_if_changed<'a>(
+        &'a self,
+        key: SearchCacheKey,
+        value: CachedValue,
+        prefix: &'a str,
+        ttl: Option<u64>,
+    ) -> BoxFuture<'a, ()> {
+        Box::pin(TieredCache::set_if_changed(self, key, value, prefix, ttl))
+    }
+
+    fn invalidate<'a>(&'a self, dn: &'a Dn) -> BoxFuture<'a, Result<usize, String>> {
+        Box::pin(TieredCache::invalidate(self, dn))
+    }
+}
+
+/// The memory cache as a [CacheStore], counting its evictions in `metrics`.
+/// Entries never expire, they are only evicted once the cache is full.
+pub struct MemoryStore<'a> {
+    cache: &'a ARCache<SearchCacheKey, CachedValue>,
+    metrics: &'a Metrics,
+}
+
+impl<'a> MemoryStore<'a> {
+    pub fn new(cache: &'a ARCache<SearchCacheKey, CachedValue>, metrics: &'a Metrics) -> Self {
+        MemoryStore { cache, metrics }
+    }
+}
+
+impl CacheStore for MemoryStore<'_> {
+    fn get<'a>(
+        &'a self,
+        key: &'a SearchCacheKey,
+        _prefix: &'a str,
+    ) -> BoxFuture<'a, Option<CachedValue>> {
+        let mut cache_read = self.cache.read();
+        Box::pin(std::future::ready(cache_read.get(key).cloned()))
+    }
+
+    fn set<'a>(
+        &'a self,
+        key: SearchCacheKey,
+        value: CachedValue,
+        _prefix: &'a str,
+        _ttl: Option<u64>,
+    ) -> BoxFuture<'a, ()> {
+        memory_cache_set(self.cache, self.metrics, key, value);
+        Box::pin(std::future::ready(()))
+    }
+
+    fn invalidate<'a>(&'a self, dn: &'a Dn) -> BoxFuture<'a, Result<usize, String>> {
+        let mut cache_write = self.cache.write();
+        let stale: Vec<_> = cache_write
+            .iter()
+            .filter(|(key, _)| key.is_under(dn))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in &stale {
+            cache_write.remove(key.clone());
         }
+        cache_write.commit();
+        Box::pin(std::future::ready(Ok(stale.len())))
+    }
+
+    fn try_quiesce(&self) -> BoxFuture<'_, ()> {
+        self.cache
+            .try_quiesce_stats(CommitStats::default())
+            .record(self.metrics);
+        Box::pin(std::future::ready(()))
     }
 }
 
-async fn cache_get(
+/// Look up `key` in `cache`, going through `tiered_cache` when it is Redis.
+pub async fn cache_get(
     cache: &CacheBackend,
+    metrics: &Metrics,
     key: &SearchCacheKey,
     redis_prefix: &str,
     tiered_cache: &Option<Arc<TieredCache>>,
 ) -> Option<CachedValue> {
     match cache {
         CacheBackend::Memory(mem_cache) => {
-            let mut cache_read = mem_cache.read();
-            cache_read.get(key).cloned()
+            MemoryStore::new(mem_cache, metrics).get(key, redis_prefix).await
+        }
+        CacheBackend::Redis(_) => match tiered_cache {
+            Some(tc) => tc.get(key, redis_prefix).await,
+            None => None,
+        },
+        CacheBackend::Custom(store) => store.get(key, redis_prefix).await,
+    }
+}
+
+/// What a commit to the memory cache evicted, and its size after.
+#[derive(Default)]
+struct CommitStats {
+    evicted: u64,
+    max: u64,
+    used: u64,
+}
+
+impl ARCacheWriteStat<SearchCacheKey> for CommitStats {
+    fn evict_from_recent(&mut self, _k: &SearchCacheKey) {
+        self.evicted += 1;
+    }
+
+    fn evict_from_frequent(&mut self, _k: &SearchCacheKey) {
+        self.evicted += 1;
+    }
+
+    fn shared_max(&mut self, i: u64) {
+        self.max = i;
+    }
+
+    fn freq(&mut self, i: u64) {
+        self.used += i;
+    }
+
+    fn recent(&mut self, i: u64) {
+        self.used += i;
+    }
+}
+
+impl CommitStats {
+    /// Count the evictions, logging them if they have not been for a while.
+    fn record(&self, metrics: &Metrics) {
+        if self.evicted == 0 {
+            return;
+        }
+        let reported = metrics.record_cache_evictions(self.evicted, CACHE_EVICTION_LOG_INTERVAL);
+        if let Some(evicted) = reported {
+            debug!(
+                evicted,
+                used = self.used,
+                max = self.max,
+                "Memory cache is full, evicting searches to make room"
+            );
+        }
+    }
+}
+
+fn memory_cache_set(
+    mem_cache: &ARCache<SearchCacheKey, CachedValue>,
+    metrics: &Metrics,
+    key: SearchCacheKey,
+    value: CachedValue,
+) {
+    let mut cache_write = mem_cache.write_stats(CommitStats::default());
+    if let Some(cache_value_size) = NonZeroUsize::new(value.size()) {
+        debug!("Updating memory cache with entry of size {}", cache_value_size);
+        let (bind_dn, base) = (key.bind_dn.clone(), key.search.base.clone());
+        cache_write.insert_sized(key, value, cache_value_size);
+        let stats = cache_write.commit();
+        stats.record(metrics);
+        if cache_value_size.get() as u64 > stats.max {
+            error!(
+                size = cache_value_size.get(),
+                size_bytes = stats.max,
+                %bind_dn,
+                %base,
+                "Search result is larger than the whole memory cache and cannot be kept as a fallback"
+            );
+            metrics.record_cache_oversized_entry();
+        }
+    } else {
+        error!("Invalid entry size, unable to add to memory cache");
+    }
+}
+
+async fn cache_set_if_changed(
+    cache: &CacheBackend,
+    metrics: &Metrics,
+    key: SearchCacheKey,
+    value: CachedValue,
+    redis_prefix: &str,
+    ttl: Option<u64>,
+    tiered_cache: &Option<Arc<TieredCache>>,
+) {
+    match cache {
+        CacheBackend::Memory(mem_cache) => {
+            MemoryStore::new(mem_cache, metrics)
+                .set_if_changed(key, value, redis_prefix, ttl)
+                .await
         }
         CacheBackend::Redis(_) => {
             if let Some(tc) = tiered_cache {
-                tc.get(key, redis_prefix).await
-            } else {
-                None
+                tc.set_if_changed(key, value, redis_prefix, ttl).await;
             }
         }
+        CacheBackend::Custom(store) => store.set_if_changed(key, value, redis_prefix, ttl).await,
     }
 }
 
-async fn cache_set_if_changed(
+/// Store `value` under `key` in `cache`. Unlike `cache_set_if_changed`, Redis
+/// is always written so that the ttl of an unchanged entry is refreshed.
+pub async fn cache_set(
     cache: &CacheBackend,
+    metrics: &Metrics,
     key: SearchCacheKey,
     value: CachedValue,
     redis_prefix: &str,
@@ -283,152 +1487,2500 @@ async fn cache_set_if_changed(
 ) {
     match cache {
         CacheBackend::Memory(mem_cache) => {
-            let mut cache_write = mem_cache.write();
-            if let Some(cache_value_size) = NonZeroUsize::new(value.size()) {
-                debug!("Updating memory cache with entry of size {}", cache_value_size);
-                cache_write.insert_sized(key, value, cache_value_size);
-            } else {
-                error!("Invalid entry size, unable to add to memory cache");
-            }
+            MemoryStore::new(mem_cache, metrics)
+                .set(key, value, redis_prefix, ttl)
+                .await
         }
         CacheBackend::Redis(_) => {
             if let Some(tc) = tiered_cache {
-                tc.set_if_changed(key, value, redis_prefix, ttl).await;
+                tc.set(key, value, redis_prefix, ttl).await;
             }
         }
+        CacheBackend::Custom(store) => store.set(key, value, redis_prefix, ttl).await,
     }
 }
 
-async fn cache_try_quiesce(cache: &CacheBackend) {
-    if let CacheBackend::Memory(mem_cache) = cache {
-        mem_cache.try_quiesce();
+/// Remove every cached search whose base is `dn` or one of its ancestors, as
+/// a write to `dn` may have changed their results. Returns how many were
+/// removed, or an error if `dn` is not a valid DN or the cache could not
+/// remove them all, as when Redis is degraded.
+pub async fn cache_invalidate_by_dn(
+    cache: &CacheBackend,
+    metrics: &Metrics,
+    tiered_cache: &Option<Arc<TieredCache>>,
+    dn: &str,
+) -> Result<usize, String> {
+    let dn = dn.parse::<Dn>()?;
+    let removed = match cache {
+        CacheBackend::Memory(mem_cache) => {
+            MemoryStore::new(mem_cache, metrics).invalidate(&dn).await
+        }
+        CacheBackend::Redis(_) => match tiered_cache {
+            Some(tc) => tc.invalidate(&dn).await,
+            None => Ok(0),
+        },
+        CacheBackend::Custom(store) => store.invalidate(&dn).await,
+    }?;
+    debug!(%dn, removed, "Invalidated cached searches");
+    Ok(removed)
+}
+
+async fn cache_try_quiesce(cache: &CacheBackend, metrics: &Metrics) {
+    match cache {
+        CacheBackend::Memory(mem_cache) => {
+            MemoryStore::new(mem_cache, metrics).try_quiesce().await
+        }
+        CacheBackend::Redis(_) => {}
+        CacheBackend::Custom(store) => store.try_quiesce().await,
     }
 }
 
-pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
-    mut r: FramedRead<R, LdapCodec>,
-    mut w: FramedWrite<W, LdapCodec>,
-    client_address: SocketAddr,
-    reported_client_address: Option<SocketAddr>,
-    app_state: Arc<AppState>,
-) {
-    if let Some(reported_client_address) = reported_client_address {
-        info!(?reported_client_address, via = ?client_address, "new client");
-    } else {
-        info!(?client_address, "new client");
+/// A summary of the searches held in a cache.
+pub struct CacheSummary {
+    pub entries: usize,
+    pub bytes: usize,
+    /// The oldest entries, oldest first.
+    pub oldest: Vec<(SearchCacheKey, std::time::SystemTime)>,
+}
+
+impl CacheSummary {
+    fn new<'a>(cached: impl Iterator<Item = (&'a SearchCacheKey, &'a CachedValue)>) -> Self {
+        let mut summary = CacheSummary {
+            entries: 0,
+            bytes: 0,
+            oldest: Vec::new(),
+        };
+        for (key, value) in cached {
+            summary.entries += 1;
+            summary.bytes += value.size();
+            summary.oldest.push((key.clone(), value.cached_at));
+            if summary.oldest.len() > CACHE_SUMMARY_OLDEST * 2 {
+                summary.trim_oldest();
+            }
+        }
+        summary.trim_oldest();
+        summary
+    }
+
+    fn trim_oldest(&mut self) {
+        self.oldest.sort_by_key(|(_, cached_at)| *cached_at);
+        self.oldest.truncate(CACHE_SUMMARY_OLDEST);
+    }
+
+    fn log(&self, cache: &str) {
+        info!(cache, entries = self.entries, bytes = self.bytes, "Cache summary");
+        for (key, cached_at) in &self.oldest {
+            info!(
+                cache,
+                bind_dn = %key.bind_dn,
+                base = %key.search.base,
+                scope = ?key.search.scope,
+                filter = ?key.search.filter,
+                age_secs = cached_at.elapsed().unwrap_or_default().as_secs(),
+                "Oldest cached search"
+            );
+        }
+    }
+}
+
+/// Summarise the memory cache, or the in memory L1 cache in front of Redis.
+pub fn cache_summary(app_state: &AppState) -> Option<CacheSummary> {
+    match (&app_state.cache, &app_state.tiered_cache) {
+        (CacheBackend::Memory(mem_cache), _) => {
+            // Only the write transaction can iterate. It is dropped without
+            // committing.
+            let cache_write = mem_cache.write();
+            Some(CacheSummary::new(cache_write.iter()))
+        }
+        (CacheBackend::Redis(_), Some(tc)) => Some(tc.l1.summary()),
+        (CacheBackend::Redis(_), None) => None,
+        (CacheBackend::Custom(_), _) => None,
+    }
+}
+
+/// Count the cached searches under `key_prefix`, including those of every
+/// tenant. The base indexes share the prefix but are not searches, so they
+/// are left out.
+async fn redis_key_count(
+    conn: &redis::aio::ConnectionManager,
+    key_prefix: &str,
+) -> redis::RedisResult<usize> {
+    let mut conn = conn.clone();
+    let pattern = format!("{}*", key_prefix);
+    let index_prefix = format!("{}index:", key_prefix);
+    let mut keys = conn.scan_match::<_, Vec<u8>>(&pattern).await?;
+    let mut count = 0usize;
+    while let Some(key) = keys.next_item().await {
+        if !key.starts_with(index_prefix.as_bytes()) {
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Record the size of the cache in the metrics.
+pub async fn sample_cache_metrics(app_state: &AppState) {
+    let cache = match &app_state.cache {
+        CacheBackend::Memory(_) => "memory",
+        CacheBackend::Redis(_) => "l1",
+        CacheBackend::Custom(_) => return,
     };
+    if let Some(summary) = cache_summary(app_state) {
+        app_state
+            .metrics
+            .set_cache_size(cache, summary.entries, summary.bytes);
+    }
+    if let Some(tc) = &app_state.tiered_cache {
+        app_state.metrics.set_redis_degraded(tc.is_degraded());
+        // Counted only once Redis works again, rather than warning each time.
+        if tc.is_degraded() {
+            return;
+        }
+    }
+    if let CacheBackend::Redis(conn) = &app_state.cache {
+        match redis_key_count(conn, &app_state.cache_key_prefix).await {
+            Ok(keys) => app_state.metrics.set_redis_keys(keys),
+            Err(e) => warn!(?e, "Unable to count Redis cache keys"),
+        }
+    }
+}
 
-    let mut state = ClientState::Unbound;
-    let redis_prefix = "ldap_proxy:".to_string();
+/// Sample the size of the cache every `interval` rather than on each scrape,
+/// as measuring it walks every entry.
+pub async fn run_cache_metrics(app_state: Arc<AppState>, interval: Duration) {
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        sample_cache_metrics(&app_state).await;
+    }
+}
 
-    // Initialize tiered cache if using Redis backend
-    let tiered_cache = match &app_state.cache {
+/// Log a summary of the cache contents, for diagnosing stale data.
+pub async fn log_cache_summary(app_state: Arc<AppState>) {
+    match &app_state.cache {
+        CacheBackend::Memory(_) => {
+            if let Some(summary) = cache_summary(&app_state) {
+                summary.log("memory");
+            }
+        }
         CacheBackend::Redis(conn) => {
-            // L1 cache size: 1000 entries (adjust as needed)
-            Some(Arc::new(TieredCache::new(conn.clone(), 1000)))
+            if let Some(summary) = cache_summary(&app_state) {
+                summary.log("l1");
+            }
+            let prefix = app_state.cache_key_prefix.as_str();
+            match redis_key_count(conn, prefix).await {
+                Ok(keys) => info!(prefix, keys, "Redis cache summary"),
+                Err(e) => warn!(?e, "Unable to count Redis cache keys"),
+            }
         }
-        _ => None,
-    };
+        CacheBackend::Custom(_) => info!("No cache summary is available for a custom cache store"),
+    }
+}
 
-    while let Some(Ok(protomsg)) = r.next().await {
-        let next_state = match (&mut state, protomsg) {
-            (
-                _,
-                LdapMsg {
-                    msgid,
-                    op: LdapOp::BindRequest(lbr),
-                    ctrl,
-                },
-            ) => {
-                let span = span!(Level::INFO, "bind");
-                let _enter = span.enter();
+/// Quiesce the memory cache or a custom store every `interval`,
+/// independently of traffic, so that an idle proxy still applies pending
+/// hits and evictions.
+pub async fn run_cache_quiesce(app_state: Arc<AppState>, interval: Duration) {
+    if matches!(app_state.cache, CacheBackend::Redis(_)) {
+        return;
+    }
 
-                trace!(?lbr);
-                let config = match app_state.binddn_map.get(&lbr.dn) {
-                    Some(dnconfig) => dnconfig.clone(),
-                    None => {
-                        if app_state.allow_all_bind_dns {
-                            DnConfig::default()
-                        } else {
-                            let resp_msg = bind_operror(msgid, "unable to bind");
-                            if w.send(resp_msg).await.is_err() {
-                                error!("Unable to send response");
-                                break;
-                            }
-                            continue;
-                        }
-                    }
-                };
+    let mut interval = tokio::time::interval(interval);
+    loop {
+        interval.tick().await;
+        cache_try_quiesce(&app_state.cache, &app_state.metrics).await;
+    }
+}
 
-                let dn = lbr.dn.clone();
+/// The Redis prefix of the searches made on a listener of `tenant`, under
+/// `key_prefix`.
+fn redis_tenant_prefix(key_prefix: &str, tenant: Option<&str>) -> String {
+    match tenant {
+        Some(tenant) => format!("{}tenant:{}:", key_prefix, tenant),
+        None => key_prefix.to_string(),
+    }
+}
 
-                let mut client = match BasicLdapClient::build(
-                    &app_state.addrs,
-                    &app_state.tls_params,
-                    app_state.max_proxy_ber_size,
-                )
-                .await
-                {
-                    Ok(c) => c,
-                    Err(e) => {
-                        error!(?e, "A client build error has occurred.");
-                        let resp_msg = bind_operror(msgid, "unable to bind");
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                        }
-                        break;
-                    }
-                };
+/// Execute the configured warm-up queries against the backend and store the
+/// results in the fallback cache, for listeners without a tenant and for each
+/// of `tenants`. The backend is retried until it becomes reachable so this
+/// should be spawned rather than awaited during startup.
+pub async fn warm_cache(
+    app_state: Arc<AppState>,
+    warm_config: CacheWarmConfig,
+    tenants: BTreeSet<String>,
+) {
+    let span = span!(Level::INFO, "cache_warm");
+    let _enter = span.enter();
+
+    if warm_config.queries.is_empty() {
+        return;
+    }
 
-                let valid = match client.bind(lbr, ctrl).await {
-                    Ok((bind_resp, ctrl)) => {
-                        let valid = bind_resp.res.code == LdapResultCode::Success;
+    let tiered_cache = &app_state.tiered_cache;
+    let tenants: Vec<Option<String>> = std::iter::once(None)
+        .chain(tenants.into_iter().map(Some))
+        .collect();
 
-                        let resp_msg = LdapMsg {
-                            msgid,
-                            op: LdapOp::BindResponse(bind_resp),
-                            ctrl,
-                        };
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                            break;
-                        }
-                        valid
-                    }
-                    Err(e) => {
-                        error!(?e, "A client bind error has occurred");
-                        let resp_msg = bind_operror(msgid, "unable to bind");
-                        if w.send(resp_msg).await.is_err() {
-                            error!("Unable to send response");
-                        }
-                        break;
-                    }
-                };
+    // Group the queries so each DN only binds once.
+    let mut queries_by_dn: BTreeMap<&str, Vec<LdapSearchRequest>> = BTreeMap::new();
+    for (bind_dn, base, scope, filter) in warm_config.queries.iter() {
+        queries_by_dn
+            .entry(bind_dn.as_str())
+            .or_default()
+            .push(LdapSearchRequest {
+                base: base.clone(),
+                scope: scope.clone(),
+                aliases: LdapDerefAliases::Never,
+                sizelimit: 0,
+                timelimit: 0,
+                typesonly: false,
+                filter: filter.inner.clone(),
+                attrs: vec![],
+            });
+    }
 
-                if valid {
-                    info!("Successful bind for {}", dn);
-                    Some(ClientState::Authenticated { dn, config, client })
-                } else {
-                    None
+    let total = warm_config.queries.len();
+    let mut succeeded = 0;
+
+    for (bind_dn, searches) in queries_by_dn {
+        let backend = app_state.backend_for(bind_dn);
+        let mut client = loop {
+            match BasicLdapClient::build(
+                &backend.addrs(),
+                backend.tls_params,
+                backend.tls_server_name,
+                app_state.max_proxy_ber_size,
+                &app_state.connect_timeouts,
+            )
+            .await
+            {
+                Ok(c) => break c,
+                Err(e) => {
+                    warn!(?e, "Backend is unreachable, retrying cache warm-up shortly");
+                    tokio::time::sleep(Duration::from_secs(5)).await;
                 }
             }
-            (
-                _,
-                LdapMsg {
-                    msgid: _,
-                    op: LdapOp::UnbindRequest,
-                    ctrl: _,
-                },
-            ) => {
-                trace!("unbind");
-                break;
-            }
+        };
+
+        let password = warm_config
+            .credentials
+            .get(bind_dn)
+            .cloned()
+            .unwrap_or_default();
+        let lbr = LdapBindRequest {
+            dn: rewrite_dn(&app_state.dn_rewrite, bind_dn),
+            cred: LdapBindCred::Simple(password),
+        };
+
+        match client.bind(lbr, vec![], app_state.bind_timeout).await {
+            Ok((bind_resp, ..)) if bind_resp.res.code == LdapResultCode::Success => {}
+            Ok((bind_resp, ..)) => {
+                error!(code = ?bind_resp.res.code, "Unable to bind as {} for cache warm-up", bind_dn);
+                continue;
+            }
+            Err(e) => {
+                error!(?e, "Unable to bind as {} for cache warm-up", bind_dn);
+                continue;
+            }
+        }
+
+        // Partition as client_process does for a client binding as this dn.
+        let normalized_dn = normalize_dn(bind_dn).unwrap_or_else(|_| bind_dn.to_string());
+        let cache_partition = app_state
+            .dn_config(&normalized_dn)
+            .map(|dnconfig| dnconfig.cache_partition(&normalized_dn))
+            .unwrap_or(normalized_dn);
+
+        for search in searches {
+            let cache_key = SearchCacheKey {
+                tenant: None,
+                bind_dn: cache_partition.clone(),
+                search: search.clone(),
+                ctrl: vec![],
+            };
+            let cache_key = if app_state.cache_key_ignore_limits {
+                cache_key.without_limits()
+            } else {
+                cache_key
+            };
+
+            let search = LdapSearchRequest {
+                base: rewrite_dn(&app_state.dn_rewrite, &search.base),
+                ..search
+            };
+
+            // Held to the same checks as a client's search, so that a partial
+            // result is never served as complete during an outage.
+            match client.search_buffered(search, vec![], None).await {
+                Ok(SearchBuffer::Complete { result, .. }) if is_truncated(&result.code) => {
+                    warn!(code = ?result.code, ?cache_key, "Cache warm-up query was truncated by the backend");
+                }
+                Ok(SearchBuffer::Complete { references, .. }) if !references.is_empty() => {
+                    warn!(
+                        references = references.len(),
+                        ?cache_key,
+                        "Cache warm-up query returned continuation references"
+                    );
+                }
+                Ok(SearchBuffer::Complete { result, .. })
+                    if !app_state.cacheable_result_codes.contains(&result.code) =>
+                {
+                    warn!(code = ?result.code, ?cache_key, "Cache warm-up query returned a non-cacheable result");
+                }
+                Ok(SearchBuffer::Complete {
+                    entries,
+                    result,
+                    ctrl,
+                    ..
+                }) => {
+                    let mut cache_value = CachedValue {
+                        cached_at: std::time::SystemTime::now(),
+                        entries,
+                        result,
+                        ctrl,
+                        source_addr: Some(client.peer_addr()),
+                    };
+                    if app_state.sort_cached_entries {
+                        cache_value.sort_entries();
+                    }
+                    for tenant in &tenants {
+                        cache_set_if_changed(
+                            &app_state.cache,
+                            &app_state.metrics,
+                            cache_key.clone().with_tenant(tenant.clone()),
+                            cache_value.clone(),
+                            &redis_tenant_prefix(&app_state.cache_key_prefix, tenant.as_deref()),
+                            app_state.cache_ttl,
+                            tiered_cache,
+                        )
+                        .await;
+                    }
+                    succeeded += 1;
+                }
+                // Not buffered with a limit, so never spilled.
+                Ok(SearchBuffer::Spilled { .. }) => {}
+                Err(e) => {
+                    error!(?e, ?cache_key, "Cache warm-up query failed");
+                }
+            }
+        }
+    }
+
+    cache_try_quiesce(&app_state.cache, &app_state.metrics).await;
+
+    info!("Cache warm-up complete, {} of {} queries succeeded", succeeded, total);
+}
+
+/// The per-session state shared by every search a client has outstanding.
+struct SearchContext<'a, W> {
+    app_state: &'a AppState,
+    w: &'a tokio::sync::Mutex<FramedWrite<W, ClientCodec>>,
+    redis_prefix: &'a str,
+    tiered_cache: &'a Option<Arc<TieredCache>>,
+    tenant: Option<&'a str>,
+    /// The searches of the session answered from the cache.
+    cache_hits: &'a AtomicU64,
+}
+
+impl<W> Clone for SearchContext<'_, W> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<W> Copy for SearchContext<'_, W> {}
+
+/// Handle a search from a bound client on `client`, falling back to the cache
+/// when the backend fails, or answering only from the cache for a session
+/// bound offline without one. If the connection has dropped, `backend_bind`
+/// is set and `reconnect_backend` is enabled, `client` is replaced by a new
+/// connection bound with it and the search is retried once. Returns the
+/// reason if the session should end.
+#[allow(clippy::too_many_arguments)]
+async fn process_search<W: AsyncWrite + Unpin>(
+    ctx: SearchContext<'_, W>,
+    dn: &str,
+    config: &DnConfig,
+    client: Option<&mut BasicLdapClient>,
+    backend_bind: Option<&(LdapBindRequest, Vec<LdapControl>)>,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+) -> Result<(), DisconnectReason> {
+    let SearchContext {
+        app_state,
+        w,
+        redis_prefix,
+        tiered_cache,
+        tenant,
+        cache_hits,
+    } = ctx;
+
+    if !config.is_scope_allowed(&sr.scope) {
+        warn!(scope = ?sr.scope, "Search scope is not allowed for {}", dn);
+        telemetry::record_result(&LdapResultCode::UnwillingToPerform);
+        let resp_msg = LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code: LdapResultCode::UnwillingToPerform,
+                matcheddn: "".to_string(),
+                message: "search scope is not allowed".to_string(),
+                referral: vec![],
+            }),
+            ctrl: vec![],
+        };
+        if w.lock().await.send(resp_msg).await.is_err() {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+        return Ok(());
+    }
+
+    let allowed = config.is_search_allowed(&sr.base, &sr.scope, &sr.filter);
+    if allowed {
+        debug!(filter = %filter_to_string(&sr.filter), "Query is granted");
+    } else {
+        warn!(
+            base = %sr.base,
+            scope = ?sr.scope,
+            filter = %filter_to_string(&sr.filter),
+            "Requested query is not allowed for {}",
+            dn
+        );
+    }
+
+    if !allowed {
+        let (code, message) = match app_state.denied_query_action {
+            DeniedQueryAction::EmptySuccess => (LdapResultCode::Success, ""),
+            DeniedQueryAction::AccessDenied | DeniedQueryAction::Disconnect => (
+                LdapResultCode::InsufficentAccessRights,
+                "requested query is not allowed",
+            ),
+        };
+        telemetry::record_result(&code);
+        if w.lock().await.send(LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultDone(LdapResult {
+                code,
+                matcheddn: "".to_string(),
+                message: message.to_string(),
+                referral: vec![],
+            }),
+            ctrl,
+        })
+        .await
+        .is_err()
+        {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+        if app_state.denied_query_action == DeniedQueryAction::Disconnect {
+            send_disconnect_notice(
+                &mut *w.lock().await,
+                LdapResultCode::InsufficentAccessRights,
+                "requested query is not allowed",
+            )
+            .await;
+            return Err(DisconnectReason::QueryDenied);
+        }
+        return Ok(());
+    }
+
+    if let Some(root_dse) = &app_state.root_dse {
+        if is_root_dse_search(&sr) {
+            debug!("Serving synthetic root dse");
+            let entry = root_dse_entry(root_dse, &sr.attrs);
+            return send_synthetic_entry(&mut *w.lock().await, msgid, entry).await;
+        }
+    }
+
+    if let Some(entry) = app_state.schema.as_ref().and_then(|schema| schema.search(&sr)) {
+        debug!("Serving schema snapshot");
+        return send_synthetic_entry(&mut *w.lock().await, msgid, entry).await;
+    }
+
+    // Projected before the cache key is built, so the trimmed result is what
+    // gets cached.
+    let root_dse = is_root_dse_search(&sr);
+    let sr = if root_dse {
+        sr
+    } else {
+        LdapSearchRequest {
+            attrs: config.project_attributes(sr.attrs),
+            ..sr
+        }
+    };
+
+    if is_sync_search(&ctrl) {
+        debug!("Relaying a sync search without caching");
+        return relay_sync_search(app_state, w, client, msgid, sr, ctrl).await;
+    }
+
+    // Searches of the same base, scope and filter then share the cache entry
+    // holding every attribute, and each is answered with those it named.
+    let (sr, selected_attrs) = if app_state.cache_attribute_superset
+        && config.default_attributes.is_none()
+        && is_superset_selectable(&sr.attrs)
+    {
+        let superset = LdapSearchRequest {
+            attrs: ATTRIBUTE_SUPERSET.iter().map(|a| a.to_string()).collect(),
+            ..sr
+        };
+        (superset, Some(sr.attrs))
+    } else {
+        (sr, None)
+    };
+    // A cached result may also answer searches with a larger size limit.
+    let sizelimit = usize::try_from(sr.sizelimit).unwrap_or(0);
+    let select = |mut entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+                  mut result: LdapResult| {
+        if let Some(attrs) = &selected_attrs {
+            for (entry, _) in entries.iter_mut() {
+                select_attributes(entry, attrs);
+            }
+        }
+        // Filtered as it is sent rather than cached, so a changed allowlist
+        // applies to cached root dse entries too.
+        if let (true, Some(allowed)) = (root_dse, &app_state.supported_controls) {
+            for (entry, _) in entries.iter_mut() {
+                filter_supported_controls(entry, allowed);
+            }
+        }
+        if sizelimit > 0 && entries.len() > sizelimit {
+            entries.truncate(sizelimit);
+            result.code = LdapResultCode::SizeLimitExceeded;
+        }
+        (entries, result)
+    };
+
+    let cache_key = SearchCacheKey {
+        tenant: tenant.map(str::to_string),
+        bind_dn: config.cache_partition(dn),
+        search: sr.clone(),
+        ctrl: ctrl.clone(),
+    };
+    let cache_key = if app_state.cache_key_ignore_limits {
+        cache_key.without_limits()
+    } else {
+        cache_key
+    };
+    debug!(?cache_key);
+    // The backend's order is what the client asked for, so it is kept.
+    let server_sorted = ctrl
+        .iter()
+        .any(|ctrl| matches!(ctrl, LdapControl::ServerSort { .. }));
+
+    if app_state.cache_mode == CacheMode::ReadThrough && !config.disable_cache {
+        let cached_value = cache_get(
+            &app_state.cache,
+            &app_state.metrics,
+            &cache_key,
+            redis_prefix,
+            tiered_cache,
+        )
+        .await;
+        if let Some(cached_value) = cached_value {
+            let age = cached_value.age_secs();
+            if age < app_state.read_through_max_age.as_secs() {
+                debug!(age, "Serving fresh cache entry without querying the backend");
+                telemetry::record_cache("hit");
+                cache_hits.fetch_add(1, Ordering::Relaxed);
+                let (entries, result) = select(cached_value.entries, cached_value.result);
+                return send_search_result(
+                    app_state,
+                    w,
+                    msgid,
+                    entries,
+                    result,
+                    cached_value.ctrl,
+                    Some(age),
+                )
+                .await;
+            }
+            debug!(age, "Cache entry is stale, querying the backend");
+        }
+    }
+
+    if app_state.in_maintenance() {
+        let cached_value = if config.disable_cache {
+            None
+        } else {
+            cache_get(
+                &app_state.cache,
+                &app_state.metrics,
+                &cache_key,
+                redis_prefix,
+                tiered_cache,
+            )
+            .await
+        };
+        let (entries, result, ctrl, cache_age) = match within_fallback_age(config, cached_value) {
+            Some(cached_value) => {
+                cache_hits.fetch_add(1, Ordering::Relaxed);
+                fallback_response(app_state, cached_value)
+            }
+            None => {
+                warn!("In maintenance mode and no cached data is available");
+                telemetry::record_cache("miss");
+                let result = LdapResult {
+                    code: LdapResultCode::Unavailable,
+                    matcheddn: "".to_string(),
+                    message: "The proxy is in maintenance and has no cached data".to_string(),
+                    referral: vec![],
+                };
+                (Vec::new(), result, Vec::new(), None)
+            }
+        };
+        let (entries, result) = select(entries, result);
+        return send_search_result(app_state, w, msgid, entries, result, ctrl, cache_age).await;
+    }
+
+    let Some(client) = client else {
+        debug!("Session was bound offline, answering from the fallback cache");
+        return match unreachable_response(ctx, config, &cache_key, msgid).await {
+            Some((entries, result, ctrl, cache_age)) => {
+                if cache_age.is_some() {
+                    cache_hits.fetch_add(1, Ordering::Relaxed);
+                }
+                let (entries, result) = select(entries, result);
+                send_search_result(app_state, w, msgid, entries, result, ctrl, cache_age).await
+            }
+            None => Err(DisconnectReason::BackendUnavailable),
+        };
+    };
+
+    let sr = LdapSearchRequest {
+        base: rewrite_dn(&app_state.dn_rewrite, &sr.base),
+        ..sr
+    };
+
+    let breaker_open = app_state
+        .breaker
+        .as_ref()
+        .is_some_and(|breaker| breaker.is_open());
+
+    let in_flight = match &app_state.in_flight_searches {
+        Some(searches) if !breaker_open && !config.disable_cache => Some(searches.join(&cache_key)),
+        _ => None,
+    };
+    let mut in_flight = match in_flight {
+        Some(InFlight::Waiter(mut rx)) => {
+            debug!("Waiting for an identical search in flight");
+            // Ok only once the first search hands over its result.
+            let shared = match rx.wait_for(Option::is_some).await {
+                Ok(shared) => shared.clone(),
+                Err(_) => None,
+            };
+            if let Some(shared) = shared {
+                debug!("Answering with the result of the identical search");
+                telemetry::record_cache("coalesced");
+                let (entries, result) = select(shared.entries.clone(), shared.result.clone());
+                return send_search_result(
+                    app_state,
+                    w,
+                    msgid,
+                    entries,
+                    result,
+                    shared.ctrl.clone(),
+                    None,
+                )
+                .await;
+            }
+            debug!("Identical search ended without a result, querying the backend");
+            None
+        }
+        Some(InFlight::Leader(guard)) => Some(*guard),
+        None => None,
+    };
+
+    let chase = app_state.chase_referrals.then(|| sr.clone());
+    // Set once the search has been answered from the cache while the
+    // backend was slow, leaving the backend's result only to refresh it.
+    let mut answered = None;
+    // Once the cooldown has passed this lets a probe through, whose outcome
+    // closes or reopens the breaker.
+    let allowed = app_state
+        .breaker
+        .as_ref()
+        .is_none_or(|breaker| breaker.allow());
+    let mut search = if !allowed {
+        debug!("Circuit breaker is open, skipping the backend");
+        Err(LdapError::CircuitOpen)
+    } else {
+        let started = Instant::now();
+        let filter = app_state.slow_op_threshold.map(|_| sr.filter.clone());
+        let retry = backend_bind
+            .filter(|_| app_state.reconnect_backend)
+            .map(|_| (sr.clone(), ctrl.clone()));
+        let search = async {
+            let search = client
+                .search_buffered(sr, ctrl, app_state.max_buffered_entries)
+                .await;
+            match (search, retry) {
+                (Err(LdapError::Transport), Some((sr, ctrl))) => {
+                    warn!("Backend connection lost, reconnecting");
+                    match open_backend_connection(app_state, dn, backend_bind, None).await {
+                        Some(new_client) => {
+                            *client = new_client;
+                            client
+                                .search_buffered(sr, ctrl, app_state.max_buffered_entries)
+                                .await
+                        }
+                        None => Err(LdapError::Transport),
+                    }
+                }
+                (search, _) => search,
+            }
+        };
+        // Boxed so that it can be dropped to abandon the search.
+        let mut search = Box::pin(search);
+        let race = app_state
+            .cache_race_timeout
+            .filter(|_| !config.disable_cache);
+        let mut raced = None;
+        if let Some(race) = race {
+            match tokio::time::timeout(race, &mut search).await {
+                Ok(search) => raced = Some(search),
+                Err(_) => {
+                    let cached_value = cache_get(
+                        &app_state.cache,
+                        &app_state.metrics,
+                        &cache_key,
+                        redis_prefix,
+                        tiered_cache,
+                    )
+                    .await;
+                    if let Some(cached_value) = within_fallback_age(config, cached_value) {
+                        debug!(
+                            race_ms = race.as_millis() as u64,
+                            "Backend is slow, answering from the cache while the search completes"
+                        );
+                        let (entries, result, ctrl, cache_age) =
+                            fallback_response(app_state, cached_value);
+                        cache_hits.fetch_add(1, Ordering::Relaxed);
+                        let (entries, result) = select(entries, result);
+                        answered = Some(
+                            send_search_result(
+                                app_state, w, msgid, entries, result, ctrl, cache_age,
+                            )
+                            .await,
+                        );
+                    }
+                }
+            }
+        }
+        let search = match (raced, config.search_deadline_ms) {
+            (Some(search), _) => search,
+            (None, Some(deadline)) => {
+                let deadline = Duration::from_millis(deadline.get());
+                let remaining = deadline.saturating_sub(started.elapsed());
+                match tokio::time::timeout(remaining, &mut search).await {
+                    Ok(search) => search,
+                    Err(_) => {
+                        drop(search);
+                        warn!(
+                            deadline_ms = deadline.as_millis() as u64,
+                            "Backend missed the search deadline, abandoning the search"
+                        );
+                        if let Err(e) = client.abandon_last().await {
+                            warn!(?e, "Unable to abandon the backend search");
+                        }
+                        Err(LdapError::Timeout)
+                    }
+                }
+            }
+            (None, None) => search.await,
+        };
+        observe_backend_latency(app_state, BackendOp::Search, dn, filter.as_ref(), started);
+        // An oversized message says nothing about the backend's health.
+        record_backend_outcome(
+            app_state,
+            matches!(search, Ok(_) | Err(LdapError::MessageTooLarge)),
+        );
+        search
+    };
+    if let Ok(SearchBuffer::Complete { result, .. }) = &search {
+        app_state
+            .metrics
+            .record_backend_result(BackendOp::Search, &result.code);
+    }
+    let references = match &mut search {
+        Ok(SearchBuffer::Complete {
+            entries,
+            references,
+            ..
+        }) => match &chase {
+            Some(sr) if !references.is_empty() => {
+                let references = std::mem::take(references);
+                chase_references(app_state, backend_bind, sr, entries, references).await
+            }
+            _ => std::mem::take(references),
+        },
+        _ => Vec::new(),
+    };
+    match (answered, &search) {
+        (None, _) | (Some(_), Ok(SearchBuffer::Complete { .. })) => {}
+        (Some(answered), Ok(SearchBuffer::Spilled { msgid, .. })) => {
+            // Too large to cache, but the rest still has to be read off the
+            // connection before it can be used again.
+            let backend_msgid = *msgid;
+            loop {
+                match client.next_search_item(backend_msgid).await {
+                    Ok(SearchItem::Done(..)) => break,
+                    Ok(_) => {}
+                    Err(e) => {
+                        warn!(?e, "Backend failed a search already answered from the cache");
+                        break;
+                    }
+                }
+            }
+            return answered;
+        }
+        (Some(answered), Err(e)) => {
+            warn!(?e, "Backend failed a search already answered from the cache");
+            return answered;
+        }
+    }
+    let (entries, result, ctrl, cache_age) = match search {
+        Ok(SearchBuffer::Spilled {
+            msgid: backend_msgid,
+            entries,
+            references,
+        }) => {
+            warn!(
+                "Search exceeded max_buffered_entries, streaming results without caching"
+            );
+            telemetry::record_cache("miss");
+            let mut w = w.lock().await;
+            send_search_references(app_state, &mut w, msgid, references).await?;
+            return stream_spilled_search(
+                app_state,
+                &mut w,
+                client,
+                msgid,
+                backend_msgid,
+                entries,
+                selected_attrs.as_deref(),
+            )
+            .await;
+        }
+        Ok(SearchBuffer::Complete {
+            entries,
+            result,
+            ctrl,
+            ..
+        }) if !config.disable_cache
+            && app_state.degraded_result_codes.contains(&result.code) =>
+        {
+            warn!(code = ?result.code, "Backend is degraded, attempting to use fallback cache");
+
+            let cached_value = cache_get(
+                &app_state.cache,
+                &app_state.metrics,
+                &cache_key,
+                redis_prefix,
+                tiered_cache,
+            )
+            .await;
+            match within_fallback_age(config, cached_value) {
+                Some(cached_value) => fallback_response(app_state, cached_value),
+                None => {
+                    warn!("No fallback data available, relaying backend result");
+                    telemetry::record_cache("miss");
+                    (entries, result, ctrl, None)
+                }
+            }
+        }
+        Ok(SearchBuffer::Complete {
+            entries,
+            result,
+            ctrl,
+            ..
+        }) => {
+            telemetry::record_cache("miss");
+            if config.disable_cache {
+                debug!("Fallback cache is disabled for this dn");
+            } else if is_truncated(&result.code) {
+                // Serving this during an outage would pass a partial result
+                // off as complete, so it is never cached.
+                warn!(
+                    code = ?result.code,
+                    entries = entries.len(),
+                    "Backend truncated the search result, relaying it without caching"
+                );
+            } else if !references.is_empty() {
+                // Without the entries the references lead to, the result is
+                // incomplete in the same way.
+                debug!(
+                    references = references.len(),
+                    "Search returned continuation references, relaying it without caching"
+                );
+            } else if app_state.cacheable_result_codes.contains(&result.code) {
+                info!("Backend is reachable, updating fallback cache");
+                // The cache and the client each need their own copy.
+                let mut cache_value = CachedValue {
+                    cached_at: std::time::SystemTime::now(),
+                    entries: entries.clone(),
+                    result: result.clone(),
+                    ctrl: ctrl.clone(),
+                    source_addr: Some(client.peer_addr()),
+                };
+                if app_state.sort_cached_entries && !server_sorted {
+                    cache_value.sort_entries();
+                }
+                if let Some(in_flight) = in_flight.take() {
+                    in_flight.complete(Arc::new(cache_value.clone()));
+                }
+                if app_state.cache_mode == CacheMode::WriteThrough {
+                    cache_set(
+                        &app_state.cache,
+                        &app_state.metrics,
+                        cache_key,
+                        cache_value,
+                        redis_prefix,
+                        app_state.cache_ttl,
+                        tiered_cache,
+                    )
+                    .await;
+                } else {
+                    cache_set_if_changed(
+                        &app_state.cache,
+                        &app_state.metrics,
+                        cache_key,
+                        cache_value,
+                        redis_prefix,
+                        app_state.cache_ttl,
+                        tiered_cache,
+                    )
+                    .await;
+                }
+            } else {
+                debug!(code = ?result.code, "Result code is not cacheable, skipping fallback cache update");
+            }
+
+            (entries, result, ctrl, None)
+        }
+        // Only `search_deadline_ms` times out a search.
+        Err(LdapError::Timeout) => {
+            let cached_value = if config.disable_cache {
+                None
+            } else {
+                cache_get(
+                    &app_state.cache,
+                    &app_state.metrics,
+                    &cache_key,
+                    redis_prefix,
+                    tiered_cache,
+                )
+                .await
+            };
+            match within_fallback_age(config, cached_value) {
+                Some(cached_value) => fallback_response(app_state, cached_value),
+                None => {
+                    warn!("No fallback data available for a search past its deadline");
+                    telemetry::record_cache("miss");
+                    let result = LdapResult {
+                        code: LdapResultCode::TimeLimitExceeded,
+                        matcheddn: "".to_string(),
+                        message: "Backend did not answer within the search deadline"
+                            .to_string(),
+                        referral: vec![],
+                    };
+                    (Vec::new(), result, Vec::new(), None)
+                }
+            }
+        }
+        Err(LdapError::MessageTooLarge) => {
+            warn!("Search exceeded max_proxy_ber_size");
+            let result = LdapResult {
+                code: LdapResultCode::AdminLimitExceeded,
+                matcheddn: "".to_string(),
+                message: "Message to or from the backend exceeds the proxy's max ber size"
+                    .to_string(),
+                referral: vec![],
+            };
+            (Vec::new(), result, Vec::new(), None)
+        }
+        Err(e) => {
+            warn!(?e, "Backend is unreachable");
+            match unreachable_response(ctx, config, &cache_key, msgid).await {
+                Some(response) => response,
+                None => return Err(DisconnectReason::BackendUnavailable),
+            }
+        }
+    };
+
+    if let Some(answered) = answered {
+        telemetry::record_cache("raced");
+        return answered;
+    }
+    if cache_age.is_some() {
+        cache_hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+        send_search_references(app_state, &mut *w.lock().await, msgid, references).await?;
+    }
+    let (entries, result) = select(entries, result);
+    send_search_result(app_state, w, msgid, entries, result, ctrl, cache_age).await
+}
+
+/// The entries, result, result controls and, when served from the cache,
+/// cache age of a search response.
+type SearchResponse = (
+    Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    LdapResult,
+    Vec<LdapControl>,
+    Option<u64>,
+);
+
+/// Whether `code` ends a search the backend cut short, leaving out entries
+/// that match.
+fn is_truncated(code: &LdapResultCode) -> bool {
+    matches!(
+        code,
+        LdapResultCode::SizeLimitExceeded | LdapResultCode::TimeLimitExceeded
+    )
+}
+
+/// The response to a search the backend cannot answer, from the fallback
+/// cache if it holds one. Returns None, having told the client, if the
+/// session should end.
+async fn unreachable_response<W: AsyncWrite + Unpin>(
+    ctx: SearchContext<'_, W>,
+    config: &DnConfig,
+    cache_key: &SearchCacheKey,
+    msgid: i32,
+) -> Option<SearchResponse> {
+    let SearchContext {
+        app_state,
+        w,
+        redis_prefix,
+        tiered_cache,
+        ..
+    } = ctx;
+
+    let cached_value = if config.disable_cache {
+        warn!("Fallback cache is disabled for this dn");
+        None
+    } else {
+        warn!("Attempting to use fallback cache");
+        cache_get(
+            &app_state.cache,
+            &app_state.metrics,
+            cache_key,
+            redis_prefix,
+            tiered_cache,
+        )
+        .await
+    };
+
+    match within_fallback_age(config, cached_value) {
+        Some(cached_value) => Some(fallback_response(app_state, cached_value)),
+        None if app_state.no_fallback_action == NoFallbackAction::EmptySuccess => {
+            error!("Backend unreachable and no fallback data available, returning no entries");
+            let result = LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            };
+            Some((Vec::new(), result, Vec::new(), None))
+        }
+        None => {
+            error!("Backend unreachable and no fallback data available");
+            telemetry::record_result(&LdapResultCode::Unavailable);
+            let resp_msg = LdapMsg {
+                msgid,
+                op: LdapOp::SearchResultDone(LdapResult {
+                    code: LdapResultCode::Unavailable,
+                    matcheddn: "".to_string(),
+                    message: "Backend LDAP server unavailable and no cached data".to_string(),
+                    referral: vec![],
+                }),
+                ctrl: vec![],
+            };
+            if w.lock().await.send(resp_msg).await.is_err() {
+                error!("Unable to send response");
+            }
+            send_disconnect_notice(
+                &mut *w.lock().await,
+                LdapResultCode::Unavailable,
+                "backend ldap server unavailable",
+            )
+            .await;
+            None
+        }
+    }
+}
+
+/// The fallback cache entry, unless it is too old to serve to this dn.
+fn within_fallback_age(
+    config: &DnConfig,
+    cached_value: Option<CachedValue>,
+) -> Option<CachedValue> {
+    let cached_value = cached_value?;
+    match config.max_fallback_age_secs {
+        Some(max_age) if cached_value.age_secs() > max_age => {
+            warn!(
+                age = cached_value.age_secs(),
+                max_age, "Fallback cache entry is too old to serve"
+            );
+            None
+        }
+        _ => Some(cached_value),
+    }
+}
+
+/// The response to serve from a fallback cache entry.
+fn fallback_response(app_state: &AppState, cached_value: CachedValue) -> SearchResponse {
+    info!(source_addr = ?cached_value.source_addr, "Serving from fallback cache (cached at: {:?})", cached_value.cached_at);
+    telemetry::record_cache("fallback");
+    let age = cached_value.age_secs();
+    let mut result = cached_value.result;
+    if app_state.annotate_cached_message {
+        result.message = cached_result_message(&result.message, age);
+    }
+    if let Some(code) = &app_state.fallback_result_code {
+        result.code = code.clone();
+    }
+    if let Some(marker) = &app_state.fallback_result_marker {
+        result.message = if result.message.is_empty() {
+            format!("{} age={}", marker, age)
+        } else {
+            format!("{} age={}: {}", marker, age, result.message)
+        };
+    }
+    (cached_value.entries, result, cached_value.ctrl, Some(age))
+}
+
+/// The diagnostic message of a result served from the fallback cache, noting
+/// its age ahead of the backend's original message.
+fn cached_result_message(message: &str, age: u64) -> String {
+    if message.is_empty() {
+        format!("Served from the proxy's fallback cache, {}s old", age)
+    } else {
+        format!(
+            "Served from the proxy's fallback cache, {}s old: {}",
+            age, message
+        )
+    }
+}
+
+/// Send the entries and final result of a search, annotated with their age if
+/// they came from the cache. Returns the reason if the session should end.
+async fn send_search_result<W: AsyncWrite + Unpin>(
+    app_state: &AppState,
+    w: &tokio::sync::Mutex<FramedWrite<W, ClientCodec>>,
+    msgid: i32,
+    entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    mut result: LdapResult,
+    ctrl: Vec<LdapControl>,
+    cache_age: Option<u64>,
+) -> Result<(), DisconnectReason> {
+    let cache_age_control = app_state
+        .cache_age_control_oid
+        .as_deref()
+        .zip(cache_age);
+
+    for (sent, (entry, ctrl)) in (1..).zip(entries) {
+        let msg = LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultEntry(entry),
+            ctrl,
+        };
+        let flush = flush_entry(app_state, sent);
+        if send_search_response(&mut *w.lock().await, msg, cache_age_control, flush)
+            .await
+            .is_err()
+        {
+            error!("Unable to send response");
+            return Err(DisconnectReason::WriteFailed);
+        }
+    }
+
+    telemetry::record_result(&result.code);
+    app_state.rewrite_referrals(&mut result.referral);
+    let msg = LdapMsg {
+        msgid,
+        op: LdapOp::SearchResultDone(result),
+        ctrl,
+    };
+    if send_search_response(&mut *w.lock().await, msg, cache_age_control, true)
+        .await
+        .is_err()
+    {
+        error!("Unable to send response");
+        return Err(DisconnectReason::WriteFailed);
+    }
+
+    Ok(())
+}
+
+/// A concurrent search that has finished with its backend connection.
+struct CompletedSearch {
+    msgid: i32,
+    /// None when the connection failed to abandon the search on the backend.
+    client: Option<BasicLdapClient>,
+    outcome: Result<(), DisconnectReason>,
+}
+
+/// Run a search on a connection owned by the operation, handing the
+/// connection back when it completes. Once `abandoned` is cancelled the search
+/// is abandoned on the backend too, and whatever the backend still sends for
+/// it is skipped as a late response, so the connection can be reused.
+#[allow(clippy::too_many_arguments)]
+async fn run_concurrent_search<W: AsyncWrite + Unpin>(
+    ctx: SearchContext<'_, W>,
+    dn: String,
+    config: Box<DnConfig>,
+    mut client: BasicLdapClient,
+    backend_bind: Option<Box<(LdapBindRequest, Vec<LdapControl>)>>,
+    msgid: i32,
+    sr: LdapSearchRequest,
+    ctrl: Vec<LdapControl>,
+    abandoned: CancellationToken,
+) -> CompletedSearch {
+    let search = process_search(
+        ctx,
+        &dn,
+        &config,
+        Some(&mut client),
+        backend_bind.as_deref(),
+        msgid,
+        sr,
+        ctrl,
+    );
+    let outcome = tokio::select! {
+        outcome = search => Some(outcome),
+        _ = abandoned.cancelled() => None,
+    };
+    let outcome = match outcome {
+        Some(outcome) => outcome,
+        None => {
+            debug!("Abandoning the backend search");
+            if let Err(e) = client.abandon_last().await {
+                warn!(?e, "Unable to abandon the backend search");
+                return CompletedSearch {
+                    msgid,
+                    client: None,
+                    outcome: Ok(()),
+                };
+            }
+            Ok(())
+        }
+    };
+    CompletedSearch {
+        msgid,
+        client: Some(client),
+        outcome,
+    }
+}
+
+/// The span of a search, with the fields recorded as it is answered.
+fn search_span(msgid: i32, dn: &str, sr: &LdapSearchRequest) -> Span {
+    span!(
+        Level::INFO,
+        "search",
+        msgid,
+        dn,
+        base = sr.base.as_str(),
+        result_code = field::Empty,
+        cache = field::Empty,
+        backend_latency_ms = field::Empty,
+    )
+}
+
+/// Connect to the backend for sessions bound as `dn` unless the circuit
+/// breaker is open. A failure to connect is reported to the breaker here,
+/// while the caller reports the outcome of the bind that follows.
+async fn connect_backend(app_state: &AppState, dn: &str) -> Result<BasicLdapClient, LdapError> {
+    if let Some(breaker) = &app_state.breaker {
+        if !breaker.allow() {
+            debug!("Circuit breaker is open, not connecting to the backend");
+            return Err(LdapError::CircuitOpen);
+        }
+    }
+
+    let backend = app_state.backend_for(dn);
+    let client = BasicLdapClient::build(
+        &backend.addrs(),
+        backend.tls_params,
+        backend.tls_server_name,
+        app_state.max_proxy_ber_size,
+        &app_state.connect_timeouts,
+    )
+    .await;
+    if client.is_err() {
+        record_backend_outcome(app_state, false);
+    }
+    client
+}
+
+/// The DN a SASL bind on `client` authenticated, asked of the backend with a
+/// "Who am I?" since it ignores the name sent with a SASL bind. None if the
+/// identity is not a DN.
+async fn sasl_identity(client: &mut BasicLdapClient) -> Option<Dn> {
+    match client.whoami().await {
+        Ok(authz_id) if authz_id.is_empty() => Some(Dn::default()),
+        Ok(authz_id) => authz_id.strip_prefix("dn:")?.parse().ok(),
+        Err(e) => {
+            warn!(?e, "Unable to ask the backend for the identity of a SASL bind");
+            None
+        }
+    }
+}
+
+/// Whether `password` matches the credentials cached for `dn` by a previous
+/// successful bind, for binding while the backend is unreachable.
+async fn offline_bind_valid(app_state: &AppState, dn: &str, password: Option<&str>) -> bool {
+    let (Some(offline_bind), Some(password)) = (&app_state.offline_bind, password) else {
+        return false;
+    };
+    let valid = offline_bind.verify(dn, password).await;
+    if valid {
+        warn!(%dn, "Backend is unreachable, bound with cached credentials");
+    } else {
+        warn!(%dn, "Backend is unreachable and the cached credentials do not match");
+    }
+    valid
+}
+
+/// A successful bind answered by the proxy without a word from the backend.
+fn bind_success(msgid: i32) -> LdapMsg {
+    telemetry::record_result(&LdapResultCode::Success);
+    LdapMsg {
+        msgid,
+        op: LdapOp::BindResponse(LdapBindResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "".to_string(),
+                referral: vec![],
+            },
+            saslcreds: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+fn offline_bind_response(msgid: i32) -> LdapMsg {
+    telemetry::record_result(&LdapResultCode::Success);
+    LdapMsg {
+        msgid,
+        op: LdapOp::BindResponse(LdapBindResponse {
+            res: LdapResult {
+                code: LdapResultCode::Success,
+                matcheddn: "".to_string(),
+                message: "bound by the proxy, the backend ldap server is unavailable"
+                    .to_string(),
+                referral: vec![],
+            },
+            saslcreds: None,
+        }),
+        ctrl: vec![],
+    }
+}
+
+/// Report to the circuit breaker whether a bind or search got a response
+/// from the backend. Rejected credentials and failed searches still count as
+/// the backend being up.
+fn record_backend_outcome(app_state: &AppState, responded: bool) {
+    if let Some(breaker) = &app_state.breaker {
+        if responded {
+            breaker.record_success();
+        } else {
+            breaker.record_failure();
+        }
+    }
+}
+
+/// Open another backend connection bound as the session's dn, so that more
+/// than one operation can be outstanding at once or to replace one that
+/// dropped.
+async fn open_backend_connection(
+    app_state: &AppState,
+    dn: &str,
+    backend_bind: Option<&(LdapBindRequest, Vec<LdapControl>)>,
+    pool_credentials: Option<&CredentialDigest>,
+) -> Option<BasicLdapClient> {
+    let (lbr, ctrl) = backend_bind?;
+
+    if let (Some(pool), Some(credentials)) = (&app_state.backend_pool, pool_credentials) {
+        if let Some(client) = pool.checkout(dn, credentials).await {
+            return Some(client);
+        }
+    }
+
+    let mut client = match connect_backend(app_state, dn).await {
+        Ok(c) => c,
+        Err(e) => {
+            warn!(?e, "Unable to open an additional backend connection");
+            return None;
+        }
+    };
+
+    let bind_result = client
+        .bind(lbr.clone(), ctrl.clone(), app_state.bind_timeout)
+        .await;
+    record_backend_outcome(app_state, bind_result.is_ok());
+    match bind_result {
+        Ok((bind_resp, ..)) if bind_resp.res.code == LdapResultCode::Success => {
+            debug!(%dn, "Opened an additional backend connection");
+            Some(client)
+        }
+        Ok((bind_resp, ..)) => {
+            warn!(code = ?bind_resp.res.code, "Backend rejected the bind for an additional connection");
+            None
+        }
+        Err(e) => {
+            warn!(?e, "Unable to bind an additional backend connection");
+            None
+        }
+    }
+}
+
+/// Follow the continuation references of a search to the servers they
+/// name, bound as the session's dn, adding the entries found there that are
+/// not already in `entries`. Returns the references that could not be
+/// followed, to be relayed to the client instead.
+async fn chase_references(
+    app_state: &AppState,
+    backend_bind: Option<&(LdapBindRequest, Vec<LdapControl>)>,
+    sr: &LdapSearchRequest,
+    entries: &mut Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+    references: Vec<SearchReference>,
+) -> Vec<SearchReference> {
+    let Some((lbr, bind_ctrl)) = backend_bind else {
+        debug!("Session credentials are not kept, relaying continuation references");
+        return references;
+    };
+
+    let dn_key = |dn: &str| normalize_dn(dn).unwrap_or_else(|_| dn.to_string()).to_lowercase();
+    let mut seen: HashSet<String> = entries.iter().map(|(entry, _)| dn_key(&entry.dn)).collect();
+    let mut unfollowed = Vec::new();
+    for (reference, ctrl) in references {
+        // Each of the uris of a reference leads to the same entries.
+        let mut found = None;
+        for uri in &reference.uris {
+            found = chase_reference(app_state, lbr, bind_ctrl, sr, uri).await;
+            if found.is_some() {
+                break;
+            }
+        }
+        match found {
+            Some(found) => {
+                for (entry, ctrl) in found {
+                    if seen.insert(dn_key(&entry.dn)) {
+                        entries.push((entry, ctrl));
+                    } else {
+                        debug!(dn = %entry.dn, "Skipping duplicate entry from a referral");
+                    }
+                }
+            }
+            None => unfollowed.push((reference, ctrl)),
+        }
+    }
+    unfollowed
+}
+
+/// Run `sr` against the server named by the referral `uri`, with the base
+/// and scope the uri gives. Only ldaps referrals to one of the
+/// `referral_hosts` can be followed.
+async fn chase_reference(
+    app_state: &AppState,
+    lbr: &LdapBindRequest,
+    bind_ctrl: &[LdapControl],
+    sr: &LdapSearchRequest,
+    uri: &str,
+) -> Option<Vec<(LdapSearchResultEntry, Vec<LdapControl>)>> {
+    let url = match Url::parse(uri) {
+        Ok(url) if url.scheme() == "ldaps" => url,
+        Ok(_) => {
+            debug!(%uri, "Only ldaps referrals can be chased");
+            return None;
+        }
+        Err(e) => {
+            warn!(%uri, ?e, "Unable to parse referral");
+            return None;
+        }
+    };
+    let host = url.host_str()?.trim_matches(['[', ']']);
+    let Some(tls_params) = app_state.referral_connectors.get(&host.to_lowercase()) else {
+        debug!(%uri, "Referral host is not one of the referral_hosts");
+        return None;
+    };
+    let addrs: Vec<SocketAddr> =
+        match tokio::net::lookup_host((host, url.port().unwrap_or(636))).await {
+            Ok(addrs) => addrs.collect(),
+            Err(e) => {
+                warn!(%uri, ?e, "Unable to resolve referral host");
+                return None;
+            }
+        };
+    let base = match url.path().trim_start_matches('/') {
+        "" => sr.base.clone(),
+        base => percent_decode(base)?,
+    };
+    // ldap://host/dn?attributes?scope?filter?extensions
+    let scope = match url.query().and_then(|query| query.split('?').nth(1)) {
+        Some("base") => LdapSearchScope::Base,
+        Some("one") => LdapSearchScope::OneLevel,
+        Some("sub") => LdapSearchScope::Subtree,
+        _ => sr.scope.clone(),
+    };
+
+    let mut client = match BasicLdapClient::build(
+        &addrs,
+        tls_params,
+        Some(host),
+        app_state.max_proxy_ber_size,
+        &app_state.connect_timeouts,
+    )
+    .await
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!(%uri, ?e, "Unable to connect to referral server");
+            return None;
+        }
+    };
+    match client
+        .bind(lbr.clone(), bind_ctrl.to_vec(), app_state.bind_timeout)
+        .await
+    {
+        Ok((bind_resp, ..)) if bind_resp.res.code == LdapResultCode::Success => {}
+        Ok((bind_resp, ..)) => {
+            warn!(%uri, code = ?bind_resp.res.code, "Referral server rejected the bind");
+            return None;
+        }
+        Err(e) => {
+            warn!(%uri, ?e, "Unable to bind to referral server");
+            return None;
+        }
+    }
+    let search = LdapSearchRequest {
+        base,
+        scope,
+        ..sr.clone()
+    };
+    match client.search(search, vec![]).await {
+        Ok((entries, result, _)) if result.code == LdapResultCode::Success => {
+            debug!(%uri, entries = entries.len(), "Chased referral");
+            Some(entries)
+        }
+        Ok((_, result, _)) => {
+            warn!(%uri, code = ?result.code, "Referral server failed the search");
+            None
+        }
+        Err(e) => {
+            warn!(%uri, ?e, "Unable to search referral server");
+            None
+        }
+    }
+}
+
+/// Undo the percent encoding of the dn in an ldap url.
+fn percent_decode(encoded: &str) -> Option<String> {
+    let mut decoded = Vec::with_capacity(encoded.len());
+    let mut rest = encoded.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = std::str::from_utf8(tail.get(..2)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            rest = &tail[2..];
+        } else {
+            decoded.push(byte);
+            rest = tail;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+/// Why a client session ended.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DisconnectReason {
+    /// The client sent an unbind.
+    Unbind,
+    /// The client closed the connection without an unbind.
+    ClientClosed,
+    IdleTimeout,
+    /// The client sent something that could not be decoded, or an operation
+    /// that is not supported in the session's state.
+    ProtocolError,
+    /// A search that was not allowed, with `denied_query_action = "disconnect"`.
+    QueryDenied,
+    /// The backend failed and there was nothing to answer from instead.
+    BackendUnavailable,
+    /// A response could not be written to the client.
+    WriteFailed,
+}
+
+impl DisconnectReason {
+    /// The label of the reason in metrics. These must not change.
+    pub fn label(self) -> &'static str {
+        match self {
+            DisconnectReason::Unbind => "unbind",
+            DisconnectReason::ClientClosed => "client_closed",
+            DisconnectReason::IdleTimeout => "idle_timeout",
+            DisconnectReason::ProtocolError => "protocol_error",
+            DisconnectReason::QueryDenied => "query_denied",
+            DisconnectReason::BackendUnavailable => "backend_unavailable",
+            DisconnectReason::WriteFailed => "write_failed",
+        }
+    }
+}
+
+pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
+    mut r: FramedRead<R, ClientCodec>,
+    w: FramedWrite<W, ClientCodec>,
+    client_address: ClientAddress,
+    reported_client_address: Option<SocketAddr>,
+    reported_client_cn: Option<String>,
+    tenant: Option<String>,
+    app_state: Arc<AppState>,
+) {
+    if let Some(reported_client_address) = reported_client_address {
+        info!(
+            ?reported_client_address,
+            ?reported_client_cn,
+            via = %client_address,
+            ?tenant,
+            "new client"
+        );
+    } else {
+        info!(%client_address, ?tenant, "new client");
+    };
+
+    let started = Instant::now();
+    let mut binds = 0u64;
+    let mut searches = 0u64;
+    let cache_hits = AtomicU64::new(0);
+
+    let mut state = ClientState::Unbound;
+    let redis_prefix = redis_tenant_prefix(&app_state.cache_key_prefix, tenant.as_deref());
+
+    // Searches may run concurrently, so their responses share the writer.
+    let w = tokio::sync::Mutex::new(w);
+    let ctx = SearchContext {
+        app_state: &app_state,
+        w: &w,
+        redis_prefix: &redis_prefix,
+        tiered_cache: &app_state.tiered_cache,
+        tenant: tenant.as_deref(),
+        cache_hits: &cache_hits,
+    };
+    let max_concurrent_ops = app_state.max_concurrent_ops;
+    let mut in_flight = FuturesUnordered::new();
+    // The outstanding searches, by msgid, so that they can be abandoned.
+    let mut pending: HashMap<i32, CancellationToken> = HashMap::new();
+
+    let reason = 'session: loop {
+        let can_read = in_flight.len() < max_concurrent_ops;
+        let next = tokio::select! {
+            Some(CompletedSearch { msgid, client, outcome }) = in_flight.next() => {
+                pending.remove(&msgid);
+                if let Some(client) = client {
+                    state.release_connection(&app_state, client);
+                }
+                if let Err(reason) = outcome {
+                    break reason;
+                }
+                continue;
+            }
+            next = r.next(), if can_read => next,
+            _ = idle(app_state.client_idle_timeout), if in_flight.is_empty() => {
+                info!("Disconnecting idle client");
+                send_disconnect_notice(
+                    &mut *w.lock().await,
+                    LdapResultCode::Unavailable,
+                    "idle timeout",
+                )
+                .await;
+                break DisconnectReason::IdleTimeout;
+            }
+        };
+
+        let protomsg = match next {
+            Some(Ok(protomsg)) => protomsg,
+            Some(Err(e)) => {
+                if let Some(UnsupportedBindVersion { msgid, version }) =
+                    e.get_ref().and_then(|e| e.downcast_ref())
+                {
+                    warn!(version, "Rejecting bind with unsupported ldap version");
+                    let resp_msg = bind_error(
+                        *msgid,
+                        LdapResultCode::ProtocolError,
+                        "only ldap version 3 is supported",
+                    );
+                    if w.lock().await.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                } else {
+                    error!(?e, "Unable to decode client message");
+                    send_disconnect_notice(
+                        &mut *w.lock().await,
+                        LdapResultCode::ProtocolError,
+                        "invalid message",
+                    )
+                    .await;
+                }
+                break DisconnectReason::ProtocolError;
+            }
+            None => break DisconnectReason::ClientClosed,
+        };
+        let critical = r.decoder_mut().take_critical_controls(protomsg.msgid);
+
+        match protomsg.op {
+            LdapOp::BindRequest(_) => binds += 1,
+            LdapOp::SearchRequest(_) => searches += 1,
+            _ => {}
+        }
+
+        if let Some(resp_msg) = exceeds_request_limits(&app_state, &protomsg)
+            .or_else(|| unhonored_critical_control(&app_state, &protomsg, &critical))
+        {
+            if w.lock().await.send(resp_msg).await.is_err() {
+                error!("Unable to send response");
+                break DisconnectReason::WriteFailed;
+            }
+            continue;
+        }
+
+        // In maintenance searches run one at a time, as none needs a
+        // backend connection.
+        let concurrent = max_concurrent_ops > 1 && !app_state.in_maintenance();
+        let protomsg = match (concurrent, &mut state, protomsg) {
+            (
+                true,
+                ClientState::Authenticated {
+                    dn,
+                    config,
+                    clients,
+                    backend_bind,
+                    pool_credentials,
+                    ..
+                },
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchRequest(sr),
+                    ctrl,
+                },
+            ) => {
+                if pending.contains_key(&msgid) {
+                    warn!(msgid, "Message id is already in use by an outstanding operation");
+                    send_disconnect_notice(
+                        &mut *w.lock().await,
+                        LdapResultCode::ProtocolError,
+                        "message id is already in use",
+                    )
+                    .await;
+                    break DisconnectReason::ProtocolError;
+                }
+
+                let client = match clients.pop() {
+                    Some(client) => client,
+                    None => match open_backend_connection(
+                        &app_state,
+                        dn,
+                        backend_bind.as_deref(),
+                        pool_credentials.as_ref(),
+                    )
+                    .await
+                    {
+                        Some(client) => client,
+                        // Wait for an outstanding operation to release its connection.
+                        None => loop {
+                            match in_flight.next().await {
+                                Some(CompletedSearch {
+                                    msgid: done_msgid,
+                                    client,
+                                    outcome,
+                                }) => {
+                                    pending.remove(&done_msgid);
+                                    if let Err(reason) = outcome {
+                                        break 'session reason;
+                                    }
+                                    if let Some(client) = client {
+                                        break client;
+                                    }
+                                }
+                                None => {
+                                    error!("No backend connection available");
+                                    break 'session DisconnectReason::BackendUnavailable;
+                                }
+                            }
+                        },
+                    },
+                };
+
+                let span = search_span(msgid, dn, &sr);
+                let abandoned = CancellationToken::new();
+                pending.insert(msgid, abandoned.clone());
+                in_flight.push(
+                    run_concurrent_search(
+                        ctx,
+                        dn.clone(),
+                        config.clone(),
+                        client,
+                        backend_bind.clone(),
+                        msgid,
+                        sr,
+                        ctrl,
+                        abandoned,
+                    )
+                    .instrument(span),
+                );
+                continue;
+            }
+            (
+                _,
+                _,
+                LdapMsg {
+                    msgid: _,
+                    op: LdapOp::AbandonRequest(abandoned),
+                    ctrl: _,
+                },
+            ) => {
+                // There is no response to an abandon, even when there is
+                // nothing to abandon.
+                match pending.get(&abandoned) {
+                    Some(search) => {
+                        debug!(abandoned, "Abandoning outstanding search");
+                        search.cancel();
+                    }
+                    None => trace!(abandoned, "No outstanding operation to abandon"),
+                }
+                continue;
+            }
+            (_, _, protomsg) => protomsg,
+        };
+
+        // Anything other than a search waits for the outstanding operations so
+        // that it applies after the searches sent before it.
+        while let Some(CompletedSearch {
+            msgid,
+            client,
+            outcome,
+        }) = in_flight.next().await
+        {
+            pending.remove(&msgid);
+            if let Some(client) = client {
+                state.release_connection(&app_state, client);
+            }
+            if let Err(reason) = outcome {
+                break 'session reason;
+            }
+        }
+
+        let next_state = match (&mut state, protomsg) {
+            (
+                current,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::BindRequest(lbr),
+                    ctrl,
+                },
+            ) => {
+                let span = span!(
+                    Level::INFO,
+                    "bind",
+                    dn = field::Empty,
+                    result_code = field::Empty,
+                    backend_latency_ms = field::Empty,
+                );
+                let _enter = span.enter();
+
+                trace!(lbr = ?redact(&lbr));
+                let mut dn = match normalize_dn(&lbr.dn) {
+                    Ok(dn) => {
+                        span.record("dn", dn.as_str());
+                        dn
+                    }
+                    Err(e) => {
+                        warn!(%e, "Rejecting bind with an invalid dn");
+                        let resp_msg =
+                            bind_error(msgid, LdapResultCode::InvalidDNSyntax, "invalid dn");
+                        if w.lock().await.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break DisconnectReason::WriteFailed;
+                        }
+                        continue;
+                    }
+                };
+
+                // Only an empty DN with an empty password is anonymous.
+                let unauthenticated = dn.is_empty()
+                    && matches!(&lbr.cred, LdapBindCred::Simple(pw) if !pw.is_empty());
+                if unauthenticated && !app_state.allow_unauthenticated_bind {
+                    warn!("Rejecting bind with an empty dn and a password");
+                    let resp_msg = bind_error(
+                        msgid,
+                        LdapResultCode::InvalidCredentials,
+                        "a password was given without a dn",
+                    );
+                    if w.lock().await.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                    continue;
+                }
+
+                let mut config = match app_state.dn_config(&dn) {
+                    Some(dnconfig) => dnconfig.clone(),
+                    None => {
+                        let resp_msg = bind_operror(msgid, "unable to bind");
+                        if w.lock().await.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break DisconnectReason::WriteFailed;
+                        }
+                        continue;
+                    }
+                };
+
+                let source = match (reported_client_address, &client_address) {
+                    (Some(reported), _) => Some(reported.ip()),
+                    (None, ClientAddress::Tcp(addr)) => Some(addr.ip()),
+                    (None, ClientAddress::Unix(_)) => None,
+                };
+                if !config.is_source_allowed(source) {
+                    warn!(%dn, ?source, "Rejecting bind from a source address that is not allowed");
+                    let resp_msg = bind_operror(msgid, "access denied from this source address");
+                    if w.lock().await.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                    continue;
+                }
+
+                // Claimed before the backend is contacted so that concurrent
+                // binds cannot exceed the limit. A session rebinding as the
+                // same DN already holds one of the sessions, which is released
+                // when the new state replaces it.
+                let mut session = match config.max_sessions {
+                    Some(max_sessions) => {
+                        let rebind = matches!(
+                            current,
+                            ClientState::Authenticated { session: Some(held), .. }
+                                | ClientState::Offline { session: Some(held), .. }
+                                | ClientState::SaslInProgress { session: Some(held), .. }
+                                if held.dn() == dn
+                        );
+                        match app_state
+                            .session_counts
+                            .try_acquire(&dn, max_sessions.get() + usize::from(rebind))
+                        {
+                            Some(guard) => Some(guard),
+                            None => {
+                                warn!(%dn, max_sessions, "Rejecting bind over the session limit");
+                                let resp_msg = bind_error(
+                                    msgid,
+                                    LdapResultCode::AdminLimitExceeded,
+                                    "too many sessions are bound as this dn",
+                                );
+                                if w.lock().await.send(resp_msg).await.is_err() {
+                                    error!("Unable to send response");
+                                    break DisconnectReason::WriteFailed;
+                                }
+                                continue;
+                            }
+                        }
+                    }
+                    None => None,
+                };
+
+                // Only password binds can be matched against a pooled connection.
+                let pool_credentials = match (&app_state.backend_pool, &lbr.cred) {
+                    (Some(_), LdapBindCred::Simple(pw)) if !pw.is_empty() => {
+                        Some(credential_digest(pw))
+                    }
+                    _ => None,
+                };
+
+                // Binds answered offline drop their controls, so they are
+                // not answered so with critical ones.
+                let offline_allowed = critical.is_empty()
+                    || app_state.critical_control_action == CriticalControlAction::Ignore;
+
+                // Only password binds are cached for validating offline.
+                let offline_password = match (&app_state.offline_bind, &lbr.cred) {
+                    (Some(_), LdapBindCred::Simple(pw)) if !dn.is_empty() && !pw.is_empty() => {
+                        Some(pw.clone())
+                    }
+                    _ => None,
+                };
+                let cached_password = match (&app_state.bind_cache, &lbr.cred) {
+                    (Some(_), LdapBindCred::Simple(pw)) if !dn.is_empty() && !pw.is_empty() => {
+                        Some(pw.clone())
+                    }
+                    _ => None,
+                };
+
+                // A SASL bind as the same DN continues the exchange on the
+                // connection that started it. Any other bind abandons it.
+                let sasl_client = match std::mem::replace(current, ClientState::Unbound) {
+                    ClientState::SaslInProgress {
+                        dn: sasl_dn,
+                        client,
+                        ..
+                    } if sasl_dn == dn && matches!(lbr.cred, LdapBindCred::SASL(_)) => {
+                        debug!("Continuing SASL bind");
+                        Some(client)
+                    }
+                    ClientState::SaslInProgress { .. } => {
+                        debug!("Abandoning SASL bind in progress");
+                        None
+                    }
+                    previous => {
+                        *current = previous;
+                        None
+                    }
+                };
+
+                // Kept to open more backend connections when operations run
+                // concurrently or per operation, to replace a connection that
+                // drops and to chase referrals. Only the last round of a SASL
+                // exchange would be kept, which cannot be replayed on its
+                // own, so those sessions hold their connection.
+                let keep_bind = (app_state.max_concurrent_ops > 1
+                    || app_state.reconnect_backend
+                    || app_state.chase_referrals
+                    || app_state.backend_mode == BackendMode::PerOperation)
+                    && sasl_client.is_none();
+                let backend_bind = keep_bind.then(|| {
+                    Box::new((
+                        LdapBindRequest {
+                            dn: rewrite_dn(&app_state.dn_rewrite, &lbr.dn),
+                            ..lbr.clone()
+                        },
+                        ctrl.clone(),
+                    ))
+                });
+
+                // Repeating the credentials of a recent bind is answered by the
+                // proxy, when the session needs no newly bound connection: it
+                // keeps those of its bind as the same DN, or opens them per
+                // operation. Binds with controls expect the backend's answer.
+                let rebind = matches!(
+                    current,
+                    ClientState::Authenticated { dn: bound, .. } if *bound == dn
+                );
+                let bind_cached = match (&app_state.bind_cache, &cached_password) {
+                    (Some(bind_cache), Some(pw))
+                        if ctrl.is_empty()
+                            && (rebind || app_state.backend_mode == BackendMode::PerOperation) =>
+                    {
+                        bind_cache.verify(&dn, pw).await
+                    }
+                    _ => false,
+                };
+                if bind_cached {
+                    debug!(%dn, "Bound with the credentials of a recent bind");
+                    let clients = match current {
+                        ClientState::Authenticated { clients, .. } if rebind => {
+                            std::mem::take(clients)
+                        }
+                        _ => Vec::new(),
+                    };
+                    if w.lock().await.send(bind_success(msgid)).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                    state = ClientState::Authenticated {
+                        dn,
+                        config: Box::new(config),
+                        clients,
+                        backend_bind,
+                        pool_credentials,
+                        session,
+                    };
+                    continue;
+                }
+
+                if app_state.in_maintenance() {
+                    if app_state.maintenance_bind_action == MaintenanceBindAction::Offline
+                        && offline_allowed
+                        && offline_bind_valid(&app_state, &dn, offline_password.as_deref()).await
+                    {
+                        let resp_msg = offline_bind_response(msgid);
+                        if w.lock().await.send(resp_msg).await.is_err() {
+                            error!("Unable to send response");
+                            break DisconnectReason::WriteFailed;
+                        }
+                        state = ClientState::Offline {
+                            dn,
+                            config: Box::new(config),
+                            session,
+                        };
+                        continue;
+                    }
+                    warn!(%dn, "Rejecting bind in maintenance mode");
+                    let resp_msg = bind_error(
+                        msgid,
+                        LdapResultCode::Unavailable,
+                        "the proxy is in maintenance",
+                    );
+                    if w.lock().await.send(resp_msg).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                    continue;
+                }
+
+                let pooled = match (&app_state.backend_pool, &pool_credentials) {
+                    (Some(pool), Some(credentials)) => pool.checkout(&dn, credentials).await,
+                    _ => None,
+                };
+
+                let (client, valid, sasl_in_progress) = if let Some(client) = pooled {
+                    if w.lock().await.send(bind_success(msgid)).await.is_err() {
+                        error!("Unable to send response");
+                        break DisconnectReason::WriteFailed;
+                    }
+                    (client, true, false)
+                } else {
+                    let connected = match sasl_client {
+                        Some(client) => Ok(client),
+                        None => connect_backend(&app_state, &dn).await,
+                    };
+                    let mut client = match connected {
+                        Ok(c) => c,
+                        Err(e) => {
+                            error!(?e, "A client build error has occurred.");
+                            if offline_allowed
+                                && offline_bind_valid(
+                                    &app_state,
+                                    &dn,
+                                    offline_password.as_deref(),
+                                )
+                                .await
+                            {
+                                let resp_msg = offline_bind_response(msgid);
+                                if w.lock().await.send(resp_msg).await.is_err() {
+                                    error!("Unable to send response");
+                                    break DisconnectReason::WriteFailed;
+                                }
+                                state = ClientState::Offline {
+                                    dn,
+                                    config: Box::new(config),
+                                    session,
+                                };
+                                continue;
+                            }
+                            let resp_msg = bind_operror(msgid, "unable to bind");
+                            if w.lock().await.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                            }
+                            send_disconnect_notice(
+                                &mut *w.lock().await,
+                                LdapResultCode::Unavailable,
+                                "backend ldap server unavailable",
+                            )
+                            .await;
+                            break DisconnectReason::BackendUnavailable;
+                        }
+                    };
+
+                    let sasl = matches!(lbr.cred, LdapBindCred::SASL(_));
+                    let lbr = LdapBindRequest {
+                        dn: rewrite_dn(&app_state.dn_rewrite, &lbr.dn),
+                        ..lbr
+                    };
+
+                    let started = Instant::now();
+                    let bind_result = client.bind(lbr, ctrl, app_state.bind_timeout).await;
+                    observe_backend_latency(&app_state, BackendOp::Bind, &dn, None, started);
+                    // An oversized message says nothing about the backend's health.
+                    record_backend_outcome(
+                        &app_state,
+                        matches!(bind_result, Ok(_) | Err(LdapError::MessageTooLarge)),
+                    );
+                    let (valid, sasl_in_progress) = match bind_result {
+                        Ok((mut bind_resp, ctrl, password_policy)) => {
+                            app_state
+                                .metrics
+                                .record_backend_result(BackendOp::Bind, &bind_resp.res.code);
+                            telemetry::record_result(&bind_resp.res.code);
+                            let mut valid = bind_resp.res.code == LdapResultCode::Success;
+                            let sasl_in_progress =
+                                bind_resp.res.code == LdapResultCode::SaslBindInProgress;
+                            let rejected =
+                                bind_resp.res.code == LdapResultCode::InvalidCredentials;
+
+                            // A SASL session is bound as the identity the
+                            // exchange authenticated. Naming another DN is
+                            // refused, and an empty name takes that identity
+                            // along with its configuration.
+                            if valid && sasl {
+                                let named = rewrite_dn(&app_state.dn_rewrite, &dn).parse::<Dn>();
+                                valid = match (sasl_identity(&mut client).await, named) {
+                                    (Some(identity), Ok(named))
+                                        if identity.eq_ignore_case(&named) =>
+                                    {
+                                        true
+                                    }
+                                    (Some(identity), _) if dn.is_empty() => {
+                                        let identity = identity.to_string();
+                                        match app_state.dn_config(&identity) {
+                                            Some(identity_config)
+                                                if identity_config.is_source_allowed(source) =>
+                                            {
+                                                let guard =
+                                                    identity_config.max_sessions.map(|max| {
+                                                        app_state
+                                                            .session_counts
+                                                            .try_acquire(&identity, max.get())
+                                                    });
+                                                match guard {
+                                                    Some(None) => false,
+                                                    guard => {
+                                                        config = identity_config.clone();
+                                                        session = guard.flatten();
+                                                        span.record("dn", identity.as_str());
+                                                        dn = identity;
+                                                        true
+                                                    }
+                                                }
+                                            }
+                                            _ => false,
+                                        }
+                                    }
+                                    _ => false,
+                                };
+                                if !valid {
+                                    warn!(%dn, "Rejecting SASL bind as an identity other than its name");
+                                    bind_resp.res.code = LdapResultCode::InvalidCredentials;
+                                    bind_resp.res.message =
+                                        "the bind name is not the authenticated identity".to_string();
+                                    bind_resp.saslcreds = None;
+                                }
+                            }
+                            app_state.rewrite_referrals(&mut bind_resp.res.referral);
+
+                            if let Some(policy) =
+                                password_policy.as_deref().and_then(parse_password_policy)
+                            {
+                                if policy != PasswordPolicyResponse::default() {
+                                    info!(%dn, ?policy, "Backend sent a password policy warning");
+                                }
+                            }
+
+                            let resp_msg = LdapMsg {
+                                msgid,
+                                op: LdapOp::BindResponse(bind_resp),
+                                ctrl,
+                            };
+                            let sent = match password_policy {
+                                // Sent with its value in place of the bare
+                                // control `LdapControl` decodes it to.
+                                Some(value) => {
+                                    let mut msg = resp_msg;
+                                    msg.ctrl.retain(|c| {
+                                        !matches!(c, LdapControl::PasswordPolicyRequest { .. })
+                                    });
+                                    let msg = ResponseWithControl {
+                                        msg,
+                                        oid: OID_PASSWORD_POLICY.to_string(),
+                                        value,
+                                    };
+                                    w.lock().await.send(msg).await
+                                }
+                                None => w.lock().await.send(resp_msg).await,
+                            };
+                            if sent.is_err() {
+                                error!("Unable to send response");
+                                break DisconnectReason::WriteFailed;
+                            }
+
+                            // Derived in the background, as the kdf is slow.
+                            if let Some(offline_bind) = &app_state.offline_bind {
+                                match &offline_password {
+                                    Some(pw) if valid => {
+                                        let offline_bind = offline_bind.clone();
+                                        let dn = dn.clone();
+                                        let pw = pw.clone();
+                                        tokio::spawn(async move {
+                                            offline_bind.store(&dn, &pw).await
+                                        });
+                                    }
+                                    _ if rejected => offline_bind.forget(&dn),
+                                    _ => {}
+                                }
+                            }
+                            if let Some(bind_cache) = &app_state.bind_cache {
+                                match &cached_password {
+                                    Some(pw) if valid => {
+                                        let bind_cache = bind_cache.clone();
+                                        let dn = dn.clone();
+                                        let pw = pw.clone();
+                                        tokio::spawn(async move {
+                                            bind_cache.store(&dn, &pw).await
+                                        });
+                                    }
+                                    _ if rejected => bind_cache.forget(&dn),
+                                    _ => {}
+                                }
+                            }
+                            (valid, sasl_in_progress)
+                        }
+                        Err(LdapError::MessageTooLarge) => {
+                            warn!("Bind exceeded max_proxy_ber_size");
+                            let resp_msg = bind_error(
+                                msgid,
+                                LdapResultCode::AdminLimitExceeded,
+                                "message to or from the backend exceeds the proxy's max ber size",
+                            );
+                            if w.lock().await.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                                break DisconnectReason::WriteFailed;
+                            }
+                            (false, false)
+                        }
+                        Err(e) => {
+                            error!(?e, "A client bind error has occurred");
+                            if offline_allowed
+                                && offline_bind_valid(
+                                    &app_state,
+                                    &dn,
+                                    offline_password.as_deref(),
+                                )
+                                .await
+                            {
+                                let resp_msg = offline_bind_response(msgid);
+                                if w.lock().await.send(resp_msg).await.is_err() {
+                                    error!("Unable to send response");
+                                    break DisconnectReason::WriteFailed;
+                                }
+                                state = ClientState::Offline {
+                                    dn,
+                                    config: Box::new(config),
+                                    session,
+                                };
+                                continue;
+                            }
+                            let resp_msg = bind_operror(msgid, "unable to bind");
+                            if w.lock().await.send(resp_msg).await.is_err() {
+                                error!("Unable to send response");
+                            }
+                            send_disconnect_notice(
+                                &mut *w.lock().await,
+                                LdapResultCode::Unavailable,
+                                "backend ldap server unavailable",
+                            )
+                            .await;
+                            break DisconnectReason::BackendUnavailable;
+                        }
+                    };
+
+                    (client, valid, sasl_in_progress)
+                };
+
+                if sasl_in_progress {
+                    debug!("SASL bind in progress for {}", dn);
+                    Some(ClientState::SaslInProgress {
+                        dn,
+                        client,
+                        session,
+                    })
+                } else if valid {
+                    info!("Successful bind for {}", dn);
+                    let mut authenticated = ClientState::Authenticated {
+                        dn,
+                        config: Box::new(config),
+                        clients: Vec::new(),
+                        backend_bind,
+                        pool_credentials,
+                        session,
+                    };
+                    authenticated.release_connection(&app_state, client);
+                    Some(authenticated)
+                } else {
+                    None
+                }
+            }
+            (
+                _,
+                LdapMsg {
+                    msgid: _,
+                    op: LdapOp::UnbindRequest,
+                    ctrl: _,
+                },
+            ) => {
+                trace!("unbind");
+                break DisconnectReason::Unbind;
+            }
+
+            (
+                ClientState::Authenticated {
+                    dn,
+                    config,
+                    clients,
+                    backend_bind,
+                    pool_credentials,
+                    ..
+                },
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchRequest(sr),
+                    ctrl,
+                },
+            ) => {
+                let span = search_span(msgid, dn, &sr);
+                let _enter = span.enter();
+
+                // In per operation mode a connection is only held while a
+                // search runs. Without one the search is answered from the
+                // fallback cache, as if the connection had failed.
+                let mut client = match clients.pop() {
+                    Some(client) => Some(client),
+                    None if app_state.in_maintenance() => None,
+                    None if app_state.backend_mode == BackendMode::PerOperation => {
+                        open_backend_connection(
+                            &app_state,
+                            dn,
+                            backend_bind.as_deref(),
+                            pool_credentials.as_ref(),
+                        )
+                        .await
+                    }
+                    None => {
+                        error!("No backend connection available");
+                        break DisconnectReason::BackendUnavailable;
+                    }
+                };
+
+                let outcome = process_search(
+                    ctx,
+                    dn,
+                    config,
+                    client.as_mut(),
+                    backend_bind.as_deref(),
+                    msgid,
+                    sr,
+                    ctrl,
+                )
+                .await;
+                if let Some(client) = client {
+                    release_connection(
+                        &app_state,
+                        dn,
+                        clients,
+                        backend_bind.is_some(),
+                        pool_credentials.as_ref(),
+                        client,
+                    );
+                }
+                if let Err(reason) = outcome {
+                    break reason;
+                }
 
+                None
+            }
             (
-                ClientState::Authenticated {
+                ClientState::Offline {
                     dn,
                     config,
-                    ref mut client,
+                    ..
                 },
                 LdapMsg {
                     msgid,
@@ -436,143 +3988,19 @@ pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
                     ctrl,
                 },
             ) => {
-                let span = span!(Level::INFO, "search");
+                let span = search_span(msgid, dn, &sr);
                 let _enter = span.enter();
 
-                if config.allowed_queries.is_empty() {
-                    debug!("All queries are allowed");
-                } else {
-                    let allow_key = (
-                        sr.base.clone(),
-                        sr.scope.clone(),
-                        LdapFilterWrapper {
-                            inner: sr.filter.clone(),
-                        },
-                    );
-
-                    if config.allowed_queries.contains(&allow_key) {
-                        debug!("Query is granted");
-                    } else {
-                        warn!(?allow_key, "Requested query is not allowed for {}", dn);
-                        if w.send(LdapMsg {
-                            msgid,
-                            op: LdapOp::SearchResultDone(LdapResult {
-                                code: LdapResultCode::Success,
-                                matcheddn: "".to_string(),
-                                message: "".to_string(),
-                                referral: vec![],
-                            }),
-                            ctrl,
-                        })
-                        .await
-                        .is_err()
-                        {
-                            error!("Unable to send response");
-                        }
-                        break;
-                    }
-                };
-
-                let cache_key = SearchCacheKey {
-                    bind_dn: dn.clone(),
-                    search: sr.clone(),
-                    ctrl: ctrl.clone(),
-                };
-                debug!(?cache_key);
-
-                let (entries, result, ctrl) = match client.search(sr, ctrl).await {
-                    Ok(data) => {
-                        info!("Backend is reachable, updating fallback cache");
-                        let (entries, result, ctrl) = data;
-                        
-                        let cache_value = CachedValue {
-                            cached_at: std::time::SystemTime::now(),
-                            entries: entries.clone(),
-                            result: result.clone(),
-                            ctrl: ctrl.clone(),
-                        };
-                        
-                        cache_set_if_changed(
-                            &app_state.cache,
-                            cache_key.clone(),
-                            cache_value,
-                            &redis_prefix,
-                            app_state.cache_ttl,
-                            &tiered_cache,
-                        )
-                        .await;
-                        
-                        (entries, result, ctrl)
-                    }
-                    Err(e) => {
-                        warn!(?e, "Backend is unreachable, attempting to use fallback cache");
-                        
-                        match cache_get(&app_state.cache, &cache_key, &redis_prefix, &tiered_cache).await {
-                            Some(cached_value) => {
-                                info!("Serving from fallback cache (cached at: {:?})", cached_value.cached_at);
-                                (
-                                    cached_value.entries.clone(),
-                                    cached_value.result.clone(),
-                                    cached_value.ctrl.clone(),
-                                )
-                            }
-                            None => {
-                                error!("Backend unreachable and no fallback data available");
-                                let resp_msg = LdapMsg {
-                                    msgid,
-                                    op: LdapOp::SearchResultDone(LdapResult {
-                                        code: LdapResultCode::Unavailable,
-                                        matcheddn: "".to_string(),
-                                        message: "Backend LDAP server unavailable and no cached data".to_string(),
-                                        referral: vec![],
-                                    }),
-                                    ctrl: vec![],
-                                };
-                                if w.send(resp_msg).await.is_err() {
-                                    error!("Unable to send response");
-                                }
-                                break;
-                            }
-                        }
-                    }
-                };
-
-                for (entry, ctrl) in entries {
-                    if w.send(LdapMsg {
-                        msgid,
-                        op: LdapOp::SearchResultEntry(entry),
-                        ctrl,
-                    })
-                    .await
-                    .is_err()
-                    {
-                        error!("Unable to send response");
-                        break;
-                    }
-                }
-
-                if w.send(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultDone(result),
-                    ctrl,
-                })
-                .await
-                .is_err()
+                if let Err(reason) =
+                    process_search(ctx, dn, config, None, None, msgid, sr, ctrl).await
                 {
-                    error!("Unable to send response");
-                    break;
+                    break reason;
                 }
 
-                cache_try_quiesce(&app_state.cache).await;
-
                 None
             }
             (
-                ClientState::Authenticated {
-                    dn,
-                    config: _,
-                    client: _,
-                },
+                ClientState::Authenticated { dn, .. } | ClientState::Offline { dn, .. },
                 LdapMsg {
                     msgid,
                     op: LdapOp::ExtendedRequest(ler),
@@ -588,7 +4016,7 @@ pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
                             referral: vec![],
                         },
                         name: None,
-                        value: Some(Vec::from(dn.as_str())),
+                        value: Some(app_state.whoami_format.authz_id(dn).into_bytes()),
                     }),
                     _ => LdapOp::ExtendedResponse(LdapExtendedResponse {
                         res: LdapResult {
@@ -602,7 +4030,49 @@ pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
                     }),
                 };
 
-                if w.send(LdapMsg {
+                if w.lock().await.send(LdapMsg {
+                    msgid,
+                    op,
+                    ctrl: vec![],
+                })
+                .await
+                .is_err()
+                {
+                    error!("Unable to send response");
+                    break DisconnectReason::WriteFailed;
+                }
+
+                None
+            }
+            (
+                ClientState::Authenticated { dn, config, .. }
+                | ClientState::Offline { dn, config, .. },
+                LdapMsg {
+                    msgid,
+                    op:
+                        op @ (LdapOp::ModifyRequest(_)
+                        | LdapOp::AddRequest(_)
+                        | LdapOp::DelRequest(_)
+                        | LdapOp::ModifyDNRequest(_)),
+                    ctrl: _,
+                },
+            ) if config.read_only => {
+                // Checked before anything else about the write, so that the
+                // backend never sees it whatever its own access control.
+                warn!(%dn, msgid, "Rejecting write by a read only DN");
+                let res = LdapResult {
+                    code: LdapResultCode::InsufficentAccessRights,
+                    matcheddn: "".to_string(),
+                    message: "read only".to_string(),
+                    referral: vec![],
+                };
+                let op = match op {
+                    LdapOp::ModifyRequest(_) => LdapOp::ModifyResponse(res),
+                    LdapOp::AddRequest(_) => LdapOp::AddResponse(res),
+                    LdapOp::DelRequest(_) => LdapOp::DelResponse(res),
+                    _ => LdapOp::ModifyDNResponse(res),
+                };
+                if w.lock().await.send(LdapMsg {
                     msgid,
                     op,
                     ctrl: vec![],
@@ -611,22 +4081,111 @@ pub async fn client_process<W: AsyncWrite + Unpin, R: AsyncRead + Unpin>(
                 .is_err()
                 {
                     error!("Unable to send response");
-                    break;
+                    break DisconnectReason::WriteFailed;
+                }
+
+                None
+            }
+            (
+                ClientState::Unbound,
+                LdapMsg {
+                    msgid,
+                    op: LdapOp::SearchRequest(sr),
+                    ctrl: _,
+                },
+            ) => {
+                // Root dse and schema probes need not bind, but nothing else
+                // is answered.
+                let span = search_span(msgid, "", &sr);
+                let _enter = span.enter();
+
+                let root_dse = app_state
+                    .root_dse
+                    .as_ref()
+                    .filter(|_| is_root_dse_search(&sr));
+                let schema = app_state.schema.as_ref().and_then(|schema| schema.search(&sr));
+                let outcome = match (root_dse, schema) {
+                    (Some(root_dse), _) => {
+                        debug!("Serving synthetic root dse");
+                        let entry = root_dse_entry(root_dse, &sr.attrs);
+                        send_synthetic_entry(&mut *w.lock().await, msgid, entry).await
+                    }
+                    (None, Some(entry)) => {
+                        debug!("Serving schema snapshot");
+                        send_synthetic_entry(&mut *w.lock().await, msgid, entry).await
+                    }
+                    (None, None) => {
+                        warn!("Rejecting search from a client that has not bound");
+                        telemetry::record_result(&LdapResultCode::OperationsError);
+                        let resp_msg = LdapMsg {
+                            msgid,
+                            op: LdapOp::SearchResultDone(LdapResult {
+                                code: LdapResultCode::OperationsError,
+                                matcheddn: "".to_string(),
+                                message: "not bound".to_string(),
+                                referral: vec![],
+                            }),
+                            ctrl: vec![],
+                        };
+                        match w.lock().await.send(resp_msg).await {
+                            Ok(()) => Ok(()),
+                            Err(_) => {
+                                error!("Unable to send response");
+                                Err(DisconnectReason::WriteFailed)
+                            }
+                        }
+                    }
+                };
+                if let Err(reason) = outcome {
+                    break reason;
                 }
 
                 None
             }
             (_, msg) => {
-                debug!(?msg);
-                break;
+                debug!(msg = ?redact(&msg));
+                send_disconnect_notice(
+                    &mut *w.lock().await,
+                    LdapResultCode::ProtocolError,
+                    "unexpected or unsupported operation",
+                )
+                .await;
+                break DisconnectReason::ProtocolError;
             }
         };
 
         if let Some(next_state) = next_state {
             state = next_state;
         }
+    };
+
+    if let (
+        Some(pool),
+        ClientState::Authenticated {
+            dn,
+            clients,
+            pool_credentials: Some(credentials),
+            ..
+        },
+    ) = (&app_state.backend_pool, state)
+    {
+        for client in clients {
+            pool.checkin(&dn, credentials, client);
+        }
     }
-    info!("Disconnect for {}", client_address);
+
+    app_state.metrics.record_disconnect(reason);
+    info!(
+        ?reason,
+        binds,
+        searches,
+        cache_hits = cache_hits.load(Ordering::Relaxed),
+        duration_ms = started.elapsed().as_millis() as u64,
+        ?reported_client_address,
+        ?reported_client_cn,
+        "Disconnect for {}",
+        client_address
+    );
 }
 
 #[derive(Debug, Clone)]
@@ -635,12 +4194,57 @@ pub enum LdapError {
     ConnectError,
     Transport,
     InvalidProtocolState,
+    /// The backend was not contacted because the circuit breaker is open.
+    CircuitOpen,
+    /// The backend did not respond in time.
+    Timeout,
+    /// A request or response exceeded `max_proxy_ber_size`.
+    MessageTooLarge,
+}
+
+impl LdapError {
+    /// Classify an error reading from or writing to the backend.
+    fn from_io(e: &std::io::Error) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<MessageTooLarge>()) {
+            LdapError::MessageTooLarge
+        } else {
+            LdapError::Transport
+        }
+    }
+}
+
+pub enum SearchItem {
+    Entry(LdapSearchResultEntry, Vec<LdapControl>),
+    /// Such as the sync info messages of a sync search.
+    Intermediate(LdapIntermediateResponse, Vec<LdapControl>),
+    /// A continuation reference to entries held by another server.
+    Reference(LdapSearchResultReference, Vec<LdapControl>),
+    Done(LdapResult, Vec<LdapControl>),
+}
+
+pub type SearchReference = (LdapSearchResultReference, Vec<LdapControl>);
+
+pub enum SearchBuffer {
+    Complete {
+        entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+        references: Vec<SearchReference>,
+        result: LdapResult,
+        ctrl: Vec<LdapControl>,
+    },
+    /// The search exceeded the buffer limit. The rest of the results for
+    /// `msgid` are still waiting to be read from the backend.
+    Spilled {
+        msgid: i32,
+        entries: Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+        references: Vec<SearchReference>,
+    },
 }
 
 pub struct BasicLdapClient {
-    r: FramedRead<CR, LdapCodec>,
-    w: FramedWrite<CW, LdapCodec>,
+    r: FramedRead<CR, BackendCodec>,
+    w: FramedWrite<CW, BackendCodec>,
     msg_counter: i32,
+    peer_addr: SocketAddr,
 }
 
 impl BasicLdapClient {
@@ -649,17 +4253,20 @@ impl BasicLdapClient {
         self.msg_counter
     }
 
+    /// Connect to the first of `addrs` that accepts a connection within its
+    /// connect timeout.
     pub async fn build(
         addrs: &[SocketAddr],
         tls_connector: &SslConnector,
+        server_name: Option<&str>,
         max_ber_size: Option<usize>,
+        connect_timeouts: &ConnectTimeouts,
     ) -> Result<Self, LdapError> {
-        let timeout = Duration::from_secs(5);
-
         let mut aiter = addrs.iter();
 
-        let tcpstream = loop {
+        let (tcpstream, peer_addr) = loop {
             if let Some(addr) = aiter.next() {
+                let timeout = connect_timeouts.for_addr(addr);
                 let sleep = tokio::time::sleep(timeout);
                 tokio::pin!(sleep);
                 tokio::select! {
@@ -667,7 +4274,7 @@ impl BasicLdapClient {
                         match maybe_stream {
                             Ok(t) => {
                                 trace!(?addr, "connection established");
-                                break t;
+                                break (t, *addr);
                             }
                             Err(e) => {
                                 trace!(?addr, ?e, "error");
@@ -676,7 +4283,7 @@ impl BasicLdapClient {
                         }
                     }
                     _ = &mut sleep => {
-                        warn!(?addr, "timeout");
+                        warn!(?addr, ?timeout, "timeout");
                         continue;
                     }
                 }
@@ -686,7 +4293,19 @@ impl BasicLdapClient {
         };
 
         let mut tlsstream = Ssl::new(tls_connector.context())
-            .and_then(|tls_obj| SslStream::new(tls_obj, tcpstream))
+            .and_then(|mut tls_obj| {
+                // SNI may not carry an IP address.
+                if let Some(name) = server_name
+                    .filter(|name| name.trim_matches(['[', ']']).parse::<IpAddr>().is_err())
+                {
+                    tls_obj.set_hostname(name)?;
+                }
+                tls::prepare_resumption(
+                    &mut tls_obj,
+                    format!("{}|{}", peer_addr, server_name.unwrap_or_default()),
+                );
+                SslStream::new(tls_obj, tcpstream)
+            })
             .map_err(|e| {
                 error!(?e, "openssl");
                 LdapError::TlsError
@@ -698,25 +4317,70 @@ impl BasicLdapClient {
                 error!(?e, "openssl");
                 LdapError::TlsError
             })?;
+        tls::record_handshake(tlsstream.ssl());
+        trace!(resumed = tlsstream.ssl().session_reused(), "tls handshake complete");
 
         let (r, w) = tokio::io::split(tlsstream);
 
-        let w = FramedWrite::new(w, LdapCodec::new(max_ber_size));
-        let r = FramedRead::new(r, LdapCodec::new(max_ber_size));
+        let w = FramedWrite::new(w, BackendCodec::new(max_ber_size));
+        let r = FramedRead::new(r, BackendCodec::new(max_ber_size));
 
-        info!("Connected to remote ldap server");
+        info!(%peer_addr, "Connected to remote ldap server");
         Ok(BasicLdapClient {
             r,
             w,
             msg_counter: 0,
+            peer_addr,
         })
     }
 
+    /// The backend address this client is connected to.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    /// Read the next message for the operation with `ck_msgid`. Operations
+    /// are issued one at a time, so a lower msgid can only be a late or
+    /// duplicate response to one that has completed, and is skipped.
+    async fn recv_response(&mut self, ck_msgid: i32) -> Result<LdapMsg, LdapError> {
+        loop {
+            match self.r.next().await {
+                Some(Ok(msg)) if msg.msgid == ck_msgid => break Ok(msg),
+                Some(Ok(msg)) if msg.msgid > 0 && msg.msgid < ck_msgid => {
+                    warn!(
+                        msgid = msg.msgid,
+                        expected = ck_msgid,
+                        "Skipping late response to a completed operation"
+                    );
+                    trace!(msg = ?redact(&msg));
+                }
+                Some(Ok(msg)) => {
+                    error!("invalid msgid, sequence error.");
+                    trace!(msg = ?redact(&msg));
+                    break Err(LdapError::InvalidProtocolState);
+                }
+                Some(Err(e)) => {
+                    error!(?e, "unable to receive from ldap server");
+                    break Err(LdapError::from_io(&e));
+                }
+                None => {
+                    error!("connection closed");
+                    break Err(LdapError::Transport);
+                }
+            }
+        }
+    }
+
+    /// Bind with `lbr`, giving up if the backend has not answered within
+    /// `timeout`. The connection should not be reused after a timeout. The
+    /// response comes with its controls and the value of any password policy
+    /// response control, which its `LdapControl` does not carry.
     pub async fn bind(
         &mut self,
         lbr: LdapBindRequest,
         ctrl: Vec<LdapControl>,
-    ) -> Result<(LdapBindResponse, Vec<LdapControl>), LdapError> {
+        timeout: Duration,
+    ) -> Result<(LdapBindResponse, Vec<LdapControl>, Option<Vec<u8>>), LdapError> {
         let ck_msgid = self.next_msgid();
 
         let msg = LdapMsg {
@@ -727,49 +4391,78 @@ impl BasicLdapClient {
 
         self.w.send(msg).await.map_err(|e| {
             error!(?e, "unable to transmit to ldap server");
-            LdapError::Transport
+            LdapError::from_io(&e)
         })?;
 
-        match self.r.next().await {
-            Some(Ok(LdapMsg {
-                msgid,
+        let resp = tokio::time::timeout(timeout, self.recv_response(ck_msgid))
+            .await
+            .map_err(|_| {
+                error!(?timeout, "timed out waiting for a bind response");
+                LdapError::Timeout
+            })?;
+
+        match resp? {
+            LdapMsg {
+                msgid: _,
                 op: LdapOp::BindResponse(bind_resp),
                 ctrl,
-            })) => {
-                if msgid == ck_msgid {
-                    Ok((bind_resp, ctrl))
+            } => {
+                let password_policy = self.r.decoder_mut().take_password_policy(ck_msgid);
+                Ok((bind_resp, ctrl, password_policy))
+            }
+            msg => {
+                trace!(msg = ?redact(&msg));
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    /// Issue a "Who am I?" extended operation, returning the authorization
+    /// identity the connection is bound as. Also used to check the
+    /// connection is still usable.
+    pub async fn whoami(&mut self) -> Result<String, LdapError> {
+        let ck_msgid = self.next_msgid();
+
+        let msg = LdapMsg {
+            msgid: ck_msgid,
+            op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                value: None,
+            }),
+            ctrl: vec![],
+        };
+
+        self.w.send(msg).await.map_err(|e| {
+            error!(?e, "unable to transmit to ldap server");
+            LdapError::from_io(&e)
+        })?;
+
+        match self.recv_response(ck_msgid).await? {
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::ExtendedResponse(resp),
+                ctrl: _,
+            } => {
+                if resp.res.code == LdapResultCode::Success {
+                    Ok(String::from_utf8_lossy(&resp.value.unwrap_or_default()).into_owned())
                 } else {
-                    error!("invalid msgid, sequence error.");
                     Err(LdapError::InvalidProtocolState)
                 }
             }
-            Some(Ok(msg)) => {
-                trace!(?msg);
+            msg => {
+                trace!(msg = ?redact(&msg));
                 Err(LdapError::InvalidProtocolState)
             }
-            Some(Err(e)) => {
-                error!(?e, "unable to receive from ldap server");
-                Err(LdapError::Transport)
-            }
-            None => {
-                error!("connection closed");
-                Err(LdapError::Transport)
-            }
         }
     }
 
-    pub async fn search(
+    /// Send a search, returning the msgid to read its responses with
+    /// [Self::next_search_item].
+    pub async fn send_search(
         &mut self,
         sr: LdapSearchRequest,
         ctrl: Vec<LdapControl>,
-    ) -> Result<
-        (
-            Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
-            LdapResult,
-            Vec<LdapControl>,
-        ),
-        LdapError,
-    > {
+    ) -> Result<i32, LdapError> {
         let ck_msgid = self.next_msgid();
 
         let msg = LdapMsg {
@@ -780,50 +4473,124 @@ impl BasicLdapClient {
 
         self.w.send(msg).await.map_err(|e| {
             error!(?e, "unable to transmit to ldap server");
-            LdapError::Transport
+            LdapError::from_io(&e)
         })?;
 
+        Ok(ck_msgid)
+    }
+
+    /// Abandon the operation most recently sent. Whatever the backend still
+    /// sends for it is skipped by later operations as a late response.
+    pub async fn abandon_last(&mut self) -> Result<(), LdapError> {
+        let abandoned = self.msg_counter;
+        let msg = LdapMsg {
+            msgid: self.next_msgid(),
+            op: LdapOp::AbandonRequest(abandoned),
+            ctrl: vec![],
+        };
+
+        self.w.send(msg).await.map_err(|e| {
+            error!(?e, "unable to transmit to ldap server");
+            LdapError::from_io(&e)
+        })
+    }
+
+    /// Read the next response to the search with `ck_msgid`.
+    pub async fn next_search_item(&mut self, ck_msgid: i32) -> Result<SearchItem, LdapError> {
+        match self.recv_response(ck_msgid).await? {
+            // This terminates the iteration of entries.
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::SearchResultDone(search_res),
+                ctrl,
+            } => Ok(SearchItem::Done(search_res, ctrl)),
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::SearchResultEntry(search_entry),
+                ctrl,
+            } => Ok(SearchItem::Entry(search_entry, ctrl)),
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::IntermediateResponse(response),
+                ctrl,
+            } => Ok(SearchItem::Intermediate(response, ctrl)),
+            LdapMsg {
+                msgid: _,
+                op: LdapOp::SearchResultReference(reference),
+                ctrl,
+            } => Ok(SearchItem::Reference(reference, ctrl)),
+            msg => {
+                trace!(msg = ?redact(&msg));
+                Err(LdapError::InvalidProtocolState)
+            }
+        }
+    }
+
+    pub async fn search(
+        &mut self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Result<
+        (
+            Vec<(LdapSearchResultEntry, Vec<LdapControl>)>,
+            LdapResult,
+            Vec<LdapControl>,
+        ),
+        LdapError,
+    > {
+        match self.search_buffered(sr, ctrl, None).await? {
+            SearchBuffer::Complete {
+                entries,
+                result,
+                ctrl,
+                ..
+            } => Ok((entries, result, ctrl)),
+            SearchBuffer::Spilled { .. } => Err(LdapError::InvalidProtocolState),
+        }
+    }
+
+    /// Run a search, buffering at most `max_entries` entries. If the search
+    /// returns more than that, the buffered entries are handed back and the
+    /// remainder must be read with [Self::next_search_item].
+    pub async fn search_buffered(
+        &mut self,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+        max_entries: Option<usize>,
+    ) -> Result<SearchBuffer, LdapError> {
+        let ck_msgid = self.send_search(sr, ctrl).await?;
+
         let mut entries = Vec::new();
+        let mut references = Vec::new();
         loop {
-            match self.r.next().await {
-                // This terminates the iteration of entries.
-                Some(Ok(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultDone(search_res),
-                    ctrl,
-                })) => {
-                    if msgid == ck_msgid {
-                        break Ok((entries, search_res, ctrl));
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
+            match self.next_search_item(ck_msgid).await? {
+                SearchItem::Entry(entry, ctrl) => {
+                    entries.push((entry, ctrl));
+                    if max_entries.is_some_and(|max| entries.len() > max) {
+                        break Ok(SearchBuffer::Spilled {
+                            msgid: ck_msgid,
+                            entries,
+                            references,
+                        });
                     }
                 }
-                Some(Ok(LdapMsg {
-                    msgid,
-                    op: LdapOp::SearchResultEntry(search_entry),
-                    ctrl,
-                })) => {
-                    if msgid == ck_msgid {
-                        entries.push((search_entry, ctrl))
-                    } else {
-                        error!("invalid msgid, sequence error.");
-                        break Err(LdapError::InvalidProtocolState);
-                    }
+                SearchItem::Reference(reference, ctrl) => {
+                    references.push((reference, ctrl));
                 }
-                Some(Ok(msg)) => {
-                    trace!(?msg);
-                    break Err(LdapError::InvalidProtocolState);
-                }
-                Some(Err(e)) => {
-                    error!(?e, "unable to receive from ldap server");
-                    break Err(LdapError::Transport);
+                // Only meaningful while the search runs, so there is
+                // nothing to keep for the cache.
+                SearchItem::Intermediate(response, _) => {
+                    warn!(?response, "Skipping intermediate response to a buffered search");
                 }
-                None => {
-                    error!("connection closed");
-                    break Err(LdapError::Transport);
+                SearchItem::Done(result, ctrl) => {
+                    break Ok(SearchBuffer::Complete {
+                        entries,
+                        references,
+                        result,
+                        ctrl,
+                    })
                 }
             }
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,162 @@
+//! Reading the PROXY protocol v2 header a load balancer sends ahead of a
+//! client connection, including the TLV extensions after the addresses
+//! that `haproxy_protocol` parses over.
+
+use crate::ProxyProtocolMode;
+use haproxy_protocol::{AsyncReadError, Error, ProxyHdrV2, RemoteAddress};
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncRead, AsyncReadExt};
+use tracing::{debug, error};
+
+/// The signature, version and command, family and length.
+const HDR_FIXED_LEN: usize = 16;
+/// Larger than the limit of `ProxyHdrV2::parse_from_read`, as the SSL TLV
+/// alone can take a few hundred bytes.
+const HDR_SIZE_LIMIT: usize = 4096;
+
+const PP2_TYPE_SSL: u8 = 0x20;
+const PP2_SUBTYPE_SSL_CN: u8 = 0x22;
+const PP2_CLIENT_SSL: u8 = 0x01;
+const PP2_CLIENT_CERT_CONN: u8 = 0x02;
+
+/// The starts of PROXY v2 and v1 headers.
+const HDR_SIGNATURES: [&[u8]; 2] = [b"\r\n\r\n\0\r\nQUIT\n", b"PROXY "];
+
+#[derive(Debug, Clone)]
+pub struct ProxyHeader {
+    pub remote_addr: RemoteAddress,
+    /// The CN of the certificate the client presented to the load balancer,
+    /// when it terminated TLS and verified that certificate.
+    pub ssl_client_cn: Option<String>,
+}
+
+/// Why a connection was refused for its PROXY header.
+#[derive(Debug)]
+pub enum RefusedHeader {
+    /// The header was required, and could not be read.
+    Read(AsyncReadError),
+    /// The header was forbidden, and the connection started with one.
+    Forbidden,
+}
+
+/// Take the PROXY header `mode` calls for from the start of `stream`. Without
+/// a mode nothing is read.
+pub async fn accept_proxy_header<S: AsyncBufRead + Unpin>(
+    stream: &mut S,
+    mode: Option<ProxyProtocolMode>,
+) -> Result<Option<ProxyHeader>, RefusedHeader> {
+    match mode {
+        None => Ok(None),
+        Some(ProxyProtocolMode::Require) => read_proxy_v2(stream)
+            .await
+            .map(|(_, hdr)| Some(hdr))
+            .map_err(RefusedHeader::Read),
+        Some(ProxyProtocolMode::Forbid) => {
+            // Peeked, so that the bytes are left for the TLS handshake.
+            let buf = stream
+                .fill_buf()
+                .await
+                .map_err(|e| RefusedHeader::Read(AsyncReadError::Io(e)))?;
+            let starts_with_header = HDR_SIGNATURES.iter().any(|sig| {
+                let len = buf.len().min(sig.len());
+                len > 0 && buf[..len] == sig[..len]
+            });
+            if starts_with_header {
+                return Err(RefusedHeader::Forbidden);
+            }
+            Ok(None)
+        }
+    }
+}
+
+/// Read a PROXY v2 header from the start of `stream`, leaving the stream
+/// at the first byte the client sent.
+pub async fn read_proxy_v2<S: AsyncRead + Unpin>(
+    mut stream: S,
+) -> Result<(S, ProxyHeader), AsyncReadError> {
+    let mut buf = vec![0; HDR_FIXED_LEN];
+    stream
+        .read_exact(&mut buf)
+        .await
+        .map_err(AsyncReadError::Io)?;
+
+    match ProxyHdrV2::parse(&buf) {
+        Ok(_) => {}
+        Err(Error::Incomplete { need }) => {
+            let resize_to = buf.len() + usize::from(need);
+            if resize_to > HDR_SIZE_LIMIT {
+                error!(
+                    "proxy header request was larger than {} bytes, refusing to proceed.",
+                    HDR_SIZE_LIMIT
+                );
+                return Err(AsyncReadError::RequestTooLarge);
+            }
+            buf.resize(resize_to, 0);
+            stream
+                .read_exact(&mut buf[HDR_FIXED_LEN..])
+                .await
+                .map_err(AsyncReadError::Io)?;
+        }
+        Err(Error::Invalid) => return Err(AsyncReadError::Invalid),
+        Err(Error::UnableToComplete) => return Err(AsyncReadError::UnableToComplete),
+    }
+
+    let hdr = match ProxyHdrV2::parse(&buf) {
+        Ok((took, hdr)) if took == buf.len() => hdr,
+        Ok(_) => return Err(AsyncReadError::InconsistentRead),
+        Err(Error::Invalid) => return Err(AsyncReadError::Invalid),
+        Err(_) => return Err(AsyncReadError::UnableToComplete),
+    };
+
+    // The addresses have a fixed size for each family, the TLVs fill the
+    // rest of the header.
+    let address_len = match buf[13] >> 4 {
+        0x1 => 12,
+        0x2 => 36,
+        0x3 => 216,
+        _ => 0,
+    };
+    let tlvs = buf.get(HDR_FIXED_LEN + address_len..).unwrap_or_default();
+
+    Ok((
+        stream,
+        ProxyHeader {
+            remote_addr: hdr.to_remote_addr(),
+            ssl_client_cn: ssl_client_cn(tlvs),
+        },
+    ))
+}
+
+/// Split `data` into its type, value pairs, stopping at the first one that
+/// runs past the end.
+fn tlvs(mut data: &[u8]) -> impl Iterator<Item = (u8, &[u8])> {
+    std::iter::from_fn(move || {
+        let (&kind, rest) = data.split_first()?;
+        let len = u16::from_be_bytes(rest.get(..2)?.try_into().ok()?) as usize;
+        let value = rest.get(2..2 + len)?;
+        data = &rest[2 + len..];
+        Some((kind, value))
+    })
+}
+
+/// The client certificate CN from the SSL TLV, if the client presented a
+/// certificate on this connection and the load balancer verified it.
+fn ssl_client_cn(data: &[u8]) -> Option<String> {
+    let (_, ssl) = tlvs(data).find(|(kind, _)| *kind == PP2_TYPE_SSL)?;
+    // One byte of client flags and four of verify result, then sub-TLVs.
+    let (&client, rest) = ssl.split_first()?;
+    let verify = u32::from_be_bytes(rest.get(..4)?.try_into().ok()?);
+    let (_, cn) = tlvs(&rest[4..]).find(|(kind, _)| *kind == PP2_SUBTYPE_SSL_CN)?;
+
+    let presented = PP2_CLIENT_SSL | PP2_CLIENT_CERT_CONN;
+    if client & presented != presented || verify != 0 {
+        debug!(client, verify, "ignoring unverified client certificate CN");
+        return None;
+    }
+    match std::str::from_utf8(cn) {
+        Ok(cn) => Some(cn.to_string()),
+        Err(_) => {
+            debug!("ignoring client certificate CN that is not utf8");
+            None
+        }
+    }
+}
@@ -0,0 +1,197 @@
+//! Formatting of protocol messages for logging, with bind credentials and
+//! the values of sensitive attributes masked.
+
+use ldap3_proto::proto::*;
+use std::collections::HashSet;
+use std::fmt;
+use std::sync::OnceLock;
+
+const MASK: &str = "***";
+
+static SENSITIVE_ATTRIBUTES: OnceLock<HashSet<String>> = OnceLock::new();
+
+pub fn default_sensitive_attributes() -> Vec<String> {
+    [
+        "userPassword",
+        "unicodePwd",
+        "sambaNTPassword",
+        "sambaLMPassword",
+        "ipaNTHash",
+        "krbPrincipalKey",
+        "oathTOTPToken",
+        "oathHOTPToken",
+    ]
+    .into_iter()
+    .map(str::to_string)
+    .collect()
+}
+
+fn lowercase_set(attributes: &[String]) -> HashSet<String> {
+    attributes.iter().map(|a| a.to_ascii_lowercase()).collect()
+}
+
+/// Set the attribute types masked by `redact`. This can only be set once, at
+/// startup, and `default_sensitive_attributes` is used if it never is.
+pub fn set_sensitive_attributes(attributes: &[String]) {
+    if SENSITIVE_ATTRIBUTES.set(lowercase_set(attributes)).is_err() {
+        tracing::warn!("Sensitive attributes are already set, ignoring");
+    }
+}
+
+fn sensitive_attributes() -> &'static HashSet<String> {
+    SENSITIVE_ATTRIBUTES.get_or_init(|| lowercase_set(&default_sensitive_attributes()))
+}
+
+/// A value whose `Debug` output is safe to log.
+pub struct Redacted<'a, T> {
+    inner: &'a T,
+    sensitive: &'a HashSet<String>,
+}
+
+/// Wrap `inner` for logging, masking the configured sensitive attributes.
+pub fn redact<T>(inner: &T) -> Redacted<'_, T> {
+    Redacted {
+        inner,
+        sensitive: sensitive_attributes(),
+    }
+}
+
+impl<'a, T> Redacted<'a, T> {
+    /// Wrap `inner` for logging, masking the attribute types in `sensitive`,
+    /// which must be lower case.
+    pub fn with_attributes(inner: &'a T, sensitive: &'a HashSet<String>) -> Self {
+        Redacted { inner, sensitive }
+    }
+
+    fn wrap<'b, U>(&'b self, inner: &'b U) -> Redacted<'b, U> {
+        Redacted {
+            inner,
+            sensitive: self.sensitive,
+        }
+    }
+}
+
+impl<T> fmt::Debug for Redacted<'_, Vec<T>>
+where
+    for<'b> Redacted<'b, T>: fmt::Debug,
+{
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.inner.iter().map(|item| self.wrap(item)))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapMsg> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LdapMsg")
+            .field("msgid", &self.inner.msgid)
+            .field("op", &self.wrap(&self.inner.op))
+            .field("ctrl", &self.inner.ctrl)
+            .finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapOp> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.inner {
+            LdapOp::BindRequest(lbr) => f.debug_tuple("BindRequest").field(&self.wrap(lbr)).finish(),
+            LdapOp::SearchResultEntry(entry) => f
+                .debug_tuple("SearchResultEntry")
+                .field(&self.wrap(entry))
+                .finish(),
+            LdapOp::AddRequest(req) => f
+                .debug_tuple("AddRequest")
+                .field(&format_args!(
+                    "LdapAddRequest {{ dn: {:?}, attributes: {:?} }}",
+                    req.dn,
+                    self.wrap(&req.attributes)
+                ))
+                .finish(),
+            LdapOp::ModifyRequest(req) => f
+                .debug_tuple("ModifyRequest")
+                .field(&format_args!(
+                    "LdapModifyRequest {{ dn: {:?}, changes: {:?} }}",
+                    req.dn,
+                    self.wrap(&req.changes)
+                ))
+                .finish(),
+            LdapOp::CompareRequest(req) if self.sensitive.contains(&req.atype.to_ascii_lowercase()) => f
+                .debug_tuple("CompareRequest")
+                .field(&format_args!(
+                    "LdapCompareRequest {{ dn: {:?}, atype: {:?}, val: {:?} }}",
+                    req.dn, req.atype, MASK
+                ))
+                .finish(),
+            // Extended operations such as password modify carry secrets in
+            // their values.
+            LdapOp::ExtendedRequest(req) => f
+                .debug_tuple("ExtendedRequest")
+                .field(&format_args!(
+                    "LdapExtendedRequest {{ name: {:?}, value: {:?} }}",
+                    req.name,
+                    req.value.as_ref().map(|_| MASK)
+                ))
+                .finish(),
+            op => op.fmt(f),
+        }
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapBindRequest> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("LdapBindRequest");
+        f.field("dn", &self.inner.dn);
+        match &self.inner.cred {
+            LdapBindCred::Simple(_) => f.field("cred", &format_args!("Simple({:?})", MASK)),
+            LdapBindCred::SASL(sasl) => f.field(
+                "cred",
+                &format_args!(
+                    "SASL {{ mechanism: {:?}, credentials: {:?} }}",
+                    sasl.mechanism, MASK
+                ),
+            ),
+        };
+        f.finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapSearchResultEntry> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LdapSearchResultEntry")
+            .field("dn", &self.inner.dn)
+            .field("attributes", &self.wrap(&self.inner.attributes))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapModify> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LdapModify")
+            .field("operation", &self.inner.operation)
+            .field("modification", &self.wrap(&self.inner.modification))
+            .finish()
+    }
+}
+
+impl fmt::Debug for Redacted<'_, LdapPartialAttribute> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut f = f.debug_struct("LdapPartialAttribute");
+        f.field("atype", &self.inner.atype);
+        if self
+            .sensitive
+            .contains(&self.inner.atype.to_ascii_lowercase())
+        {
+            f.field("vals", &[MASK]);
+        } else {
+            let vals: Vec<_> = self
+                .inner
+                .vals
+                .iter()
+                .map(|val| String::from_utf8_lossy(val))
+                .collect();
+            f.field("vals", &vals);
+        }
+        f.finish()
+    }
+}
@@ -0,0 +1,85 @@
+use crate::{AddressPreference, AppState};
+use std::io;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+use url::Url;
+
+const LDAPS_PORT: u16 = 636;
+
+/// Order resolved backend addresses by preference, dropping duplicates. The
+/// order within each address family is kept as returned by the resolver.
+pub fn order_addrs(
+    resolved: impl IntoIterator<Item = SocketAddr>,
+    preference: AddressPreference,
+) -> Vec<SocketAddr> {
+    let mut addrs: Vec<SocketAddr> = Vec::new();
+    for addr in resolved {
+        if !addrs.contains(&addr) {
+            addrs.push(addr);
+        }
+    }
+
+    match preference {
+        AddressPreference::System => {}
+        AddressPreference::Ipv4First => addrs.sort_by_key(|a| a.is_ipv6()),
+        AddressPreference::Ipv6First => addrs.sort_by_key(|a| a.is_ipv4()),
+    }
+    addrs
+}
+
+/// Resolve every address (both A and AAAA records) of the backend in
+/// `ldap_url`.
+pub fn resolve_backend_addrs(
+    url: &Url,
+    preference: AddressPreference,
+) -> io::Result<Vec<SocketAddr>> {
+    let resolved = url.socket_addrs(|| Some(LDAPS_PORT))?;
+    Ok(order_addrs(resolved, preference))
+}
+
+/// Re-resolve `url` into `current`, keeping the previous addresses on
+/// failure.
+async fn refresh_addrs(current: &RwLock<Vec<SocketAddr>>, url: &Url, preference: AddressPreference) {
+    let c_url = url.clone();
+    let resolved =
+        tokio::task::spawn_blocking(move || resolve_backend_addrs(&c_url, preference)).await;
+
+    match resolved {
+        Ok(Ok(addrs)) if !addrs.is_empty() => {
+            let mut current = current.write().unwrap();
+            if *current != addrs {
+                info!(%url, ?addrs, "Backend addresses changed");
+                *current = addrs;
+            } else {
+                debug!(%url, "Backend addresses unchanged");
+            }
+        }
+        Ok(Ok(_)) => warn!(%url, "url address resolved to no addresses, keeping previous"),
+        Ok(Err(e)) => warn!(%url, ?e, "url address resolver error, keeping previous"),
+        Err(e) => warn!(?e, "url address resolver task failed"),
+    }
+}
+
+/// Periodically re-resolve every backend so that DNS changes are picked up
+/// without a restart. On failure the previous addresses are kept.
+pub async fn run_refresh(
+    app_state: Arc<AppState>,
+    url: Url,
+    preference: AddressPreference,
+    refresh: Duration,
+) {
+    let mut interval = tokio::time::interval(refresh);
+    // The first tick completes immediately, and startup already resolved.
+    interval.tick().await;
+
+    loop {
+        interval.tick().await;
+
+        refresh_addrs(&app_state.addrs, &url, preference).await;
+        for backend in &app_state.routed_backends {
+            refresh_addrs(&backend.addrs, &backend.ldap_url, preference).await;
+        }
+    }
+}
@@ -0,0 +1,179 @@
+use crate::dn::normalize_dn;
+use crate::proxy::BasicLdapClient;
+use crate::AppState;
+use ldap3_proto::proto::{
+    LdapDerefAliases, LdapFilter, LdapResultCode, LdapSearchRequest, LdapSearchResultEntry,
+    LdapSearchScope,
+};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use tracing::{debug, info, warn};
+
+/// Used when the root dse does not name the subschema subentry.
+const DEFAULT_SUBSCHEMA_DN: &str = "cn=subschema";
+const STARTUP_RETRY: Duration = Duration::from_secs(5);
+
+/// The backend's subschema subentry, fetched once at startup and refreshed
+/// periodically, so that schema searches are answered without querying the
+/// backend.
+#[derive(Default)]
+pub struct SchemaSnapshot {
+    entry: RwLock<Option<LdapSearchResultEntry>>,
+}
+
+fn base_search(base: &str, filter: LdapFilter, attrs: Vec<String>) -> LdapSearchRequest {
+    LdapSearchRequest {
+        base: base.to_string(),
+        scope: LdapSearchScope::Base,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter,
+        attrs,
+    }
+}
+
+/// A filter a schema probe may use, `(objectClass=*)` or
+/// `(objectClass=subschema)`.
+fn is_schema_filter(filter: &LdapFilter) -> bool {
+    match filter {
+        LdapFilter::Present(attr) => attr.eq_ignore_ascii_case("objectclass"),
+        LdapFilter::Equality(attr, value) => {
+            attr.eq_ignore_ascii_case("objectclass") && value.eq_ignore_ascii_case("subschema")
+        }
+        _ => false,
+    }
+}
+
+impl SchemaSnapshot {
+    /// Answer `sr` from the snapshot if it is a base scoped search of the
+    /// subschema subentry, honouring the attribute selection of the request.
+    /// Returns None when there is no snapshot yet or `sr` is another search.
+    pub fn search(&self, sr: &LdapSearchRequest) -> Option<LdapSearchResultEntry> {
+        if sr.scope != LdapSearchScope::Base || !is_schema_filter(&sr.filter) {
+            return None;
+        }
+        let entry = self.entry.read().unwrap();
+        let entry = entry.as_ref()?;
+        if !normalize_dn(&sr.base)
+            .ok()?
+            .eq_ignore_ascii_case(&normalize_dn(&entry.dn).ok()?)
+        {
+            return None;
+        }
+
+        let all = sr.attrs.is_empty() || sr.attrs.iter().any(|a| a == "*" || a == "+");
+        let attributes = entry
+            .attributes
+            .iter()
+            .filter(|attr| all || sr.attrs.iter().any(|a| a.eq_ignore_ascii_case(&attr.atype)))
+            .cloned()
+            .collect();
+        Some(LdapSearchResultEntry {
+            dn: entry.dn.clone(),
+            attributes,
+        })
+    }
+
+    /// Fetch the subschema subentry named by the backend's root dse,
+    /// anonymously, replacing the snapshot. The previous snapshot is kept on
+    /// failure. Returns whether the snapshot was replaced.
+    pub async fn refresh(&self, app_state: &AppState) -> bool {
+        let backend = app_state.backend_for("");
+        let mut client = match BasicLdapClient::build(
+            &backend.addrs(),
+            backend.tls_params,
+            backend.tls_server_name,
+            app_state.max_proxy_ber_size,
+            &app_state.connect_timeouts,
+        )
+        .await
+        {
+            Ok(c) => c,
+            Err(e) => {
+                warn!(
+                    ?e,
+                    "Backend is unreachable, keeping the previous schema snapshot"
+                );
+                return false;
+            }
+        };
+
+        let root_dse = base_search(
+            "",
+            LdapFilter::Present("objectClass".to_string()),
+            vec!["subschemaSubentry".to_string()],
+        );
+        let subschema_dn = match client.search(root_dse, vec![]).await {
+            Ok((entries, _, _)) => entries
+                .iter()
+                .flat_map(|(entry, _)| entry.attributes.iter())
+                .find(|attr| attr.atype.eq_ignore_ascii_case("subschemaSubentry"))
+                .and_then(|attr| attr.vals.first())
+                .and_then(|val| String::from_utf8(val.clone()).ok()),
+            Err(e) => {
+                warn!(
+                    ?e,
+                    "Unable to read the root dse, keeping the previous schema snapshot"
+                );
+                return false;
+            }
+        };
+        let subschema_dn = subschema_dn.unwrap_or_else(|| DEFAULT_SUBSCHEMA_DN.to_string());
+
+        let subschema = base_search(
+            &subschema_dn,
+            LdapFilter::Equality("objectClass".to_string(), "subschema".to_string()),
+            vec!["*".to_string(), "+".to_string()],
+        );
+        match client.search(subschema, vec![]).await {
+            Ok((mut entries, result, _)) if result.code == LdapResultCode::Success => {
+                match entries.pop() {
+                    Some((entry, _)) => {
+                        debug!(dn = %entry.dn, "Schema snapshot refreshed");
+                        *self.entry.write().unwrap() = Some(entry);
+                        true
+                    }
+                    None => {
+                        warn!(dn = %subschema_dn, "Subschema subentry not found");
+                        false
+                    }
+                }
+            }
+            Ok((_, result, _)) => {
+                warn!(code = ?result.code, dn = %subschema_dn, "Unable to read the subschema subentry");
+                false
+            }
+            Err(e) => {
+                warn!(?e, dn = %subschema_dn, "Unable to read the subschema subentry");
+                false
+            }
+        }
+    }
+}
+
+/// Fetch the schema snapshot now and then every `refresh`. Until the first
+/// fetch succeeds it is retried shortly, as the backend may still be starting.
+pub async fn run_refresh(
+    app_state: Arc<AppState>,
+    snapshot: Arc<SchemaSnapshot>,
+    refresh: Duration,
+) {
+    while !snapshot.refresh(&app_state).await {
+        tokio::time::sleep(STARTUP_RETRY).await;
+    }
+    info!("Schema snapshot fetched from the backend");
+
+    let mut interval = tokio::time::interval(refresh);
+    // The first tick completes immediately, and the snapshot was just fetched.
+    interval.tick().await;
+    loop {
+        interval.tick().await;
+        if app_state.in_maintenance() {
+            debug!("In maintenance mode, skipping the schema refresh");
+            continue;
+        }
+        snapshot.refresh(&app_state).await;
+    }
+}
@@ -0,0 +1,55 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+/// The number of bound sessions for each DN, for enforcing `max_sessions`.
+#[derive(Default)]
+pub struct SessionCounts {
+    counts: Mutex<HashMap<String, usize>>,
+}
+
+impl SessionCounts {
+    /// Count a session for `dn`, unless it already has `max` sessions. The
+    /// session is counted until the returned guard is dropped.
+    pub fn try_acquire(self: &Arc<Self>, dn: &str, max: usize) -> Option<SessionGuard> {
+        let mut counts = self.counts.lock().unwrap();
+        let count = counts.entry(dn.to_string()).or_default();
+        if *count >= max {
+            return None;
+        }
+        *count += 1;
+        Some(SessionGuard {
+            counts: self.clone(),
+            dn: dn.to_string(),
+        })
+    }
+
+    /// The number of sessions currently counted for `dn`.
+    pub fn count(&self, dn: &str) -> usize {
+        self.counts.lock().unwrap().get(dn).copied().unwrap_or(0)
+    }
+}
+
+/// A session counted towards the limit of its DN, released on drop so the
+/// count stays right however the session ends.
+pub struct SessionGuard {
+    counts: Arc<SessionCounts>,
+    dn: String,
+}
+
+impl SessionGuard {
+    pub fn dn(&self) -> &str {
+        &self.dn
+    }
+}
+
+impl Drop for SessionGuard {
+    fn drop(&mut self) {
+        let mut counts = self.counts.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&self.dn) {
+            *count -= 1;
+            if *count == 0 {
+                counts.remove(&self.dn);
+            }
+        }
+    }
+}
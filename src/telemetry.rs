@@ -0,0 +1,61 @@
+//! Export of the spans of client operations to an OpenTelemetry collector
+//! over OTLP, when `[tracing]` is configured.
+
+use crate::TracingConfig;
+use ldap3_proto::LdapResultCode;
+use opentelemetry_otlp::{ExporterBuildError, SpanExporter, WithExportConfig};
+use opentelemetry_sdk::trace::SdkTracerProvider;
+use opentelemetry_sdk::Resource;
+use serde::Deserialize;
+use std::time::Duration;
+use tracing::Span;
+
+#[derive(Deserialize)]
+struct TracingSection {
+    #[serde(default)]
+    tracing: Option<TracingConfig>,
+}
+
+/// Read only the `[tracing]` section of a config file, so that the exporter
+/// can be installed before the rest of the config is loaded.
+pub fn tracing_config(contents: &str) -> Result<Option<TracingConfig>, toml::de::Error> {
+    Ok(toml::from_str::<TracingSection>(contents)?.tracing)
+}
+
+/// A provider exporting spans in batches to `config.otlp_endpoint`. Nothing
+/// is sent until spans are recorded, so the collector need not be up yet.
+pub fn tracer_provider(config: &TracingConfig) -> Result<SdkTracerProvider, ExporterBuildError> {
+    let exporter = SpanExporter::builder()
+        .with_http()
+        .with_endpoint(config.otlp_endpoint.as_str())
+        .with_timeout(Duration::from_secs(config.export_timeout_secs))
+        .build()?;
+    Ok(SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build())
+}
+
+/// Record the result code of the operation on the current span.
+pub fn record_result(code: &LdapResultCode) {
+    Span::current().record("result_code", crate::metrics::result_code_label(code));
+}
+
+/// Record how the cache took part in the current search: `hit` when a fresh
+/// entry was served without the backend, `fallback` when an entry was served
+/// because the backend failed, `raced` when an entry was served because the
+/// backend was slower than `cache_race_timeout_ms`, `coalesced` when an
+/// identical search in flight answered it, and `miss` when the backend
+/// answered.
+pub fn record_cache(disposition: &'static str) {
+    Span::current().record("cache", disposition);
+}
+
+/// Record how long the backend took to answer the current operation.
+pub fn record_backend_latency(latency: Duration) {
+    Span::current().record("backend_latency_ms", latency.as_millis() as u64);
+}
@@ -0,0 +1,211 @@
+//! TLS session resumption for backend connections, so that reconnecting to a
+//! backend can skip the full handshake, and the TLS setup for client
+//! connections.
+
+use openssl::asn1::Asn1Time;
+use openssl::error::ErrorStack;
+use openssl::ex_data::Index;
+use openssl::pkey::PKey;
+use openssl::ssl::{
+    Ssl, SslAcceptor, SslConnectorBuilder, SslContext, SslContextRef, SslMethod, SslRef,
+    SslSession, SslSessionCacheMode,
+};
+use openssl::x509::{X509Ref, X509};
+use std::collections::HashMap;
+use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Mutex, OnceLock};
+use tracing::{trace, warn};
+
+/// Warn at startup when the server certificate expires within this many
+/// days.
+const EXPIRY_WARNING_DAYS: i32 = 30;
+
+/// The latest session each backend issued, keyed by its address and server
+/// name. It is kept in the ex data of the connector's context, so a session
+/// is only ever resumed with the context that created it.
+///
+/// Sessions are held in their encoded form, since OpenSSL marks the session
+/// of a connection that is dropped without a TLS shutdown as not resumable.
+#[derive(Default)]
+pub struct TlsSessionCache {
+    sessions: Mutex<HashMap<String, Vec<u8>>>,
+    resumed: AtomicU64,
+    full: AtomicU64,
+}
+
+impl TlsSessionCache {
+    /// The number of handshakes that resumed a session.
+    pub fn resumed_count(&self) -> u64 {
+        self.resumed.load(Ordering::Relaxed)
+    }
+
+    /// The number of full handshakes.
+    pub fn full_count(&self) -> u64 {
+        self.full.load(Ordering::Relaxed)
+    }
+}
+
+#[derive(Clone, Copy)]
+struct Indexes {
+    cache: Index<SslContext, TlsSessionCache>,
+    key: Index<Ssl, String>,
+}
+
+fn indexes() -> Result<Indexes, ErrorStack> {
+    static INDEXES: OnceLock<Result<Indexes, ErrorStack>> = OnceLock::new();
+    INDEXES
+        .get_or_init(|| {
+            Ok(Indexes {
+                cache: SslContext::new_ex_index()?,
+                key: Ssl::new_ex_index()?,
+            })
+        })
+        .clone()
+}
+
+/// Keep the sessions backends issue to connections made with `builder`, and
+/// offer them again on the next connection to the same backend.
+pub fn enable_session_resumption(builder: &mut SslConnectorBuilder) -> Result<(), ErrorStack> {
+    let indexes = indexes()?;
+    // Sessions are only stored by the callback, as the internal cache is
+    // never consulted by clients.
+    builder.set_session_cache_mode(
+        SslSessionCacheMode::CLIENT | SslSessionCacheMode::NO_INTERNAL_STORE,
+    );
+    builder.set_ex_data(indexes.cache, TlsSessionCache::default());
+    builder.set_new_session_callback(move |ssl, session| {
+        let cache = ssl.ssl_context().ex_data(indexes.cache);
+        if let (Some(cache), Some(key)) = (cache, ssl.ex_data(indexes.key)) {
+            match session.to_der() {
+                Ok(der) => {
+                    trace!(%key, "Storing backend tls session");
+                    cache.sessions.lock().unwrap().insert(key.clone(), der);
+                }
+                Err(e) => trace!(?e, %key, "Unable to encode the backend tls session"),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// The session cache of `context`, if resumption is enabled on it.
+pub fn session_cache(context: &SslContextRef) -> Option<&TlsSessionCache> {
+    context.ex_data(indexes().ok()?.cache)
+}
+
+/// Offer the stored session for `key` on `ssl` before it connects, and
+/// remember `key` so a new session is stored under it.
+pub fn prepare_resumption(ssl: &mut SslRef, key: String) {
+    let Ok(indexes) = indexes() else {
+        return;
+    };
+    let Some(cache) = ssl.ssl_context().ex_data(indexes.cache) else {
+        return;
+    };
+    let session = cache
+        .sessions
+        .lock()
+        .unwrap()
+        .get(&key)
+        .and_then(|der| SslSession::from_der(der).ok());
+    if let Some(session) = session {
+        // SAFETY: the session was issued to a connection made with this same
+        // context, as each context holds its own cache.
+        if let Err(e) = unsafe { ssl.set_session(&session) } {
+            trace!(?e, %key, "Unable to offer the stored tls session");
+        }
+    }
+    ssl.set_ex_data(indexes.key, key);
+}
+
+/// Count whether the completed handshake on `ssl` resumed a session.
+pub fn record_handshake(ssl: &SslRef) {
+    if let Some(cache) = session_cache(ssl.ssl_context()) {
+        if ssl.session_reused() {
+            cache.resumed.fetch_add(1, Ordering::Relaxed);
+        } else {
+            cache.full.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+/// Build the acceptor for client connections from the certificate chain and
+/// private key. The chain must not be empty, its leaf certificate must not
+/// have expired and the key must belong to it, so that a bad pair is found
+/// at startup rather than by the first client handshake.
+pub fn server_tls_acceptor(tls_chain: &Path, tls_key: &Path) -> Result<SslAcceptor, String> {
+    let pem = std::fs::read(tls_chain).map_err(|e| {
+        format!(
+            "Unable to load certificate chain {} -> {:?}",
+            tls_chain.display(),
+            e
+        )
+    })?;
+    let chain = X509::stack_from_pem(&pem).map_err(|e| {
+        format!(
+            "Unable to load certificate chain {} -> {:?}",
+            tls_chain.display(),
+            e
+        )
+    })?;
+    let Some(leaf) = chain.first() else {
+        return Err(format!(
+            "Certificate chain {} holds no certificates",
+            tls_chain.display()
+        ));
+    };
+    check_expiry(leaf, tls_chain)?;
+
+    let key = std::fs::read(tls_key)
+        .map_err(|e| e.to_string())
+        .and_then(|pem| PKey::private_key_from_pem(&pem).map_err(|e| format!("{:?}", e)))
+        .map_err(|e| format!("Unable to load private key {} -> {}", tls_key.display(), e))?;
+    if !leaf.public_key().is_ok_and(|public| public.public_eq(&key)) {
+        return Err(format!(
+            "Private key {} does not match the certificate in {}",
+            tls_key.display(),
+            tls_chain.display()
+        ));
+    }
+
+    let mut tls_builder = SslAcceptor::mozilla_intermediate_v5(SslMethod::tls())
+        .map_err(|e| format!("Unable to create tls acceptor -> {:?}", e))?;
+
+    tls_builder
+        .set_certificate_chain_file(tls_chain)
+        .map_err(|e| format!("Unable to load certificate chain -> {:?}", e))?;
+
+    tls_builder
+        .set_private_key(&key)
+        .map_err(|e| format!("Unable to load private key -> {:?}", e))?;
+
+    tls_builder
+        .check_private_key()
+        .map_err(|e| format!("Unable to validate private key -> {:?}", e))?;
+
+    Ok(tls_builder.build())
+}
+
+/// Fail if `leaf` has expired, and warn if it expires soon.
+fn check_expiry(leaf: &X509Ref, tls_chain: &Path) -> Result<(), String> {
+    let remaining = Asn1Time::days_from_now(0)
+        .and_then(|now| now.diff(leaf.not_after()))
+        .map_err(|e| format!("Unable to check certificate expiry -> {:?}", e))?;
+    if remaining.days < 0 || remaining.secs < 0 {
+        return Err(format!(
+            "Certificate in {} expired on {}",
+            tls_chain.display(),
+            leaf.not_after()
+        ));
+    }
+    if remaining.days < EXPIRY_WARNING_DAYS {
+        warn!(
+            expires = %leaf.not_after(),
+            days_left = remaining.days,
+            "Certificate in {} expires soon",
+            tls_chain.display()
+        );
+    }
+    Ok(())
+}
@@ -0,0 +1,1004 @@
+#![allow(dead_code)]
+
+use concread::arcache::ARCacheBuilder;
+use futures_util::sink::SinkExt;
+use futures_util::stream::StreamExt;
+use ldap3_proto::control::LdapControl;
+use ldap3_proto::proto::*;
+use ldap3_proto::LdapCodec;
+use ldap_proxy::codec::{BackendCodec, ClientCodec, ResponseWithControl};
+use ldap_proxy::offline::OfflineBindCache;
+use ldap_proxy::pool::BackendPool;
+use ldap_proxy::proxy::{self, CachedValue, ClientAddress, SearchCacheKey};
+use ldap_proxy::schema::SchemaSnapshot;
+use ldap_proxy::tls;
+use ldap_proxy::{AddrInfoSource, AppState, CacheBackend, Config, RoutedBackend};
+use openssl::asn1::Asn1Time;
+use openssl::bn::BigNum;
+use openssl::ec::{EcGroup, EcKey};
+use openssl::hash::MessageDigest;
+use openssl::nid::Nid;
+use openssl::pkey::{PKey, Private};
+use openssl::ssl::{
+    NameType, Ssl, SslAcceptor, SslConnector, SslConnectorBuilder, SslMethod, SslVerifyMode,
+};
+use openssl::x509::extension::SubjectAlternativeName;
+use openssl::x509::{X509Builder, X509NameBuilder, X509};
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll};
+use std::time::Duration;
+use tokio::io::{AsyncWrite, AsyncWriteExt, DuplexStream, ReadHalf, WriteHalf};
+use tokio::net::TcpListener;
+use tokio::task::JoinHandle;
+use tokio_openssl::SslStream;
+use tokio_util::codec::{FramedRead, FramedWrite};
+
+pub const BASE_CONFIG: &str = r#"
+    bind = "127.0.0.1:3636"
+    tls_chain = "/etc/ldap-proxy/chain.pem"
+    tls_key = "/etc/ldap-proxy/key.pem"
+    ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+    ldap_url = "ldaps://ldap.example.com"
+"#;
+
+/// Build an `AppState` from a config snippet appended to `BASE_CONFIG`. The
+/// backend addresses and TLS parameters are supplied by the caller.
+pub fn app_state(extra_config: &str, addrs: Vec<SocketAddr>, tls_params: SslConnector) -> AppState {
+    let config_str = format!("{}\n{}", BASE_CONFIG, extra_config);
+    let config = toml::from_str::<Config>(&config_str).expect("Failed to parse config");
+
+    let breaker = config.circuit_breaker();
+    let offline_bind = config.offline_bind_cache();
+    let bind_cache = config.bind_cache();
+    let connect_timeouts = config.connect_timeouts();
+    let tls_server_name = config.backend_tls_name().map(str::to_string);
+    // The mock backend serves every referral host.
+    let referral_connectors = config
+        .referral_hosts
+        .iter()
+        .map(|host| (host.to_lowercase(), tls_params.clone()))
+        .collect();
+    let cache = ARCacheBuilder::new()
+        .set_size(1024 * 1024, 0)
+        .build()
+        .expect("Failed to build cache");
+
+    AppState {
+        tls_params,
+        tls_server_name,
+        addrs: RwLock::new(addrs),
+        routed_backends: Vec::new(),
+        binddn_map: config.binddn_map,
+        cache: CacheBackend::Memory(Arc::new(cache)),
+        cache_ttl: None,
+        cache_key_prefix: "ldap_proxy:".to_string(),
+        max_incoming_ber_size: config.max_incoming_ber_size,
+        max_proxy_ber_size: config.max_proxy_ber_size,
+        max_buffered_entries: config.max_buffered_entries,
+        response_flush_entries: config.response_flush_entries.get(),
+        allow_all_bind_dns: config.allow_all_bind_dns,
+        dn_defaults: config.dn_defaults,
+        default_dn_config: config.default_dn_config.unwrap_or_default(),
+        allow_unauthenticated_bind: config.allow_unauthenticated_bind,
+        remote_ip_addr_info: AddrInfoSource::None,
+        cacheable_result_codes: config.cacheable_result_codes,
+        degraded_result_codes: config.degraded_result_codes,
+        sort_cached_entries: config.sort_cached_entries,
+        cache_mode: config.cache_mode,
+        cache_attribute_superset: config.cache_attribute_superset,
+        cache_key_ignore_limits: config.cache_key_ignore_limits,
+        read_through_max_age: Duration::from_secs(config.read_through_max_age_secs.get()),
+        cache_race_timeout: config
+            .cache_race_timeout_ms
+            .map(|ms| Duration::from_millis(ms.get())),
+        require_ldap_v3: config.require_ldap_v3,
+        root_dse: config.root_dse,
+        supported_controls: config.supported_controls,
+        schema: config
+            .cache_schema_at_startup
+            .then(|| Arc::new(SchemaSnapshot::default())),
+        cache_age_control_oid: config
+            .annotate_cached_responses
+            .then_some(config.cache_age_control_oid)
+            .flatten(),
+        annotate_cached_message: config.annotate_cached_message,
+        fallback_result_code: config.fallback_result_code,
+        fallback_result_marker: config.fallback_result_marker,
+        backend_pool: config.backend_pool.as_ref().map(BackendPool::new),
+        dn_rewrite: config.dn_rewrite,
+        referral_rewrite: config.referral_rewrite,
+        strip_unmatched_referrals: config.strip_unmatched_referrals,
+        chase_referrals: config.chase_referrals,
+        referral_connectors,
+        slow_op_threshold: config.slow_op_threshold_ms.map(|ms| Duration::from_millis(ms.get())),
+        denied_query_action: config.denied_query_action,
+        no_fallback_action: config.no_fallback_action,
+        max_dn_length: config.max_dn_length.get(),
+        max_filter_depth: config.max_filter_depth.get(),
+        max_concurrent_ops: config.max_concurrent_ops.get(),
+        breaker,
+        tiered_cache: None,
+        in_flight_searches: config.coalesce_searches.then(Default::default),
+        bind_timeout: Duration::from_secs(config.backend_bind_timeout_secs.get()),
+        connect_timeouts,
+        reconnect_backend: config.reconnect_backend,
+        backend_mode: config.backend_mode,
+        whoami_format: config.whoami_format,
+        client_idle_timeout: config
+            .client_idle_timeout_secs
+            .map(|secs| Duration::from_secs(secs.get())),
+        metrics: Default::default(),
+        session_counts: Default::default(),
+        offline_bind,
+        bind_cache,
+        maintenance: AtomicBool::new(false),
+        maintenance_bind_action: config.maintenance_bind_action,
+        critical_control_action: config.critical_control_action,
+    }
+}
+
+/// An `AppState` with no reachable backend.
+pub fn offline_app_state(extra_config: &str) -> AppState {
+    let tls_params = SslConnector::builder(SslMethod::tls_client())
+        .expect("Failed to create connector")
+        .build();
+    app_state(extra_config, Vec::new(), tls_params)
+}
+
+pub struct TestClient {
+    r: FramedRead<ReadHalf<DuplexStream>, BackendCodec>,
+    w: FramedWrite<WriteHalf<DuplexStream>, LdapCodec>,
+    handle: JoinHandle<()>,
+    flushes: Arc<AtomicUsize>,
+}
+
+/// The proxy's side of a test client's stream, counting how often the proxy
+/// flushes it.
+struct FlushCounter {
+    inner: WriteHalf<DuplexStream>,
+    flushes: Arc<AtomicUsize>,
+}
+
+impl AsyncWrite for FlushCounter {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        self.flushes.fetch_add(1, Ordering::SeqCst);
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+impl TestClient {
+    /// Spawn `client_process` for a new client connected over an in memory stream.
+    pub fn spawn(app_state: Arc<AppState>) -> Self {
+        Self::spawn_with_reported_address(app_state, None)
+    }
+
+    /// As `spawn`, with the client address reported by the PROXY protocol.
+    pub fn spawn_with_reported_address(
+        app_state: Arc<AppState>,
+        reported_client_address: Option<SocketAddr>,
+    ) -> Self {
+        Self::spawn_with(app_state, reported_client_address, None)
+    }
+
+    /// As `spawn`, for a client of a listener labelled with `tenant`.
+    pub fn spawn_for_tenant(app_state: Arc<AppState>, tenant: &str) -> Self {
+        Self::spawn_with(app_state, None, Some(tenant.to_string()))
+    }
+
+    fn spawn_with(
+        app_state: Arc<AppState>,
+        reported_client_address: Option<SocketAddr>,
+        tenant: Option<String>,
+    ) -> Self {
+        let (client, server) = tokio::io::duplex(64 * 1024);
+
+        let (sr, sw) = tokio::io::split(server);
+        let flushes = Arc::new(AtomicUsize::new(0));
+        let sw = FlushCounter {
+            inner: sw,
+            flushes: flushes.clone(),
+        };
+        let sr = FramedRead::new(sr, ClientCodec::new(None, app_state.require_ldap_v3));
+        let sw = FramedWrite::new(sw, ClientCodec::new(None, app_state.require_ldap_v3));
+        let client_address = ClientAddress::Tcp(
+            "127.0.0.1:40000".parse().expect("invalid address"),
+        );
+        let handle = tokio::spawn(proxy::client_process(
+            sr,
+            sw,
+            client_address,
+            reported_client_address,
+            None,
+            tenant,
+            app_state,
+        ));
+
+        let (cr, cw) = tokio::io::split(client);
+        TestClient {
+            r: FramedRead::new(cr, BackendCodec::new(None)),
+            w: FramedWrite::new(cw, LdapCodec::new(None)),
+            handle,
+            flushes,
+        }
+    }
+
+    /// How many times the proxy has flushed its writes to this client.
+    pub fn flush_count(&self) -> usize {
+        self.flushes.load(Ordering::SeqCst)
+    }
+
+    pub async fn send(&mut self, msg: LdapMsg) {
+        self.w.send(msg).await.expect("Failed to send message");
+    }
+
+    pub async fn send_raw(&mut self, bytes: &[u8]) {
+        self.w
+            .get_mut()
+            .write_all(bytes)
+            .await
+            .expect("Failed to send bytes");
+    }
+
+    /// Receive the next message, or `None` if the proxy closed the connection.
+    pub async fn recv(&mut self) -> Option<LdapMsg> {
+        tokio::time::timeout(Duration::from_secs(10), self.r.next())
+            .await
+            .expect("Timed out waiting for a response")
+            .map(|msg| msg.expect("Failed to decode response"))
+    }
+
+    /// The value of the password policy control on the response `msgid`,
+    /// if that was the last one received with such a control.
+    pub fn take_password_policy(&mut self, msgid: i32) -> Option<Vec<u8>> {
+        self.r.decoder_mut().take_password_policy(msgid)
+    }
+
+    /// Wait for `client_process` to exit.
+    pub async fn join(self) {
+        tokio::time::timeout(Duration::from_secs(10), self.handle)
+            .await
+            .expect("Timed out waiting for the session to end")
+            .expect("client_process panicked");
+    }
+}
+
+pub type Handler = Arc<dyn Fn(&LdapMsg) -> Vec<LdapMsg> + Send + Sync>;
+
+/// A control by oid and value.
+type RawControl = (String, Vec<u8>);
+
+/// A TLS LDAP server on localhost that answers requests with a handler and
+/// records every request it receives.
+pub struct MockBackend {
+    pub addr: SocketAddr,
+    cert: X509,
+    requests: Arc<Mutex<Vec<LdapMsg>>>,
+    online: Arc<AtomicBool>,
+    connections: Arc<AtomicUsize>,
+    open_connections: Arc<AtomicUsize>,
+    /// Bumped to drop the connections opened before it changed.
+    generation: Arc<AtomicUsize>,
+    server_names: Arc<Mutex<Vec<Option<String>>>>,
+    /// How long searches wait before they are answered.
+    search_delay: Arc<Mutex<Duration>>,
+    /// A control added to every bind response.
+    bind_control: Arc<Mutex<Option<RawControl>>>,
+}
+
+pub fn self_signed_cert(hostname: &str) -> (PKey<Private>, X509) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).expect("ec group");
+    let pkey = PKey::from_ec_key(EcKey::generate(&group).expect("ec key")).expect("pkey");
+
+    let mut name = X509NameBuilder::new().expect("name builder");
+    name.append_entry_by_text("CN", hostname).expect("cn");
+    let name = name.build();
+
+    let mut builder = X509Builder::new().expect("x509 builder");
+    builder.set_version(2).expect("version");
+    let serial = BigNum::from_u32(1)
+        .and_then(|bn| bn.to_asn1_integer())
+        .expect("serial");
+    builder.set_serial_number(&serial).expect("serial");
+    builder.set_subject_name(&name).expect("subject");
+    builder.set_issuer_name(&name).expect("issuer");
+    builder.set_pubkey(&pkey).expect("pubkey");
+    let not_before = Asn1Time::days_from_now(0).expect("not before");
+    let not_after = Asn1Time::days_from_now(1).expect("not after");
+    builder.set_not_before(&not_before).expect("not before");
+    builder.set_not_after(&not_after).expect("not after");
+    let san = SubjectAlternativeName::new()
+        .dns(hostname)
+        .build(&builder.x509v3_context(None, None))
+        .expect("san");
+    builder.append_extension(san).expect("san");
+    builder
+        .sign(&pkey, MessageDigest::sha256())
+        .expect("sign");
+
+    (pkey, builder.build())
+}
+
+/// The default handler: binds succeed and searches return no entries.
+pub fn default_handler(msg: &LdapMsg) -> Vec<LdapMsg> {
+    match &msg.op {
+        LdapOp::BindRequest(_) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: ldap_result(LdapResultCode::Success),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }],
+        LdapOp::SearchRequest(_) => search_response(msg.msgid, Vec::new(), LdapResultCode::Success),
+        LdapOp::ExtendedRequest(_) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: ldap_result(LdapResultCode::Success),
+                name: None,
+                value: None,
+            }),
+            ctrl: vec![],
+        }],
+        _ => vec![],
+    }
+}
+
+pub fn ldap_result(code: LdapResultCode) -> LdapResult {
+    LdapResult {
+        code,
+        matcheddn: "".to_string(),
+        message: "".to_string(),
+        referral: vec![],
+    }
+}
+
+pub fn search_response(
+    msgid: i32,
+    entries: Vec<LdapSearchResultEntry>,
+    code: LdapResultCode,
+) -> Vec<LdapMsg> {
+    entries
+        .into_iter()
+        .map(|entry| LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultEntry(entry),
+            ctrl: vec![],
+        })
+        .chain(std::iter::once(LdapMsg {
+            msgid,
+            op: LdapOp::SearchResultDone(ldap_result(code)),
+            ctrl: vec![],
+        }))
+        .collect()
+}
+
+pub fn entry(dn: &str) -> LdapSearchResultEntry {
+    LdapSearchResultEntry {
+        dn: dn.to_string(),
+        attributes: vec![LdapPartialAttribute {
+            atype: "cn".to_string(),
+            vals: vec![b"test".to_vec()],
+        }],
+    }
+}
+
+impl MockBackend {
+    pub async fn start(handler: Handler) -> Self {
+        Self::start_with_hostname(handler, "localhost").await
+    }
+
+    pub async fn start_with_hostname(handler: Handler, hostname: &str) -> Self {
+        let (pkey, cert) = self_signed_cert(hostname);
+
+        let mut acceptor =
+            SslAcceptor::mozilla_intermediate_v5(SslMethod::tls()).expect("acceptor");
+        acceptor.set_private_key(&pkey).expect("private key");
+        acceptor.set_certificate(&cert).expect("certificate");
+        let server_names = Arc::new(Mutex::new(Vec::new()));
+        let c_server_names = server_names.clone();
+        acceptor.set_servername_callback(move |ssl, _| {
+            let name = ssl.servername(NameType::HOST_NAME).map(str::to_string);
+            #[allow(clippy::unwrap_used)]
+            c_server_names.lock().unwrap().push(name);
+            Ok(())
+        });
+        let acceptor = acceptor.build();
+
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let online = Arc::new(AtomicBool::new(true));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let open_connections = Arc::new(AtomicUsize::new(0));
+        let generation = Arc::new(AtomicUsize::new(0));
+        let search_delay = Arc::new(Mutex::new(Duration::ZERO));
+        let bind_control = Arc::new(Mutex::new(None));
+
+        let c_requests = requests.clone();
+        let c_online = online.clone();
+        let c_connections = connections.clone();
+        let c_open_connections = open_connections.clone();
+        let c_generation = generation.clone();
+        let c_search_delay = search_delay.clone();
+        let c_bind_control = bind_control.clone();
+        tokio::spawn(async move {
+            while let Ok((tcpstream, _)) = listener.accept().await {
+                if !c_online.load(Ordering::SeqCst) {
+                    continue;
+                }
+                c_connections.fetch_add(1, Ordering::SeqCst);
+                let acceptor = acceptor.clone();
+                let handler = handler.clone();
+                let requests = c_requests.clone();
+                let online = c_online.clone();
+                let generation = c_generation.clone();
+                let opened_in = generation.load(Ordering::SeqCst);
+                let open_connections = c_open_connections.clone();
+                let search_delay = c_search_delay.clone();
+                let bind_control = c_bind_control.clone();
+                open_connections.fetch_add(1, Ordering::SeqCst);
+                let serve = async move {
+                    let Ok(ssl) = Ssl::new(acceptor.context()) else {
+                        return;
+                    };
+                    let Ok(mut tlsstream) = SslStream::new(ssl, tcpstream) else {
+                        return;
+                    };
+                    if SslStream::accept(Pin::new(&mut tlsstream)).await.is_err() {
+                        return;
+                    }
+                    let (r, w) = tokio::io::split(tlsstream);
+                    let mut r = FramedRead::new(r, ClientCodec::new(None, false));
+                    let mut w = FramedWrite::new(w, ClientCodec::new(None, false));
+                    while let Some(Ok(msg)) = r.next().await {
+                        if !online.load(Ordering::SeqCst)
+                            || generation.load(Ordering::SeqCst) != opened_in
+                        {
+                            break;
+                        }
+                        #[allow(clippy::unwrap_used)]
+                        requests.lock().unwrap().push(msg.clone());
+                        if matches!(msg.op, LdapOp::SearchRequest(_)) {
+                            #[allow(clippy::unwrap_used)]
+                            let delay = *search_delay.lock().unwrap();
+                            tokio::time::sleep(delay).await;
+                        }
+                        for resp in handler(&msg) {
+                            #[allow(clippy::unwrap_used)]
+                            let control = bind_control.lock().unwrap().clone();
+                            let sent = match control {
+                                Some((oid, value))
+                                    if matches!(resp.op, LdapOp::BindResponse(_)) =>
+                                {
+                                    let resp = ResponseWithControl {
+                                        msg: resp,
+                                        oid,
+                                        value,
+                                    };
+                                    w.send(resp).await
+                                }
+                                _ => w.send(resp).await,
+                            };
+                            if sent.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                };
+                tokio::spawn(async move {
+                    serve.await;
+                    open_connections.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+
+        MockBackend {
+            addr,
+            cert,
+            requests,
+            online,
+            connections,
+            open_connections,
+            generation,
+            server_names,
+            search_delay,
+            bind_control,
+        }
+    }
+
+    /// This backend's certificate in PEM form.
+    pub fn cert_pem(&self) -> Vec<u8> {
+        self.cert.to_pem().expect("pem")
+    }
+
+    /// A connector that trusts this backend's certificate.
+    pub fn tls_params(&self) -> SslConnector {
+        self.tls_builder().build()
+    }
+
+    /// As `tls_params`, resuming sessions with this backend.
+    pub fn tls_params_with_resumption(&self) -> SslConnector {
+        let mut builder = self.tls_builder();
+        tls::enable_session_resumption(&mut builder).expect("session resumption");
+        builder.build()
+    }
+
+    fn tls_builder(&self) -> SslConnectorBuilder {
+        let mut builder = SslConnector::builder(SslMethod::tls_client()).expect("connector");
+        builder
+            .cert_store_mut()
+            .add_cert(self.cert.clone())
+            .expect("add cert");
+        builder
+            .verify_param_mut()
+            .set_host("localhost")
+            .expect("set host");
+        builder.set_verify(SslVerifyMode::PEER);
+        builder
+    }
+
+    pub fn app_state(&self, extra_config: &str) -> AppState {
+        app_state(extra_config, vec![self.addr], self.tls_params())
+    }
+
+    /// A routed backend sending sessions bound under `dn_suffix` here.
+    pub fn routed_backend(&self, dn_suffix: &str) -> RoutedBackend {
+        RoutedBackend {
+            dn_suffix: dn_suffix.parse().expect("Invalid dn suffix"),
+            ldap_url: format!("ldaps://localhost:{}", self.addr.port())
+                .parse()
+                .expect("Invalid url"),
+            tls_params: self.tls_params(),
+            tls_server_name: Some("localhost".to_string()),
+            addrs: RwLock::new(vec![self.addr]),
+        }
+    }
+
+    /// The SNI name sent with each TLS handshake.
+    pub fn server_names(&self) -> Vec<Option<String>> {
+        #[allow(clippy::unwrap_used)]
+        self.server_names.lock().unwrap().clone()
+    }
+
+    /// The number of connections accepted while online.
+    pub fn connection_count(&self) -> usize {
+        self.connections.load(Ordering::SeqCst)
+    }
+
+    /// The number of connections currently open.
+    pub fn open_connection_count(&self) -> usize {
+        self.open_connections.load(Ordering::SeqCst)
+    }
+
+    /// Wait for the number of open connections to settle at `count`, as a
+    /// connection the proxy closes is only seen to close a little later.
+    pub async fn wait_for_open_connections(&self, count: usize) {
+        let settled = async {
+            while self.open_connection_count() != count {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), settled)
+            .await
+            .unwrap_or_else(|_| {
+                panic!(
+                    "Expected {} open connections, found {}",
+                    count,
+                    self.open_connection_count()
+                )
+            });
+    }
+
+    /// Simulate an outage. Open connections are dropped on their next request
+    /// and new connections are refused.
+    pub fn set_online(&self, online: bool) {
+        self.online.store(online, Ordering::SeqCst);
+    }
+
+    /// Drop every open connection on its next request, while still accepting
+    /// new ones.
+    pub fn drop_connections(&self) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+    }
+
+    /// Answer every search only after `delay`.
+    pub fn set_search_delay(&self, delay: Duration) {
+        #[allow(clippy::unwrap_used)]
+        let mut search_delay = self.search_delay.lock().unwrap();
+        *search_delay = delay;
+    }
+
+    /// Add the control `oid` with `value` to every bind response.
+    pub fn set_bind_control(&self, oid: &str, value: Vec<u8>) {
+        #[allow(clippy::unwrap_used)]
+        let mut bind_control = self.bind_control.lock().unwrap();
+        *bind_control = Some((oid.to_string(), value));
+    }
+
+    pub fn requests(&self) -> Vec<LdapMsg> {
+        #[allow(clippy::unwrap_used)]
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn search_count(&self) -> usize {
+        self.requests()
+            .iter()
+            .filter(|msg| matches!(msg.op, LdapOp::SearchRequest(_)))
+            .count()
+    }
+
+    pub fn bind_count(&self) -> usize {
+        self.requests()
+            .iter()
+            .filter(|msg| matches!(msg.op, LdapOp::BindRequest(_)))
+            .count()
+    }
+}
+
+/// Wait for the credentials of a bind as `dn` to be cached, as they are
+/// derived in the background once the bind is answered.
+pub async fn wait_for_credentials(cache: &OfflineBindCache, dn: &str) {
+    let cached = async {
+        while !cache.contains(dn) {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+    };
+    tokio::time::timeout(Duration::from_secs(5), cached)
+        .await
+        .unwrap_or_else(|_| panic!("Credentials for {} were not cached", dn));
+}
+
+pub fn bind_request(msgid: i32, dn: &str, pw: &str) -> LdapMsg {
+    LdapMsg {
+        msgid,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::Simple(pw.to_string()),
+        }),
+        ctrl: vec![],
+    }
+}
+
+pub fn search_request(base: &str) -> LdapSearchRequest {
+    LdapSearchRequest {
+        base: base.to_string(),
+        scope: LdapSearchScope::Subtree,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Present("objectClass".to_string()),
+        attrs: vec![],
+    }
+}
+
+impl TestClient {
+    /// Bind and assert the result code of the bind response.
+    pub async fn bind(&mut self, msgid: i32, dn: &str, pw: &str) -> LdapResultCode {
+        self.send(bind_request(msgid, dn, pw)).await;
+        match self.recv().await.map(|msg| msg.op) {
+            Some(LdapOp::BindResponse(resp)) => resp.res.code,
+            op => panic!("Unexpected bind response {:?}", op),
+        }
+    }
+
+    /// Search and collect all entries and the final result.
+    pub async fn search(
+        &mut self,
+        msgid: i32,
+        sr: LdapSearchRequest,
+    ) -> (Vec<LdapSearchResultEntry>, LdapResult) {
+        self.search_with_controls(msgid, sr, vec![]).await
+    }
+
+    /// As `search`, sending `ctrl` with the request.
+    pub async fn search_with_controls(
+        &mut self,
+        msgid: i32,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> (Vec<LdapSearchResultEntry>, LdapResult) {
+        self.send(LdapMsg {
+            msgid,
+            op: LdapOp::SearchRequest(sr),
+            ctrl,
+        })
+        .await;
+        let mut entries = Vec::new();
+        loop {
+            match self.recv().await.map(|msg| msg.op) {
+                Some(LdapOp::SearchResultEntry(entry)) => entries.push(entry),
+                Some(LdapOp::SearchResultDone(result)) => return (entries, result),
+                op => panic!("Unexpected search response {:?}", op),
+            }
+        }
+    }
+
+    /// Run a search and return every response message, including controls.
+    pub async fn search_msgs(&mut self, msgid: i32, sr: LdapSearchRequest) -> Vec<LdapMsg> {
+        self.search_msgs_with_controls(msgid, sr, vec![]).await
+    }
+
+    /// As `search_msgs`, sending `ctrl` with the request.
+    pub async fn search_msgs_with_controls(
+        &mut self,
+        msgid: i32,
+        sr: LdapSearchRequest,
+        ctrl: Vec<LdapControl>,
+    ) -> Vec<LdapMsg> {
+        self.send(LdapMsg {
+            msgid,
+            op: LdapOp::SearchRequest(sr),
+            ctrl,
+        })
+        .await;
+        let mut msgs = Vec::new();
+        while let Some(msg) = self.recv().await {
+            let done = matches!(msg.op, LdapOp::SearchResultDone(_));
+            msgs.push(msg);
+            if done {
+                break;
+            }
+        }
+        msgs
+    }
+}
+
+/// Look up a search in the memory fallback cache.
+pub fn memory_cache_get(
+    app_state: &AppState,
+    bind_dn: &str,
+    sr: &LdapSearchRequest,
+) -> Option<CachedValue> {
+    let CacheBackend::Memory(cache) = &app_state.cache else {
+        return None;
+    };
+    let key = SearchCacheKey::new(bind_dn.to_string(), sr.clone(), vec![]);
+    let mut read_txn = cache.read();
+    read_txn.get(&key).cloned()
+}
+
+/// Store a successful search result in the memory fallback cache.
+pub fn memory_cache_set(
+    app_state: &AppState,
+    bind_dn: &str,
+    sr: &LdapSearchRequest,
+    entries: Vec<LdapSearchResultEntry>,
+) {
+    memory_cache_set_aged(app_state, bind_dn, sr, entries, Duration::ZERO)
+}
+
+/// As `memory_cache_set`, for a result cached `age` ago.
+pub fn memory_cache_set_aged(
+    app_state: &AppState,
+    bind_dn: &str,
+    sr: &LdapSearchRequest,
+    entries: Vec<LdapSearchResultEntry>,
+    age: Duration,
+) {
+    let CacheBackend::Memory(cache) = &app_state.cache else {
+        return;
+    };
+    let key = SearchCacheKey::new(bind_dn.to_string(), sr.clone(), vec![]);
+    let value = CachedValue {
+        cached_at: std::time::SystemTime::now() - age,
+        entries: entries.into_iter().map(|e| (e, vec![])).collect(),
+        result: ldap_result(LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+    let size = NonZeroUsize::new(value.size()).expect("empty cache value");
+    let mut write_txn = cache.write();
+    write_txn.insert_sized(key, value, size);
+    write_txn.commit();
+}
+
+/// Make a plain HTTP GET request, returning the status code and body.
+pub async fn http_get(addr: SocketAddr, path: &str) -> (u16, String) {
+    use tokio::io::AsyncReadExt;
+
+    let mut stream = tokio::net::TcpStream::connect(addr)
+        .await
+        .expect("Failed to connect");
+    stream
+        .write_all(format!("GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).as_bytes())
+        .await
+        .expect("Failed to send request");
+    let mut response = String::new();
+    tokio::time::timeout(Duration::from_secs(10), stream.read_to_string(&mut response))
+        .await
+        .expect("Timed out waiting for a response")
+        .expect("Failed to read response");
+
+    let (head, body) = response.split_once("\r\n\r\n").expect("No header terminator");
+    let status = head
+        .split_whitespace()
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .expect("No status code");
+    (status, body.to_string())
+}
+
+/// Log lines written by the current thread's subscriber while capturing.
+#[derive(Clone, Default)]
+pub struct Logs(Arc<Mutex<Vec<u8>>>);
+
+impl std::io::Write for Logs {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0.lock().expect("poisoned").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Logs {
+    /// Capture the logs of this thread until the guard is dropped. The test
+    /// runtime runs spawned sessions on this thread too.
+    pub fn capture() -> (Self, tracing::subscriber::DefaultGuard) {
+        let logs = Logs::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        (logs, tracing::subscriber::set_default(subscriber))
+    }
+
+    /// The first line containing `pattern`.
+    pub fn line(&self, pattern: &str) -> Option<String> {
+        let logs = String::from_utf8(self.0.lock().expect("poisoned").clone()).expect("utf8");
+        logs.lines()
+            .find(|line| line.contains(pattern))
+            .map(str::to_string)
+    }
+}
+
+/// A Redis server speaking just enough of RESP for the cache, which answers
+/// every command with an error while failing.
+pub struct MockRedis {
+    pub addr: SocketAddr,
+    failing: Arc<AtomicBool>,
+    commands: Arc<AtomicUsize>,
+    keys: Arc<Mutex<Vec<(String, String)>>>,
+}
+
+impl MockRedis {
+    pub async fn start() -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let failing = Arc::new(AtomicBool::new(false));
+        let commands = Arc::new(AtomicUsize::new(0));
+        let keys = Arc::new(Mutex::new(Vec::new()));
+
+        let c_failing = failing.clone();
+        let c_commands = commands.clone();
+        let c_keys = keys.clone();
+        tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                let failing = c_failing.clone();
+                let commands = c_commands.clone();
+                let keys = c_keys.clone();
+                tokio::spawn(async move {
+                    let (read, mut write) = stream.into_split();
+                    let mut read = tokio::io::BufReader::new(read);
+                    while let Some((command, key)) = read_resp_command(&mut read).await {
+                        commands.fetch_add(1, Ordering::SeqCst);
+                        if let Some(key) = key {
+                            #[allow(clippy::unwrap_used)]
+                            keys.lock().unwrap().push((command.to_ascii_uppercase(), key));
+                        }
+                        let reply: Vec<u8> = match command.to_ascii_uppercase().as_str() {
+                            _ if failing.load(Ordering::SeqCst) => b"-ERR injected failure\r\n".to_vec(),
+                            "PING" => b"+PONG\r\n".to_vec(),
+                            "GET" => b"$-1\r\n".to_vec(),
+                            "ZADD" | "ZREMRANGEBYSCORE" | "EXPIRE" | "DEL" | "ZREM" => b":1\r\n".to_vec(),
+                            "ZRANGEBYSCORE" => b"*0\r\n".to_vec(),
+                            // Every key written so far, in a single page.
+                            "SCAN" => {
+                                #[allow(clippy::unwrap_used)]
+                                let mut written: Vec<String> = keys
+                                    .lock()
+                                    .unwrap()
+                                    .iter()
+                                    .filter(|(command, _)| matches!(command.as_str(), "SET" | "SETEX" | "ZADD"))
+                                    .map(|(_, key)| key.clone())
+                                    .collect();
+                                written.sort();
+                                written.dedup();
+                                let mut reply = format!("*2\r\n$1\r\n0\r\n*{}\r\n", written.len());
+                                for key in written {
+                                    reply.push_str(&format!("${}\r\n{}\r\n", key.len(), key));
+                                }
+                                reply.into_bytes()
+                            }
+                            _ => b"+OK\r\n".to_vec(),
+                        };
+                        if write.write_all(&reply).await.is_err() {
+                            break;
+                        }
+                    }
+                });
+            }
+        });
+
+        MockRedis {
+            addr,
+            failing,
+            commands,
+            keys,
+        }
+    }
+
+    pub fn set_failing(&self, failing: bool) {
+        self.failing.store(failing, Ordering::SeqCst);
+    }
+
+    /// The number of commands received.
+    pub fn command_count(&self) -> usize {
+        self.commands.load(Ordering::SeqCst)
+    }
+
+    /// The first argument of each command received that had one, which is
+    /// the key of those the cache sends.
+    pub fn keys(&self) -> Vec<String> {
+        self.sent().into_iter().map(|(_, key)| key).collect()
+    }
+
+    /// The name and key of each command received that had a key.
+    pub fn sent(&self) -> Vec<(String, String)> {
+        #[allow(clippy::unwrap_used)]
+        self.keys.lock().unwrap().clone()
+    }
+}
+
+/// Read a command sent as an array of bulk strings, returning its name and
+/// its first argument.
+async fn read_resp_command<R: tokio::io::AsyncBufRead + Unpin>(
+    read: &mut R,
+) -> Option<(String, Option<String>)> {
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt};
+
+    let mut line = String::new();
+    read.read_line(&mut line).await.ok()?;
+    let count: usize = line.trim_end().strip_prefix('*')?.parse().ok()?;
+    let mut name = None;
+    let mut key = None;
+    for _ in 0..count {
+        line.clear();
+        read.read_line(&mut line).await.ok()?;
+        let len: usize = line.trim_end().strip_prefix('$')?.parse().ok()?;
+        let mut arg = vec![0; len + 2];
+        read.read_exact(&mut arg).await.ok()?;
+        arg.truncate(len);
+        let arg = String::from_utf8_lossy(&arg).into_owned();
+        match name {
+            None => name = Some(arg),
+            Some(_) => {
+                key.get_or_insert(arg);
+            }
+        }
+    }
+    Some((name?, key))
+}
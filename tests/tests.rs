@@ -1,8 +1,12 @@
 // use ldap_proxy::proxy::BasicLdapClient;
 
+mod common;
+
 use ldap3_proto::proto::LdapResult;
-use ldap_proxy::proxy::CachedValue;
-use ldap_proxy::Config;
+use ldap_proxy::proxy::{CachedValue, SearchCacheKey};
+use ldap_proxy::{Config, ListenAddr};
+use std::net::SocketAddr;
+use std::path::PathBuf;
 use std::time::SystemTime;
 
 #[test]
@@ -18,8 +22,12 @@ fn test_config_load() {
         Some("/etc/ldap-proxy/ldap-ca.pem")
     );
     
-    // Test default fallback_cache_bytes value
-    assert_eq!(config.fallback_cache_bytes, 268435456); // 256 MB
+    // Test default cache size
+    assert_eq!(config.fallback_cache_bytes, None);
+    assert!(matches!(
+        config.cache_config(),
+        ldap_proxy::CacheConfig::Memory { size_bytes: 268435456 } // 256 MB
+    ));
 }
 
 #[test]
@@ -34,7 +42,11 @@ fn test_config_custom_cache_size() {
     "#;
     
     let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
-    assert_eq!(config.fallback_cache_bytes, 536870912); // 512 MB
+    assert_eq!(config.fallback_cache_bytes, Some(536870912)); // 512 MB
+    assert!(matches!(
+        config.cache_config(),
+        ldap_proxy::CacheConfig::Memory { size_bytes: 536870912 }
+    ));
 }
 
 #[test]
@@ -64,11 +76,13 @@ fn test_cachedvalue() {
             referral: Vec::with_capacity(5),
         },
         ctrl: Vec::with_capacity(5),
+        source_addr: None,
     };
-    assert_eq!(cv.size(), 144);
+    assert_eq!(cv.size(), 176);
 }
 
 #[test]
+#[allow(clippy::vec_init_then_push)]
 fn test_cachedvalue_size_calculation() {
     use ldap3_proto::proto::{LdapSearchResultEntry, LdapPartialAttribute};
     
@@ -96,6 +110,7 @@ fn test_cachedvalue_size_calculation() {
             referral: Vec::new(),
         },
         ctrl: Vec::new(),
+        source_addr: None,
     };
     
     // Size should be greater than base struct size due to entry data
@@ -166,7 +181,7 @@ fn test_cache_config_memory() {
     "#;
     
     let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
-    match config.cache {
+    match config.cache_config() {
         ldap_proxy::CacheConfig::Memory { size_bytes } => {
             assert_eq!(size_bytes, 536870912);
         }
@@ -191,7 +206,7 @@ fn test_cache_config_redis() {
     "#;
     
     let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
-    match config.cache {
+    match config.cache_config() {
         ldap_proxy::CacheConfig::Redis { url, ttl_seconds, key_prefix } => {
             assert_eq!(url, "redis://localhost:6379");
             assert_eq!(ttl_seconds, Some(3600));
@@ -213,10 +228,7357 @@ fn test_cache_config_default() {
     
     let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
     // When no cache is specified, should use default (Memory with 256MB)
-    match config.cache {
+    match config.cache_config() {
         ldap_proxy::CacheConfig::Memory { size_bytes } => {
             assert_eq!(size_bytes, 268435456); // 256 MB
         }
         _ => panic!("Expected default Memory cache config"),
     }
-}
\ No newline at end of file
+}
+#[test]
+fn test_config_bind_single() {
+    let config = toml::from_str::<Config>(include_str!("test_config.toml"))
+        .expect("Failed to parse config");
+    let expected: SocketAddr = "127.0.0.1:3636".parse().expect("invalid address");
+    assert_eq!(config.bind.addrs(), &[ListenAddr::Tcp(expected)]);
+    assert_eq!(config.listen_backlog, 1024);
+}
+
+#[test]
+fn test_config_bind_multiple() {
+    let config_str = r#"
+        bind = ["0.0.0.0:3636", "[::]:3636", "unix:/run/ldap-proxy/ldap.sock"]
+        listen_backlog = 4096
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+    "#;
+
+    let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
+    let v4: SocketAddr = "0.0.0.0:3636".parse().expect("invalid address");
+    let v6: SocketAddr = "[::]:3636".parse().expect("invalid address");
+    assert_eq!(
+        config.bind.addrs(),
+        &[
+            ListenAddr::Tcp(v4),
+            ListenAddr::Tcp(v6),
+            ListenAddr::Unix(PathBuf::from("/run/ldap-proxy/ldap.sock")),
+        ]
+    );
+    assert_eq!(config.listen_backlog, 4096);
+}
+
+#[test]
+fn test_config_bind_invalid() {
+    let config_str = r#"
+        bind = ["127.0.0.1:3636", "unix:"]
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+    "#;
+
+    assert!(toml::from_str::<Config>(config_str).is_err());
+}
+
+#[test]
+fn test_config_cache_warm() {
+    let config_str = r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+
+        [cache_warm]
+        queries = [
+            ["", "", "base", "(objectclass=*)"],
+            ["cn=svc,dc=example,dc=com", "dc=example,dc=com", "subtree", "(uid=*)"],
+        ]
+
+        [cache_warm.credentials]
+        "cn=svc,dc=example,dc=com" = "hunter2"
+    "#;
+
+    let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
+    let cache_warm = config.cache_warm.expect("cache_warm missing");
+    assert_eq!(cache_warm.queries.len(), 2);
+    assert_eq!(cache_warm.queries[1].0, "cn=svc,dc=example,dc=com");
+    assert_eq!(
+        cache_warm.credentials.get("cn=svc,dc=example,dc=com").map(String::as_str),
+        Some("hunter2")
+    );
+    // The warm-up section must not be mistaken for a bind DN.
+    assert!(!config.binddn_map.contains_key("cache_warm"));
+    // Credentials are never printed.
+    assert!(!format!("{:?}", cache_warm).contains("hunter2"));
+}
+
+#[test]
+fn test_search_cache_key_redis_key() {
+    use ldap3_proto::proto::{LdapDerefAliases, LdapFilter, LdapSearchRequest, LdapSearchScope};
+
+    let search = LdapSearchRequest {
+        base: "dc=example,dc=com".to_string(),
+        scope: LdapSearchScope::Subtree,
+        aliases: LdapDerefAliases::Never,
+        sizelimit: 0,
+        timelimit: 0,
+        typesonly: false,
+        filter: LdapFilter::Equality("uid".to_string(), "alice".to_string()),
+        attrs: vec!["cn".to_string()],
+    };
+    let key = SearchCacheKey::new("cn=svc,dc=example,dc=com".to_string(), search, vec![]);
+
+    let redis_key = key.to_redis_key("ldap_proxy:").expect("Failed to derive key");
+    // This value must never change, otherwise existing Redis caches are
+    // silently invalidated on upgrade.
+    assert_eq!(redis_key, "ldap_proxy:724ecbcba7a31b0aaca19ba37cb4ea9882e03bc6a86ce32a18777d2cde630c26");
+}
+
+fn allowed_bases_config(config_str: &str) -> ldap_proxy::DnConfig {
+    let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
+    config
+        .binddn_map
+        .get("cn=svc,dc=example,dc=com")
+        .cloned()
+        .expect("missing dn config")
+}
+
+#[test]
+fn test_allowed_bases_subtree() {
+    use ldap3_proto::proto::{LdapFilter, LdapSearchScope};
+
+    let dn_config = allowed_bases_config(
+        r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+
+        ["cn=svc,dc=example,dc=com"]
+        allowed_bases = ["ou=People,dc=example,dc=com"]
+    "#,
+    );
+
+    let filter = LdapFilter::Present("objectClass".to_string());
+    assert!(dn_config.is_search_allowed(
+        "ou=people,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    assert!(dn_config.is_search_allowed(
+        "uid=alice,OU=People,DC=Example,DC=com",
+        &LdapSearchScope::Base,
+        &filter
+    ));
+    // Sibling subtree and parent are denied.
+    assert!(!dn_config.is_search_allowed(
+        "ou=groups,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    assert!(!dn_config.is_search_allowed(
+        "dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    // A suffix match must fall on an RDN boundary.
+    assert!(!dn_config.is_search_allowed(
+        "ou=notpeople,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    // DNs are compared RDN by RDN, so an escaped comma is not a boundary and
+    // spacing does not matter.
+    assert!(!dn_config.is_search_allowed(
+        r"cn=x\,ou=people,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    assert!(dn_config.is_search_allowed(
+        "uid=alice, ou=people, dc=example, dc=com",
+        &LdapSearchScope::Base,
+        &filter
+    ));
+    assert!(!dn_config.is_search_allowed(
+        "not a dn",
+        &LdapSearchScope::Base,
+        &filter
+    ));
+}
+
+#[test]
+fn test_allowed_bases_with_allowed_queries() {
+    use ldap3_proto::proto::{LdapFilter, LdapSearchScope};
+
+    let dn_config = allowed_bases_config(
+        r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+
+        ["cn=svc,dc=example,dc=com"]
+        allowed_bases = ["ou=people,dc=example,dc=com"]
+        allowed_queries = [
+            ["ou=people,dc=example,dc=com", "subtree", "(objectclass=*)"],
+            ["dc=example,dc=com", "subtree", "(objectclass=*)"],
+        ]
+    "#,
+    );
+
+    let filter = LdapFilter::Present("objectclass".to_string());
+    assert!(dn_config.is_search_allowed(
+        "ou=people,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+    // Within the allowed base, but not an allowed query.
+    assert!(!dn_config.is_search_allowed(
+        "ou=people,dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &LdapFilter::Present("uid".to_string())
+    ));
+    // An allowed query, but outside of the allowed bases.
+    assert!(!dn_config.is_search_allowed(
+        "dc=example,dc=com",
+        &LdapSearchScope::Subtree,
+        &filter
+    ));
+}
+
+#[tokio::test]
+async fn test_notice_of_disconnection_on_unexpected_op() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use std::sync::Arc;
+
+    let app_state = Arc::new(common::offline_app_state(""));
+    let mut client = common::TestClient::spawn(app_state);
+
+    // Deletes are not supported by the proxy.
+    client
+        .send(LdapMsg {
+            msgid: 1,
+            op: LdapOp::DelRequest("cn=foo".to_string()),
+            ctrl: vec![],
+        })
+        .await;
+
+    let notice = client.recv().await.expect("Expected a notice of disconnection");
+    assert_eq!(notice.msgid, 0);
+    match notice.op {
+        LdapOp::ExtendedResponse(resp) => {
+            assert_eq!(resp.name.as_deref(), Some("1.3.6.1.4.1.1466.20036"));
+            assert_eq!(resp.res.code, ldap3_proto::LdapResultCode::ProtocolError);
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+    assert!(client.recv().await.is_none());
+    client.join().await;
+}
+
+async fn search_with_result_code(
+    code: ldap3_proto::LdapResultCode,
+) -> Option<ldap_proxy::proxy::CachedValue> {
+    use std::sync::Arc;
+
+    let backend_code = code.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => {
+            common::search_response(msg.msgid, vec![], backend_code.clone())
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cacheable_result_codes = ["success", "no_such_object"]
+        ["cn=svc"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let sr = common::search_request("ou=missing,dc=example,dc=com");
+    let (_, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.code, code);
+
+    common::memory_cache_get(&app_state, "cn=svc", &sr)
+}
+
+#[tokio::test]
+async fn test_cacheable_result_code_is_cached() {
+    let cached = search_with_result_code(ldap3_proto::LdapResultCode::NoSuchObject)
+        .await
+        .expect("NoSuchObject result was not cached");
+    assert_eq!(cached.result.code, ldap3_proto::LdapResultCode::NoSuchObject);
+}
+
+#[tokio::test]
+async fn test_transient_result_code_is_not_cached() {
+    assert!(search_with_result_code(ldap3_proto::LdapResultCode::Busy)
+        .await
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_truncated_result_is_relayed_but_not_cached() {
+    use std::sync::Arc;
+
+    for code in [
+        ldap3_proto::LdapResultCode::SizeLimitExceeded,
+        ldap3_proto::LdapResultCode::TimeLimitExceeded,
+    ] {
+        let backend_code = code.clone();
+        let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+            ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+                msg.msgid,
+                vec![
+                    common::entry("cn=a,dc=example,dc=com"),
+                    common::entry("cn=b,dc=example,dc=com"),
+                ],
+                backend_code.clone(),
+            ),
+            _ => common::default_handler(msg),
+        }))
+        .await;
+
+        // Even listing the truncation codes as cacheable does not cache them.
+        let app_state = Arc::new(backend.app_state(
+            r#"
+            cacheable_result_codes = ["success", "size_limit_exceeded", "time_limit_exceeded"]
+            ["cn=svc"]
+        "#,
+        ));
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(
+            client.bind(1, "cn=svc", "password").await,
+            ldap3_proto::LdapResultCode::Success
+        );
+
+        let sr = common::search_request("dc=example,dc=com");
+        let (entries, result) = client.search(2, sr.clone()).await;
+        assert_eq!(result.code, code);
+        assert_eq!(entries.len(), 2);
+        assert!(common::memory_cache_get(&app_state, "cn=svc", &sr).is_none());
+    }
+}
+
+/// Encode a simple bind and patch the protocol version to LDAPv2.
+fn ldapv2_bind_bytes(msgid: i32) -> Vec<u8> {
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Encoder;
+
+    let mut buf = BytesMut::new();
+    ldap3_proto::LdapCodec::default()
+        .encode(common::bind_request(msgid, "cn=svc", "password"), &mut buf)
+        .expect("Failed to encode bind");
+    // SEQUENCE, len, INTEGER msgid, [APPLICATION 0], len, INTEGER version
+    let version_pos = buf
+        .windows(3)
+        .position(|w| w == [0x02, 0x01, 0x03])
+        .map(|pos| pos + 2)
+        .expect("version not found");
+    buf[version_pos] = 2;
+    buf.to_vec()
+}
+
+#[tokio::test]
+async fn test_ldapv2_bind_rejected() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let app_state = Arc::new(common::offline_app_state(
+        r#"
+        ["cn=svc"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    client.send_raw(&ldapv2_bind_bytes(7)).await;
+
+    let resp = client.recv().await.expect("Expected a bind response");
+    assert_eq!(resp.msgid, 7);
+    match resp.op {
+        LdapOp::BindResponse(bind_resp) => {
+            assert_eq!(bind_resp.res.code, ldap3_proto::LdapResultCode::ProtocolError);
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+    client.join().await;
+}
+
+#[tokio::test]
+async fn test_ldapv2_bind_forwarded_when_not_required() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        require_ldap_v3 = false
+        ["cn=svc"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    client.send_raw(&ldapv2_bind_bytes(1)).await;
+
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::BindResponse(bind_resp)) => {
+            assert_eq!(bind_resp.res.code, ldap3_proto::LdapResultCode::Success);
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+    assert_eq!(backend.requests().len(), 1);
+}
+
+#[tokio::test]
+async fn test_root_dse_synthetic_response() {
+    use ldap3_proto::proto::{LdapFilter, LdapSearchScope};
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        [root_dse]
+        supportedLDAPVersion = ["3"]
+        supportedExtension = ["1.3.6.1.4.1.4203.1.11.3"]
+        namingContexts = ["dc=example,dc=com"]
+
+        ["cn=svc"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    let mut sr = common::search_request("");
+    sr.scope = LdapSearchScope::Base;
+    sr.filter = LdapFilter::Present("objectClass".to_string());
+
+    // Clients may discover it before binding, but nothing else.
+    let (entries, result) = client.search(1, sr.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].attributes.len(), 3);
+    let (_, result) = client.search(2, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::OperationsError);
+
+    assert_eq!(
+        client.bind(3, "cn=svc", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let (entries, result) = client.search(4, sr.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "");
+    assert_eq!(entries[0].attributes.len(), 3);
+
+    // Only the requested attributes are returned.
+    sr.attrs = vec!["namingcontexts".to_string()];
+    let (entries, _) = client.search(5, sr).await;
+    assert_eq!(entries[0].attributes.len(), 1);
+    assert_eq!(entries[0].attributes[0].atype, "namingContexts");
+    assert_eq!(entries[0].attributes[0].vals, vec![b"dc=example,dc=com".to_vec()]);
+
+    // The backend was never asked.
+    assert_eq!(backend.search_count(), 0);
+
+    // Other searches are still proxied.
+    client.search(6, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(backend.search_count(), 1);
+}
+
+#[tokio::test]
+async fn test_cache_group_sharing() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=shared,dc=example,dc=com")],
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader1"]
+        cache_group = "readers"
+        ["cn=reader2"]
+        cache_group = "readers"
+        ["cn=isolated"]
+    "#,
+    ));
+
+    let mut reader1 = common::TestClient::spawn(app_state.clone());
+    let mut reader2 = common::TestClient::spawn(app_state.clone());
+    let mut isolated = common::TestClient::spawn(app_state.clone());
+    for (client, dn) in [
+        (&mut reader1, "cn=reader1"),
+        (&mut reader2, "cn=reader2"),
+        (&mut isolated, "cn=isolated"),
+    ] {
+        assert_eq!(
+            client.bind(1, dn, "password").await,
+            ldap3_proto::LdapResultCode::Success
+        );
+    }
+
+    let sr = common::search_request("dc=example,dc=com");
+    let (entries, _) = reader1.search(3, sr.clone()).await;
+    assert_eq!(entries.len(), 1);
+
+    backend.set_online(false);
+
+    // reader2 shares the group's cache entry.
+    let (entries, result) = reader2.search(3, sr.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+
+    // The isolated DN has its own partition and nothing cached.
+    let (entries, result) = isolated.search(3, sr).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+    assert!(entries.is_empty());
+}
+
+#[tokio::test]
+async fn test_cached_responses_annotated_with_age() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let oid = "1.3.6.1.4.1.99999.1";
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=cached,dc=example,dc=com")],
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        annotate_cached_responses = true
+        cache_age_control_oid = "{}"
+        ["cn=reader"]
+    "#,
+        oid
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let is_age_control = |c: &LdapControl| matches!(c, LdapControl::Unknown { oid: o } if o == oid);
+    let sr = common::search_request("dc=example,dc=com");
+
+    // Live responses carry no annotation.
+    let msgs = client.search_msgs(2, sr.clone()).await;
+    assert_eq!(msgs.len(), 2);
+    assert!(msgs.iter().all(|m| !m.ctrl.iter().any(is_age_control)));
+
+    backend.set_online(false);
+
+    let msgs = client.search_msgs(3, sr).await;
+    assert_eq!(msgs.len(), 2);
+    assert!(msgs.iter().all(|m| m.ctrl.iter().any(is_age_control)));
+}
+
+#[test]
+fn test_response_with_control_encoding() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapCodec;
+    use ldap_proxy::codec::{ClientCodec, ResponseWithControl};
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let msg = LdapMsg {
+        msgid: 7,
+        op: LdapOp::SearchResultDone(LdapResult {
+            code: ldap3_proto::LdapResultCode::Success,
+            matcheddn: "".to_string(),
+            message: "".to_string(),
+            referral: vec![],
+        }),
+        ctrl: vec![LdapControl::Unknown {
+            oid: "1.2.3.4".to_string(),
+        }],
+    };
+
+    let mut buf = BytesMut::new();
+    ClientCodec::new(None, true)
+        .encode(
+            ResponseWithControl {
+                msg,
+                oid: "1.2.3.5".to_string(),
+                value: b"42".to_vec(),
+            },
+            &mut buf,
+        )
+        .expect("Failed to encode");
+
+    assert!(buf.windows(4).any(|w| w == [0x04, 0x02, b'4', b'2']));
+
+    let decoded = LdapCodec::new(None)
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    assert!(buf.is_empty());
+    assert_eq!(decoded.msgid, 7);
+    assert!(matches!(decoded.op, LdapOp::SearchResultDone(_)));
+    assert_eq!(
+        decoded.ctrl,
+        vec![
+            LdapControl::Unknown {
+                oid: "1.2.3.4".to_string()
+            },
+            LdapControl::Unknown {
+                oid: "1.2.3.5".to_string()
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_backend_codec_keeps_referrals() {
+    use ldap3_proto::proto::{LdapBindResponse, LdapMsg, LdapOp};
+    use ldap3_proto::LdapCodec;
+    use ldap_proxy::codec::BackendCodec;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let referral = vec![
+        "ldap://internal-dc01:389/dc=example,dc=com".to_string(),
+        "ldap://internal-dc02:389/dc=example,dc=com".to_string(),
+    ];
+    let result = LdapResult {
+        code: ldap3_proto::LdapResultCode::Referral,
+        matcheddn: "dc=example,dc=com".to_string(),
+        message: "".to_string(),
+        referral: referral.clone(),
+    };
+
+    let mut buf = BytesMut::new();
+    for (msgid, op) in [
+        (1, LdapOp::SearchResultDone(result.clone())),
+        (
+            2,
+            LdapOp::BindResponse(LdapBindResponse {
+                res: result,
+                saslcreds: None,
+            }),
+        ),
+    ] {
+        LdapCodec::new(None)
+            .encode(
+                LdapMsg {
+                    msgid,
+                    op,
+                    ctrl: vec![],
+                },
+                &mut buf,
+            )
+            .expect("Failed to encode");
+    }
+
+    let mut codec = BackendCodec::new(None);
+    let decoded = codec
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    let LdapOp::SearchResultDone(res) = decoded.op else {
+        panic!("Unexpected op {:?}", decoded.op);
+    };
+    assert_eq!(res.referral, referral);
+
+    let decoded = codec
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    let LdapOp::BindResponse(resp) = decoded.op else {
+        panic!("Unexpected op {:?}", decoded.op);
+    };
+    assert_eq!(resp.res.referral, referral);
+    assert!(buf.is_empty());
+}
+
+#[test]
+fn test_backend_codec_rejects_overlong_referral() {
+    use ldap_proxy::codec::BackendCodec;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Decoder;
+
+    // A searchResultDone whose referral claims 16 bytes but holds only 5.
+    let mut buf = BytesMut::from(
+        &[
+            0x30, 0x13, 0x02, 0x01, 0x01, 0x65, 0x0e, 0x0a, 0x01, 0x0a, 0x04, 0x00, 0x04, 0x00,
+            0xa3, 0x10, 0x04, 0x03, b'a', b'b', b'c',
+        ][..],
+    );
+    // The referral is not read past its result, and the frame is not decoded.
+    let decoded = BackendCodec::new(None).decode(&mut buf);
+    assert!(matches!(decoded, Ok(None) | Err(_)), "{decoded:?}");
+}
+
+#[test]
+fn test_client_codec_keeps_manage_dsa_it_criticality() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapCodec;
+    use ldap_proxy::codec::ClientCodec;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    for criticality in [true, false] {
+        let mut buf = BytesMut::new();
+        LdapCodec::new(None)
+            .encode(
+                LdapMsg {
+                    msgid: 1,
+                    op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+                    ctrl: vec![LdapControl::ManageDsaIT { criticality }],
+                },
+                &mut buf,
+            )
+            .expect("Failed to encode");
+
+        let decoded = ClientCodec::new(None, true)
+            .decode(&mut buf)
+            .expect("Failed to decode")
+            .expect("Incomplete message");
+        assert_eq!(decoded.ctrl, vec![LdapControl::ManageDsaIT { criticality }]);
+    }
+}
+
+#[test]
+fn test_client_codec_waits_for_truncated_frame() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapCodec;
+    use ldap_proxy::codec::ClientCodec;
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let mut frame = BytesMut::new();
+    LdapCodec::new(None)
+        .encode(
+            LdapMsg {
+                msgid: 1,
+                op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+                ctrl: vec![LdapControl::ManageDsaIT { criticality: true }],
+            },
+            &mut frame,
+        )
+        .expect("Failed to encode");
+
+    // Every prefix of the frame is incomplete and must not be decoded.
+    for len in 1..frame.len() {
+        let mut buf = BytesMut::from(&frame[..len]);
+        let decoded = ClientCodec::new(None, true)
+            .decode(&mut buf)
+            .expect("Failed to decode");
+        assert!(decoded.is_none(), "decoded a frame truncated to {len} bytes");
+    }
+}
+
+#[test]
+fn test_client_codec_sasl_bind() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{
+        LdapBindCred, LdapBindRequest, LdapBindResponse, LdapMsg, LdapOp, SaslCredentials,
+    };
+    use ldap3_proto::LdapCodec;
+    use ldap_proxy::codec::{BackendCodec, ClientCodec};
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    let request = LdapMsg {
+        msgid: 1,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: "cn=svc".to_string(),
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: "DIGEST-MD5".to_string(),
+                credentials: b"response".to_vec(),
+            }),
+        }),
+        ctrl: vec![LdapControl::ManageDsaIT { criticality: true }],
+    };
+    let mut buf = BytesMut::new();
+    LdapCodec::new(None)
+        .encode(request.clone(), &mut buf)
+        .expect("Failed to encode");
+    let mut codec = ClientCodec::new(None, true);
+    let decoded = codec
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    assert!(buf.is_empty());
+    assert_eq!(decoded, request);
+
+    // The serverSaslCreds of a response are sent with their [7] tag.
+    let response = LdapMsg {
+        msgid: 1,
+        op: LdapOp::BindResponse(LdapBindResponse {
+            res: common::ldap_result(ldap3_proto::LdapResultCode::SaslBindInProgress),
+            saslcreds: Some(b"challenge".to_vec()),
+        }),
+        ctrl: vec![],
+    };
+    codec
+        .encode(response.clone(), &mut buf)
+        .expect("Failed to encode");
+    assert!(buf.windows(2).any(|w| w == [0x87, 0x09]));
+    let decoded = BackendCodec::new(None)
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    assert_eq!(decoded, response);
+}
+
+#[test]
+fn test_backend_pool_config() {
+    let config = toml::from_str::<Config>(&format!(
+        "{}\n[backend_pool]\nper_dn_idle = 2\n",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    let pool = config.backend_pool.expect("No backend_pool config");
+    assert_eq!(pool.ping_interval_seconds.get(), 60);
+
+    let zero = toml::from_str::<Config>(&format!(
+        "{}\n[backend_pool]\nper_dn_idle = 2\nping_interval_seconds = 0\n",
+        common::BASE_CONFIG
+    ));
+    assert!(zero.is_err());
+}
+
+#[tokio::test]
+async fn test_backend_pool_reuses_connection() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        [backend_pool]
+        per_dn_idle = 2
+        ["cn=service"]
+    "#,
+    ));
+    let sr = common::search_request("dc=example,dc=com");
+
+    let mut first = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        first.bind(1, "cn=service", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    first.search(2, sr.clone()).await;
+    first
+        .send(LdapMsg {
+            msgid: 3,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    first.join().await;
+
+    let mut second = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        second.bind(1, "cn=service", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = second.search(2, sr).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    assert_eq!(backend.connection_count(), 1);
+    assert_eq!(backend.bind_count(), 1);
+
+    // Different credentials never get the pooled connection.
+    let mut third = common::TestClient::spawn(app_state);
+    third.bind(1, "cn=service", "other").await;
+    assert_eq!(backend.connection_count(), 2);
+    assert_eq!(backend.bind_count(), 2);
+}
+
+#[tokio::test]
+async fn test_max_buffered_entries_spill() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(sr) => {
+            let count = if sr.base == "dc=large" { 5 } else { 2 };
+            common::search_response(
+                msg.msgid,
+                (0..count)
+                    .map(|i| common::entry(&format!("cn={},{}", i, sr.base)))
+                    .collect(),
+                ldap3_proto::LdapResultCode::Success,
+            )
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_buffered_entries = 2
+        ["cn=reader"]
+    "#,
+    ));
+    let alias = backend.app_state(
+        r#"
+        stream_threshold_entries = 2
+        ["cn=reader"]
+    "#,
+    );
+    assert_eq!(alias.max_buffered_entries, Some(2));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    // At the limit the result is buffered and cached.
+    let small = common::search_request("dc=small");
+    let (entries, result) = client.search(2, small.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 2);
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &small).is_some());
+
+    // Over the limit every entry is still delivered, but nothing is cached.
+    let large = common::search_request("dc=large");
+    let (entries, result) = client.search(3, large.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 5);
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &large).is_none());
+
+    // The session is still usable afterwards.
+    let (entries, _) = client.search(4, small).await;
+    assert_eq!(entries.len(), 2);
+}
+
+#[tokio::test]
+async fn test_disable_cache_never_serves_stale_data() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=secret,dc=example,dc=com")],
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=audit"]
+        disable_cache = true
+    "#,
+    ));
+    let sr = common::search_request("dc=example,dc=com");
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=audit", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (entries, _) = client.search(2, sr.clone()).await;
+    assert_eq!(entries.len(), 1);
+    assert!(common::memory_cache_get(&app_state, "cn=audit", &sr).is_none());
+
+    // Even with stale data present, it is never served to this DN.
+    common::memory_cache_set(
+        &app_state,
+        "cn=audit",
+        &sr,
+        vec![common::entry("cn=stale,dc=example,dc=com")],
+    );
+    backend.set_online(false);
+
+    let (entries, result) = client.search(3, sr).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+    assert!(entries.is_empty());
+}
+
+#[test]
+fn test_backend_address_preference() {
+    use ldap_proxy::resolve::order_addrs;
+    use ldap_proxy::AddressPreference;
+
+    let resolved: Vec<SocketAddr> = [
+        "[2001:db8::1]:636",
+        "192.0.2.1:636",
+        "[2001:db8::2]:636",
+        "192.0.2.2:636",
+        "192.0.2.1:636",
+    ]
+    .iter()
+    .map(|a| a.parse().expect("invalid address"))
+    .collect();
+    let addr = |a: &str| -> SocketAddr { a.parse().expect("invalid address") };
+
+    // Every distinct address is kept, in resolver order.
+    assert_eq!(
+        order_addrs(resolved.clone(), AddressPreference::System),
+        vec![
+            addr("[2001:db8::1]:636"),
+            addr("192.0.2.1:636"),
+            addr("[2001:db8::2]:636"),
+            addr("192.0.2.2:636"),
+        ]
+    );
+    assert_eq!(
+        order_addrs(resolved.clone(), AddressPreference::Ipv4First),
+        vec![
+            addr("192.0.2.1:636"),
+            addr("192.0.2.2:636"),
+            addr("[2001:db8::1]:636"),
+            addr("[2001:db8::2]:636"),
+        ]
+    );
+    assert_eq!(
+        order_addrs(resolved, AddressPreference::Ipv6First),
+        vec![
+            addr("[2001:db8::1]:636"),
+            addr("[2001:db8::2]:636"),
+            addr("192.0.2.1:636"),
+            addr("192.0.2.2:636"),
+        ]
+    );
+
+    let config: Config = toml::from_str(&format!(
+        "{}\naddress_preference = \"ipv6_first\"\nresolve_refresh_secs = 300",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.address_preference, AddressPreference::Ipv6First);
+    assert_eq!(config.resolve_refresh_secs.map(std::num::NonZeroU64::get), Some(300));
+
+    let zero = toml::from_str::<Config>(&format!(
+        "{}\nresolve_refresh_secs = 0",
+        common::BASE_CONFIG
+    ));
+    assert!(zero.is_err());
+}
+
+#[test]
+fn test_resolve_backend_addrs() {
+    use ldap_proxy::resolve::resolve_backend_addrs;
+    use ldap_proxy::AddressPreference;
+
+    let url = "ldaps://localhost".parse().expect("invalid url");
+    let addrs = resolve_backend_addrs(&url, AddressPreference::Ipv4First).expect("resolve");
+    assert!(addrs.contains(&"127.0.0.1:636".parse().expect("invalid address")));
+
+    let url = "ldaps://[::1]:1636".parse().expect("invalid url");
+    let addrs = resolve_backend_addrs(&url, AddressPreference::System).expect("resolve");
+    assert_eq!(addrs, vec!["[::1]:1636".parse().expect("invalid address")]);
+}
+
+#[tokio::test]
+async fn test_stale_backend_msgid_is_skipped() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut resps = Vec::new();
+            if msg.msgid > 2 {
+                // A late duplicate of the previous search's results.
+                resps.extend(common::search_response(
+                    msg.msgid - 1,
+                    vec![common::entry("cn=stale,dc=example,dc=com")],
+                    ldap3_proto::LdapResultCode::Success,
+                ));
+            }
+            resps.extend(common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=current,dc=example,dc=com")],
+                ldap3_proto::LdapResultCode::Success,
+            ));
+            resps
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    // Without the cache a sequence error would surface as unavailable.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        disable_cache = true
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let sr = common::search_request("dc=example,dc=com");
+    for msgid in [2, 3] {
+        let (entries, result) = client.search(msgid, sr.clone()).await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dn, "cn=current,dc=example,dc=com");
+    }
+
+    // The session kept its backend connection throughout.
+    assert_eq!(backend.connection_count(), 1);
+    assert_eq!(backend.search_count(), 2);
+}
+
+#[tokio::test]
+async fn test_degraded_result_code_served_from_cache() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let busy = Arc::new(AtomicBool::new(false));
+    let c_busy = busy.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        LdapOp::SearchRequest(_) if c_busy.load(Ordering::SeqCst) => {
+            common::search_response(msg.msgid, vec![], ldap3_proto::LdapResultCode::Busy)
+        }
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=cached,dc=example,dc=com")],
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let cached = common::search_request("dc=example,dc=com");
+    client.search(2, cached.clone()).await;
+
+    busy.store(true, Ordering::SeqCst);
+
+    // A busy backend is answered from the cache when there is data.
+    let (entries, result) = client.search(3, cached).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+
+    // Otherwise the busy result is relayed and the session continues.
+    let uncached = common::search_request("ou=other,dc=example,dc=com");
+    let (_, result) = client.search(4, uncached.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Busy);
+    let (_, result) = client.search(5, uncached).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Busy);
+}
+
+fn run_check_config(name: &str, config: &str) -> std::process::Output {
+    run_check(name, config, &[])
+}
+
+fn run_check(name: &str, config: &str, extra_args: &[&str]) -> std::process::Output {
+    let path = std::env::temp_dir().join(format!(
+        "ldap-proxy-check-{}-{}.toml",
+        name,
+        std::process::id()
+    ));
+    std::fs::write(&path, config).expect("Failed to write config");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_ldap-proxy"))
+        .arg("--check-config")
+        .arg("--config")
+        .arg(&path)
+        .args(extra_args)
+        .output()
+        .expect("Failed to run ldap-proxy");
+    let _ = std::fs::remove_file(&path);
+    output
+}
+
+#[test]
+fn test_check_config_reports_all_problems() {
+    let output = run_check_config(
+        "invalid",
+        r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "/nonexistent/chain.pem"
+        tls_key = "/nonexistent/key.pem"
+        ldap_ca = "/nonexistent/ldap-ca.pem"
+        ldap_url = "ldaps://localhost"
+
+        ["cn=reader"]
+        allowed_queries = [
+            ["", "base", "(objectClass=*"],
+            ["", "base", "bogus"],
+        ]
+    "#,
+    );
+    assert!(!output.status.success());
+
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("\"(objectClass=*\""));
+    assert!(stderr.contains("\"bogus\""));
+    assert!(stderr.contains("certificate chain"));
+    assert!(stderr.contains("ldap-ca.pem"));
+}
+
+#[test]
+fn test_check_config_valid() {
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-check-tls-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create dir");
+    let (pkey, cert) = common::self_signed_cert("localhost");
+    let chain = dir.join("chain.pem");
+    let key = dir.join("key.pem");
+    std::fs::write(&chain, cert.to_pem().expect("pem")).expect("write chain");
+    std::fs::write(&key, pkey.private_key_to_pem_pkcs8().expect("pem")).expect("write key");
+
+    let output = run_check_config(
+        "valid",
+        &format!(
+            r#"
+            bind = "127.0.0.1:3636"
+            tls_chain = "{chain}"
+            tls_key = "{key}"
+            ldap_ca = "{chain}"
+            ldap_url = "ldaps://localhost"
+
+            ["cn=reader"]
+            allowed_queries = [["", "base", "(objectClass=*)"]]
+        "#,
+            chain = chain.display(),
+            key = key.display(),
+        ),
+    );
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[tokio::test]
+async fn test_cached_value_records_source_addr() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let sr = common::search_request("dc=example,dc=com");
+    client.search(2, sr.clone()).await;
+
+    let cached = common::memory_cache_get(&app_state, "cn=reader", &sr).expect("not cached");
+    assert_eq!(cached.source_addr, Some(backend.addr));
+
+    // Values cached before source_addr was recorded still load.
+    let mut json = serde_json::to_value(&cached).expect("serialize");
+    json.as_object_mut()
+        .expect("object")
+        .remove("source_addr");
+    let old: CachedValue = serde_json::from_value(json).expect("deserialize");
+    assert_eq!(old.source_addr, None);
+}
+
+const DN_REWRITE_CONFIG: &str = r#"
+    [[dn_rewrite]]
+    client_suffix = "dc=local"
+    backend_suffix = "dc=example,dc=com"
+
+    [[dn_rewrite]]
+    client_suffix = ""
+    backend_suffix = "ou=people,dc=example,dc=com"
+"#;
+
+#[test]
+fn test_rewrite_dn() {
+    let config: Config = toml::from_str(&format!("{}\n{}", common::BASE_CONFIG, DN_REWRITE_CONFIG))
+        .expect("Failed to parse config");
+    let rewrite = |dn| ldap_proxy::rewrite_dn(&config.dn_rewrite, dn);
+
+    assert_eq!(rewrite("uid=alice"), "uid=alice,ou=people,dc=example,dc=com");
+    assert_eq!(rewrite("ou=groups,DC=Local"), "ou=groups,dc=example,dc=com");
+    assert_eq!(rewrite("dc=local"), "dc=example,dc=com");
+    // Suffixes only match whole RDNs.
+    assert_eq!(rewrite("dc=xlocal"), "dc=xlocal,ou=people,dc=example,dc=com");
+    // The empty DN is left alone.
+    assert_eq!(rewrite(""), "");
+}
+
+#[tokio::test]
+async fn test_dn_rewrite_bind_and_search() {
+    use ldap3_proto::proto::{LdapExtendedRequest, LdapMsg, LdapOp};
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        "{}\n[\"uid=alice\"]",
+        DN_REWRITE_CONFIG
+    )));
+
+    // The bind map is keyed by the client DN.
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "uid=alice", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = client
+        .search(2, common::search_request("ou=groups,dc=local"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    let requests = backend.requests();
+    assert!(matches!(
+        &requests[0].op,
+        LdapOp::BindRequest(lbr) if lbr.dn == "uid=alice,ou=people,dc=example,dc=com"
+    ));
+    assert!(matches!(
+        &requests[1].op,
+        LdapOp::SearchRequest(sr) if sr.base == "ou=groups,dc=example,dc=com"
+    ));
+
+    // "Who am I?" answers with the authzId of the DN the client bound as.
+    client
+        .send(LdapMsg {
+            msgid: 3,
+            op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                value: None,
+            }),
+            ctrl: vec![],
+        })
+        .await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::ExtendedResponse(resp)) => {
+            assert_eq!(resp.value, Some(b"dn:uid=alice".to_vec()))
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+}
+
+#[test]
+fn test_cachedvalue_data_differs() {
+    let value = |entries: Vec<&str>| CachedValue {
+        cached_at: SystemTime::now(),
+        entries: entries
+            .into_iter()
+            .map(|dn| (common::entry(dn), vec![]))
+            .collect(),
+        result: common::ldap_result(ldap3_proto::LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+
+    let a = value(vec!["cn=a"]);
+    let mut b = value(vec!["cn=a"]);
+    b.cached_at = SystemTime::UNIX_EPOCH;
+    b.source_addr = Some("127.0.0.1:636".parse().expect("invalid address"));
+
+    // Only the data is compared.
+    assert!(!a.data_differs(&b));
+    assert!(a.data_differs(&value(vec!["cn=b"])));
+}
+
+#[test]
+fn test_cachedvalue_sort_entries() {
+    let value = |entries: Vec<&str>| CachedValue {
+        cached_at: SystemTime::now(),
+        entries: entries
+            .into_iter()
+            .map(|dn| (common::entry(dn), vec![]))
+            .collect(),
+        result: common::ldap_result(ldap3_proto::LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+
+    let mut a = value(vec!["cn=a", "cn=b", "cn=c"]);
+    let mut b = value(vec!["cn=c", "cn=a", "cn=b"]);
+    assert!(a.data_differs(&b));
+
+    a.sort_entries();
+    b.sort_entries();
+    assert!(!a.data_differs(&b));
+    let dns: Vec<_> = b.entries.iter().map(|(e, _)| e.dn.as_str()).collect();
+    assert_eq!(dns, vec!["cn=a", "cn=b", "cn=c"]);
+
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert!(!config.sort_cached_entries);
+}
+
+/// Bind as a dn that is only allowed to search one base, and make a search
+/// outside of it.
+async fn denied_search(
+    action: &str,
+) -> (
+    common::MockBackend,
+    common::TestClient,
+    ldap3_proto::proto::LdapResult,
+) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        denied_query_action = "{}"
+        ["cn=reader"]
+        allowed_bases = ["ou=people,dc=example,dc=com"]
+    "#,
+        action
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    let (entries, result) = client
+        .search(2, common::search_request("ou=groups,dc=example,dc=com"))
+        .await;
+    assert!(entries.is_empty());
+    assert_eq!(backend.search_count(), 0);
+    (backend, client, result)
+}
+
+#[tokio::test]
+async fn test_denied_query_action_access_denied() {
+    let (backend, mut client, result) = denied_search("access_denied").await;
+    assert_eq!(
+        result.code,
+        ldap3_proto::LdapResultCode::InsufficentAccessRights
+    );
+
+    let (_, result) = client
+        .search(3, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 1);
+}
+
+#[tokio::test]
+async fn test_denied_query_action_empty_success() {
+    let (backend, mut client, result) = denied_search("empty_success").await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    let (_, result) = client
+        .search(3, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 1);
+}
+
+#[tokio::test]
+async fn test_denied_query_action_disconnect() {
+    let (_backend, mut client, result) = denied_search("disconnect").await;
+    assert_eq!(
+        result.code,
+        ldap3_proto::LdapResultCode::InsufficentAccessRights
+    );
+
+    match client.recv().await.map(|msg| msg.op) {
+        Some(ldap3_proto::proto::LdapOp::ExtendedResponse(resp)) => {
+            assert_eq!(resp.res.code, ldap3_proto::LdapResultCode::InsufficentAccessRights)
+        }
+        op => panic!("Expected a notice of disconnection, got {:?}", op),
+    }
+    assert!(client.recv().await.is_none());
+    client.join().await;
+}
+
+#[test]
+fn test_denied_query_action_default() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(
+        config.denied_query_action,
+        ldap_proxy::DeniedQueryAction::AccessDenied
+    );
+}
+
+#[tokio::test]
+async fn test_health_endpoints() {
+    use ldap_proxy::health::{self, BackendHealth};
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(""));
+    let backend_health = Arc::new(BackendHealth::default());
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let addr = listener.local_addr().expect("No local addr");
+    tokio::spawn(health::serve(
+        listener,
+        app_state.clone(),
+        backend_health.clone(),
+    ));
+
+    let (status, _) = common::http_get(addr, "/livez").await;
+    assert_eq!(status, 200);
+
+    // Nothing has been checked yet, so no backend is known to be reachable.
+    let (status, _) = common::http_get(addr, "/readyz").await;
+    assert_eq!(status, 503);
+
+    backend_health.check(&app_state).await;
+    let (status, body) = common::http_get(addr, "/readyz").await;
+    assert_eq!(status, 200);
+    let body: serde_json::Value = serde_json::from_str(&body).expect("Invalid json");
+    assert_eq!(body["ready"], true);
+    assert_eq!(body["backend"]["reachable"][0], backend.addr.to_string());
+    assert_eq!(body["cache"], "memory");
+
+    backend.set_online(false);
+    backend_health.check(&app_state).await;
+    let (status, body) = common::http_get(addr, "/readyz").await;
+    assert_eq!(status, 503);
+    let body: serde_json::Value = serde_json::from_str(&body).expect("Invalid json");
+    assert_eq!(body["backend"]["unreachable"][0], backend.addr.to_string());
+
+    // Liveness does not depend on the backend.
+    let (status, _) = common::http_get(addr, "/livez").await;
+    assert_eq!(status, 200);
+
+    let (status, _) = common::http_get(addr, "/unknown").await;
+    assert_eq!(status, 404);
+}
+
+#[test]
+fn test_health_config() {
+    let config: Config = toml::from_str(&format!(
+        "{}\n[health]\nbind = \"127.0.0.1:8080\"\n",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    let health = config.health.expect("No health config");
+    assert_eq!(health.bind, "127.0.0.1:8080".parse().expect("Invalid addr"));
+    assert_eq!(health.check_interval_seconds.get(), 10);
+
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert!(config.health.is_none());
+
+    let zero = toml::from_str::<Config>(&format!(
+        "{}\n[health]\nbind = \"127.0.0.1:8080\"\ncheck_interval_seconds = 0\n",
+        common::BASE_CONFIG
+    ));
+    assert!(zero.is_err());
+}
+
+/// Searches of `ou=stuck` are never answered by this backend.
+fn stuck_search_handler(msg: &ldap3_proto::proto::LdapMsg) -> Vec<ldap3_proto::proto::LdapMsg> {
+    match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(sr) if sr.base.starts_with("ou=stuck") => vec![],
+        _ => common::default_handler(msg),
+    }
+}
+
+fn search_msg(msgid: i32, base: &str) -> ldap3_proto::proto::LdapMsg {
+    ldap3_proto::proto::LdapMsg {
+        msgid,
+        op: ldap3_proto::proto::LdapOp::SearchRequest(common::search_request(base)),
+        ctrl: vec![],
+    }
+}
+
+#[tokio::test]
+async fn test_max_concurrent_ops() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(stuck_search_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_concurrent_ops = 2
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    // The second search is answered while the first is still outstanding, on
+    // a second backend connection.
+    client.send(search_msg(2, "ou=stuck,dc=example,dc=com")).await;
+    client.send(search_msg(3, "ou=people,dc=example,dc=com")).await;
+    let msg = client.recv().await.expect("Connection closed");
+    assert_eq!(msg.msgid, 3);
+    assert!(matches!(msg.op, LdapOp::SearchResultDone(_)));
+    assert_eq!(backend.connection_count(), 2);
+
+    // A message id may not be reused while its operation is outstanding.
+    client.send(search_msg(2, "ou=people,dc=example,dc=com")).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::ExtendedResponse(resp)) => {
+            assert_eq!(resp.res.code, ldap3_proto::LdapResultCode::ProtocolError)
+        }
+        op => panic!("Expected a notice of disconnection, got {:?}", op),
+    }
+    assert!(client.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_max_concurrent_ops_reuses_connections() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_concurrent_ops = 4
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    for msgid in 2..6 {
+        client.send(search_msg(msgid, "ou=people,dc=example,dc=com")).await;
+    }
+    // Anything other than a search waits for the outstanding searches.
+    client
+        .send(ldap3_proto::proto::LdapMsg {
+            msgid: 6,
+            op: LdapOp::ExtendedRequest(ldap3_proto::proto::LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                value: None,
+            }),
+            ctrl: vec![],
+        })
+        .await;
+
+    let mut done = Vec::new();
+    loop {
+        let msg = client.recv().await.expect("Connection closed");
+        match msg.op {
+            LdapOp::SearchResultDone(_) => done.push(msg.msgid),
+            LdapOp::ExtendedResponse(_) => {
+                assert_eq!(msg.msgid, 6);
+                break;
+            }
+            op => panic!("Unexpected response {:?}", op),
+        }
+    }
+    done.sort();
+    assert_eq!(done, vec![2, 3, 4, 5]);
+    let connections = backend.connection_count();
+    assert!((1..=4).contains(&connections));
+
+    // Idle connections are reused rather than opening more.
+    for msgid in 7..11 {
+        let (_, result) = client
+            .search(msgid, common::search_request("ou=people,dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    }
+    assert_eq!(backend.connection_count(), connections);
+}
+
+#[tokio::test]
+async fn test_max_concurrent_ops_default_is_serial() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(stuck_search_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    for msgid in 2..5 {
+        client.send(search_msg(msgid, "ou=people,dc=example,dc=com")).await;
+    }
+    for msgid in 2..5 {
+        let msg = client.recv().await.expect("Connection closed");
+        assert_eq!(msg.msgid, msgid);
+        assert!(matches!(msg.op, LdapOp::SearchResultDone(_)));
+    }
+    assert_eq!(backend.connection_count(), 1);
+}
+
+/// A Redis cache config pointing at a local port nothing listens on.
+async fn unreachable_redis_config() -> ldap_proxy::CacheConfig {
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let addr = listener.local_addr().expect("No local addr");
+    drop(listener);
+
+    toml::from_str(&format!(
+        r#"
+        type = "redis"
+        url = "redis://{}"
+    "#,
+        addr
+    ))
+    .expect("Failed to parse cache config")
+}
+
+#[tokio::test]
+async fn test_require_cache_unreachable_redis() {
+    let cache_config = unreachable_redis_config().await;
+    let result = ldap_proxy::CacheBackend::from_config(&cache_config, true).await;
+    let Err(e) = result else {
+        panic!("Startup should fail when the cache is required");
+    };
+    assert!(e.contains("Unable to connect to Redis"), "{}", e);
+}
+
+#[tokio::test]
+async fn test_optional_cache_unreachable_redis() {
+    let cache_config = unreachable_redis_config().await;
+    let (cache, _) = ldap_proxy::CacheBackend::from_config(&cache_config, false)
+        .await
+        .expect("Startup should continue when the cache is optional");
+    assert!(matches!(cache, ldap_proxy::CacheBackend::Memory(_)));
+}
+
+#[test]
+fn test_require_cache_default() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert!(config.require_cache);
+}
+
+#[test]
+fn test_normalize_dn() {
+    use ldap_proxy::dn::normalize_dn;
+
+    let normalize = |dn: &str| normalize_dn(dn).expect("Failed to parse dn");
+
+    assert_eq!(normalize(""), "");
+    assert_eq!(
+        normalize("CN=Administrator, DC=example ,dc=com"),
+        "cn=Administrator,dc=example,dc=com"
+    );
+    assert_eq!(
+        normalize("  cn = Administrator ,  dc = example , dc = com  "),
+        "cn=Administrator,dc=example,dc=com"
+    );
+    // Attribute values keep their case.
+    assert_ne!(normalize("cn=Administrator"), normalize("cn=administrator"));
+    // The values of a multi-valued RDN are unordered.
+    assert_eq!(normalize("UID=b+CN=a,dc=com"), normalize("cn=a + uid=b,dc=com"));
+    // Escaped and hex escaped characters are equivalent.
+    assert_eq!(normalize("cn=Smith\\, John,dc=com"), "cn=Smith\\, John,dc=com");
+    assert_eq!(normalize("cn=Smith\\2C John,dc=com"), "cn=Smith\\, John,dc=com");
+    assert_eq!(normalize("cn=\\4a\\c3\\a9r\\c3\\b4me"), "cn=Jérôme");
+    // Escaped spaces at the ends of a value are significant.
+    assert_eq!(normalize("cn=\\ padded\\ ,dc=com"), "cn=\\ padded\\ ,dc=com");
+    assert_eq!(normalize("OID.2.5.4.3=test"), "2.5.4.3=test");
+    assert_eq!(normalize("cn=#04024869, dc=com"), "cn=#04024869,dc=com");
+    assert_eq!(normalize("cn=a;dc=com"), "cn=a,dc=com");
+
+    for invalid in ["cn", "cn=a,", "=a", "c_n=a", "cn=a\\zz", "cn=a\"b", "cn=#0", "1.02=a"] {
+        assert!(normalize_dn(invalid).is_err(), "{:?} should be invalid", invalid);
+    }
+}
+
+#[test]
+fn test_binddn_map_keys_normalized() {
+    let config: Config = toml::from_str(&format!(
+        r#"{}
+        ["CN=Administrator, DC=example, DC=com"]
+        allowed_bases = ["dc=example,dc=com"]
+    "#,
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert!(config
+        .binddn_map
+        .contains_key("cn=Administrator,dc=example,dc=com"));
+
+    let duplicate = toml::from_str::<Config>(&format!(
+        r#"{}
+        ["cn=Administrator,dc=example,dc=com"]
+        ["CN=Administrator, DC=example, DC=com"]
+    "#,
+        common::BASE_CONFIG
+    ));
+    let Err(e) = duplicate else {
+        panic!("Equivalent bind dns should be rejected");
+    };
+    assert!(e.to_string().contains("configured more than once"), "{}", e);
+
+    let invalid = toml::from_str::<Config>(&format!(
+        r#"{}
+        ["cn=a,"]
+    "#,
+        common::BASE_CONFIG
+    ));
+    assert!(invalid.is_err());
+}
+
+#[tokio::test]
+async fn test_bind_dn_normalized_lookup() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=Administrator,dc=example,dc=com"]
+        allowed_bases = ["dc=example,dc=com"]
+    "#,
+    ));
+
+    for (msgid, bind_dn) in [
+        "cn=Administrator,dc=example,dc=com",
+        "CN=Administrator,DC=example,DC=com",
+        "cn=Administrator, dc=example, dc=com",
+        " Cn = Administrator ,dc=example,Dc=com ",
+    ]
+    .into_iter()
+    .enumerate()
+    {
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(
+            client.bind(msgid as i32 + 1, bind_dn, "password").await,
+            ldap3_proto::LdapResultCode::Success,
+            "{:?} should match the bind map",
+            bind_dn
+        );
+    }
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=administrator,dc=example,dc=com", "password").await,
+        ldap3_proto::LdapResultCode::OperationsError
+    );
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=Administrator,", "password").await,
+        ldap3_proto::LdapResultCode::InvalidDNSyntax
+    );
+    assert_eq!(backend.bind_count(), 4);
+}
+
+#[test]
+fn test_circuit_breaker() {
+    use ldap_proxy::breaker::CircuitBreaker;
+    use std::time::Duration;
+
+    let breaker = CircuitBreaker::new(3, Duration::from_millis(50));
+    assert!(breaker.allow());
+    breaker.record_failure();
+    breaker.record_failure();
+    assert!(!breaker.is_open());
+    // A success resets the count of consecutive failures.
+    breaker.record_success();
+    breaker.record_failure();
+    breaker.record_failure();
+    assert!(breaker.allow());
+
+    breaker.record_failure();
+    assert!(breaker.is_open());
+    assert!(!breaker.allow());
+
+    // After the cooldown only a single probe is let through.
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow());
+    assert!(!breaker.allow());
+    breaker.record_failure();
+    assert!(!breaker.allow());
+
+    std::thread::sleep(Duration::from_millis(60));
+    assert!(breaker.allow());
+    breaker.record_success();
+    assert!(!breaker.is_open());
+    assert!(breaker.allow());
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_short_circuits_connects() {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    // A backend that accepts connections and immediately drops them.
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let addr = listener.local_addr().expect("No local addr");
+    let accepted = Arc::new(AtomicUsize::new(0));
+    let c_accepted = accepted.clone();
+    tokio::spawn(async move {
+        while let Ok((stream, _)) = listener.accept().await {
+            c_accepted.fetch_add(1, Ordering::SeqCst);
+            drop(stream);
+        }
+    });
+
+    let tls_params = openssl::ssl::SslConnector::builder(openssl::ssl::SslMethod::tls_client())
+        .expect("Failed to create connector")
+        .build();
+    let app_state = Arc::new(common::app_state(
+        r#"
+        breaker_threshold = 2
+        breaker_cooldown_secs = 60
+        ["cn=reader"]
+    "#,
+        vec![addr],
+        tls_params,
+    ));
+
+    for attempt in 1..=4 {
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(
+            client.bind(1, "cn=reader", "password").await,
+            ldap3_proto::LdapResultCode::OperationsError
+        );
+        // Only the attempts up to the threshold reach the backend.
+        assert_eq!(accepted.load(Ordering::SeqCst), attempt.min(2));
+    }
+    assert!(app_state.breaker.as_ref().expect("No breaker").is_open());
+}
+
+#[tokio::test]
+async fn test_circuit_breaker_serves_searches_from_cache() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        breaker_threshold = 1
+        breaker_cooldown_secs = 1
+        ["cn=reader"]
+    "#,
+    ));
+    let sr = common::search_request("dc=example,dc=com");
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    common::memory_cache_set(
+        &app_state,
+        "cn=reader",
+        &sr,
+        vec![common::entry("cn=cached,dc=example,dc=com")],
+    );
+
+    // Another session failing to reach the backend opens the breaker.
+    app_state
+        .breaker
+        .as_ref()
+        .expect("No breaker")
+        .record_failure();
+
+    let (entries, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "cn=cached,dc=example,dc=com");
+    assert_eq!(backend.search_count(), 0);
+
+    // After the cooldown the session's next search probes the backend, and
+    // its success closes the breaker without any bind.
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    let (_, result) = client.search(3, sr).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 1);
+    assert!(!app_state.breaker.as_ref().expect("No breaker").is_open());
+}
+
+#[test]
+fn test_circuit_breaker_config() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert!(config.circuit_breaker().is_none());
+    assert_eq!(config.breaker_cooldown_secs, 30);
+
+    let zero_threshold =
+        toml::from_str::<Config>(&format!("breaker_threshold = 0\n{}", common::BASE_CONFIG));
+    assert!(zero_threshold.is_err());
+}
+
+#[test]
+fn test_redact_bind_credentials() {
+    use ldap3_proto::proto::{LdapBindCred, LdapBindRequest, LdapMsg, LdapOp, SaslCredentials};
+    use ldap_proxy::redact::redact;
+
+    let simple = LdapBindRequest {
+        dn: "cn=Directory Manager".to_string(),
+        cred: LdapBindCred::Simple("hunter2".to_string()),
+    };
+    let logged = format!("{:?}", redact(&simple));
+    assert!(logged.contains("cn=Directory Manager"));
+    assert!(logged.contains("***"));
+    assert!(!logged.contains("hunter2"));
+
+    let sasl = LdapMsg {
+        msgid: 1,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: String::new(),
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: "PLAIN".to_string(),
+                credentials: b"\0user\0hunter2".to_vec(),
+            }),
+        }),
+        ctrl: vec![],
+    };
+    let logged = format!("{:?}", redact(&sasl));
+    assert!(logged.contains("PLAIN"));
+    assert!(!logged.contains("hunter2"));
+    // The bytes of the credentials must not appear either.
+    assert!(!logged.contains("104, 117, 110"));
+}
+
+#[test]
+fn test_redact_sensitive_attributes() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp, LdapPartialAttribute, LdapSearchResultEntry};
+    use ldap_proxy::redact::{redact, Redacted};
+    use std::collections::HashSet;
+
+    let msg = LdapMsg {
+        msgid: 2,
+        op: LdapOp::SearchResultEntry(LdapSearchResultEntry {
+            dn: "uid=alice,dc=example,dc=com".to_string(),
+            attributes: vec![
+                LdapPartialAttribute {
+                    atype: "cn".to_string(),
+                    vals: vec![b"Alice".to_vec()],
+                },
+                LdapPartialAttribute {
+                    atype: "USERPASSWORD".to_string(),
+                    vals: vec![b"{SSHA}secrethash".to_vec()],
+                },
+                LdapPartialAttribute {
+                    atype: "employeeNumber".to_string(),
+                    vals: vec![b"12345".to_vec()],
+                },
+            ],
+        }),
+        ctrl: vec![],
+    };
+
+    let logged = format!("{:?}", redact(&msg));
+    assert!(logged.contains("Alice"));
+    assert!(logged.contains("12345"));
+    assert!(!logged.contains("secrethash"));
+
+    let sensitive: HashSet<String> = ["employeenumber".to_string()].into();
+    let logged = format!("{:?}", Redacted::with_attributes(&msg, &sensitive));
+    assert!(logged.contains("Alice"));
+    assert!(logged.contains("secrethash"));
+    assert!(!logged.contains("12345"));
+}
+
+#[test]
+fn test_sensitive_attributes_config() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert!(config
+        .sensitive_attributes
+        .iter()
+        .any(|a| a == "userPassword"));
+
+    let config = toml::from_str::<Config>(&format!(
+        "sensitive_attributes = [\"employeeNumber\"]\n{}",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.sensitive_attributes, vec!["employeeNumber"]);
+}
+
+/// Bind, take the backend offline and search for something that was never
+/// cached.
+async fn search_without_fallback(
+    extra_config: &str,
+) -> (common::TestClient, ldap3_proto::proto::LdapResult) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        {}
+        ["cn=reader"]
+    "#,
+        extra_config
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    backend.set_online(false);
+
+    let (entries, result) = client
+        .search(2, common::search_request("dc=example,dc=com"))
+        .await;
+    assert!(entries.is_empty());
+    (client, result)
+}
+
+#[tokio::test]
+async fn test_no_fallback_action_unavailable() {
+    let (mut client, result) = search_without_fallback("").await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+
+    // The unavailable reply is followed by a notice of disconnection.
+    let notice = client.recv().await.expect("Expected a disconnect notice");
+    assert!(matches!(
+        notice.op,
+        ldap3_proto::proto::LdapOp::ExtendedResponse(_)
+    ));
+    assert!(client.recv().await.is_none());
+}
+
+#[tokio::test]
+async fn test_no_fallback_action_empty_success() {
+    let (mut client, result) =
+        search_without_fallback(r#"no_fallback_action = "empty_success""#).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    // The connection stays open for further searches.
+    let (entries, result) = client
+        .search(3, common::search_request("dc=example,dc=com"))
+        .await;
+    assert!(entries.is_empty());
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_backend_tls_sni() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    // The host of ldap_url is sent by default.
+    assert_eq!(
+        backend.server_names(),
+        vec![Some("ldap.example.com".to_string())]
+    );
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ldap_tls_sni = "ldap-frontend.example.com"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    assert_eq!(
+        backend.server_names(),
+        vec![Some("ldap-frontend.example.com".to_string())]
+    );
+}
+
+#[tokio::test]
+async fn test_backend_tls_sni_omitted_for_ip_address() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ldap_tls_sni = "127.0.0.1"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    assert_eq!(backend.server_names(), vec![None]);
+}
+
+#[test]
+fn test_backend_tls_name() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(config.backend_tls_name(), Some("ldap.example.com"));
+
+    let config = toml::from_str::<Config>(&format!(
+        "{}\nldap_tls_sni = \"ldap-frontend.example.com\"",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.backend_tls_name(), Some("ldap-frontend.example.com"));
+}
+
+/// Check connectivity to a backend whose certificate is issued to
+/// `cert_hostname`, while `ldap_url` names localhost.
+async fn check_backend_certificate(
+    name: &'static str,
+    cert_hostname: &str,
+    extra_config: &str,
+) -> std::process::Output {
+    use std::sync::Arc;
+
+    let backend =
+        common::MockBackend::start_with_hostname(Arc::new(common::default_handler), cert_hostname)
+            .await;
+    let dir = std::env::temp_dir().join(format!(
+        "ldap-proxy-verify-{}-{}",
+        name,
+        std::process::id()
+    ));
+    std::fs::create_dir_all(&dir).expect("Failed to create dir");
+    let (pkey, cert) = common::self_signed_cert("localhost");
+    let chain = dir.join("chain.pem");
+    let key = dir.join("key.pem");
+    let ldap_ca = dir.join("ldap-ca.pem");
+    std::fs::write(&chain, cert.to_pem().expect("pem")).expect("write chain");
+    std::fs::write(&key, pkey.private_key_to_pem_pkcs8().expect("pem")).expect("write key");
+    std::fs::write(&ldap_ca, backend.cert_pem()).expect("write ldap_ca");
+
+    let config = format!(
+        r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "{chain}"
+        tls_key = "{key}"
+        ldap_ca = "{ldap_ca}"
+        ldap_url = "ldaps://localhost:{port}"
+        {extra_config}
+
+        ["cn=reader"]
+    "#,
+        chain = chain.display(),
+        key = key.display(),
+        ldap_ca = ldap_ca.display(),
+        port = backend.addr.port(),
+    );
+    let output = tokio::task::spawn_blocking(move || {
+        run_check(name, &config, &["--check-connectivity"])
+    })
+    .await
+    .expect("check panicked");
+    let _ = std::fs::remove_dir_all(&dir);
+    output
+}
+
+#[tokio::test]
+async fn test_backend_certificate_hostname_verified() {
+    let output = check_backend_certificate("matching", "localhost", "").await;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+
+    // A certificate that chains to ldap_ca but names another host is rejected.
+    let output = check_backend_certificate("mismatched", "other.example.com", "").await;
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("Unable to connect to backend"));
+}
+
+#[tokio::test]
+async fn test_backend_certificate_insecure_skip_verify() {
+    let output = check_backend_certificate(
+        "skip-verify",
+        "other.example.com",
+        "ldap_tls_insecure_skip_verify = true",
+    )
+    .await;
+    assert!(
+        output.status.success(),
+        "{}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+}
+
+#[tokio::test]
+async fn test_backend_bind_timeout() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // The backend accepts the connection but never answers binds.
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::BindRequest(_) => vec![],
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        backend_bind_timeout_secs = 1
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    let started = Instant::now();
+    let code = tokio::time::timeout(Duration::from_secs(5), client.bind(1, "cn=reader", "password"))
+        .await
+        .expect("The bind was not timed out");
+    assert_eq!(code, ldap3_proto::LdapResultCode::OperationsError);
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[test]
+fn test_backend_bind_timeout_config() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(config.backend_bind_timeout_secs.get(), 10);
+
+    let zero = toml::from_str::<Config>(&format!(
+        "backend_bind_timeout_secs = 0\n{}",
+        common::BASE_CONFIG
+    ));
+    assert!(zero.is_err());
+}
+
+const REFERRAL_REWRITE_CONFIG: &str = r#"
+    [[referral_rewrite]]
+    backend_url = "ldap://internal-dc01:389"
+    client_url = "ldaps://ldap-proxy.example.com:636"
+"#;
+
+#[test]
+fn test_rewrite_referral() {
+    let config: Config = toml::from_str(&format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        REFERRAL_REWRITE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    let rewrite = |referral| ldap_proxy::rewrite_referral(&config.referral_rewrite, referral);
+
+    assert_eq!(
+        rewrite("ldap://internal-dc01:389/ou=people,dc=example,dc=com??sub").as_deref(),
+        Some("ldaps://ldap-proxy.example.com:636/ou=people,dc=example,dc=com??sub")
+    );
+    assert_eq!(
+        rewrite("LDAP://Internal-DC01:389").as_deref(),
+        Some("ldaps://ldap-proxy.example.com:636")
+    );
+    // The host and port must match exactly.
+    assert_eq!(rewrite("ldap://internal-dc01:3890/dc=example,dc=com"), None);
+    assert_eq!(rewrite("ldap://internal-dc02:389/dc=example,dc=com"), None);
+}
+
+/// Search a backend that answers every search with a referral to each of
+/// `referrals`, returning the referrals the client receives.
+async fn relayed_referrals(extra_config: &str, referrals: &[&str]) -> Vec<String> {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let referrals: Vec<String> = referrals.iter().map(|r| r.to_string()).collect();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut resp =
+                common::search_response(msg.msgid, vec![], ldap3_proto::LdapResultCode::Referral);
+            if let Some(LdapOp::SearchResultDone(result)) = resp.last_mut().map(|m| &mut m.op) {
+                result.referral = referrals.clone();
+            }
+            resp
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        "{}\n{}\n[\"cn=reader\"]",
+        extra_config, REFERRAL_REWRITE_CONFIG
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = client
+        .search(2, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Referral);
+    result.referral
+}
+
+#[tokio::test]
+async fn test_referral_rewrite() {
+    let referrals = relayed_referrals(
+        "",
+        &[
+            "ldap://internal-dc01:389/dc=example,dc=com",
+            "ldap://partner.example.net/dc=partner,dc=net",
+        ],
+    )
+    .await;
+    // Referrals that no rule matches are relayed unchanged by default.
+    assert_eq!(
+        referrals,
+        vec![
+            "ldaps://ldap-proxy.example.com:636/dc=example,dc=com",
+            "ldap://partner.example.net/dc=partner,dc=net",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_referral_rewrite_strip_unmatched() {
+    let referrals = relayed_referrals(
+        "strip_unmatched_referrals = true",
+        &[
+            "ldap://internal-dc01:389/dc=example,dc=com",
+            "ldap://partner.example.net/dc=partner,dc=net",
+        ],
+    )
+    .await;
+    assert_eq!(
+        referrals,
+        vec!["ldaps://ldap-proxy.example.com:636/dc=example,dc=com"]
+    );
+}
+
+#[test]
+fn test_quiesce_interval_config() {
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(config.quiesce_interval_secs.get(), 5);
+
+    let config = toml::from_str::<Config>(&format!(
+        "quiesce_interval_secs = 30\n{}",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.quiesce_interval_secs.get(), 30);
+
+    let zero = toml::from_str::<Config>(&format!(
+        "quiesce_interval_secs = 0\n{}",
+        common::BASE_CONFIG
+    ));
+    assert!(zero.is_err());
+}
+
+#[test]
+fn test_cache_summary() {
+    use ldap_proxy::proxy::cache_summary;
+    use ldap_proxy::CacheBackend;
+    use std::num::NonZeroUsize;
+    use std::time::Duration;
+
+    let app_state = common::offline_app_state("");
+    let CacheBackend::Memory(cache) = &app_state.cache else {
+        panic!("Expected a memory cache");
+    };
+
+    let now = SystemTime::now();
+    let mut total = 0;
+    let mut cache_write = cache.write();
+    for i in 0..15u64 {
+        let value = CachedValue {
+            cached_at: now - Duration::from_secs(i * 60),
+            entries: vec![(common::entry("cn=test,dc=example,dc=com"), vec![])],
+            result: common::ldap_result(ldap3_proto::LdapResultCode::Success),
+            ctrl: vec![],
+            source_addr: None,
+        };
+        total += value.size();
+        let key = SearchCacheKey::new(
+            format!("cn=reader{}", i),
+            common::search_request("dc=example,dc=com"),
+            vec![],
+        );
+        let size = NonZeroUsize::new(value.size()).expect("size");
+        cache_write.insert_sized(key, value, size);
+    }
+    cache_write.commit();
+
+    let summary = cache_summary(&app_state).expect("No summary");
+    assert_eq!(summary.entries, 15);
+    assert_eq!(summary.bytes, total);
+    // The ten oldest entries, oldest first.
+    assert_eq!(summary.oldest.len(), 10);
+    let ages: Vec<_> = summary
+        .oldest
+        .iter()
+        .map(|(_, cached_at)| now.duration_since(*cached_at).expect("age").as_secs() / 60)
+        .collect();
+    assert_eq!(ages, (5..15).rev().collect::<Vec<_>>());
+}
+
+#[tokio::test]
+async fn test_client_idle_timeout() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        client_idle_timeout_secs = 1
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let started = Instant::now();
+
+    let notice = tokio::time::timeout(Duration::from_secs(5), client.recv())
+        .await
+        .expect("The idle client was not disconnected")
+        .expect("Expected a disconnect notice");
+    assert!(matches!(notice.op, LdapOp::ExtendedResponse(_)));
+    assert!(client.recv().await.is_none());
+    assert!(started.elapsed() >= Duration::from_secs(1));
+}
+
+#[tokio::test]
+async fn test_client_idle_timeout_disabled() {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    assert!(tokio::time::timeout(Duration::from_secs(2), client.recv())
+        .await
+        .is_err());
+}
+
+/// Bind, search, drop the backend connection and search again, returning the
+/// second result and the backend.
+async fn search_after_dropped_connection(
+    extra_config: &str,
+) -> (common::MockBackend, ldap3_proto::proto::LdapResult) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        {}
+        ["cn=reader"]
+    "#,
+        extra_config
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = client
+        .search(2, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    backend.drop_connections();
+
+    let (_, result) = client
+        .search(3, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    (backend, result)
+}
+
+#[tokio::test]
+async fn test_reconnect_backend() {
+    let (backend, result) = search_after_dropped_connection("reconnect_backend = true").await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    // The new connection was bound with the session's credentials.
+    assert_eq!(backend.connection_count(), 2);
+    assert_eq!(backend.bind_count(), 2);
+    assert_eq!(backend.search_count(), 2);
+}
+
+#[tokio::test]
+async fn test_reconnect_backend_disabled() {
+    // Off by default.
+    let (backend, result) = search_after_dropped_connection("").await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+    assert_eq!(backend.connection_count(), 1);
+}
+
+/// Binds as `cn=wrong` are rejected and searches of `ou=missing` find nothing.
+fn result_code_handler(msg: &ldap3_proto::proto::LdapMsg) -> Vec<ldap3_proto::proto::LdapMsg> {
+    use ldap3_proto::proto::{LdapBindResponse, LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+
+    match &msg.op {
+        LdapOp::BindRequest(lbr) if lbr.dn == "cn=wrong" => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::ldap_result(LdapResultCode::InvalidCredentials),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }],
+        LdapOp::SearchRequest(sr) if sr.base.starts_with("ou=missing") => {
+            common::search_response(msg.msgid, vec![], LdapResultCode::NoSuchObject)
+        }
+        _ => common::default_handler(msg),
+    }
+}
+
+#[tokio::test]
+async fn test_backend_result_metrics() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::health::{self, BackendHealth};
+    use ldap_proxy::metrics::BackendOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(result_code_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        ["cn=wrong"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=wrong", "password").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(
+        client.bind(2, "cn=reader", "password").await,
+        LdapResultCode::Success
+    );
+    for msgid in 3..5 {
+        let (_, result) = client
+            .search(msgid, common::search_request("dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, LdapResultCode::Success);
+    }
+    let (_, result) = client
+        .search(5, common::search_request("ou=missing,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, LdapResultCode::NoSuchObject);
+
+    let metrics = &app_state.metrics;
+    assert_eq!(
+        metrics.backend_result_count(BackendOp::Bind, &LdapResultCode::Success),
+        1
+    );
+    assert_eq!(
+        metrics.backend_result_count(BackendOp::Bind, &LdapResultCode::InvalidCredentials),
+        1
+    );
+    assert_eq!(
+        metrics.backend_result_count(BackendOp::Search, &LdapResultCode::Success),
+        2
+    );
+    assert_eq!(
+        metrics.backend_result_count(BackendOp::Search, &LdapResultCode::NoSuchObject),
+        1
+    );
+
+    let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+        .await
+        .expect("Failed to bind");
+    let addr = listener.local_addr().expect("No local addr");
+    tokio::spawn(health::serve(
+        listener,
+        app_state.clone(),
+        Arc::new(BackendHealth::default()),
+    ));
+
+    let (status, body) = common::http_get(addr, "/metrics").await;
+    assert_eq!(status, 200);
+    let samples: Vec<_> = body.lines().filter(|line| !line.starts_with('#')).collect();
+    assert_eq!(
+        samples,
+        vec![
+            r#"backend_result_total{op="bind",code="invalid_credentials"} 1"#,
+            r#"backend_result_total{op="bind",code="success"} 1"#,
+            r#"backend_result_total{op="search",code="no_such_object"} 1"#,
+            r#"backend_result_total{op="search",code="success"} 2"#,
+        ]
+    );
+}
+
+#[test]
+fn test_ip_cidr_contains() {
+    use ldap_proxy::IpCidr;
+    use std::net::IpAddr;
+
+    let contains = |cidr: &str, addr: &str| {
+        cidr.parse::<IpCidr>()
+            .expect("Invalid cidr")
+            .contains(addr.parse::<IpAddr>().expect("Invalid address"))
+    };
+    assert!(contains("10.0.0.0/8", "10.1.2.3"));
+    assert!(!contains("10.0.0.0/8", "11.0.0.1"));
+    assert!(contains("192.0.2.7", "192.0.2.7"));
+    assert!(!contains("192.0.2.7", "192.0.2.8"));
+    assert!(contains("0.0.0.0/0", "203.0.113.1"));
+    assert!(contains("2001:db8::/32", "2001:db8:1::1"));
+    assert!(!contains("2001:db8::/32", "2001:db9::1"));
+    // IPv4 clients of a dual stack listener are matched as IPv4.
+    assert!(contains("10.0.0.0/8", "::ffff:10.0.0.1"));
+    assert!(!contains("10.0.0.0/8", "2001:db8::1"));
+
+    assert!("10.0.0.0/33".parse::<IpCidr>().is_err());
+    assert!("10.0.0.0/".parse::<IpCidr>().is_err());
+    assert!("not-an-address/8".parse::<IpCidr>().is_err());
+}
+
+/// Bind as a DN restricted by source address, returning the result and the
+/// number of binds that reached the backend.
+async fn bind_from(reported_address: &str) -> (ldap3_proto::LdapResultCode, usize) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=admin"]
+        allowed_source_cidrs = ["10.0.0.0/8", "2001:db8::/32"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn_with_reported_address(
+        app_state,
+        Some(reported_address.parse().expect("Invalid address")),
+    );
+    let code = client.bind(1, "cn=admin", "password").await;
+    (code, backend.bind_count())
+}
+
+#[tokio::test]
+async fn test_allowed_source_cidrs() {
+    assert_eq!(
+        bind_from("10.20.30.40:50000").await,
+        (ldap3_proto::LdapResultCode::Success, 1)
+    );
+    assert_eq!(
+        bind_from("[2001:db8::5]:50000").await,
+        (ldap3_proto::LdapResultCode::Success, 1)
+    );
+}
+
+#[tokio::test]
+async fn test_allowed_source_cidrs_denied() {
+    // The reported address is checked, not the address of the proxy in front.
+    assert_eq!(
+        bind_from("192.0.2.1:50000").await,
+        (ldap3_proto::LdapResultCode::OperationsError, 0)
+    );
+}
+
+/// Searches carrying ManageDsaIT see the referral object itself, while others
+/// see an ordinary entry.
+fn manage_dsa_it_handler(msg: &ldap3_proto::proto::LdapMsg) -> Vec<ldap3_proto::proto::LdapMsg> {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::LdapOp;
+
+    match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let manage_dsa_it = msg
+                .ctrl
+                .iter()
+                .any(|ctrl| matches!(ctrl, LdapControl::ManageDsaIT { .. }));
+            let dn = if manage_dsa_it {
+                "cn=referral,dc=example,dc=com"
+            } else {
+                "cn=entry,dc=example,dc=com"
+            };
+            common::search_response(
+                msg.msgid,
+                vec![common::entry(dn)],
+                ldap3_proto::LdapResultCode::Success,
+            )
+        }
+        _ => common::default_handler(msg),
+    }
+}
+
+fn manage_dsa_it() -> Vec<ldap3_proto::control::LdapControl> {
+    vec![ldap3_proto::control::LdapControl::ManageDsaIT { criticality: true }]
+}
+
+#[tokio::test]
+async fn test_manage_dsa_it_forwarded() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(manage_dsa_it_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (entries, _) = client
+        .search_with_controls(2, common::search_request("dc=example,dc=com"), manage_dsa_it())
+        .await;
+    assert_eq!(entries[0].dn, "cn=referral,dc=example,dc=com");
+
+    let search = backend
+        .requests()
+        .into_iter()
+        .find(|msg| matches!(msg.op, LdapOp::SearchRequest(_)))
+        .expect("No search reached the backend");
+    // The criticality is kept, so a backend that does not support the
+    // control refuses the search rather than following the referral.
+    assert!(matches!(
+        search.ctrl.as_slice(),
+        [LdapControl::ManageDsaIT { criticality: true }]
+    ));
+}
+
+#[tokio::test]
+async fn test_manage_dsa_it_cached_separately() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(manage_dsa_it_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    client
+        .search(2, common::search_request("dc=example,dc=com"))
+        .await;
+    client
+        .search_with_controls(3, common::search_request("dc=example,dc=com"), manage_dsa_it())
+        .await;
+
+    backend.set_online(false);
+
+    // Each search is answered from its own cache entry.
+    let (entries, _) = client
+        .search(4, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(entries[0].dn, "cn=entry,dc=example,dc=com");
+    let (entries, _) = client
+        .search_with_controls(5, common::search_request("dc=example,dc=com"), manage_dsa_it())
+        .await;
+    assert_eq!(entries[0].dn, "cn=referral,dc=example,dc=com");
+}
+
+#[tokio::test]
+async fn test_manage_dsa_it_not_served_from_plain_search() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(manage_dsa_it_handler)).await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    client
+        .search(2, common::search_request("dc=example,dc=com"))
+        .await;
+
+    backend.set_online(false);
+
+    // Only the search without the control was cached.
+    let (entries, result) = client
+        .search_with_controls(3, common::search_request("dc=example,dc=com"), manage_dsa_it())
+        .await;
+    assert!(entries.is_empty());
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+}
+
+/// Searches of `ou=big` return an entry larger than the proxy's 1024 byte
+/// limit on backend messages.
+fn large_entry_handler(msg: &ldap3_proto::proto::LdapMsg) -> Vec<ldap3_proto::proto::LdapMsg> {
+    use ldap3_proto::proto::{LdapOp, LdapPartialAttribute, LdapSearchResultEntry};
+
+    match &msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.starts_with("ou=big") => {
+            let entry = LdapSearchResultEntry {
+                dn: "cn=big,ou=big".to_string(),
+                attributes: vec![LdapPartialAttribute {
+                    atype: "description".to_string(),
+                    vals: vec![vec![b'x'; 4096]],
+                }],
+            };
+            common::search_response(msg.msgid, vec![entry], ldap3_proto::LdapResultCode::Success)
+        }
+        _ => common::default_handler(msg),
+    }
+}
+
+async fn max_proxy_ber_size_client() -> (common::MockBackend, common::TestClient) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(large_entry_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_incoming_ber_size = 65536
+        max_proxy_ber_size = 1024
+        reconnect_backend = true
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    (backend, client)
+}
+
+#[tokio::test]
+async fn test_max_proxy_ber_size_request() {
+    use ldap3_proto::proto::LdapFilter;
+
+    let (backend, mut client) = max_proxy_ber_size_client().await;
+
+    // Within max_incoming_ber_size, but too large to forward.
+    let sr = ldap3_proto::proto::LdapSearchRequest {
+        filter: LdapFilter::Equality("cn".to_string(), "x".repeat(4096)),
+        ..common::search_request("dc=example,dc=com")
+    };
+    let (entries, result) = client.search(2, sr).await;
+    assert!(entries.is_empty());
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::AdminLimitExceeded);
+    assert_eq!(backend.search_count(), 0);
+
+    // The session and its backend connection are still usable.
+    let (_, result) = client
+        .search(3, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), 1);
+}
+
+#[tokio::test]
+async fn test_max_proxy_ber_size_response() {
+    let (backend, mut client) = max_proxy_ber_size_client().await;
+
+    let (entries, result) = client.search(2, common::search_request("ou=big")).await;
+    assert!(entries.is_empty());
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::AdminLimitExceeded);
+
+    // The backend connection can not be read past the oversized message, so
+    // the next search reconnects.
+    let (_, result) = client
+        .search(3, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), 2);
+}
+
+/// Run the same search twice with `cache_mode`, waiting `pause` in between,
+/// and return how many reached the backend.
+async fn repeated_search_count(extra_config: &str, pause: std::time::Duration) -> usize {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg: &ldap3_proto::proto::LdapMsg| {
+        match &msg.op {
+            ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=a,dc=example,dc=com")],
+                ldap3_proto::LdapResultCode::Success,
+            ),
+            _ => common::default_handler(msg),
+        }
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        {}
+        ["cn=reader"]
+    "#,
+        extra_config
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    for msgid in 2..4 {
+        let (entries, result) = client
+            .search(msgid, common::search_request("dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+        tokio::time::sleep(pause).await;
+    }
+    backend.search_count()
+}
+
+#[tokio::test]
+async fn test_cache_mode_fallback_queries_backend() {
+    use std::time::Duration;
+
+    assert_eq!(repeated_search_count("", Duration::ZERO).await, 2);
+    assert_eq!(
+        repeated_search_count(r#"cache_mode = "write_through""#, Duration::ZERO).await,
+        2
+    );
+}
+
+#[tokio::test]
+async fn test_cache_mode_read_through() {
+    use std::time::Duration;
+
+    // The second search is served from the cache.
+    assert_eq!(
+        repeated_search_count(r#"cache_mode = "read_through""#, Duration::ZERO).await,
+        1
+    );
+
+    // Once the entry is older than the max age the backend is queried again.
+    assert_eq!(
+        repeated_search_count(
+            "cache_mode = \"read_through\"\nread_through_max_age_secs = 1",
+            Duration::from_millis(1100)
+        )
+        .await,
+        2
+    );
+}
+
+#[test]
+fn test_cache_mode_config() {
+    use ldap_proxy::CacheMode;
+
+    let config: Config = toml::from_str(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(config.cache_mode, CacheMode::Fallback);
+    assert_eq!(config.read_through_max_age_secs.get(), 60);
+
+    let config: Config = toml::from_str(&format!(
+        "cache_mode = \"write_through\"\n{}",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.cache_mode, CacheMode::WriteThrough);
+
+    assert!(toml::from_str::<Config>(&format!(
+        "read_through_max_age_secs = 0\n{}",
+        common::BASE_CONFIG
+    ))
+    .is_err());
+}
+
+/// Bind with an empty DN and `pw`, returning the result and the number of
+/// binds that reached the backend.
+async fn empty_dn_bind(extra_config: &str, pw: &str) -> (ldap3_proto::LdapResultCode, usize) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        {}
+        [""]
+    "#,
+        extra_config
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    let code = client.bind(1, "", pw).await;
+    (code, backend.bind_count())
+}
+
+#[tokio::test]
+async fn test_anonymous_bind() {
+    assert_eq!(
+        empty_dn_bind("", "").await,
+        (ldap3_proto::LdapResultCode::Success, 1)
+    );
+}
+
+#[tokio::test]
+async fn test_unauthenticated_bind_rejected() {
+    assert_eq!(
+        empty_dn_bind("", "password").await,
+        (ldap3_proto::LdapResultCode::InvalidCredentials, 0)
+    );
+    assert_eq!(
+        empty_dn_bind("allow_unauthenticated_bind = true", "password").await,
+        (ldap3_proto::LdapResultCode::Success, 1)
+    );
+}
+
+#[test]
+fn test_filter_to_string_round_trip() {
+    use ldap_proxy::LdapFilterWrapper;
+
+    for filter in [
+        "(objectClass=*)",
+        "(uid=john)",
+        "(&(objectClass=person)(|(uid=john)(cn=John*))(!(mail=*)))",
+        "(cn=*oh*n*)",
+        "(cn=J*n)",
+        "(uidNumber>=1000)",
+        "(uidNumber<=2000)",
+        "(cn~=jon)",
+        "(cn:dn:caseExactMatch:=John)",
+        "(:1.2.3:=value)",
+        "(cn=\"John Smith\")",
+        "(cn=\"a=b\")",
+    ] {
+        let parsed = filter
+            .parse::<LdapFilterWrapper>()
+            .expect("Invalid filter");
+        let rendered = parsed.to_filter_string();
+        let reparsed = rendered
+            .parse::<LdapFilterWrapper>()
+            .unwrap_or_else(|err| panic!("{} rendered as {}: {}", filter, rendered, err));
+        assert_eq!(parsed.inner, reparsed.inner, "{} rendered as {}", filter, rendered);
+    }
+
+    let parsed = "(uid=john)".parse::<LdapFilterWrapper>().unwrap();
+    assert_eq!(parsed.to_filter_string(), "(uid=john)");
+}
+
+#[test]
+fn test_dn_ends_with() {
+    use ldap_proxy::dn::Dn;
+
+    let dn = |s: &str| s.parse::<Dn>().expect("Invalid dn");
+    let user = dn("cn=bob,ou=Contractors,dc=example,dc=com");
+    assert!(user.ends_with(&dn("ou=contractors, dc=example, dc=com")));
+    assert!(user.ends_with(&dn("dc=example,dc=com")));
+    assert!(user.ends_with(&user));
+    assert!(user.ends_with(&dn("")));
+    assert!(!user.ends_with(&dn("ou=employees,dc=example,dc=com")));
+    assert!(!user.ends_with(&dn("x=cn=bob,ou=Contractors,dc=example,dc=com")));
+    // Whole RDNs must match.
+    assert!(!user.ends_with(&dn("s=Contractors,dc=example,dc=com")));
+}
+
+#[test]
+fn test_backend_config() {
+    let config_str = format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        r#"
+        [[backend]]
+        ldap_url = "ldaps://contractors.example.com"
+        ldap_ca = "/etc/ldap-proxy/contractors-ca.pem"
+        dn_suffix = "ou=contractors,dc=example,dc=com"
+
+        ["cn=bob,ou=contractors,dc=example,dc=com"]
+    "#
+    );
+    let config = toml::from_str::<Config>(&config_str).expect("Failed to parse config");
+    assert_eq!(config.backends.len(), 1);
+    assert_eq!(
+        config.backends[0].ldap_url.as_str(),
+        "ldaps://contractors.example.com"
+    );
+    assert_eq!(
+        config.backends[0].dn_suffix.to_string(),
+        "ou=contractors,dc=example,dc=com"
+    );
+    // The backend table is not mistaken for a bind DN.
+    assert_eq!(config.binddn_map.len(), 1);
+
+    let invalid = format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        r#"
+        [[backend]]
+        ldap_url = "ldaps://contractors.example.com"
+        ldap_ca = "/etc/ldap-proxy/contractors-ca.pem"
+        dn_suffix = "not a dn"
+    "#
+    );
+    assert!(toml::from_str::<Config>(&invalid).is_err());
+}
+
+#[tokio::test]
+async fn test_backend_routing_by_bind_dn() {
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let employees = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let contractors = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let mut app_state = employees.app_state(
+        r#"
+        ["cn=alice,ou=employees,dc=example,dc=com"]
+        ["cn=bob,ou=contractors,dc=example,dc=com"]
+        ["cn=eve,ou=Contractors,dc=example,dc=com"]
+    "#,
+    );
+    app_state
+        .routed_backends
+        .push(contractors.routed_backend("ou=contractors,dc=example,dc=com"));
+    let app_state = Arc::new(app_state);
+
+    let bind_and_search = |dn: &'static str| {
+        let app_state = app_state.clone();
+        async move {
+            let mut client = common::TestClient::spawn(app_state);
+            assert_eq!(client.bind(1, dn, "password").await, LdapResultCode::Success);
+            let (_, result) = client.search(2, common::search_request("dc=example,dc=com")).await;
+            assert_eq!(result.code, LdapResultCode::Success);
+        }
+    };
+
+    bind_and_search("cn=alice,ou=employees,dc=example,dc=com").await;
+    assert_eq!(employees.bind_count(), 1);
+    assert_eq!(employees.search_count(), 1);
+    assert_eq!(contractors.bind_count(), 0);
+
+    bind_and_search("cn=bob,ou=contractors,dc=example,dc=com").await;
+    assert_eq!(employees.bind_count(), 1);
+    assert_eq!(contractors.bind_count(), 1);
+    assert_eq!(contractors.search_count(), 1);
+
+    // The suffix matches regardless of case.
+    bind_and_search("cn=eve,ou=Contractors,dc=example,dc=com").await;
+    assert_eq!(employees.bind_count(), 1);
+    assert_eq!(contractors.bind_count(), 2);
+}
+
+#[tokio::test]
+async fn test_backend_tls_session_resumption() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::tls;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(common::app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+        vec![backend.addr],
+        backend.tls_params_with_resumption(),
+    ));
+
+    for _ in 0..2 {
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(
+            client.bind(1, "cn=reader", "password").await,
+            LdapResultCode::Success
+        );
+    }
+    assert_eq!(backend.connection_count(), 2);
+
+    let cache = tls::session_cache(app_state.tls_params.context()).expect("No session cache");
+    assert_eq!(cache.full_count(), 1);
+    assert_eq!(cache.resumed_count(), 1);
+}
+
+#[test]
+fn test_ldap_tls_session_resumption_config() {
+    let parse = |extra: &str| {
+        toml::from_str::<Config>(&format!("{}\n{}", common::BASE_CONFIG, extra))
+            .expect("Failed to parse config")
+    };
+    assert!(parse("").ldap_tls_session_resumption);
+    assert!(!parse("ldap_tls_session_resumption = false").ldap_tls_session_resumption);
+}
+
+#[tokio::test]
+async fn test_max_sessions() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=svc"]
+        max_sessions = 2
+        ["cn=other"]
+    "#,
+    ));
+
+    let mut sessions = Vec::new();
+    for _ in 0..2 {
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(client.bind(1, "cn=svc", "password").await, LdapResultCode::Success);
+        sessions.push(client);
+    }
+    assert_eq!(app_state.session_counts.count("cn=svc"), 2);
+
+    let mut rejected = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        rejected.bind(1, "cn=svc", "password").await,
+        LdapResultCode::AdminLimitExceeded
+    );
+    // Rejected before the backend is contacted.
+    assert_eq!(backend.bind_count(), 2);
+    // Other DNs are not limited.
+    assert_eq!(rejected.bind(2, "cn=other", "password").await, LdapResultCode::Success);
+
+    // A session may rebind as the same DN while at the limit.
+    assert_eq!(
+        sessions[0].bind(2, "cn=svc", "password").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(app_state.session_counts.count("cn=svc"), 2);
+
+    // Ending a session frees its place.
+    let mut ended = sessions.pop().expect("No session");
+    ended
+        .send(LdapMsg {
+            msgid: 3,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    ended.join().await;
+    assert_eq!(app_state.session_counts.count("cn=svc"), 1);
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=svc", "password").await, LdapResultCode::Success);
+    assert_eq!(app_state.session_counts.count("cn=svc"), 2);
+}
+
+#[tokio::test]
+async fn test_annotate_cached_message() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let handler: common::Handler = Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut msgs = common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=cached,dc=example,dc=com")],
+                LdapResultCode::Success,
+            );
+            if let Some(LdapOp::SearchResultDone(result)) = msgs.last_mut().map(|m| &mut m.op) {
+                result.message = "backend says hi".to_string();
+            }
+            msgs
+        }
+        _ => common::default_handler(msg),
+    });
+    let sr = common::search_request("dc=example,dc=com");
+
+    let backend = common::MockBackend::start(handler.clone()).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        annotate_cached_message = true
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    // Live results keep the backend's message.
+    let (_, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.message, "backend says hi");
+
+    backend.set_online(false);
+    let (entries, result) = client.search(3, sr.clone()).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert!(
+        result.message.starts_with("Served from the proxy's fallback cache, "),
+        "{}",
+        result.message
+    );
+    assert!(result.message.ends_with("s old: backend says hi"), "{}", result.message);
+
+    // Fresh entries served in read_through mode are not a fallback.
+    let backend = common::MockBackend::start(handler).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        annotate_cached_message = true
+        cache_mode = "read_through"
+        read_through_max_age_secs = 3600
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    client.search(2, sr.clone()).await;
+    let (_, result) = client.search(3, sr).await;
+    assert_eq!(backend.search_count(), 1);
+    assert_eq!(result.message, "backend says hi");
+}
+
+#[test]
+fn test_offline_bind_config() {
+    let parse = |extra: &str| {
+        toml::from_str::<Config>(&format!("{}\n{}", common::BASE_CONFIG, extra))
+            .expect("Failed to parse config")
+    };
+    assert!(parse("").offline_bind_cache().is_none());
+
+    let config = parse(
+        r#"
+        [offline_bind]
+        ttl_secs = 60
+        kdf = "pbkdf2_sha256"
+    "#,
+    );
+    let offline_bind = config.offline_bind.as_ref().expect("offline_bind is set");
+    assert!(!offline_bind.enabled);
+    assert_eq!(offline_bind.ttl_secs.get(), 60);
+    assert_eq!(offline_bind.kdf, ldap_proxy::OfflineBindKdf::Pbkdf2Sha256);
+    assert!(config.offline_bind_cache().is_none());
+
+    let config = parse(
+        r#"
+        [offline_bind]
+        enabled = true
+    "#,
+    );
+    let offline_bind = config.offline_bind.as_ref().expect("offline_bind is set");
+    assert_eq!(offline_bind.ttl_secs.get(), 900);
+    assert_eq!(offline_bind.kdf, ldap_proxy::OfflineBindKdf::Scrypt);
+    assert!(config.offline_bind_cache().is_some());
+}
+
+#[tokio::test]
+async fn test_offline_bind_cache() {
+    use ldap_proxy::offline::OfflineBindCache;
+    use ldap_proxy::OfflineBindKdf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let ttl = Duration::from_secs(60);
+    for cache in [
+        OfflineBindCache::new(OfflineBindKdf::Scrypt, ttl),
+        OfflineBindCache::new(OfflineBindKdf::Pbkdf2Sha256, ttl),
+        OfflineBindCache::keyed(ttl).expect("Failed to create key"),
+    ] {
+        let cache = Arc::new(cache);
+        assert!(!cache.verify("cn=svc", "password").await);
+
+        cache.store("cn=svc", "password").await;
+        assert!(cache.verify("cn=svc", "password").await);
+        assert!(!cache.verify("cn=svc", "Password").await);
+        assert!(!cache.verify("cn=other", "password").await);
+
+        // A new password replaces the old one.
+        cache.store("cn=svc", "changed").await;
+        assert!(cache.verify("cn=svc", "changed").await);
+        assert!(!cache.verify("cn=svc", "password").await);
+
+        cache.forget("cn=svc");
+        assert!(!cache.verify("cn=svc", "changed").await);
+    }
+
+    let cache = Arc::new(OfflineBindCache::new(
+        OfflineBindKdf::Pbkdf2Sha256,
+        Duration::from_secs(1),
+    ));
+    cache.store("cn=svc", "password").await;
+    tokio::time::sleep(Duration::from_millis(1100)).await;
+    assert!(!cache.verify("cn=svc", "password").await);
+    assert!(!cache.contains("cn=svc"));
+}
+
+#[tokio::test]
+async fn test_offline_bind_cache_limits_derivations() {
+    use ldap_proxy::offline::OfflineBindCache;
+    use ldap_proxy::OfflineBindKdf;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let cache = Arc::new(OfflineBindCache::new(
+        OfflineBindKdf::Pbkdf2Sha256,
+        Duration::from_secs(60),
+    ));
+    let dns: Vec<String> = (0..5).map(|i| format!("cn=svc{i}")).collect();
+    for dn in &dns {
+        cache.store(dn, "password").await;
+    }
+
+    // Only one bind as a DN is verified at a time.
+    let (first, second) = tokio::join!(
+        cache.verify("cn=svc0", "password"),
+        cache.verify("cn=svc0", "password")
+    );
+    assert!(first != second);
+
+    // Past the limit of concurrent derivations, binds fail fast.
+    let verified = futures_util::future::join_all(dns.iter().map(|dn| cache.verify(dn, "password")))
+        .await
+        .into_iter()
+        .filter(|verified| *verified)
+        .count();
+    assert_eq!(verified, 4);
+
+    // Once they finish, the credentials verify again.
+    assert!(cache.verify("cn=svc4", "password").await);
+}
+
+#[tokio::test]
+async fn test_offline_bind() {
+    use ldap3_proto::proto::{LdapBindCred, LdapBindResponse, LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::BindRequest(lbr) => {
+            let code = match &lbr.cred {
+                LdapBindCred::Simple(pw) if pw == "password" => LdapResultCode::Success,
+                _ => LdapResultCode::InvalidCredentials,
+            };
+            vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::ldap_result(code),
+                    saslcreds: None,
+                }),
+                ctrl: vec![],
+            }]
+        }
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=cached,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        [offline_bind]
+        enabled = true
+        kdf = "pbkdf2_sha256"
+        ["cn=svc"]
+        ["cn=other"]
+    "#,
+    ));
+    let offline_bind = app_state.offline_bind.clone().expect("offline_bind is enabled");
+
+    let sr = common::search_request("dc=example,dc=com");
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc", "password").await,
+        LdapResultCode::Success
+    );
+    let (entries, _) = client.search(2, sr.clone()).await;
+    assert_eq!(entries.len(), 1);
+    common::wait_for_credentials(&offline_bind, "cn=svc").await;
+
+    // A password the backend rejects is never cached.
+    let mut other = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        other.bind(1, "cn=other", "wrong").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert!(!offline_bind.contains("cn=other"));
+
+    backend.set_online(false);
+
+    // The cached password binds, and searches are answered from the cache.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc", "password").await,
+        LdapResultCode::Success
+    );
+    let (entries, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "cn=cached,dc=example,dc=com");
+
+    // Any other password, or a DN that never bound, is rejected.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_ne!(
+        client.bind(1, "cn=svc", "wrong").await,
+        LdapResultCode::Success
+    );
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_ne!(
+        client.bind(1, "cn=other", "password").await,
+        LdapResultCode::Success
+    );
+
+    // Once the backend rejects the cached password it no longer binds offline.
+    backend.set_online(true);
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc", "changed").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert!(!offline_bind.contains("cn=svc"));
+}
+
+#[test]
+fn test_tracing_config() {
+    let contents = format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        r#"
+        [tracing]
+        otlp_endpoint = "http://localhost:4318/v1/traces"
+    "#
+    );
+    let tracing_config = ldap_proxy::telemetry::tracing_config(&contents)
+        .expect("Failed to parse config")
+        .expect("tracing is set");
+    assert_eq!(
+        tracing_config.otlp_endpoint.as_str(),
+        "http://localhost:4318/v1/traces"
+    );
+    assert_eq!(tracing_config.service_name, "ldap-proxy");
+    assert_eq!(tracing_config.export_timeout_secs, 10);
+    // Nothing is exported until spans are recorded.
+    ldap_proxy::telemetry::tracer_provider(&tracing_config).expect("Failed to build provider");
+
+    assert!(ldap_proxy::telemetry::tracing_config(common::BASE_CONFIG)
+        .expect("Failed to parse config")
+        .is_none());
+}
+
+#[tokio::test]
+async fn test_operation_spans() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use opentelemetry::trace::TracerProvider;
+    use opentelemetry_sdk::trace::{InMemorySpanExporter, SdkTracerProvider, SpanData};
+    use std::sync::Arc;
+    use tracing_subscriber::layer::SubscriberExt;
+
+    let exporter = InMemorySpanExporter::default();
+    let provider = SdkTracerProvider::builder()
+        .with_simple_exporter(exporter.clone())
+        .build();
+    let subscriber = tracing_subscriber::registry()
+        .with(tracing_opentelemetry::layer().with_tracer(provider.tracer("test")));
+    // The test runtime runs the session on this thread.
+    let _guard = tracing::subscriber::set_default(subscriber);
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=cached,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=svc"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "CN=svc", "password").await,
+        LdapResultCode::Success
+    );
+    let sr = common::search_request("dc=example,dc=com");
+    client.search(2, sr.clone()).await;
+    backend.set_online(false);
+    client.search(3, sr).await;
+    client
+        .send(ldap3_proto::proto::LdapMsg {
+            msgid: 4,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    client.join().await;
+
+    let attribute = |span: &SpanData, key: &str| {
+        span.attributes
+            .iter()
+            .find(|kv| kv.key.as_str() == key)
+            .map(|kv| kv.value.to_string())
+    };
+    let spans = exporter.get_finished_spans().expect("Failed to get spans");
+
+    let bind = spans
+        .iter()
+        .find(|span| span.name == "bind")
+        .expect("No bind span");
+    assert_eq!(attribute(bind, "dn").as_deref(), Some("cn=svc"));
+    assert_eq!(attribute(bind, "result_code").as_deref(), Some("success"));
+    assert!(attribute(bind, "backend_latency_ms").is_some());
+
+    let searches: Vec<_> = spans.iter().filter(|span| span.name == "search").collect();
+    assert_eq!(searches.len(), 2);
+    for search in &searches {
+        assert_eq!(attribute(search, "dn").as_deref(), Some("cn=svc"));
+        assert_eq!(
+            attribute(search, "base").as_deref(),
+            Some("dc=example,dc=com")
+        );
+        assert_eq!(attribute(search, "result_code").as_deref(), Some("success"));
+    }
+    let cache = |msgid: &str| {
+        searches
+            .iter()
+            .find(|span| attribute(span, "msgid").as_deref() == Some(msgid))
+            .and_then(|span| attribute(span, "cache"))
+    };
+    assert_eq!(cache("2").as_deref(), Some("miss"));
+    assert_eq!(cache("3").as_deref(), Some("fallback"));
+    assert!(searches
+        .iter()
+        .all(|span| attribute(span, "backend_latency_ms").is_some()));
+}
+
+#[test]
+fn test_project_attributes() {
+    let project = |extra: &str, attrs: &[&str]| {
+        let config = toml::from_str::<Config>(&format!(
+            "{}\n[\"cn=svc\"]\n{}",
+            common::BASE_CONFIG,
+            extra
+        ))
+        .expect("Failed to parse config");
+        config.binddn_map["cn=svc"]
+            .project_attributes(attrs.iter().map(|attr| attr.to_string()).collect())
+    };
+
+    // Without default_attributes the request is unchanged.
+    assert!(project("", &[]).is_empty());
+    assert_eq!(project("", &["*", "+"]), vec!["*", "+"]);
+
+    let defaults = r#"default_attributes = ["cn", "mail"]"#;
+    assert_eq!(project(defaults, &[]), vec!["cn", "mail"]);
+    assert_eq!(project(defaults, &["*"]), vec!["cn", "mail"]);
+    assert_eq!(project(defaults, &["MAIL", "memberOf"]), vec!["MAIL"]);
+    assert_eq!(project(defaults, &["*", "CN", "+"]), vec!["cn", "mail"]);
+    assert_eq!(project(defaults, &["memberOf"]), vec!["1.1"]);
+    assert_eq!(project(defaults, &["1.1"]), vec!["1.1"]);
+
+    let overriding = format!("{}\nexplicit_attributes = \"override\"", defaults);
+    assert_eq!(project(&overriding, &[]), vec!["cn", "mail"]);
+    assert_eq!(project(&overriding, &["memberOf"]), vec!["memberOf"]);
+    assert_eq!(
+        project(&overriding, &["*", "memberOf", "CN"]),
+        vec!["cn", "mail", "memberOf"]
+    );
+    assert_eq!(project(&overriding, &["1.1"]), vec!["1.1"]);
+}
+
+#[tokio::test]
+async fn test_default_attributes_sent_to_backend() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=svc"]
+        default_attributes = ["cn", "mail"]
+        ["cn=other"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=svc", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let mut sr = common::search_request("dc=example,dc=com");
+    sr.attrs = vec!["*".to_string()];
+    client.search(2, sr.clone()).await;
+    sr.attrs = vec!["mail".to_string(), "userPassword".to_string()];
+    client.search(3, sr.clone()).await;
+
+    // A DN without default_attributes is unchanged.
+    let mut other = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        other.bind(1, "cn=other", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    sr.attrs = vec!["*".to_string()];
+    other.search(2, sr.clone()).await;
+
+    let requested: Vec<Vec<String>> = backend
+        .requests()
+        .into_iter()
+        .filter_map(|msg| match msg.op {
+            LdapOp::SearchRequest(sr) => Some(sr.attrs),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        requested,
+        vec![
+            vec!["cn".to_string(), "mail".to_string()],
+            vec!["mail".to_string()],
+            vec!["*".to_string()],
+        ]
+    );
+
+    // The result is cached under the projected request.
+    sr.attrs = vec!["cn".to_string(), "mail".to_string()];
+    assert!(common::memory_cache_get(&app_state, "cn=svc", &sr).is_some());
+}
+
+#[tokio::test]
+async fn test_sasl_bind_rounds() {
+    use ldap3_proto::proto::{
+        LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedResponse, LdapMsg, LdapOp,
+        SaslCredentials,
+    };
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    // The first round is answered with a challenge, and the response to it
+    // completes the bind.
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::BindRequest(LdapBindRequest {
+            cred: LdapBindCred::SASL(sasl),
+            ..
+        }) => {
+            let (code, saslcreds) = match sasl.credentials.as_slice() {
+                b"start" => (LdapResultCode::SaslBindInProgress, Some(b"challenge".to_vec())),
+                b"response" => (LdapResultCode::Success, Some(b"rspauth".to_vec())),
+                _ => (LdapResultCode::InvalidCredentials, None),
+            };
+            vec![LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::BindResponse(LdapBindResponse {
+                    res: common::ldap_result(code),
+                    saslcreds,
+                }),
+                ctrl: vec![],
+            }]
+        }
+        LdapOp::ExtendedRequest(_) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: common::ldap_result(LdapResultCode::Success),
+                name: None,
+                value: Some(b"dn:cn=svc".to_vec()),
+            }),
+            ctrl: vec![],
+        }],
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=svc"]
+    "#,
+    ));
+
+    let sasl_bind = |msgid: i32, credentials: &[u8]| LdapMsg {
+        msgid,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: "cn=svc".to_string(),
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: "DIGEST-MD5".to_string(),
+                credentials: credentials.to_vec(),
+            }),
+        }),
+        ctrl: vec![],
+    };
+    let bind_response = |msg: Option<LdapMsg>| match msg.map(|msg| msg.op) {
+        Some(LdapOp::BindResponse(resp)) => resp,
+        op => panic!("Unexpected response {:?}", op),
+    };
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    client.send(sasl_bind(1, b"start")).await;
+    let resp = bind_response(client.recv().await);
+    assert_eq!(resp.res.code, LdapResultCode::SaslBindInProgress);
+    assert_eq!(resp.saslcreds.as_deref(), Some(&b"challenge"[..]));
+
+    client.send(sasl_bind(2, b"response")).await;
+    let resp = bind_response(client.recv().await);
+    assert_eq!(resp.res.code, LdapResultCode::Success);
+    assert_eq!(resp.saslcreds.as_deref(), Some(&b"rspauth"[..]));
+
+    // Both rounds went over one backend connection, which the session keeps.
+    assert_eq!(backend.connection_count(), 1);
+    let (_, result) = client
+        .search(3, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), 1);
+
+    // A simple bind abandons an exchange in progress.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    client.send(sasl_bind(1, b"start")).await;
+    let resp = bind_response(client.recv().await);
+    assert_eq!(resp.res.code, LdapResultCode::SaslBindInProgress);
+    assert_eq!(
+        client.bind(2, "cn=svc", "password").await,
+        LdapResultCode::Success
+    );
+    assert_eq!(backend.connection_count(), 3);
+}
+
+#[tokio::test]
+async fn test_sasl_bind_takes_the_authenticated_identity() {
+    use ldap3_proto::proto::{
+        LdapBindCred, LdapBindRequest, LdapBindResponse, LdapExtendedRequest,
+        LdapExtendedResponse, LdapMsg, LdapOp, SaslCredentials,
+    };
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    // Any SASL bind authenticates as cn=svc, whatever name it was sent with.
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::BindRequest(LdapBindRequest {
+            cred: LdapBindCred::SASL(_),
+            ..
+        }) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::ldap_result(LdapResultCode::Success),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }],
+        LdapOp::ExtendedRequest(_) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::ExtendedResponse(LdapExtendedResponse {
+                res: common::ldap_result(LdapResultCode::Success),
+                name: None,
+                value: Some(b"dn:cn=svc".to_vec()),
+            }),
+            ctrl: vec![],
+        }],
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        [""]
+        ["cn=admin"]
+        ["cn=svc"]
+    "#,
+    ));
+
+    let sasl_bind = |dn: &str| LdapMsg {
+        msgid: 1,
+        op: LdapOp::BindRequest(LdapBindRequest {
+            dn: dn.to_string(),
+            cred: LdapBindCred::SASL(SaslCredentials {
+                mechanism: "EXTERNAL".to_string(),
+                credentials: vec![],
+            }),
+        }),
+        ctrl: vec![],
+    };
+    let whoami = LdapMsg {
+        msgid: 2,
+        op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+            value: None,
+        }),
+        ctrl: vec![],
+    };
+
+    // Naming a DN other than the authenticated identity is refused.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    client.send(sasl_bind("cn=admin")).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::BindResponse(resp)) => {
+            assert_eq!(resp.res.code, LdapResultCode::InvalidCredentials)
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+    client.send(whoami.clone()).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::ExtendedResponse(resp)) => {
+            assert_ne!(resp.res.code, LdapResultCode::Success)
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+
+    // An empty name is bound as the authenticated identity.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    client.send(sasl_bind("")).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::BindResponse(resp)) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        op => panic!("Unexpected response {:?}", op),
+    }
+    client.send(whoami).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::ExtendedResponse(resp)) => {
+            assert_eq!(resp.value.as_deref(), Some(&b"dn:cn=svc"[..]))
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+}
+
+#[test]
+fn test_tenants_config() {
+    let config_str = r#"
+        bind = ["127.0.0.1:3636", "127.0.0.1:3637", "unix:/run/ldap-proxy/ldap.sock"]
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+
+        [tenants]
+        "127.0.0.1:3637" = "acme"
+        "unix:/run/ldap-proxy/ldap.sock" = "globex"
+    "#;
+
+    let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
+    let tenant = |addr: &str| {
+        config
+            .tenants
+            .get(&addr.parse::<ListenAddr>().expect("invalid address"))
+            .map(String::as_str)
+    };
+    assert_eq!(tenant("127.0.0.1:3636"), None);
+    assert_eq!(tenant("127.0.0.1:3637"), Some("acme"));
+    assert_eq!(tenant("unix:/run/ldap-proxy/ldap.sock"), Some("globex"));
+    // The section must not be mistaken for a bind DN.
+    assert!(!config.binddn_map.contains_key("tenants"));
+}
+
+#[test]
+fn test_search_cache_key_tenant() {
+    let key = SearchCacheKey::new(
+        "cn=svc".to_string(),
+        common::search_request("dc=example,dc=com"),
+        vec![],
+    );
+    let acme = key.clone().with_tenant(Some("acme".to_string()));
+    let globex = key.clone().with_tenant(Some("globex".to_string()));
+
+    assert_ne!(acme, key);
+    assert_ne!(acme, globex);
+    let redis_key = |key: &SearchCacheKey| {
+        key.to_redis_key("ldap_proxy:")
+            .expect("Failed to derive key")
+    };
+    assert_ne!(redis_key(&acme), redis_key(&key));
+    assert_ne!(redis_key(&acme), redis_key(&globex));
+    assert_eq!(redis_key(&key.clone().with_tenant(None)), redis_key(&key));
+}
+
+#[tokio::test]
+async fn test_tenants_do_not_share_cache() {
+    use ldap3_proto::proto::LdapMsg;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=a,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_mode = "read_through"
+        ["cn=reader"]
+    "#,
+    ));
+
+    let search = |tenant: Option<&'static str>| {
+        let app_state = app_state.clone();
+        async move {
+            let mut client = match tenant {
+                Some(tenant) => common::TestClient::spawn_for_tenant(app_state, tenant),
+                None => common::TestClient::spawn(app_state),
+            };
+            assert_eq!(
+                client.bind(1, "cn=reader", "password").await,
+                LdapResultCode::Success
+            );
+            let (entries, result) = client
+                .search(2, common::search_request("dc=example,dc=com"))
+                .await;
+            assert_eq!(result.code, LdapResultCode::Success);
+            assert_eq!(entries.len(), 1);
+        }
+    };
+
+    // Each tenant misses the entries cached for the others.
+    search(Some("acme")).await;
+    assert_eq!(backend.search_count(), 1);
+    search(Some("globex")).await;
+    assert_eq!(backend.search_count(), 2);
+    search(None).await;
+    assert_eq!(backend.search_count(), 3);
+
+    // And hits its own.
+    search(Some("acme")).await;
+    search(Some("globex")).await;
+    search(None).await;
+    assert_eq!(backend.search_count(), 3);
+}
+
+#[test]
+fn test_codecs_server_sort_controls() {
+    use ldap3_proto::control::{LdapControl, ServerSortRequet, ServerSortResult};
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::{LdapCodec, LdapResultCode};
+    use ldap_proxy::codec::{BackendCodec, ClientCodec, ResponseWithControl};
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::{Decoder, Encoder};
+
+    // The sort keys of a request survive decoding.
+    let sort = LdapControl::ServerSort {
+        sort_requests: vec![
+            ServerSortRequet {
+                attribute_name: "sn".to_string(),
+                ordering_rule: Some("2.5.13.3".to_string()),
+                reverse_order: true,
+            },
+            ServerSortRequet {
+                attribute_name: "cn".to_string(),
+                ordering_rule: None,
+                reverse_order: false,
+            },
+        ],
+    };
+    let request = LdapMsg {
+        msgid: 1,
+        op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+        ctrl: vec![sort, LdapControl::ManageDsaIT { criticality: true }],
+    };
+    let mut buf = BytesMut::new();
+    LdapCodec::new(None)
+        .encode(request.clone(), &mut buf)
+        .expect("Failed to encode");
+    let decoded = ClientCodec::new(None, true)
+        .decode(&mut buf)
+        .expect("Failed to decode")
+        .expect("Incomplete message");
+    assert!(buf.is_empty());
+    assert_eq!(decoded, request);
+
+    // Sort results, with and without the attribute that failed, are encoded
+    // for the client as RFC 2891 has them and decoded from the backend.
+    for (attribute_type, value) in [
+        (None, vec![0x30, 0x03, 0x0a, 0x01, 0x00]),
+        (
+            Some("jpegPhoto".to_string()),
+            [&[0x30, 0x0e, 0x0a, 0x01, 0x35, 0x80, 0x09][..], b"jpegPhoto"].concat(),
+        ),
+    ] {
+        let result_code = if attribute_type.is_some() {
+            LdapResultCode::UnwillingToPerform
+        } else {
+            LdapResultCode::Success
+        };
+        let sort_result = LdapControl::ServerSortResult {
+            sort_result: ServerSortResult {
+                result_code,
+                attribute_type,
+            },
+        };
+        let done = |ctrl: Vec<LdapControl>| LdapMsg {
+            msgid: 1,
+            op: LdapOp::SearchResultDone(common::ldap_result(LdapResultCode::Success)),
+            ctrl,
+        };
+
+        let mut codec = ClientCodec::new(None, true);
+        codec
+            .encode(done(vec![sort_result.clone()]), &mut buf)
+            .expect("Failed to encode");
+        assert!(buf.windows(value.len()).any(|w| w == value));
+        // Alongside a control added by the proxy.
+        codec
+            .encode(
+                ResponseWithControl {
+                    msg: done(vec![sort_result.clone()]),
+                    oid: "1.2.3.5".to_string(),
+                    value: b"42".to_vec(),
+                },
+                &mut buf,
+            )
+            .expect("Failed to encode");
+
+        let mut codec = BackendCodec::new(None);
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("Failed to decode")
+            .expect("Incomplete message");
+        assert_eq!(decoded, done(vec![sort_result.clone()]));
+        let decoded = codec
+            .decode(&mut buf)
+            .expect("Failed to decode")
+            .expect("Incomplete message");
+        assert_eq!(
+            decoded.ctrl,
+            vec![
+                LdapControl::Unknown {
+                    oid: "1.2.3.5".to_string()
+                },
+                sort_result,
+            ]
+        );
+        assert!(buf.is_empty());
+    }
+}
+
+#[tokio::test]
+async fn test_server_sort_order_is_cached() {
+    use ldap3_proto::control::{LdapControl, ServerSortRequet, ServerSortResult};
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    // The backend sorts by cn, descending when asked to.
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut entries = vec![
+                common::entry("cn=b,dc=example,dc=com"),
+                common::entry("cn=a,dc=example,dc=com"),
+                common::entry("cn=c,dc=example,dc=com"),
+            ];
+            let mut ctrl = vec![];
+            for c in &msg.ctrl {
+                if let LdapControl::ServerSort { sort_requests } = c {
+                    entries.sort_by(|a, b| a.dn.cmp(&b.dn));
+                    if sort_requests[0].reverse_order {
+                        entries.reverse();
+                    }
+                    ctrl.push(LdapControl::ServerSortResult {
+                        sort_result: ServerSortResult {
+                            result_code: LdapResultCode::Success,
+                            attribute_type: None,
+                        },
+                    });
+                }
+            }
+            let mut msgs = common::search_response(msg.msgid, entries, LdapResultCode::Success);
+            if let Some(done) = msgs.last_mut() {
+                done.ctrl = ctrl;
+            }
+            msgs
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    // Sorting cached entries by DN must not undo the requested order.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        sort_cached_entries = true
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        LdapResultCode::Success
+    );
+    let sort = LdapControl::ServerSort {
+        sort_requests: vec![ServerSortRequet {
+            attribute_name: "cn".to_string(),
+            ordering_rule: None,
+            reverse_order: true,
+        }],
+    };
+    let expected = [
+        "cn=c,dc=example,dc=com",
+        "cn=b,dc=example,dc=com",
+        "cn=a,dc=example,dc=com",
+    ];
+    let assert_sorted = |msgs: Vec<LdapMsg>| {
+        let dns: Vec<_> = msgs
+            .iter()
+            .filter_map(|msg| match &msg.op {
+                LdapOp::SearchResultEntry(entry) => Some(entry.dn.as_str()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(dns, expected);
+        let done = msgs.last().expect("no response");
+        assert!(matches!(done.op, LdapOp::SearchResultDone(_)));
+        assert!(done.ctrl.iter().any(|c| matches!(
+            c,
+            LdapControl::ServerSortResult { sort_result }
+                if sort_result.result_code == LdapResultCode::Success
+        )));
+    };
+
+    let sr = common::search_request("dc=example,dc=com");
+    assert_sorted(
+        client
+            .search_msgs_with_controls(2, sr.clone(), vec![sort.clone()])
+            .await,
+    );
+    // The sort keys reached the backend.
+    assert!(backend
+        .requests()
+        .iter()
+        .any(|msg| msg.ctrl.contains(&sort)));
+
+    backend.set_online(false);
+    assert_sorted(
+        client
+            .search_msgs_with_controls(3, sr, vec![sort.clone()])
+            .await,
+    );
+}
+
+#[test]
+fn test_redis_compression_round_trip() {
+    use ldap_proxy::compression::{compress, decompress};
+    use ldap_proxy::RedisCompression;
+
+    let value = CachedValue {
+        cached_at: std::time::SystemTime::now(),
+        entries: (0..50)
+            .map(|i| (common::entry(&format!("cn=user{},dc=example,dc=com", i)), vec![]))
+            .collect(),
+        result: common::ldap_result(ldap3_proto::LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+    let json = serde_json::to_vec(&value).expect("Failed to serialize");
+
+    for compression in [
+        RedisCompression::None,
+        RedisCompression::Zstd,
+        RedisCompression::Gzip,
+    ] {
+        let stored = compress(compression, json.clone()).expect("Failed to compress");
+        if compression == RedisCompression::None {
+            assert_eq!(stored, json);
+        } else {
+            assert!(stored.len() < json.len());
+        }
+        // Whatever the current setting, every stored form reads back.
+        let read = decompress(&stored).expect("Failed to decompress");
+        assert_eq!(*read, *json);
+        let read: CachedValue = serde_json::from_slice(&read).expect("Failed to deserialize");
+        assert!(!read.data_differs(&value));
+    }
+
+    let config = toml::from_str::<Config>(&format!(
+        "{}\nredis_compression = \"zstd\"",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.redis_compression, RedisCompression::Zstd);
+    let config = toml::from_str::<Config>(common::BASE_CONFIG).expect("Failed to parse config");
+    assert_eq!(config.redis_compression, RedisCompression::None);
+}
+
+#[test]
+fn test_dn_ancestors() {
+    use ldap_proxy::dn::Dn;
+
+    let dn = "uid=a, ou=People,dc=example,dc=com".parse::<Dn>().expect("Invalid dn");
+    let ancestors: Vec<String> = dn.ancestors().map(|dn| dn.to_string()).collect();
+    assert_eq!(
+        ancestors,
+        vec![
+            "uid=a,ou=People,dc=example,dc=com",
+            "ou=People,dc=example,dc=com",
+            "dc=example,dc=com",
+            "dc=com",
+            "",
+        ]
+    );
+}
+
+#[tokio::test]
+async fn test_cache_invalidate_by_dn() {
+    use ldap_proxy::proxy::cache_invalidate_by_dn;
+
+    let app_state = common::offline_app_state("");
+    let bases = [
+        "dc=example,dc=com",
+        "ou=people,dc=example,dc=com",
+        "uid=a,ou=People,dc=example,dc=com",
+        "ou=groups,dc=example,dc=com",
+        "dc=other,dc=com",
+    ];
+    for base in bases {
+        common::memory_cache_set(
+            &app_state,
+            "cn=reader",
+            &common::search_request(base),
+            vec![common::entry(base)],
+        );
+    }
+
+    let removed = cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "uid=A,ou=people,dc=example,dc=com",
+    )
+    .await
+    .expect("Failed to invalidate");
+    assert_eq!(removed, 3);
+
+    let cached = |base: &str| {
+        common::memory_cache_get(&app_state, "cn=reader", &common::search_request(base))
+    };
+    assert!(cached("dc=example,dc=com").is_none());
+    assert!(cached("ou=people,dc=example,dc=com").is_none());
+    assert!(cached("uid=a,ou=People,dc=example,dc=com").is_none());
+    assert!(cached("ou=groups,dc=example,dc=com").is_some());
+    assert!(cached("dc=other,dc=com").is_some());
+
+    let invalid = cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "not a dn",
+    );
+    assert!(invalid.await.is_err());
+}
+
+#[tokio::test]
+async fn test_schema_snapshot() {
+    use ldap3_proto::proto::{
+        LdapFilter, LdapMsg, LdapOp, LdapPartialAttribute, LdapSearchResultEntry, LdapSearchScope,
+    };
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let attr = |atype: &str, val: &str| LdapPartialAttribute {
+        atype: atype.to_string(),
+        vals: vec![val.as_bytes().to_vec()],
+    };
+    let backend = common::MockBackend::start(Arc::new(move |msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.is_empty() => {
+            let root_dse = LdapSearchResultEntry {
+                dn: "".to_string(),
+                attributes: vec![attr("subschemaSubentry", "cn=Subschema")],
+            };
+            common::search_response(msg.msgid, vec![root_dse], LdapResultCode::Success)
+        }
+        LdapOp::SearchRequest(sr) if sr.base == "cn=Subschema" => {
+            let subschema = LdapSearchResultEntry {
+                dn: "cn=Subschema".to_string(),
+                attributes: vec![
+                    attr("objectClass", "subschema"),
+                    attr("attributeTypes", "( 2.5.4.3 NAME 'cn' SUP name )"),
+                ],
+            };
+            common::search_response(msg.msgid, vec![subschema], LdapResultCode::Success)
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_schema_at_startup = true
+        ["cn=svc"]
+    "#,
+    ));
+    let schema = app_state.schema.as_ref().expect("No schema snapshot");
+    assert!(schema.refresh(&app_state).await);
+    assert_eq!(backend.search_count(), 2);
+
+    let mut sr = common::search_request("cn=subschema");
+    sr.scope = LdapSearchScope::Base;
+    sr.filter = LdapFilter::Equality("objectClass".to_string(), "subschema".to_string());
+    sr.attrs = vec!["attributeTypes".to_string()];
+
+    // Probes are answered without binding.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    let (entries, result) = client.search(1, sr.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "cn=Subschema");
+    assert_eq!(entries[0].attributes.len(), 1);
+    assert_eq!(entries[0].attributes[0].atype, "attributeTypes");
+
+    // As are those of bound clients.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=svc", "password").await, LdapResultCode::Success);
+    sr.attrs = vec![];
+    let (entries, result) = client.search(2, sr).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries[0].attributes.len(), 2);
+
+    // The backend was not asked again.
+    assert_eq!(backend.search_count(), 2);
+}
+
+#[test]
+fn test_config_error_names_invalid_filter() {
+    use ldap_proxy::ConfigError;
+
+    let config_str = format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        r#"
+        ["cn=good"]
+        allowed_queries = [["dc=example,dc=com", "subtree", "(objectClass=*)"]]
+
+        ["cn=bad,dc=example,dc=com"]
+        allowed_queries = [
+            ["dc=example,dc=com", "subtree", "(uid=*)"],
+            ["dc=example,dc=com", "subtree", "(&(uid=a)"],
+        ]
+    "#
+    );
+    match Config::from_toml(&config_str) {
+        Err(ConfigError::InvalidAllowedQuery {
+            bind_dn,
+            filter,
+            message,
+        }) => {
+            assert_eq!(bind_dn, "cn=bad,dc=example,dc=com");
+            assert_eq!(filter, "(&(uid=a)");
+            assert!(!message.is_empty());
+        }
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
+    }
+
+    let config_str = format!(
+        "{}\n{}",
+        common::BASE_CONFIG,
+        r#"
+        [cache_warm]
+        queries = [["cn=warm", "dc=example,dc=com", "subtree", "uid=a)"]]
+    "#
+    );
+    let err = Config::from_toml(&config_str).expect_err("Invalid filter accepted");
+    assert!(matches!(
+        &err,
+        ConfigError::InvalidWarmQuery { bind_dn, filter, .. }
+            if bind_dn == "cn=warm" && filter == "uid=a)"
+    ));
+    assert!(err.to_string().contains("\"cn=warm\""));
+
+    // Other problems are still reported by the deserializer.
+    let err = Config::from_toml("bind = 1").expect_err("Invalid config accepted");
+    assert!(matches!(err, ConfigError::Toml(_)));
+    assert!(Config::from_toml(common::BASE_CONFIG).is_ok());
+}
+
+#[tokio::test]
+async fn test_default_dn_config() {
+    use ldap_proxy::PermissiveDefaultAction;
+    use std::sync::Arc;
+
+    let config = |extra: &str| {
+        toml::from_str::<Config>(&format!("{}\n{}", common::BASE_CONFIG, extra))
+            .expect("Failed to parse config")
+    };
+    let permissive = config("allow_all_bind_dns = true");
+    assert!(permissive.is_default_dn_permissive());
+    assert_eq!(permissive.permissive_default_action, PermissiveDefaultAction::Warn);
+    assert!(!config("").is_default_dn_permissive());
+    let strict = config(
+        r#"
+        allow_all_bind_dns = true
+        permissive_default_action = "error"
+        [default_dn_config]
+        allowed_queries = [["ou=people,dc=example,dc=com", "subtree", "(uid=*)"]]
+        ["cn=admin"]
+    "#,
+    );
+    assert!(!strict.is_default_dn_permissive());
+    assert_eq!(strict.permissive_default_action, PermissiveDefaultAction::Error);
+    assert!(strict.binddn_map.contains_key("cn=admin"));
+    assert!(!strict.binddn_map.contains_key("default_dn_config"));
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        allow_all_bind_dns = true
+        [default_dn_config]
+        allowed_queries = [["ou=people,dc=example,dc=com", "subtree", "(uid=*)"]]
+        ["cn=admin"]
+    "#,
+    ));
+
+    // A DN without a bind map entry is held to the default config.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=unknown", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let mut sr = common::search_request("ou=people,dc=example,dc=com");
+    sr.filter = ldap3_proto::proto::LdapFilter::Present("uid".to_string());
+    let (_, result) = client.search(2, sr).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    let (_, result) = client.search(3, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::InsufficentAccessRights);
+
+    // DNs in the bind map are unaffected.
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=admin", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = client.search(2, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+}
+
+#[tokio::test]
+async fn test_backend_per_operation() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap_proxy::BackendMode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=a,dc=example,dc=com")],
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        backend_mode = "per_operation"
+        ["cn=reader"]
+    "#,
+    ));
+    assert_eq!(app_state.backend_mode, BackendMode::PerOperation);
+
+    // The connection that validated the bind is closed straight away.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    backend.wait_for_open_connections(0).await;
+
+    // Each search binds its own connection and closes it once answered.
+    for msgid in 2..4 {
+        let (entries, result) = client
+            .search(msgid, common::search_request("dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+        backend.wait_for_open_connections(0).await;
+    }
+    assert_eq!(backend.connection_count(), 3);
+    assert_eq!(backend.bind_count(), 3);
+
+    // Searches still fall back to the cache when no connection can be opened.
+    backend.set_online(false);
+    let (entries, result) = client.search(4, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_backend_per_operation_pooled() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        backend_mode = "per_operation"
+        [backend_pool]
+        per_dn_idle = 2
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    // The connection is parked in the pool between searches and reused.
+    for msgid in 2..5 {
+        let (_, result) = client
+            .search(msgid, common::search_request("dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    }
+    assert_eq!(backend.connection_count(), 1);
+    assert_eq!(backend.bind_count(), 1);
+    assert_eq!(backend.open_connection_count(), 1);
+}
+
+#[tokio::test]
+async fn test_cache_size_metrics() {
+    use ldap_proxy::proxy::sample_cache_metrics;
+
+    let app_state = common::offline_app_state("");
+    assert!(!app_state.metrics.render().contains("cache_entries"));
+
+    for base in ["dc=example,dc=com", "ou=people,dc=example,dc=com"] {
+        common::memory_cache_set(
+            &app_state,
+            "cn=reader",
+            &common::search_request(base),
+            vec![common::entry(base)],
+        );
+    }
+    sample_cache_metrics(&app_state).await;
+
+    let (entries, bytes) = app_state
+        .metrics
+        .cache_size("memory")
+        .expect("Cache size not sampled");
+    assert_eq!(entries, 2);
+    assert!(bytes > 0);
+    let rendered = app_state.metrics.render();
+    assert!(rendered.contains("# TYPE cache_entries gauge"));
+    assert!(rendered.contains(r#"cache_entries{cache="memory"} 2"#));
+    assert!(rendered.contains(&format!(r#"cache_bytes{{cache="memory"}} {}"#, bytes)));
+    assert!(!rendered.contains("redis_keys"));
+}
+
+#[tokio::test]
+async fn test_abandon() {
+    use ldap3_proto::proto::{LdapExtendedRequest, LdapMsg, LdapOp};
+    use std::sync::Arc;
+
+    let abandon = |msgid: i32, abandoned: i32| LdapMsg {
+        msgid,
+        op: LdapOp::AbandonRequest(abandoned),
+        ctrl: vec![],
+    };
+    let whoami = |msgid: i32| LdapMsg {
+        msgid,
+        op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+            name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+            value: None,
+        }),
+        ctrl: vec![],
+    };
+
+    let backend = common::MockBackend::start(Arc::new(stuck_search_handler)).await;
+
+    // With serial operations there is never anything to abandon, and the
+    // session carries on without a response.
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+    let mut client = common::TestClient::spawn(app_state);
+    client.send(abandon(1, 7)).await;
+    assert_eq!(
+        client.bind(2, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    client.send(abandon(3, 2)).await;
+    let (_, result) = client
+        .search(4, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+
+    // An outstanding search is cancelled, so that operations waiting on it
+    // can proceed.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_concurrent_ops = 2
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    client.send(search_msg(2, "ou=stuck,dc=example,dc=com")).await;
+    client.send(abandon(3, 2)).await;
+    client.send(whoami(4)).await;
+    let msg = client.recv().await.expect("Connection closed");
+    assert_eq!(msg.msgid, 4);
+    assert!(matches!(msg.op, LdapOp::ExtendedResponse(_)));
+
+    // The search is abandoned on the backend too, and its connection is
+    // reused rather than reopened.
+    let connections = backend.connection_count();
+    let (_, result) = client
+        .search(5, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), connections);
+    assert!(backend
+        .requests()
+        .iter()
+        .any(|msg| matches!(msg.op, LdapOp::AbandonRequest(_))));
+}
+
+#[tokio::test]
+async fn test_response_flush_entries() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            (0..100)
+                .map(|i| common::entry(&format!("cn=user{},dc=example,dc=com", i)))
+                .collect(),
+            ldap3_proto::LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+
+    let mut flushes = Vec::new();
+    for extra in ["response_flush_entries = 1", "", "max_buffered_entries = 10"] {
+        let app_state = Arc::new(backend.app_state(&format!("{}\n[\"cn=reader\"]", extra)));
+        let mut client = common::TestClient::spawn(app_state);
+        assert_eq!(
+            client.bind(1, "cn=reader", "password").await,
+            ldap3_proto::LdapResultCode::Success
+        );
+        let before = client.flush_count();
+        let (entries, result) = client
+            .search(2, common::search_request("dc=example,dc=com"))
+            .await;
+        assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+        assert_eq!(entries.len(), 100);
+        flushes.push(client.flush_count() - before);
+    }
+
+    // Every entry and the result, or every 64th entry and the result, both
+    // when the result is buffered and when it is streamed.
+    assert_eq!(flushes, vec![101, 2, 2]);
+}
+
+#[tokio::test]
+async fn test_whoami_format() {
+    use ldap3_proto::proto::{LdapExtendedRequest, LdapMsg, LdapOp};
+    use ldap_proxy::WhoamiFormat;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let whoami = |format: &'static str| {
+        let config = format!("{format}\n[\"uid=alice,ou=people,dc=example,dc=com\"]");
+        let app_state = Arc::new(backend.app_state(&config));
+        async move {
+            let mut client = common::TestClient::spawn(app_state);
+            assert_eq!(
+                client.bind(1, "uid=alice,ou=people,dc=example,dc=com", "password").await,
+                ldap3_proto::LdapResultCode::Success
+            );
+            client
+                .send(LdapMsg {
+                    msgid: 2,
+                    op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                        name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                        value: None,
+                    }),
+                    ctrl: vec![],
+                })
+                .await;
+            match client.recv().await.map(|msg| msg.op) {
+                Some(LdapOp::ExtendedResponse(resp)) => resp.value,
+                op => panic!("Unexpected response {:?}", op),
+            }
+        }
+    };
+
+    // The authzId is prefixed with "dn:" by default, as RFC 4532 expects.
+    assert_eq!(
+        whoami("").await,
+        Some(b"dn:uid=alice,ou=people,dc=example,dc=com".to_vec())
+    );
+    assert_eq!(
+        whoami(r#"whoami_format = "raw""#).await,
+        Some(b"uid=alice,ou=people,dc=example,dc=com".to_vec())
+    );
+    assert_eq!(whoami(r#"whoami_format = "u""#).await, Some(b"u:alice".to_vec()));
+
+    assert_eq!(WhoamiFormat::Dn.authz_id(""), "");
+    assert_eq!(WhoamiFormat::U.authz_id("uid=#04056461"), "dn:uid=#04056461");
+}
+
+#[tokio::test]
+async fn test_search_deadline() {
+    use ldap3_proto::proto::LdapOp;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(stuck_search_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        search_deadline_ms = 200
+    "#,
+    ));
+    let cached = common::search_request("ou=stuck,dc=example,dc=com");
+    common::memory_cache_set(
+        &app_state,
+        "cn=reader",
+        &cached,
+        vec![common::entry("cn=cached,dc=example,dc=com")],
+    );
+
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+
+    // A search the backend does not answer in time is served from the cache.
+    let (entries, result) = client.search(2, cached).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "cn=cached,dc=example,dc=com");
+
+    // Without a cached result the client is told the time limit was hit.
+    let (entries, result) = client
+        .search(3, common::search_request("ou=stuck,ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::TimeLimitExceeded);
+    assert!(entries.is_empty());
+
+    // The backend searches were abandoned and the connection is still used.
+    let (_, result) = client
+        .search(4, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), 1);
+    let abandoned = backend
+        .requests()
+        .iter()
+        .filter(|msg| matches!(msg.op, LdapOp::AbandonRequest(_)))
+        .count();
+    assert_eq!(abandoned, 2);
+}
+
+#[test]
+fn test_tls_key_mismatch_fails_startup() {
+    use ldap_proxy::tls::server_tls_acceptor;
+
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-tls-mismatch-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create dir");
+    let (pkey, cert) = common::self_signed_cert("localhost");
+    let (other_pkey, _) = common::self_signed_cert("localhost");
+    let chain = dir.join("chain.pem");
+    let key = dir.join("key.pem");
+    let other_key = dir.join("other-key.pem");
+    let empty = dir.join("empty.pem");
+    std::fs::write(&chain, cert.to_pem().expect("pem")).expect("write chain");
+    std::fs::write(&key, pkey.private_key_to_pem_pkcs8().expect("pem")).expect("write key");
+    let other_pem = other_pkey.private_key_to_pem_pkcs8().expect("pem");
+    std::fs::write(&other_key, other_pem).expect("write key");
+    std::fs::write(&empty, "").expect("write empty chain");
+
+    assert!(server_tls_acceptor(&chain, &key).is_ok());
+    let error = |chain, key| server_tls_acceptor(chain, key).err().unwrap_or_default();
+    assert!(error(&chain, &other_key).contains("does not match"));
+    assert!(error(&empty, &key).contains("holds no certificates"));
+
+    let config = dir.join("config.toml");
+    std::fs::write(
+        &config,
+        format!(
+            r#"
+            bind = "127.0.0.1:0"
+            tls_chain = "{chain}"
+            tls_key = "{key}"
+            ldap_ca = "{chain}"
+            ldap_url = "ldaps://localhost"
+        "#,
+            chain = chain.display(),
+            key = other_key.display(),
+        ),
+    )
+    .expect("Failed to write config");
+    let output = std::process::Command::new(env!("CARGO_BIN_EXE_ldap-proxy"))
+        .arg("--config")
+        .arg(&config)
+        .output()
+        .expect("Failed to run ldap-proxy");
+    let _ = std::fs::remove_dir_all(&dir);
+
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stdout).contains("does not match"));
+}
+
+#[test]
+fn test_config_secrets() {
+    use ldap_proxy::{CacheConfig, ConfigError};
+
+    let dir = std::env::temp_dir().join(format!("ldap-proxy-secrets-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("Failed to create dir");
+    let secret_file = dir.join("reader-password");
+    std::fs::write(&secret_file, "from-file\n").expect("Failed to write secret");
+    std::env::set_var("LDAP_PROXY_TEST_REDIS_PASSWORD", "from-env");
+
+    let config = |url: &str, password: &str| {
+        Config::from_toml(&format!(
+            r#"
+            {}
+            [cache]
+            type = "redis"
+            url = "{}"
+            [cache_warm]
+            credentials = {{ "cn=reader" = "{}" }}
+        "#,
+            common::BASE_CONFIG,
+            url,
+            password
+        ))
+    };
+
+    // Environment variables are substituted and files are read.
+    let resolved = config(
+        "redis://:${LDAP_PROXY_TEST_REDIS_PASSWORD}@localhost:6379",
+        &format!("@file:{}", secret_file.display()),
+    )
+    .expect("Failed to resolve secrets");
+    match resolved.cache_config() {
+        CacheConfig::Redis { url, .. } => assert_eq!(url, "redis://:from-env@localhost:6379"),
+        cache => panic!("Unexpected cache config {:?}", cache),
+    }
+    let credentials = &resolved.cache_warm.as_ref().expect("No cache_warm").credentials;
+    assert_eq!(credentials["cn=reader"], "from-file");
+    assert!(!format!("{:?}", resolved.cache).contains("from-env"));
+
+    // A missing secret names the field it was for.
+    let err = config("redis://:${LDAP_PROXY_TEST_UNSET}@localhost:6379", "plain")
+        .expect_err("Missing variable accepted");
+    assert!(matches!(&err, ConfigError::Secret { field, .. } if field == "cache.url"));
+    assert!(err.to_string().contains("LDAP_PROXY_TEST_UNSET is not set"));
+    let missing = dir.join("missing");
+    let err = config("redis://localhost", &format!("@file:{}", missing.display()))
+        .expect_err("Missing file accepted");
+    assert!(matches!(&err, ConfigError::Secret { field, .. } if field.starts_with("cache_warm")));
+
+    let _ = std::fs::remove_dir_all(&dir);
+}
+
+#[tokio::test]
+async fn test_session_summary() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let (logs, _guard) = common::Logs::capture();
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=cached,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(r#"["cn=svc"]"#));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=svc", "password").await, LdapResultCode::Success);
+    let sr = common::search_request("dc=example,dc=com");
+    client.search(2, sr.clone()).await;
+    // Served from the fallback cache.
+    backend.set_online(false);
+    client.search(3, sr).await;
+    client
+        .send(ldap3_proto::proto::LdapMsg {
+            msgid: 4,
+            op: LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    client.join().await;
+
+    let summary = logs.line("Disconnect for").expect("No session summary");
+    assert!(summary.contains("binds=1 searches=2 cache_hits=1"), "{}", summary);
+    assert!(summary.contains("duration_ms="), "{}", summary);
+}
+
+#[tokio::test]
+async fn test_cache_attribute_superset() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp, LdapPartialAttribute, LdapSearchResultEntry};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let attribute = |atype: &str, val: &str| LdapPartialAttribute {
+        atype: atype.to_string(),
+        vals: vec![val.as_bytes().to_vec()],
+    };
+    // The backend answers with every attribute whatever is asked for.
+    let backend = common::MockBackend::start(Arc::new(move |msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![LdapSearchResultEntry {
+                dn: "uid=alice,dc=example,dc=com".to_string(),
+                attributes: vec![
+                    attribute("cn", "Alice"),
+                    attribute("cn;lang-fr", "Alice"),
+                    attribute("mail", "alice@example.com"),
+                    attribute("entryUUID", "1234"),
+                ],
+            }],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let search = |attrs: &[&str]| {
+        let mut sr = common::search_request("dc=example,dc=com");
+        sr.attrs = attrs.iter().map(|a| a.to_string()).collect();
+        sr
+    };
+    let names = |entries: &[LdapSearchResultEntry]| {
+        entries[0]
+            .attributes
+            .iter()
+            .map(|attr| attr.atype.clone())
+            .collect::<Vec<_>>()
+    };
+
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_attribute_superset = true
+        cache_mode = "read_through"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    // The backend is asked for every attribute, and the client gets those it
+    // named.
+    let (entries, _) = client.search(2, search(&["CN"])).await;
+    assert_eq!(names(&entries), vec!["cn", "cn;lang-fr"]);
+    let sent = backend
+        .requests()
+        .into_iter()
+        .find_map(|msg| match msg.op {
+            LdapOp::SearchRequest(sr) => Some(sr.attrs),
+            _ => None,
+        })
+        .expect("No search sent");
+    assert_eq!(sent, vec!["*", "+"]);
+
+    // Narrower searches of the same base, scope and filter are cache hits.
+    let (entries, _) = client.search(3, search(&["mail", "entryUUID"])).await;
+    assert_eq!(names(&entries), vec!["mail", "entryUUID"]);
+    assert_eq!(backend.search_count(), 1);
+
+    // Searches for all attributes are cached as they were.
+    let (entries, _) = client.search(4, search(&[])).await;
+    assert_eq!(entries[0].attributes.len(), 4);
+    assert_eq!(backend.search_count(), 2);
+
+    // The superset is also the fallback during an outage.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_attribute_superset = true
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    client.search(2, search(&["cn"])).await;
+    backend.set_online(false);
+    let (entries, result) = client.search(3, search(&["mail"])).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(names(&entries), vec!["mail"]);
+}
+
+#[test]
+fn test_fallback_cache_bytes_precedence() {
+    use ldap_proxy::CacheConfig;
+
+    let cache_config = |extra: &str| {
+        toml::from_str::<Config>(&format!("{}\n{}", common::BASE_CONFIG, extra))
+            .expect("Failed to parse config")
+            .cache_config()
+    };
+
+    // size_bytes in the [cache] section wins over the deprecated option.
+    assert!(matches!(
+        cache_config(
+            r#"
+            fallback_cache_bytes = 1024
+            [cache]
+            type = "memory"
+            size_bytes = 2048
+        "#
+        ),
+        CacheConfig::Memory { size_bytes: 2048 }
+    ));
+    // So does any other [cache] section.
+    assert!(matches!(
+        cache_config(
+            r#"
+            fallback_cache_bytes = 1024
+            [cache]
+            type = "redis"
+            url = "redis://localhost:6379"
+        "#
+        ),
+        CacheConfig::Redis { .. }
+    ));
+    // Without a [cache] section the deprecated option sizes the memory cache.
+    assert!(matches!(
+        cache_config("fallback_cache_bytes = 1024"),
+        CacheConfig::Memory { size_bytes: 1024 }
+    ));
+    // Without either the default size is used.
+    assert!(matches!(
+        cache_config(""),
+        CacheConfig::Memory {
+            size_bytes: 268435456
+        }
+    ));
+}
+
+#[test]
+fn test_backoff() {
+    use ldap_proxy::backoff::Backoff;
+    use ldap_proxy::breaker::CircuitBreaker;
+    use ldap_proxy::health::check_delay;
+    use std::time::Duration;
+
+    let config = toml::from_str::<Config>(&format!(
+        "{}\nbackoff_initial_ms = 100\nbackoff_max_ms = 1000",
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    let backoff = config.backoff().expect("No backoff");
+    assert!(toml::from_str::<Config>(common::BASE_CONFIG)
+        .expect("Failed to parse config")
+        .backoff()
+        .is_none());
+    for jitter in ["nan", "inf", "-inf"] {
+        assert!(toml::from_str::<Config>(&format!(
+            "{}
+backoff_initial_ms = 100
+backoff_jitter = {}",
+            common::BASE_CONFIG,
+            jitter
+        ))
+        .is_err());
+    }
+
+    // Health checks back off while the backend stays down, up to the max,
+    // and return to the interval once it recovers.
+    let interval = Duration::from_secs(10);
+    let base_delays: Vec<_> = (0..6).map(|failures| backoff.base_delay(failures)).collect();
+    assert_eq!(
+        base_delays,
+        [100, 200, 400, 800, 1000, 1000].map(Duration::from_millis)
+    );
+    for failures in 1..8 {
+        let delay = check_delay(interval, Some(&backoff), failures);
+        let base = backoff.base_delay(failures - 1);
+        assert!(delay <= base && delay >= base.mul_f64(0.8), "{:?}", delay);
+    }
+    assert_eq!(check_delay(interval, Some(&backoff), 0), interval);
+    assert_eq!(check_delay(interval, None, 3), interval);
+    assert_eq!(backoff.base_delay(u32::MAX), Duration::from_millis(1000));
+
+    // Each failed probe doubles the breaker's wait, and recovering resets it.
+    let breaker = CircuitBreaker::new(1, Duration::from_secs(30)).with_backoff(Backoff::new(
+        Duration::from_millis(100),
+        Duration::from_millis(400),
+        0.0,
+    ));
+    breaker.record_failure();
+    for wait in [100, 200, 400, 400] {
+        std::thread::sleep(Duration::from_millis(wait - 50));
+        assert!(!breaker.allow(), "probed before {}ms", wait);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow(), "no probe after {}ms", wait);
+        breaker.record_failure();
+    }
+    std::thread::sleep(Duration::from_millis(410));
+    assert!(breaker.allow());
+    breaker.record_success();
+    breaker.record_failure();
+    std::thread::sleep(Duration::from_millis(110));
+    assert!(breaker.allow());
+}
+
+#[tokio::test]
+async fn test_search_before_bind() {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(result_code_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        ["cn=wrong"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+
+    // The client is told it has not bound, and stays connected.
+    let (entries, result) = client
+        .search(1, common::search_request("dc=example,dc=com"))
+        .await;
+    assert!(entries.is_empty());
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::OperationsError);
+    assert_eq!(result.message, "not bound");
+    assert_eq!(backend.search_count(), 0);
+
+    // So is one whose bind was rejected.
+    assert_eq!(
+        client.bind(2, "cn=wrong", "password").await,
+        ldap3_proto::LdapResultCode::InvalidCredentials
+    );
+    let (_, result) = client
+        .search(3, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::OperationsError);
+
+    assert_eq!(
+        client.bind(4, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    let (_, result) = client
+        .search(5, common::search_request("dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+}
+
+/// A PROXY v2 header for a TCP over IPv4 connection from `src`, followed
+/// by `tlvs`.
+fn proxy_v2_header(src: std::net::SocketAddrV4, tlvs: &[u8]) -> Vec<u8> {
+    let mut hdr = b"\x0D\x0A\x0D\x0A\x00\x0D\x0A\x51\x55\x49\x54\x0A\x21\x11".to_vec();
+    hdr.extend_from_slice(&(12 + tlvs.len() as u16).to_be_bytes());
+    hdr.extend_from_slice(&src.ip().octets());
+    hdr.extend_from_slice(&[10, 0, 0, 1]);
+    hdr.extend_from_slice(&src.port().to_be_bytes());
+    hdr.extend_from_slice(&636u16.to_be_bytes());
+    hdr.extend_from_slice(tlvs);
+    hdr
+}
+
+/// An SSL TLV with the given client flags and verify result, carrying the
+/// version and CN sub-TLVs.
+fn ssl_tlv(client: u8, verify: u32, cn: &str) -> Vec<u8> {
+    let mut value = vec![client];
+    value.extend_from_slice(&verify.to_be_bytes());
+    // PP2_SUBTYPE_SSL_VERSION
+    value.push(0x21);
+    value.extend_from_slice(&7u16.to_be_bytes());
+    value.extend_from_slice(b"TLSv1.3");
+    // PP2_SUBTYPE_SSL_CN
+    value.push(0x22);
+    value.extend_from_slice(&(cn.len() as u16).to_be_bytes());
+    value.extend_from_slice(cn.as_bytes());
+
+    let mut tlv = vec![0x20];
+    tlv.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    tlv.extend_from_slice(&value);
+    tlv
+}
+
+#[tokio::test]
+async fn test_proxy_v2_ssl_client_cn() {
+    use haproxy_protocol::RemoteAddress;
+    use ldap_proxy::proxy_header::read_proxy_v2;
+    use tokio::io::AsyncReadExt;
+
+    let src: std::net::SocketAddrV4 = "192.0.2.10:50000".parse().expect("Invalid address");
+
+    // An unrelated TLV ahead of the SSL one, PP2_TYPE_ALPN.
+    let mut tlvs = vec![0x01, 0x00, 0x02, b'h', b'2'];
+    tlvs.extend(ssl_tlv(0x07, 0, "svc-client"));
+    let mut stream = proxy_v2_header(src, &tlvs);
+    stream.extend_from_slice(b"client hello");
+
+    let (mut rest, hdr) = read_proxy_v2(stream.as_slice())
+        .await
+        .expect("Failed to read proxy header");
+    assert!(matches!(hdr.remote_addr, RemoteAddress::TcpV4 { src: s, .. } if s == src));
+    assert_eq!(hdr.ssl_client_cn.as_deref(), Some("svc-client"));
+    // The client's own bytes are left on the stream.
+    let mut after = Vec::new();
+    rest.read_to_end(&mut after).await.expect("Failed to read");
+    assert_eq!(after, b"client hello");
+
+    // A certificate that failed verification is not reported.
+    let stream = proxy_v2_header(src, &ssl_tlv(0x07, 1, "svc-client"));
+    let (_, hdr) = read_proxy_v2(stream.as_slice())
+        .await
+        .expect("Failed to read proxy header");
+    assert_eq!(hdr.ssl_client_cn, None);
+
+    // Nor is one when the client sent no certificate on this connection.
+    let stream = proxy_v2_header(src, &ssl_tlv(0x01, 0, "svc-client"));
+    let (_, hdr) = read_proxy_v2(stream.as_slice())
+        .await
+        .expect("Failed to read proxy header");
+    assert_eq!(hdr.ssl_client_cn, None);
+
+    // Without TLVs only the address is reported.
+    let stream = proxy_v2_header(src, &[]);
+    let (_, hdr) = read_proxy_v2(stream.as_slice())
+        .await
+        .expect("Failed to read proxy header");
+    assert!(matches!(hdr.remote_addr, RemoteAddress::TcpV4 { .. }));
+    assert_eq!(hdr.ssl_client_cn, None);
+}
+
+#[tokio::test]
+async fn test_max_fallback_age() {
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        max_fallback_age_secs = 60
+    "#,
+    ));
+    let recent = common::search_request("ou=people,dc=example,dc=com");
+    common::memory_cache_set_aged(
+        &app_state,
+        "cn=reader",
+        &recent,
+        vec![common::entry("cn=recent,dc=example,dc=com")],
+        Duration::from_secs(10),
+    );
+    let old = common::search_request("ou=groups,dc=example,dc=com");
+    common::memory_cache_set_aged(
+        &app_state,
+        "cn=reader",
+        &old,
+        vec![common::entry("cn=old,dc=example,dc=com")],
+        Duration::from_secs(120),
+    );
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    backend.set_online(false);
+
+    // An entry within the window is served during the outage.
+    let (entries, result) = client.search(2, recent).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].dn, "cn=recent,dc=example,dc=com");
+
+    // An older one is not, but it is kept in the cache.
+    let (entries, result) = client.search(3, old.clone()).await;
+    assert_eq!(result.code, ldap3_proto::LdapResultCode::Unavailable);
+    assert!(entries.is_empty());
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &old).is_some());
+}
+
+#[tokio::test]
+async fn test_disconnect_reason() {
+    use ldap_proxy::proxy::DisconnectReason;
+    use std::sync::Arc;
+
+    let (logs, _guard) = common::Logs::capture();
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        denied_query_action = "disconnect"
+        ["cn=reader"]
+        allowed_bases = ["ou=people,dc=example,dc=com"]
+    "#,
+    ));
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(
+        client.bind(1, "cn=reader", "password").await,
+        ldap3_proto::LdapResultCode::Success
+    );
+    client
+        .search(2, common::search_request("ou=groups,dc=example,dc=com"))
+        .await;
+    client.join().await;
+
+    let summary = logs.line("Disconnect for").expect("No session summary");
+    assert!(summary.contains("reason=QueryDenied"), "{}", summary);
+    assert_eq!(app_state.metrics.disconnect_count(DisconnectReason::QueryDenied), 1);
+
+    // A clean unbind is counted apart from it.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    client
+        .send(ldap3_proto::proto::LdapMsg {
+            msgid: 1,
+            op: ldap3_proto::proto::LdapOp::UnbindRequest,
+            ctrl: vec![],
+        })
+        .await;
+    client.join().await;
+    assert_eq!(app_state.metrics.disconnect_count(DisconnectReason::Unbind), 1);
+    assert!(app_state
+        .metrics
+        .render()
+        .contains(r#"disconnects_total{reason="query_denied"} 1"#));
+}
+
+#[tokio::test]
+async fn test_cache_key_ignore_limits() {
+    use ldap3_proto::proto::{LdapOp, LdapSearchRequest};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![
+                common::entry("cn=a,dc=example,dc=com"),
+                common::entry("cn=b,dc=example,dc=com"),
+            ],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_key_ignore_limits = true
+        ["cn=reader"]
+    "#,
+    ));
+    let limited = |sizelimit, timelimit| LdapSearchRequest {
+        sizelimit,
+        timelimit,
+        ..common::search_request("dc=example,dc=com")
+    };
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let (entries, _) = client.search(2, limited(5, 30)).await;
+    assert_eq!(entries.len(), 2);
+    // The backend was sent the client's own limits.
+    let forwarded = backend
+        .requests()
+        .into_iter()
+        .find_map(|msg| match msg.op {
+            LdapOp::SearchRequest(sr) => Some(sr),
+            _ => None,
+        })
+        .expect("No search reached the backend");
+    assert_eq!((forwarded.sizelimit, forwarded.timelimit), (5, 30));
+    // It is cached without them.
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &limited(0, 0)).is_some());
+
+    // A search differing only in its limits is served the same entry.
+    backend.set_online(false);
+    let (entries, result) = client.search(3, limited(10, 0)).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 2);
+
+    // It is cut to a smaller size limit.
+    let (entries, result) = client.search(4, limited(1, 0)).await;
+    assert_eq!(result.code, LdapResultCode::SizeLimitExceeded);
+    assert_eq!(entries.len(), 1);
+
+    // typesOnly changes the result, so it does not share the entry.
+    let (entries, result) = client
+        .search(
+            5,
+            LdapSearchRequest {
+                typesonly: true,
+                ..limited(0, 0)
+            },
+        )
+        .await;
+    assert_eq!(result.code, LdapResultCode::Unavailable);
+    assert!(entries.is_empty());
+}
+
+/// Bind as `dn` and search outside and inside ou=people, returning the bind
+/// result and, if it succeeded, the result codes of both searches.
+async fn dn_default_session(
+    allow_all: bool,
+    dn: &str,
+) -> (
+    ldap3_proto::LdapResultCode,
+    Option<(ldap3_proto::LdapResultCode, ldap3_proto::LdapResultCode)>,
+) {
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        allow_all_bind_dns = {}
+        [[dn_default]]
+        dn_suffix = "ou=Services,dc=example,dc=com"
+        allowed_bases = ["ou=people,dc=example,dc=com"]
+        ["cn=reader"]
+    "#,
+        allow_all
+    )));
+
+    let mut client = common::TestClient::spawn(app_state);
+    let bound = client.bind(1, dn, "password").await;
+    if bound != ldap3_proto::LdapResultCode::Success {
+        return (bound, None);
+    }
+    let (_, groups) = client
+        .search(2, common::search_request("ou=groups,dc=example,dc=com"))
+        .await;
+    let (_, people) = client
+        .search(3, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    (bound, Some((groups.code, people.code)))
+}
+
+#[tokio::test]
+async fn test_dn_default() {
+    use ldap3_proto::LdapResultCode;
+
+    let service = "cn=app,ou=services,dc=example,dc=com";
+    let other = "cn=app,ou=people,dc=example,dc=com";
+    let restricted = Some((
+        LdapResultCode::InsufficentAccessRights,
+        LdapResultCode::Success,
+    ));
+
+    // A DN under the suffix binds with the config of the entry.
+    assert_eq!(
+        dn_default_session(false, service).await,
+        (LdapResultCode::Success, restricted.clone())
+    );
+    // Others are still rejected.
+    assert_eq!(
+        dn_default_session(false, other).await,
+        (LdapResultCode::OperationsError, None)
+    );
+
+    // The entry is used ahead of allow_all_bind_dns, which only applies to
+    // DNs no entry matches.
+    assert_eq!(
+        dn_default_session(true, service).await,
+        (LdapResultCode::Success, restricted)
+    );
+    assert_eq!(
+        dn_default_session(true, other).await,
+        (
+            LdapResultCode::Success,
+            Some((LdapResultCode::Success, LdapResultCode::Success))
+        )
+    );
+}
+
+#[tokio::test]
+async fn test_coalesce_searches() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    const CLIENTS: usize = 8;
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=shared,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        coalesce_searches = true
+        ["cn=reader"]
+    "#,
+    ));
+
+    let mut clients = Vec::new();
+    for _ in 0..CLIENTS {
+        let mut client = common::TestClient::spawn(app_state.clone());
+        assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+        clients.push(client);
+    }
+
+    // Held long enough for every search to arrive while the first is in flight.
+    backend.set_search_delay(Duration::from_millis(300));
+    let sr = common::search_request("dc=example,dc=com");
+    let results = futures_util::future::join_all(
+        clients
+            .iter_mut()
+            .map(|client| client.search(2, sr.clone())),
+    )
+    .await;
+
+    for (entries, result) in results {
+        assert_eq!(result.code, LdapResultCode::Success);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].dn, "cn=shared,dc=example,dc=com");
+    }
+    assert_eq!(backend.search_count(), 1);
+
+    // Once it has completed the next search goes to the backend again.
+    backend.set_search_delay(Duration::ZERO);
+    clients[0].search(3, sr.clone()).await;
+    assert_eq!(backend.search_count(), 2);
+
+    // Searches that would not share a cache entry are not coalesced.
+    let other = common::search_request("ou=people,dc=example,dc=com");
+    backend.set_search_delay(Duration::from_millis(300));
+    let (first, second) = clients.split_at_mut(1);
+    futures_util::future::join(first[0].search(4, sr), second[0].search(4, other)).await;
+    assert_eq!(backend.search_count(), 4);
+}
+
+#[tokio::test]
+async fn test_bind_password_policy_control() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::codec::{
+        parse_password_policy, PasswordPolicyError, PasswordPolicyResponse, OID_PASSWORD_POLICY,
+    };
+    use std::sync::Arc;
+
+    // A warning that the password expires in an hour.
+    let expiring = vec![0x30, 0x06, 0xa0, 0x04, 0x80, 0x02, 0x0e, 0x10];
+    assert_eq!(
+        parse_password_policy(&expiring),
+        Some(PasswordPolicyResponse {
+            time_before_expiration: Some(3600),
+            ..Default::default()
+        })
+    );
+    assert_eq!(
+        parse_password_policy(&[0x30, 0x03, 0x81, 0x01, 0x01]),
+        Some(PasswordPolicyResponse {
+            error: Some(PasswordPolicyError::AccountLocked),
+            ..Default::default()
+        })
+    );
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    backend.set_bind_control(OID_PASSWORD_POLICY, expiring.clone());
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+    ));
+    let (logs, _guard) = common::Logs::capture();
+    let mut client = common::TestClient::spawn(app_state);
+
+    let request = common::bind_request(1, "cn=reader", "password");
+    let ppolicy = LdapControl::PasswordPolicyRequest { criticality: false };
+    client
+        .send(LdapMsg {
+            ctrl: vec![ppolicy.clone()],
+            ..request
+        })
+        .await;
+    let resp = client.recv().await.expect("No bind response");
+    match &resp.op {
+        LdapOp::BindResponse(resp) => assert_eq!(resp.res.code, LdapResultCode::Success),
+        op => panic!("Unexpected bind response {:?}", op),
+    }
+
+    // The request control reaches the backend, and the response control
+    // reaches the client once, with its value intact.
+    let requests = backend.requests();
+    assert_eq!(requests[0].ctrl, vec![ppolicy.clone()]);
+    assert_eq!(resp.ctrl, vec![ppolicy]);
+    assert_eq!(client.take_password_policy(1), Some(expiring));
+
+    let line = logs
+        .line("password policy warning")
+        .expect("No password policy log line");
+    assert!(line.contains("3600"), "{}", line);
+}
+
+#[tokio::test]
+async fn test_memory_cache_pressure() {
+    use ldap3_proto::proto::LdapPartialAttribute;
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::proxy::{self, CachedValue, SearchCacheKey};
+    use ldap_proxy::CacheBackend;
+
+    const SIZE_BYTES: usize = 256 * 1024;
+
+    let mut app_state = common::offline_app_state("");
+    app_state.cache = CacheBackend::memory(SIZE_BYTES).expect("Failed to build cache");
+    let (logs, _guard) = common::Logs::capture();
+
+    let cache_value = |dn: &str, value_len: usize| {
+        let mut entry = common::entry(dn);
+        entry.attributes.push(LdapPartialAttribute {
+            atype: "description".to_string(),
+            vals: vec![vec![b'x'; value_len]],
+        });
+        CachedValue {
+            cached_at: std::time::SystemTime::now(),
+            entries: vec![(entry, vec![])],
+            result: common::ldap_result(LdapResultCode::Success),
+            ctrl: vec![],
+            source_addr: None,
+        }
+    };
+    let set = |base: String, value: CachedValue| {
+        let key = SearchCacheKey::new("cn=reader".to_string(), common::search_request(&base), vec![]);
+        proxy::cache_set(&app_state.cache, &app_state.metrics, key, value, "", None, &None)
+    };
+
+    // Several times what the cache holds, so that older searches are evicted.
+    for i in 0..64 {
+        let base = format!("cn=user{},dc=example,dc=com", i);
+        set(base.clone(), cache_value(&base, 512)).await;
+    }
+    assert!(app_state.metrics.cache_eviction_count() > 0);
+    assert_eq!(app_state.metrics.cache_oversized_entry_count(), 0);
+
+    // A single search larger than the whole cache is reported loudly.
+    let base = "cn=huge,dc=example,dc=com".to_string();
+    set(base.clone(), cache_value(&base, SIZE_BYTES * 2)).await;
+    assert_eq!(app_state.metrics.cache_oversized_entry_count(), 1);
+    let line = logs
+        .line("larger than the whole memory cache")
+        .expect("No oversized entry log line");
+    assert!(line.contains("ERROR") && line.contains("cn=huge"), "{}", line);
+
+    let rendered = app_state.metrics.render();
+    assert!(rendered.contains("cache_evictions_total "), "{}", rendered);
+    assert!(rendered.contains("cache_oversized_entries_total 1"), "{}", rendered);
+}
+
+#[tokio::test]
+async fn test_sync_search_passthrough() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapIntermediateResponse, LdapMsg, LdapOp, SyncRequestMode};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let sync_done = LdapControl::SyncDone {
+        cookie: Some(b"cookie-2".to_vec()),
+        refresh_deletes: false,
+    };
+    let c_sync_done = sync_done.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut resps = common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=synced,dc=example,dc=com")],
+                LdapResultCode::Success,
+            );
+            resps.insert(
+                1,
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::IntermediateResponse(
+                        LdapIntermediateResponse::SyncInfoRefreshPresent {
+                            cookie: Some(b"cookie-1".to_vec()),
+                            done: true,
+                        },
+                    ),
+                    ctrl: vec![],
+                },
+            );
+            if let Some(done) = resps.last_mut() {
+                done.ctrl = vec![c_sync_done.clone()];
+            }
+            resps
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    let sr = common::search_request("dc=example,dc=com");
+    let sync_request = LdapControl::SyncRequest {
+        criticality: true,
+        mode: SyncRequestMode::RefreshOnly,
+        cookie: None,
+        reload_hint: false,
+    };
+    for msgid in [2, 3] {
+        let msgs = client
+            .search_msgs_with_controls(msgid, sr.clone(), vec![sync_request.clone()])
+            .await;
+        let ops: Vec<_> = msgs.iter().map(|msg| &msg.op).collect();
+        assert!(
+            matches!(
+                ops[..],
+                [
+                    LdapOp::SearchResultEntry(_),
+                    LdapOp::IntermediateResponse(
+                        LdapIntermediateResponse::SyncInfoRefreshPresent { done: true, .. }
+                    ),
+                    LdapOp::SearchResultDone(_),
+                ]
+            ),
+            "{:?}",
+            ops
+        );
+        assert_eq!(msgs[2].ctrl, vec![sync_done.clone()]);
+        assert!(msgs.iter().all(|msg| msg.msgid == msgid));
+    }
+
+    // Both went to the backend with the sync control, and neither was cached.
+    let searches: Vec<_> = backend
+        .requests()
+        .into_iter()
+        .filter(|msg| matches!(msg.op, LdapOp::SearchRequest(_)))
+        .collect();
+    assert_eq!(searches.len(), 2);
+    assert!(searches.iter().all(|msg| msg.ctrl == vec![sync_request.clone()]));
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &sr).is_none());
+
+    // The session carries on after the sync search.
+    let (entries, result) = client.search(4, sr).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+}
+
+#[tokio::test]
+async fn test_backend_connect_timeout_per_address() {
+    use ldap_proxy::proxy::{BasicLdapClient, LdapError};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    // With a backlog of 0 and one connection queued, further connects hang.
+    let unresponsive = || {
+        let socket = tokio::net::TcpSocket::new_v4().expect("socket");
+        socket.bind("127.0.0.1:0".parse().expect("addr")).expect("bind");
+        let listener = socket.listen(0).expect("listen");
+        let addr = listener.local_addr().expect("local addr");
+        let queued = std::net::TcpStream::connect(addr).expect("connect");
+        (listener, queued, addr)
+    };
+    let (_slow_listener, _slow_queued, slow) = unresponsive();
+    let (_slower_listener, _slower_queued, slower) = unresponsive();
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = backend.app_state(&format!(
+        r#"
+        [[backend_connect_timeout]]
+        addr = "{}"
+        connect_timeout_ms = 200
+
+        [[backend_connect_timeout]]
+        addr = "{}"
+        connect_timeout_ms = 600
+    "#,
+        slow, slower
+    ));
+    let timeouts = &app_state.connect_timeouts;
+    assert_eq!(timeouts.for_addr(&slow), Duration::from_millis(200));
+    assert_eq!(timeouts.for_addr(&slower), Duration::from_millis(600));
+    assert_eq!(timeouts.for_addr(&backend.addr), Duration::from_secs(5));
+
+    // The unresponsive address is given up on after its own timeout.
+    let started = Instant::now();
+    let client = BasicLdapClient::build(
+        &[slow, backend.addr],
+        &backend.tls_params(),
+        Some("localhost"),
+        None,
+        timeouts,
+    )
+    .await
+    .expect("Failed to connect");
+    assert_eq!(client.peer_addr(), backend.addr);
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(200), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(2), "{:?}", elapsed);
+
+    // Each address waits for as long as its own timeout.
+    let started = Instant::now();
+    let failed = BasicLdapClient::build(
+        &[slow, slower],
+        &backend.tls_params(),
+        Some("localhost"),
+        None,
+        timeouts,
+    )
+    .await;
+    assert!(matches!(failed, Err(LdapError::ConnectError)));
+    let elapsed = started.elapsed();
+    assert!(elapsed >= Duration::from_millis(800), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_secs(3), "{:?}", elapsed);
+}
+
+#[tokio::test]
+async fn test_custom_cache_store() {
+    use futures_util::future::BoxFuture;
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::dn::Dn;
+    use ldap_proxy::proxy::{self, CacheStore, CachedValue, SearchCacheKey};
+    use ldap_proxy::CacheBackend;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Default)]
+    struct MockStore {
+        entries: Mutex<HashMap<SearchCacheKey, CachedValue>>,
+        sets: Mutex<Vec<(String, Option<u64>)>>,
+    }
+
+    impl CacheStore for MockStore {
+        fn get<'a>(
+            &'a self,
+            key: &'a SearchCacheKey,
+            _prefix: &'a str,
+        ) -> BoxFuture<'a, Option<CachedValue>> {
+            Box::pin(async move { self.entries.lock().unwrap().get(key).cloned() })
+        }
+
+        fn set<'a>(
+            &'a self,
+            key: SearchCacheKey,
+            value: CachedValue,
+            prefix: &'a str,
+            ttl: Option<u64>,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(async move {
+                self.sets.lock().unwrap().push((prefix.to_string(), ttl));
+                self.entries.lock().unwrap().insert(key, value);
+            })
+        }
+
+        fn invalidate<'a>(&'a self, _dn: &'a Dn) -> BoxFuture<'a, Result<usize, String>> {
+            Box::pin(async move {
+                let mut entries = self.entries.lock().unwrap();
+                let removed = entries.len();
+                entries.clear();
+                Ok(removed)
+            })
+        }
+    }
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let store = Arc::new(MockStore::default());
+    let mut app_state = backend.app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+    );
+    app_state.cache = CacheBackend::Custom(store.clone());
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let sr = common::search_request("dc=example,dc=com");
+    let (_, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+
+    // The search result went to the store through the trait object.
+    assert_eq!(store.sets.lock().unwrap().len(), 1);
+    let key = SearchCacheKey::new("cn=reader".to_string(), sr, vec![]);
+    let cached = proxy::cache_get(&app_state.cache, &app_state.metrics, &key, "", &None).await;
+    assert!(cached.is_some());
+
+    let removed = proxy::cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "dc=example,dc=com",
+    )
+    .await
+    .expect("Failed to invalidate");
+    assert_eq!(removed, 1);
+    assert!(store.entries.lock().unwrap().is_empty());
+    assert!(proxy::cache_summary(&app_state).is_none());
+}
+
+#[tokio::test]
+async fn test_custom_cache_store_default_invalidate() {
+    use futures_util::future::BoxFuture;
+    use ldap_proxy::proxy::{self, CacheStore, CachedValue, SearchCacheKey};
+    use ldap_proxy::CacheBackend;
+    use std::sync::Arc;
+
+    // A store that keeps nothing and leaves invalidation to the default.
+    struct MockStore;
+
+    impl CacheStore for MockStore {
+        fn get<'a>(
+            &'a self,
+            _key: &'a SearchCacheKey,
+            _prefix: &'a str,
+        ) -> BoxFuture<'a, Option<CachedValue>> {
+            Box::pin(std::future::ready(None))
+        }
+
+        fn set<'a>(
+            &'a self,
+            _key: SearchCacheKey,
+            _value: CachedValue,
+            _prefix: &'a str,
+            _ttl: Option<u64>,
+        ) -> BoxFuture<'a, ()> {
+            Box::pin(std::future::ready(()))
+        }
+    }
+
+    let mut app_state = common::offline_app_state("");
+    app_state.cache = CacheBackend::Custom(Arc::new(MockStore));
+
+    // The caller is told that nothing was invalidated, rather than that
+    // there was nothing stale.
+    let invalidated = proxy::cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "dc=example,dc=com",
+    )
+    .await;
+    assert_eq!(
+        invalidated,
+        Err("invalidation is not supported by this cache store".to_string())
+    );
+}
+
+#[tokio::test]
+async fn test_read_only_dn_write_rejected() {
+    use ldap3_proto::proto::{
+        LdapModify, LdapModifyRequest, LdapModifyType, LdapMsg, LdapOp, LdapPartialAttribute,
+    };
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+
+        ["cn=writer"]
+        read_only = false
+    "#,
+    ));
+    let modify = |msgid| LdapMsg {
+        msgid,
+        op: LdapOp::ModifyRequest(LdapModifyRequest {
+            dn: "uid=a,dc=example,dc=com".to_string(),
+            changes: vec![LdapModify {
+                operation: LdapModifyType::Replace,
+                modification: LdapPartialAttribute {
+                    atype: "mail".to_string(),
+                    vals: vec![b"a@example.com".to_vec()],
+                },
+            }],
+        }),
+        ctrl: vec![],
+    };
+    let is_write = |msg: &LdapMsg| matches!(msg.op, LdapOp::ModifyRequest(_));
+
+    // Read only by default, the write is refused without reaching the
+    // backend and the session carries on.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    client.send(modify(2)).await;
+    match client.recv().await {
+        Some(LdapMsg {
+            msgid: 2,
+            op: LdapOp::ModifyResponse(res),
+            ..
+        }) => assert_eq!(res.code, LdapResultCode::InsufficentAccessRights),
+        msg => panic!("Expected a modify response, got {:?}", msg),
+    }
+    let (_, result) = client.search(3, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert!(!backend.requests().iter().any(is_write));
+
+    // Without read_only the write is not refused for access rights.
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=writer", "password").await, LdapResultCode::Success);
+    client.send(modify(2)).await;
+    match client.recv().await.map(|msg| msg.op) {
+        Some(LdapOp::ModifyResponse(res)) => {
+            assert_ne!(res.code, LdapResultCode::InsufficentAccessRights)
+        }
+        Some(LdapOp::ExtendedResponse(resp)) => {
+            assert_eq!(resp.res.code, LdapResultCode::ProtocolError)
+        }
+        op => panic!("Unexpected response {:?}", op),
+    }
+}
+
+#[tokio::test]
+async fn test_root_dse_supported_control_allowlist() {
+    use ldap3_proto::proto::{LdapOp, LdapPartialAttribute, LdapSearchScope};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    const PAGED: &str = "1.2.840.113556.1.4.319";
+    const SYNC: &str = "1.3.6.1.4.1.4203.1.9.1.1";
+    const VLV: &str = "2.16.840.1.113730.3.4.9";
+
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.is_empty() => {
+            let mut entry = common::entry("");
+            entry.attributes.push(LdapPartialAttribute {
+                atype: "supportedControl".to_string(),
+                vals: [PAGED, SYNC, VLV].iter().map(|oid| oid.as_bytes().to_vec()).collect(),
+            });
+            common::search_response(msg.msgid, vec![entry], LdapResultCode::Success)
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(&format!(
+        r#"
+        supported_controls = ["{}", "{}"]
+        ["cn=reader"]
+    "#,
+        PAGED, SYNC
+    )));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    let root_dse = ldap3_proto::proto::LdapSearchRequest {
+        scope: LdapSearchScope::Base,
+        ..common::search_request("")
+    };
+    let advertised = |entries: Vec<ldap3_proto::proto::LdapSearchResultEntry>| {
+        entries[0]
+            .attributes
+            .iter()
+            .find(|attr| attr.atype == "supportedControl")
+            .expect("No supportedControl")
+            .vals
+            .clone()
+    };
+    let (entries, result) = client.search(2, root_dse.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(advertised(entries), vec![PAGED.as_bytes(), SYNC.as_bytes()]);
+
+    // The cached entry keeps the backend's list and is filtered when served.
+    backend.set_online(false);
+    let (entries, _) = client.search(3, root_dse.clone()).await;
+    assert_eq!(advertised(entries), vec![PAGED.as_bytes(), SYNC.as_bytes()]);
+    let cached = common::memory_cache_get(&app_state, "cn=reader", &root_dse).expect("not cached");
+    assert_eq!(advertised(vec![cached.entries[0].0.clone()]).len(), 3);
+}
+
+fn bind_cache_app_state(backend: &common::MockBackend) -> std::sync::Arc<ldap_proxy::AppState> {
+    std::sync::Arc::new(backend.app_state(
+        r#"
+        [bind_cache]
+        enabled = true
+        ttl_secs = 1
+
+        ["cn=reader"]
+    "#,
+    ))
+}
+
+#[tokio::test]
+async fn test_bind_cache_hit() {
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = bind_cache_app_state(&backend);
+    let bind_cache = app_state.bind_cache.clone().expect("bind_cache is enabled");
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    assert_eq!(backend.bind_count(), 1);
+    common::wait_for_credentials(&bind_cache, "cn=reader").await;
+
+    // The same credentials again are validated by the proxy, quicker than a
+    // kdf would allow, and the session keeps searching on the connection of
+    // its first bind.
+    let started = std::time::Instant::now();
+    assert_eq!(client.bind(2, "cn=reader", "password").await, LdapResultCode::Success);
+    assert!(started.elapsed() < std::time::Duration::from_millis(50), "{:?}", started.elapsed());
+    assert_eq!(backend.bind_count(), 1);
+    let (_, result) = client.search(3, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(backend.connection_count(), 1);
+
+    // Any other password is put to the backend.
+    assert_eq!(client.bind(4, "cn=reader", "other").await, LdapResultCode::Success);
+    assert_eq!(backend.bind_count(), 2);
+}
+
+#[tokio::test]
+async fn test_bind_cache_expires() {
+    use ldap3_proto::proto::{LdapBindResponse, LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+
+    let reject = Arc::new(AtomicBool::new(false));
+    let handler_reject = reject.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg: &LdapMsg| match &msg.op {
+        LdapOp::BindRequest(_) if handler_reject.load(Ordering::SeqCst) => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::BindResponse(LdapBindResponse {
+                res: common::ldap_result(LdapResultCode::InvalidCredentials),
+                saslcreds: None,
+            }),
+            ctrl: vec![],
+        }],
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = bind_cache_app_state(&backend);
+    let bind_cache = app_state.bind_cache.clone().expect("bind_cache is enabled");
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    common::wait_for_credentials(&bind_cache, "cn=reader").await;
+    assert_eq!(client.bind(2, "cn=reader", "password").await, LdapResultCode::Success);
+    assert_eq!(backend.bind_count(), 1);
+
+    // Once the ttl has passed the backend decides again, and a rejection is
+    // not cached.
+    tokio::time::sleep(std::time::Duration::from_millis(1100)).await;
+    reject.store(true, Ordering::SeqCst);
+    assert_eq!(
+        client.bind(3, "cn=reader", "password").await,
+        LdapResultCode::InvalidCredentials
+    );
+    assert_eq!(backend.bind_count(), 2);
+    reject.store(false, Ordering::SeqCst);
+    assert_eq!(client.bind(4, "cn=reader", "password").await, LdapResultCode::Success);
+    assert_eq!(backend.bind_count(), 3);
+}
+
+#[tokio::test]
+async fn test_redis_degraded_mode() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::breaker::CircuitBreaker;
+    use ldap_proxy::proxy::{self, CacheStore, CachedValue, SearchCacheKey, TieredCache};
+    use ldap_proxy::{CacheBackend, RedisCompression};
+    use std::time::Duration;
+
+    let redis = common::MockRedis::start().await;
+    let cache_config: ldap_proxy::CacheConfig = toml::from_str(&format!(
+        r#"
+        type = "redis"
+        url = "redis://{}"
+    "#,
+        redis.addr
+    ))
+    .expect("Failed to parse cache config");
+    let (cache, _) = CacheBackend::from_config(&cache_config, true)
+        .await
+        .expect("Failed to connect to the mock Redis");
+    let breaker = CircuitBreaker::new(2, Duration::from_millis(200)).for_service("Redis");
+    let tiered_cache = TieredCache::for_backend(&cache, "ldap_proxy:", RedisCompression::None, breaker);
+    let tc = tiered_cache.clone().expect("No tiered cache for Redis");
+    let mut app_state = common::offline_app_state("");
+    app_state.cache = cache;
+    app_state.tiered_cache = tiered_cache;
+
+    let key = |base: &str| {
+        SearchCacheKey::new("cn=reader".to_string(), common::search_request(base), vec![])
+    };
+    let value = CachedValue {
+        cached_at: std::time::SystemTime::now(),
+        entries: vec![(common::entry("dc=example,dc=com"), vec![])],
+        result: common::ldap_result(LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+    let degraded = |app_state: &ldap_proxy::AppState| {
+        let rendered = app_state.metrics.render();
+        rendered.lines().find(|line| line.starts_with("redis_degraded ")).map(str::to_string)
+    };
+
+    tc.set(key("dc=example,dc=com"), value.clone(), "ldap_proxy:", None).await;
+    assert!(!tc.is_degraded());
+
+    // Consecutive failures degrade the cache, after which Redis is left alone
+    // and L1 still answers.
+    redis.set_failing(true);
+    assert!(tc.get(&key("ou=a,dc=example,dc=com"), "ldap_proxy:").await.is_none());
+    assert!(tc.get(&key("ou=b,dc=example,dc=com"), "ldap_proxy:").await.is_none());
+    assert!(tc.is_degraded());
+    proxy::sample_cache_metrics(&app_state).await;
+    assert_eq!(degraded(&app_state).as_deref(), Some("redis_degraded 1"));
+
+    let commands = redis.command_count();
+    assert!(tc.get(&key("ou=c,dc=example,dc=com"), "ldap_proxy:").await.is_none());
+    tc.set(key("ou=d,dc=example,dc=com"), value.clone(), "ldap_proxy:", None).await;
+    assert!(tc.get(&key("dc=example,dc=com"), "ldap_proxy:").await.is_some());
+    assert_eq!(redis.command_count(), commands);
+
+    // An invalidation only reaches L1, which the caller is told of rather
+    // than led to believe the stale entries in Redis are gone.
+    let invalidated = proxy::cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "ou=d,dc=example,dc=com",
+    )
+    .await;
+    assert!(invalidated.is_err());
+    assert!(tc.get(&key("ou=d,dc=example,dc=com"), "ldap_proxy:").await.is_none());
+    assert_eq!(redis.command_count(), commands);
+
+    // Once the cooldown passes a probe finds Redis working again.
+    redis.set_failing(false);
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    assert!(tc.get(&key("ou=e,dc=example,dc=com"), "ldap_proxy:").await.is_none());
+    assert!(redis.command_count() > commands);
+    assert!(!tc.is_degraded());
+    proxy::sample_cache_metrics(&app_state).await;
+    assert_eq!(degraded(&app_state).as_deref(), Some("redis_degraded 0"));
+    let invalidated = proxy::cache_invalidate_by_dn(
+        &app_state.cache,
+        &app_state.metrics,
+        &app_state.tiered_cache,
+        "ou=d,dc=example,dc=com",
+    )
+    .await;
+    assert_eq!(invalidated, Ok(0));
+}
+
+#[tokio::test]
+async fn test_redis_key_prefix() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::breaker::CircuitBreaker;
+    use ldap_proxy::proxy::TieredCache;
+    use ldap_proxy::{CacheBackend, RedisCompression};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let redis = common::MockRedis::start().await;
+    let cache_config: ldap_proxy::CacheConfig = toml::from_str(&format!(
+        r#"
+        type = "redis"
+        url = "redis://{}"
+        key_prefix = "other:"
+    "#,
+        redis.addr
+    ))
+    .expect("Failed to parse cache config");
+    let key_prefix = cache_config.key_prefix();
+    assert_eq!(key_prefix, "other:");
+    let (cache, _) = CacheBackend::from_config(&cache_config, true)
+        .await
+        .expect("Failed to connect to the mock Redis");
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let mut app_state = backend.app_state(r#"["cn=reader"]"#);
+    app_state.tiered_cache = TieredCache::for_backend(
+        &cache,
+        &key_prefix,
+        RedisCompression::None,
+        CircuitBreaker::new(2, Duration::from_secs(30)),
+    );
+    app_state.cache = cache;
+    app_state.cache_key_prefix = key_prefix;
+    let app_state = Arc::new(app_state);
+
+    let mut client = common::TestClient::spawn_for_tenant(app_state, "acme");
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let (_, result) = client.search(2, common::search_request("dc=example,dc=com")).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+
+    // The search and its index entry are stored under the configured prefix,
+    // and nothing under the default one.
+    let keys = redis.keys();
+    assert!(keys.iter().any(|key| key.starts_with("other:tenant:acme:")), "{:?}", keys);
+    assert!(keys.iter().any(|key| key.starts_with("other:index:base:")), "{:?}", keys);
+    assert!(!keys.iter().any(|key| key.starts_with("ldap_proxy:")), "{:?}", keys);
+}
+
+#[tokio::test]
+async fn test_redis_base_index_is_trimmed() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::breaker::CircuitBreaker;
+    use ldap_proxy::proxy::{CacheStore, CachedValue, SearchCacheKey, TieredCache};
+    use ldap_proxy::{CacheBackend, RedisCompression};
+    use std::time::Duration;
+
+    let redis = common::MockRedis::start().await;
+    let cache_config: ldap_proxy::CacheConfig = toml::from_str(&format!(
+        r#"
+        type = "redis"
+        url = "redis://{}"
+    "#,
+        redis.addr
+    ))
+    .expect("Failed to parse cache config");
+    let (cache, _) = CacheBackend::from_config(&cache_config, true)
+        .await
+        .expect("Failed to connect to the mock Redis");
+    let tc = TieredCache::for_backend(
+        &cache,
+        "ldap_proxy:",
+        RedisCompression::None,
+        CircuitBreaker::new(2, Duration::from_secs(30)),
+    )
+    .expect("No tiered cache for Redis");
+
+    let value = CachedValue {
+        cached_at: std::time::SystemTime::now(),
+        entries: vec![],
+        result: common::ldap_result(LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+    for base in ["ou=a,dc=example,dc=com", "ou=b,dc=example,dc=com"] {
+        let key = SearchCacheKey::new("cn=reader".to_string(), common::search_request(base), vec![]);
+        CacheStore::set(tc.as_ref(), key, value.clone(), "ldap_proxy:", Some(60)).await;
+    }
+
+    // Each write both adds its search to the index of its base and drops the
+    // members that have expired, so the index does not grow without bound.
+    let index = "ldap_proxy:index:base:ou=a,dc=example,dc=com".to_string();
+    let sent = redis.sent();
+    assert!(sent.contains(&("ZADD".to_string(), index.clone())), "{:?}", sent);
+    assert!(sent.contains(&("ZREMRANGEBYSCORE".to_string(), index.clone())), "{:?}", sent);
+    assert!(sent.contains(&("EXPIRE".to_string(), index)), "{:?}", sent);
+    assert!(!sent.iter().any(|(command, _)| command == "SADD"), "{:?}", sent);
+}
+
+#[tokio::test]
+async fn test_redis_keys_exclude_base_indexes() {
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::breaker::CircuitBreaker;
+    use ldap_proxy::proxy::{self, CacheStore, CachedValue, SearchCacheKey, TieredCache};
+    use ldap_proxy::{CacheBackend, RedisCompression};
+    use std::time::Duration;
+
+    let redis = common::MockRedis::start().await;
+    let cache_config: ldap_proxy::CacheConfig = toml::from_str(&format!(
+        r#"
+        type = "redis"
+        url = "redis://{}"
+    "#,
+        redis.addr
+    ))
+    .expect("Failed to parse cache config");
+    let (cache, _) = CacheBackend::from_config(&cache_config, true)
+        .await
+        .expect("Failed to connect to the mock Redis");
+    let tiered_cache = TieredCache::for_backend(
+        &cache,
+        "ldap_proxy:",
+        RedisCompression::None,
+        CircuitBreaker::new(2, Duration::from_secs(30)),
+    );
+    let tc = tiered_cache.clone().expect("No tiered cache for Redis");
+    let mut app_state = common::offline_app_state("");
+    app_state.cache = cache;
+    app_state.tiered_cache = tiered_cache;
+
+    let value = CachedValue {
+        cached_at: std::time::SystemTime::now(),
+        entries: vec![],
+        result: common::ldap_result(LdapResultCode::Success),
+        ctrl: vec![],
+        source_addr: None,
+    };
+    for base in ["ou=a,dc=example,dc=com", "ou=b,dc=example,dc=com"] {
+        let key = SearchCacheKey::new("cn=reader".to_string(), common::search_request(base), vec![]);
+        CacheStore::set(tc.as_ref(), key, value.clone(), "ldap_proxy:", Some(60)).await;
+    }
+
+    // Two searches and their two indexes are stored, but only the searches
+    // are counted.
+    proxy::sample_cache_metrics(&app_state).await;
+    let rendered = app_state.metrics.render();
+    assert!(rendered.lines().any(|line| line == "redis_keys 2"), "{}", rendered);
+}
+
+#[test]
+fn test_binddn_table() {
+    use ldap_proxy::ConfigError;
+
+    let config = Config::from_toml(&format!(
+        r#"{}
+        [binddn.""]
+
+        [binddn."CN=Reader, DC=example, DC=com"]
+        allowed_bases = ["ou=people,dc=example,dc=com"]
+    "#,
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert_eq!(config.binddn_map.len(), 2);
+    assert!(config.binddn_map.contains_key(""));
+    assert_eq!(
+        config.binddn_map["cn=Reader,dc=example,dc=com"].allowed_bases,
+        vec!["ou=people,dc=example,dc=com"]
+    );
+    assert!(!config.binddn_map.contains_key("binddn"));
+
+    // Filters are checked in the table as at the top level.
+    let invalid = Config::from_toml(&format!(
+        r#"{}
+        [binddn."cn=bad"]
+        allowed_queries = [["dc=example,dc=com", "subtree", "(&(uid=a)"]]
+    "#,
+        common::BASE_CONFIG
+    ));
+    match invalid {
+        Err(ConfigError::InvalidAllowedQuery { bind_dn, .. }) => assert_eq!(bind_dn, "cn=bad"),
+        other => panic!("Unexpected result {:?}", other.map(|_| ())),
+    }
+
+    // A DN in both forms is configured twice.
+    let duplicate = Config::from_toml(&format!(
+        r#"{}
+        [binddn."cn=reader"]
+
+        ["CN=reader"]
+    "#,
+        common::BASE_CONFIG
+    ));
+    assert!(duplicate.is_err());
+}
+
+#[test]
+fn test_binddn_legacy_top_level_tables() {
+    let (logs, _guard) = common::Logs::capture();
+    let config = Config::from_toml(&format!(
+        r#"{}
+        [binddn."cn=reader"]
+
+        ["cn=legacy"]
+        allowed_bases = ["dc=example,dc=com"]
+    "#,
+        common::BASE_CONFIG
+    ))
+    .expect("Failed to parse config");
+    assert!(config.binddn_map.contains_key("cn=reader"));
+    assert_eq!(config.binddn_map["cn=legacy"].allowed_bases, vec!["dc=example,dc=com"]);
+    let line = logs.line("deprecated").expect("No deprecation warning");
+    assert!(line.contains("WARN") && line.contains("cn=legacy"), "{}", line);
+    assert!(!line.contains("cn=reader"), "{}", line);
+}
+
+/// Search the whole tree, returning the DNs of the entries and the uris of
+/// the references received.
+async fn search_with_references(
+    client: &mut common::TestClient,
+    msgid: i32,
+) -> (Vec<String>, Vec<String>) {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use ldap3_proto::LdapResultCode;
+
+    client
+        .send(LdapMsg {
+            msgid,
+            op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+            ctrl: vec![],
+        })
+        .await;
+    let mut dns = Vec::new();
+    let mut uris = Vec::new();
+    loop {
+        match client.recv().await.map(|msg| msg.op) {
+            Some(LdapOp::SearchResultEntry(entry)) => dns.push(entry.dn),
+            Some(LdapOp::SearchResultReference(reference)) => uris.extend(reference.uris),
+            Some(LdapOp::SearchResultDone(result)) => {
+                assert_eq!(result.code, LdapResultCode::Success);
+                return (dns, uris);
+            }
+            other => panic!("Unexpected response {:?}", other),
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_search_result_references() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp, LdapSearchResultReference};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::{Arc, OnceLock};
+
+    let port = Arc::new(OnceLock::new());
+    let c_port = port.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        LdapOp::SearchRequest(sr) if sr.base == "ou=remote,dc=example,dc=com" => {
+            let entries = vec![
+                common::entry("CN=b, DC=example, DC=com"),
+                common::entry("cn=c,dc=example,dc=com"),
+            ];
+            common::search_response(msg.msgid, entries, LdapResultCode::Success)
+        }
+        LdapOp::SearchRequest(_) => {
+            let entries = vec![
+                common::entry("cn=a,dc=example,dc=com"),
+                common::entry("cn=b,dc=example,dc=com"),
+            ];
+            let mut response = common::search_response(msg.msgid, entries, LdapResultCode::Success);
+            let uri = format!(
+                "ldaps://localhost:{}/ou=remote,dc=example,dc=com",
+                c_port.get().expect("No port")
+            );
+            let reference = LdapMsg {
+                msgid: msg.msgid,
+                op: LdapOp::SearchResultReference(LdapSearchResultReference { uris: vec![uri] }),
+                ctrl: vec![],
+            };
+            response.insert(1, reference);
+            response
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    port.set(backend.addr.port()).expect("Port already set");
+    let referral = format!(
+        "ldaps://localhost:{}/ou=remote,dc=example,dc=com",
+        backend.addr.port()
+    );
+
+    // By default the reference is relayed, and the result is not cached.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let (dns, uris) = search_with_references(&mut client, 2).await;
+    assert_eq!(dns, vec!["cn=a,dc=example,dc=com", "cn=b,dc=example,dc=com"]);
+    assert_eq!(uris, vec![referral.clone()]);
+    let sr = common::search_request("dc=example,dc=com");
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &sr).is_none());
+    let (dns, _) = search_with_references(&mut client, 3).await;
+    assert_eq!(dns.len(), 2);
+
+    // References to hosts not in referral_hosts are relayed, without sending
+    // the session's credentials there.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        chase_referrals = true
+        referral_hosts = ["ldap.example.com"]
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let binds = backend.bind_count();
+    let (dns, uris) = search_with_references(&mut client, 2).await;
+    assert_eq!(dns.len(), 2);
+    assert_eq!(uris, vec![referral.clone()]);
+    assert_eq!(backend.bind_count(), binds);
+
+    // Chased, the entries behind it are merged in without duplicates.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        chase_referrals = true
+        referral_hosts = ["LOCALHOST"]
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let binds = backend.bind_count();
+    let (dns, uris) = search_with_references(&mut client, 2).await;
+    assert_eq!(
+        dns,
+        vec![
+            "cn=a,dc=example,dc=com",
+            "cn=b,dc=example,dc=com",
+            "cn=c,dc=example,dc=com"
+        ]
+    );
+    assert!(uris.is_empty());
+    assert_eq!(backend.bind_count(), binds + 1);
+    assert!(common::memory_cache_get(&app_state, "cn=reader", &sr).is_some());
+}
+
+#[tokio::test]
+async fn test_slow_op_logging() {
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    let (logs, _guard) = common::Logs::capture();
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        slow_op_threshold_ms = 200
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let (_, result) = client
+        .search(2, common::search_request("ou=fast,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert!(logs.line("Slow backend operation").is_none());
+
+    backend.set_search_delay(Duration::from_millis(400));
+    let (_, result) = client
+        .search(3, common::search_request("ou=slow,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    let line = logs.line("Slow backend operation").expect("Slow search not logged");
+    assert!(line.contains("WARN"), "{}", line);
+    assert!(line.contains("search") && line.contains("cn=reader"), "{}", line);
+    assert!(line.contains("(objectClass=*)"), "{}", line);
+}
+
+#[tokio::test]
+async fn test_request_limits() {
+    use ldap3_proto::proto::LdapFilter;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_dn_length = 64
+        max_filter_depth = 8
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+
+    let long_dn = format!("cn={},dc=example,dc=com", "a".repeat(64));
+    assert_eq!(client.bind(1, &long_dn, "password").await, LdapResultCode::InvalidDNSyntax);
+    assert_eq!(backend.bind_count(), 0);
+    assert_eq!(client.bind(2, "cn=reader", "password").await, LdapResultCode::Success);
+
+    let nested = |depth: usize| {
+        (1..depth).fold(LdapFilter::Present("objectClass".to_string()), |filter, _| {
+            LdapFilter::Not(Box::new(filter))
+        })
+    };
+    let sr = |depth| ldap3_proto::proto::LdapSearchRequest {
+        filter: nested(depth),
+        ..common::search_request("dc=example,dc=com")
+    };
+    let (_, result) = client.search(3, sr(100)).await;
+    assert_eq!(result.code, LdapResultCode::ProtocolError);
+    let (_, result) = client.search(4, sr(9)).await;
+    assert_eq!(result.code, LdapResultCode::ProtocolError);
+    assert_eq!(backend.search_count(), 0);
+
+    // The session carries on, and filters within the limit are searched.
+    let (_, result) = client.search(5, sr(8)).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 1);
+}
+
+#[tokio::test]
+async fn test_cache_race_timeout() {
+    use ldap3_proto::LdapResultCode;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+
+    let updated = Arc::new(AtomicBool::new(false));
+    let c_updated = updated.clone();
+    let backend = common::MockBackend::start(Arc::new(move |msg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => {
+            let dn = if c_updated.load(Ordering::SeqCst) {
+                "cn=new,dc=example,dc=com"
+            } else {
+                "cn=old,dc=example,dc=com"
+            };
+            common::search_response(msg.msgid, vec![common::entry(dn)], LdapResultCode::Success)
+        }
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_race_timeout_ms = 100
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let sr = common::search_request("dc=example,dc=com");
+
+    // A backend answering within the timeout is waited for.
+    let (entries, _) = client.search(2, sr.clone()).await;
+    assert_eq!(entries[0].dn, "cn=old,dc=example,dc=com");
+
+    // A slow one loses the race to the cached result.
+    updated.store(true, Ordering::SeqCst);
+    backend.set_search_delay(Duration::from_millis(800));
+    let started = Instant::now();
+    let (entries, result) = client.search(3, sr.clone()).await;
+    assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries[0].dn, "cn=old,dc=example,dc=com");
+    assert_eq!(backend.search_count(), 2);
+
+    // The backend search still completes and refreshes the cache.
+    let (entries, _) = client.search(4, sr.clone()).await;
+    assert_eq!(entries[0].dn, "cn=new,dc=example,dc=com");
+
+    // Without a cached result the search waits for the backend.
+    let started = Instant::now();
+    let (entries, _) = client
+        .search(5, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert!(started.elapsed() >= Duration::from_millis(800));
+    assert_eq!(entries[0].dn, "cn=new,dc=example,dc=com");
+
+    // With concurrent searches the session carries on while the backend
+    // search completes.
+    backend.set_search_delay(Duration::ZERO);
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        cache_race_timeout_ms = 100
+        max_concurrent_ops = 2
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let other = common::search_request("ou=people,dc=example,dc=com");
+    client.search(2, sr.clone()).await;
+    client.search(3, other.clone()).await;
+    backend.set_search_delay(Duration::from_millis(800));
+    let started = Instant::now();
+    let (_, result) = client.search(4, sr).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    let (_, result) = client.search(5, other).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert!(started.elapsed() < Duration::from_millis(600), "{:?}", started.elapsed());
+}
+
+#[tokio::test]
+async fn test_allowed_scopes() {
+    use ldap3_proto::proto::{LdapSearchRequest, LdapSearchScope};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(common::default_handler)).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        ["cn=reader"]
+        allowed_bases = ["dc=example,dc=com"]
+        allowed_scopes = ["base", "one_level"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    let sr = |scope| LdapSearchRequest {
+        scope,
+        ..common::search_request("dc=example,dc=com")
+    };
+    let (_, result) = client.search(2, sr(LdapSearchScope::OneLevel)).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 1);
+
+    let (_, result) = client.search(3, sr(LdapSearchScope::Subtree)).await;
+    assert_eq!(result.code, LdapResultCode::UnwillingToPerform);
+    assert_eq!(backend.search_count(), 1);
+
+    // The scope is checked alongside allowed_bases, not instead of it.
+    let (_, result) = client
+        .search(4, LdapSearchRequest {
+            scope: LdapSearchScope::Base,
+            ..common::search_request("o=other")
+        })
+        .await;
+    assert_eq!(result.code, LdapResultCode::InsufficentAccessRights);
+}
+
+#[tokio::test]
+async fn test_maintenance_mode() {
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let (logs, _guard) = common::Logs::capture();
+    let backend = common::MockBackend::start(Arc::new(|msg| match &msg.op {
+        ldap3_proto::proto::LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=a,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        maintenance_bind_action = "reject"
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state.clone());
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    let cached = common::search_request("dc=example,dc=com");
+    client.search(2, cached.clone()).await;
+    assert_eq!(backend.search_count(), 1);
+
+    app_state.set_maintenance(true);
+    assert!(logs.line("Entering maintenance mode").is_some());
+    let (entries, result) = client.search(3, cached.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(entries.len(), 1);
+    let (_, result) = client
+        .search(4, common::search_request("ou=people,dc=example,dc=com"))
+        .await;
+    assert_eq!(result.code, LdapResultCode::Unavailable);
+    assert_eq!(backend.search_count(), 1);
+
+    // Binds are rejected, and the session keeps its earlier bind.
+    let binds = backend.bind_count();
+    assert_eq!(client.bind(5, "cn=reader", "password").await, LdapResultCode::Unavailable);
+    assert_eq!(backend.bind_count(), binds);
+    let (entries, _) = client.search(6, cached.clone()).await;
+    assert_eq!(entries.len(), 1);
+
+    app_state.set_maintenance(false);
+    assert!(logs.line("Leaving maintenance mode").is_some());
+    let (_, result) = client.search(7, cached).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(backend.search_count(), 2);
+}
+
+/// Encode a search with a critical ManageDsaIT control, and patch its oid to
+/// that of permissive modify, which the proxy does not know.
+fn critical_unknown_control_search_bytes(msgid: i32) -> Vec<u8> {
+    use ldap3_proto::proto::{LdapMsg, LdapOp};
+    use tokio_util::bytes::BytesMut;
+    use tokio_util::codec::Encoder;
+
+    let mut buf = BytesMut::new();
+    ldap3_proto::LdapCodec::default()
+        .encode(
+            LdapMsg {
+                msgid,
+                op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+                ctrl: manage_dsa_it(),
+            },
+            &mut buf,
+        )
+        .expect("Failed to encode search");
+    let (from, to) = (b"2.16.840.1.113730.3.4.2", b"1.2.840.113556.1.4.1413");
+    let pos = buf
+        .windows(from.len())
+        .position(|w| w == from)
+        .expect("oid not found");
+    buf[pos..pos + to.len()].copy_from_slice(to);
+    buf.to_vec()
+}
+
+#[tokio::test]
+async fn test_critical_controls() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    for (config, rejected) in [("", true), ("critical_control_action = \"ignore\"\n", false)] {
+        let backend = common::MockBackend::start(Arc::new(manage_dsa_it_handler)).await;
+        let app_state = Arc::new(backend.app_state(&format!("{}[\"cn=reader\"]", config)));
+        let mut client = common::TestClient::spawn(app_state);
+        assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+        // ManageDsaIT is relayed as critical, so it is honored.
+        let (entries, result) = client
+            .search_with_controls(2, common::search_request("dc=example,dc=com"), manage_dsa_it())
+            .await;
+        assert_eq!(result.code, LdapResultCode::Success);
+        assert_eq!(entries[0].dn, "cn=referral,dc=example,dc=com");
+        assert_eq!(backend.search_count(), 1);
+
+        // An unknown control would be relayed without its criticality.
+        client.send_raw(&critical_unknown_control_search_bytes(3)).await;
+        let result = loop {
+            match client.recv().await.map(|msg| msg.op) {
+                Some(LdapOp::SearchResultEntry(_)) => {}
+                Some(LdapOp::SearchResultDone(result)) => break result,
+                op => panic!("Unexpected search response {:?}", op),
+            }
+        };
+        if rejected {
+            assert_eq!(result.code, LdapResultCode::UnavailableCriticalExtension);
+            assert!(result.message.contains("1.2.840.113556.1.4.1413"));
+            assert_eq!(backend.search_count(), 1);
+        } else {
+            assert_eq!(result.code, LdapResultCode::Success);
+            assert_eq!(backend.search_count(), 2);
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_proxy_protocol_modes() {
+    use haproxy_protocol::RemoteAddress;
+    use ldap_proxy::proxy_header::{accept_proxy_header, RefusedHeader};
+    use ldap_proxy::ProxyProtocolMode;
+    use tokio::io::AsyncReadExt;
+
+    let config_str = r#"
+        bind = ["127.0.0.1:3636", "127.0.0.1:3637", "127.0.0.1:3638"]
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+        remote_ip_addr_info = "ProxyV2"
+
+        [proxy_protocol]
+        "127.0.0.1:3637" = "forbid"
+        "127.0.0.1:3638" = "require"
+    "#;
+    let config = toml::from_str::<Config>(config_str).expect("Failed to parse config");
+    let mode = |addr: &str| config.proxy_protocol_for(&addr.parse().expect("invalid address"));
+    // Listeners not listed follow remote_ip_addr_info.
+    assert_eq!(mode("127.0.0.1:3636"), Some(ProxyProtocolMode::Require));
+    assert_eq!(mode("127.0.0.1:3637"), Some(ProxyProtocolMode::Forbid));
+    assert_eq!(mode("127.0.0.1:3638"), Some(ProxyProtocolMode::Require));
+    assert!(!config.binddn_map.contains_key("proxy_protocol"));
+
+    let src: std::net::SocketAddrV4 = "192.0.2.10:50000".parse().expect("Invalid address");
+    let client_hello = [0x16, 0x03, 0x01, 0x00, 0x40, 0x01, 0x00, 0x00, 0x3c, 0x03, 0x03, 0x00]
+        .repeat(2);
+    let mut with_header = proxy_v2_header(src, &[]);
+    with_header.extend_from_slice(&client_hello);
+
+    // Require accepts a connection with a header, leaving the handshake.
+    let mut stream = with_header.as_slice();
+    let hdr = accept_proxy_header(&mut stream, Some(ProxyProtocolMode::Require))
+        .await
+        .expect("Failed to accept proxy header")
+        .expect("No proxy header");
+    assert!(matches!(hdr.remote_addr, RemoteAddress::TcpV4 { src: s, .. } if s == src));
+    let mut rest = Vec::new();
+    stream.read_to_end(&mut rest).await.expect("Failed to read");
+    assert_eq!(rest, client_hello);
+
+    // And refuses one without, rather than trusting the peer address.
+    let mut stream = client_hello.as_slice();
+    let accepted = accept_proxy_header(&mut stream, Some(ProxyProtocolMode::Require)).await;
+    assert!(matches!(accepted, Err(RefusedHeader::Read(_))));
+
+    // Forbid refuses a connection that starts with a header, and leaves the
+    // handshake of one without for TLS.
+    for header in [with_header.as_slice(), b"PROXY TCP4 192.0.2.10 192.0.2.1 50000 636\r\n"] {
+        let mut stream = header;
+        let accepted = accept_proxy_header(&mut stream, Some(ProxyProtocolMode::Forbid)).await;
+        assert!(matches!(accepted, Err(RefusedHeader::Forbidden)));
+    }
+    let mut stream = client_hello.as_slice();
+    let accepted = accept_proxy_header(&mut stream, Some(ProxyProtocolMode::Forbid)).await;
+    assert!(matches!(accepted, Ok(None)));
+    assert_eq!(stream, client_hello.as_slice());
+
+    // Without a mode nothing is read.
+    let mut stream = with_header.as_slice();
+    assert!(matches!(accept_proxy_header(&mut stream, None).await, Ok(None)));
+    assert_eq!(stream.len(), with_header.len());
+}
+
+#[tokio::test]
+async fn test_fallback_result_marker() {
+    use ldap3_proto::proto::LdapOp;
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    let handler: common::Handler = Arc::new(|msg| match &msg.op {
+        LdapOp::SearchRequest(_) => {
+            let mut msgs = common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=cached,dc=example,dc=com")],
+                LdapResultCode::Success,
+            );
+            if let Some(LdapOp::SearchResultDone(result)) = msgs.last_mut().map(|m| &mut m.op) {
+                result.message = "backend says hi".to_string();
+            }
+            msgs
+        }
+        _ => common::default_handler(msg),
+    });
+    let sr = common::search_request("dc=example,dc=com");
+
+    let backend = common::MockBackend::start(handler).await;
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        fallback_result_code = "unavailable"
+        fallback_result_marker = "ldap-proxy-stale"
+        reconnect_backend = true
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+
+    // Live results are relayed as the backend sent them.
+    let (_, result) = client.search(2, sr.clone()).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(result.message, "backend says hi");
+
+    // During an outage the cached entries come with the configured code
+    // and the marker.
+    backend.set_online(false);
+    let (entries, result) = client.search(3, sr.clone()).await;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(result.code, LdapResultCode::Unavailable);
+    let age = result
+        .message
+        .strip_prefix("ldap-proxy-stale age=")
+        .and_then(|rest| rest.strip_suffix(": backend says hi"))
+        .expect("No marker in the message");
+    assert!(age.parse::<u64>().is_ok(), "{}", result.message);
+
+    backend.set_online(true);
+    let (_, result) = client.search(4, sr).await;
+    assert_eq!(result.code, LdapResultCode::Success);
+    assert_eq!(result.message, "backend says hi");
+}
+
+#[tokio::test]
+async fn test_sync_search_persist() {
+    use ldap3_proto::control::LdapControl;
+    use ldap3_proto::proto::{LdapExtendedRequest, LdapMsg, LdapOp, SyncRequestMode};
+    use ldap3_proto::LdapResultCode;
+    use std::sync::Arc;
+
+    // The backend sends the refresh phase and then persists without ending
+    // the search.
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(_) if !msg.ctrl.is_empty() => vec![LdapMsg {
+            msgid: msg.msgid,
+            op: LdapOp::SearchResultEntry(common::entry("cn=synced,dc=example,dc=com")),
+            ctrl: vec![],
+        }],
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let persist = LdapMsg {
+        msgid: 2,
+        op: LdapOp::SearchRequest(common::search_request("dc=example,dc=com")),
+        ctrl: vec![LdapControl::SyncRequest {
+            criticality: true,
+            mode: SyncRequestMode::RefreshAndPersist,
+            cookie: None,
+            reload_hint: false,
+        }],
+    };
+
+    // With serial operations the abandon would never be read.
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    client.send(persist.clone()).await;
+    let msg = client.recv().await.expect("Connection closed");
+    assert_eq!(msg.msgid, 2);
+    assert!(
+        matches!(&msg.op, LdapOp::SearchResultDone(result) if result.code == LdapResultCode::UnwillingToPerform),
+        "{:?}",
+        msg.op
+    );
+    assert_eq!(backend.search_count(), 0);
+
+    // Concurrently it runs until abandoned, and the session carries on.
+    let app_state = Arc::new(backend.app_state(
+        r#"
+        max_concurrent_ops = 2
+        ["cn=reader"]
+    "#,
+    ));
+    let mut client = common::TestClient::spawn(app_state);
+    assert_eq!(client.bind(1, "cn=reader", "password").await, LdapResultCode::Success);
+    client.send(persist).await;
+    let msg = client.recv().await.expect("Connection closed");
+    assert_eq!(msg.msgid, 2);
+    assert!(matches!(msg.op, LdapOp::SearchResultEntry(_)));
+    client
+        .send(LdapMsg {
+            msgid: 3,
+            op: LdapOp::AbandonRequest(2),
+            ctrl: vec![],
+        })
+        .await;
+    client
+        .send(LdapMsg {
+            msgid: 4,
+            op: LdapOp::ExtendedRequest(LdapExtendedRequest {
+                name: "1.3.6.1.4.1.4203.1.11.3".to_string(),
+                value: None,
+            }),
+            ctrl: vec![],
+        })
+        .await;
+    let msg = client.recv().await.expect("Connection closed");
+    assert_eq!(msg.msgid, 4);
+    assert!(matches!(msg.op, LdapOp::ExtendedResponse(_)));
+}
+
+#[tokio::test]
+async fn test_cache_warm_skips_partial_results() {
+    use ldap3_proto::proto::{LdapMsg, LdapOp, LdapSearchResultReference};
+    use ldap3_proto::LdapResultCode;
+    use ldap_proxy::proxy::warm_cache;
+    use std::sync::Arc;
+
+    let backend = common::MockBackend::start(Arc::new(|msg: &LdapMsg| match &msg.op {
+        LdapOp::SearchRequest(sr) if sr.base.starts_with("ou=truncated") => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=some,ou=truncated,dc=example,dc=com")],
+            LdapResultCode::SizeLimitExceeded,
+        ),
+        LdapOp::SearchRequest(sr) if sr.base.starts_with("ou=referred") => {
+            let mut resps = common::search_response(
+                msg.msgid,
+                vec![common::entry("cn=some,ou=referred,dc=example,dc=com")],
+                LdapResultCode::Success,
+            );
+            resps.insert(
+                1,
+                LdapMsg {
+                    msgid: msg.msgid,
+                    op: LdapOp::SearchResultReference(LdapSearchResultReference {
+                        uris: vec!["ldap://other.example.com/ou=rest,dc=example,dc=com".to_string()],
+                    }),
+                    ctrl: vec![],
+                },
+            );
+            resps
+        }
+        LdapOp::SearchRequest(_) => common::search_response(
+            msg.msgid,
+            vec![common::entry("cn=all,ou=people,dc=example,dc=com")],
+            LdapResultCode::Success,
+        ),
+        _ => common::default_handler(msg),
+    }))
+    .await;
+    let config: Config = toml::from_str(
+        r#"
+        bind = "127.0.0.1:3636"
+        tls_chain = "/etc/ldap-proxy/chain.pem"
+        tls_key = "/etc/ldap-proxy/key.pem"
+        ldap_ca = "/etc/ldap-proxy/ldap-ca.pem"
+        ldap_url = "ldaps://ldap.example.com"
+
+        [cache_warm]
+        queries = [
+            ["cn=reader", "ou=truncated,dc=example,dc=com", "subtree", "(objectClass=*)"],
+            ["cn=reader", "ou=referred,dc=example,dc=com", "subtree", "(objectClass=*)"],
+            ["cn=reader", "ou=people,dc=example,dc=com", "subtree", "(objectClass=*)"],
+        ]
+
+        [cache_warm.credentials]
+        "cn=reader" = "password"
+    "#,
+    )
+    .expect("Failed to parse config");
+    let app_state = Arc::new(backend.app_state(r#"["cn=reader"]"#));
+    let cache_warm = config.cache_warm.expect("cache_warm missing");
+    let tenants = ["acme".to_string()].into();
+    warm_cache(app_state.clone(), cache_warm, tenants).await;
+    assert_eq!(backend.search_count(), 3);
+
+    // Neither a truncated result nor one with references is cached, as
+    // neither is the whole answer.
+    let cached = |base| {
+        common::memory_cache_get(&app_state, "cn=reader", &common::search_request(base))
+    };
+    assert!(cached("ou=truncated,dc=example,dc=com").is_none());
+    assert!(cached("ou=referred,dc=example,dc=com").is_none());
+    assert!(cached("ou=people,dc=example,dc=com").is_some());
+
+    // The one query made is cached for each tenant too.
+    let ldap_proxy::CacheBackend::Memory(cache) = &app_state.cache else {
+        panic!("Expected a memory cache");
+    };
+    let key = SearchCacheKey::new(
+        "cn=reader".to_string(),
+        common::search_request("ou=people,dc=example,dc=com"),
+        vec![],
+    );
+    let mut read_txn = cache.read();
+    assert!(read_txn.get(&key.clone().with_tenant(Some("acme".to_string()))).is_some());
+    assert!(read_txn.get(&key.with_tenant(Some("globex".to_string()))).is_none());
+}